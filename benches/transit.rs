@@ -0,0 +1,91 @@
+//! Measure the throughput of an encrypted transit over a local relay server
+//!
+//! Run with `cargo bench --bench transit`. Set `TRANSIT_BENCH_MIB` to change the amount of data
+//! sent per run (default: 256 MiB).
+
+#![allow(deprecated)]
+
+use futures::future;
+use magic_wormhole::{transit, Key};
+use std::time::Instant;
+
+async fn connect(
+    relay_hints: Vec<transit::RelayHint>,
+    abilities: transit::Abilities,
+) -> eyre::Result<(transit::Transit, transit::Transit)> {
+    let key = || Key::<transit::TransitKey>::new(Box::new([7u8; 32].into()));
+    let leader = transit::init(abilities, None, relay_hints.clone()).await?;
+    let follower = transit::init(abilities, None, relay_hints).await?;
+    let (leader_abilities, leader_hints) = (*leader.our_abilities(), leader.our_hints().clone());
+    let (follower_abilities, follower_hints) =
+        (*follower.our_abilities(), follower.our_hints().clone());
+
+    let ((leader, _), (follower, _)) = futures::try_join!(
+        leader.leader_connect(key(), follower_abilities, follower_hints),
+        follower.follower_connect(key(), leader_abilities, leader_hints),
+    )?;
+    Ok((leader, follower))
+}
+
+async fn run(
+    name: &str,
+    relay_hints: Vec<transit::RelayHint>,
+    abilities: transit::Abilities,
+    records: usize,
+) -> eyre::Result<()> {
+    let (mut leader, mut follower) = connect(relay_hints, abilities).await?;
+    let data = vec![0x42; transit::RECORD_SIZE];
+
+    let start = Instant::now();
+    let send = async {
+        for _ in 0..records {
+            leader.send_record(&data).await?;
+        }
+        leader.flush().await
+    };
+    let receive = async {
+        let mut received = 0;
+        while received < records * transit::RECORD_SIZE {
+            received += follower.receive_record_ref().await?.len();
+        }
+        Ok(())
+    };
+    future::try_join(send, receive).await?;
+    let elapsed = start.elapsed();
+
+    let mib = (records * transit::RECORD_SIZE) as f64 / (1024.0 * 1024.0);
+    println!(
+        "{name:<10} {mib:>6.0} MiB in {:>6.2?}: {:>8.1} MiB/s",
+        elapsed,
+        mib / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+fn main() -> eyre::Result<()> {
+    let mib: usize = match std::env::var("TRANSIT_BENCH_MIB") {
+        Ok(mib) => mib.parse()?,
+        Err(_) => 256,
+    };
+    let records = mib * 1024 * 1024 / transit::RECORD_SIZE;
+
+    async_std::task::block_on(async {
+        let relay = transit::server::RelayServer::bind("127.0.0.1:0").await?;
+        let relay_hints = vec![relay.relay_hint()?];
+        async_std::task::spawn(relay.run());
+
+        let secretbox = transit::Abilities {
+            noise_v1: false,
+            ..transit::Abilities::FORCE_RELAY
+        };
+        run("secretbox", relay_hints.clone(), secretbox, records).await?;
+        run(
+            "noise",
+            relay_hints,
+            transit::Abilities::FORCE_RELAY,
+            records,
+        )
+        .await?;
+        Ok(())
+    })
+}
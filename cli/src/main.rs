@@ -13,10 +13,11 @@ use indicatif::{MultiProgress, ProgressBar};
 use magic_wormhole::{
     forwarding, transfer,
     transit::{self, TransitInfo},
+    wordlist::Wordlist,
     MailboxConnection, Wormhole,
 };
 use std::{io::Write, path::PathBuf};
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
 
 fn install_ctrlc_handler(
 ) -> eyre::Result<impl Fn() -> futures::future::BoxFuture<'static, ()> + Clone> {
@@ -63,9 +64,13 @@ struct CommonSenderArgs {
     /// Not allowed when sending more than one file.
     #[arg(long = "rename", visible_alias = "name", value_name = "FILE_NAME")]
     file_name: Option<String>,
+    /// Send a text message instead of a file. Use "-" to read the message from stdin.
+    #[arg(long, value_name = "MESSAGE", conflicts_with_all = ["files", "file_name"])]
+    text: Option<String>,
+    /// Files or folders to send. Use "-" to send the data read from stdin
     #[arg(
         index = 1,
-        required = true,
+        required_unless_present = "text",
         num_args = 1..,
         value_name = "FILENAME|DIRNAME",
         value_hint = clap::ValueHint::AnyPath,
@@ -92,6 +97,88 @@ struct CommonReceiverArgs {
     file_path: PathBuf,
 }
 
+// receive
+#[derive(Debug, Args)]
+struct ReceiveOptions {
+    /// Save received folders as a single archive file instead of unpacking them
+    #[arg(long)]
+    no_extract: bool,
+    /// Continue a previously interrupted transfer instead of starting from scratch (requires transfer-v2 on both sides).
+    /// Files are received into a wormhole-partial-* directory in the output directory, which is kept if the transfer fails
+    /// and removed once it completed. Without this flag, a leftover directory of the same offer is emptied first
+    #[cfg(feature = "experimental-transfer-v2")]
+    #[arg(long)]
+    resume: bool,
+    /// Receive into existing files and folders at the destination, skipping files that are already identical. Nothing gets deleted (requires transfer-v2 on both sides)
+    #[cfg(feature = "experimental-transfer-v2")]
+    #[arg(long, conflicts_with_all = ["resume", "no_extract"])]
+    sync: bool,
+    /// What to do with symlinks in received folders. Symlinks pointing outside of the folder are always skipped
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = SymlinkPolicy::Recreate)]
+    symlinks: SymlinkPolicy,
+    /// Don't restore the modification times and permissions of received files and folders
+    #[arg(long)]
+    no_metadata: bool,
+    /// Only receive the files and folders matching PATTERN, like "folder/src" or "**/*.rs". May be given multiple times (requires transfer-v2 on both sides)
+    #[cfg(feature = "experimental-transfer-v2")]
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<glob::Pattern>,
+    /// Don't receive the files and folders matching PATTERN. May be given multiple times (requires transfer-v2 on both sides)
+    #[cfg(feature = "experimental-transfer-v2")]
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<glob::Pattern>,
+    /// Write the received file to stdout instead of saving it. Folders are written as a single archive if the sender uses transfer-v1
+    #[cfg_attr(
+        feature = "experimental-transfer-v2",
+        arg(long, conflicts_with_all = ["resume", "sync", "file_path"])
+    )]
+    #[cfg_attr(
+        not(feature = "experimental-transfer-v2"),
+        arg(long, conflicts_with = "file_path")
+    )]
+    stdout: bool,
+}
+
+#[cfg(feature = "experimental-transfer-v2")]
+impl ReceiveOptions {
+    /** Whether an offered path got selected with `--include` and `--exclude`. Selecting a folder selects all of its content. */
+    fn is_selected(&self, path: &[String]) -> bool {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        let matches = |patterns: &[glob::Pattern]| {
+            (1..=path.len()).any(|len| {
+                let prefix = path[..len].join("/");
+                patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_with(&prefix, options))
+            })
+        };
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum SymlinkPolicy {
+    /// Create the symlinks
+    Recreate,
+    /// Ignore the symlinks
+    Skip,
+    /// Replace the symlinks with a copy of what they point to
+    Follow,
+}
+
+impl From<SymlinkPolicy> for transfer::offer::SymlinkPolicy {
+    fn from(policy: SymlinkPolicy) -> Self {
+        match policy {
+            SymlinkPolicy::Recreate => Self::Recreate,
+            SymlinkPolicy::Skip => Self::Skip,
+            SymlinkPolicy::Follow => Self::Follow,
+        }
+    }
+}
+
 // receive, connect
 #[derive(Debug, Args)]
 struct CommonFollowerArgs {
@@ -118,9 +205,74 @@ struct CommonArgs {
     /// Disable the relay server support and force a direct connection.
     #[arg(long)]
     force_direct: bool,
-    /// Always route traffic over a relay server. This hides your IP address from the peer (but not from the server operators. Use Tor for that).
+    /// Always route traffic over a relay server. This hides your IP address from the peer (but not from the server operators. Use --tor for that).
     #[arg(long, conflicts_with = "force_direct")]
     force_relay: bool,
+    /// Find the other side on the local network, without any rendezvous or relay server. Both sides need to use it
+    #[arg(
+        long,
+        conflicts_with_all = ["relay_server", "rendezvous_server", "force_relay", "proxy", "tor"],
+    )]
+    lan: bool,
+    /// Use up to N connections in parallel, which can speed up transfers over links with a high latency. Only used if the other side enables it as well
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(1..=16),
+    )]
+    parallel: u8,
+    /// Tunnel all connections through a SOCKS5 or HTTP CONNECT proxy. Direct connections to the peer are disabled. Defaults to $ALL_PROXY unless --lan or --force-direct is given. Hosts listed in $NO_PROXY are connected to directly
+    #[arg(
+        long,
+        conflicts_with = "force_direct",
+        value_name = "socks5://HOSTNAME:PORT",
+        value_hint = clap::ValueHint::Url,
+    )]
+    proxy: Option<magic_wormhole::proxy::Proxy>,
+    /// Route all connections through Tor, so that neither the servers nor the peer learn your IP address. Needs a Tor daemon listening on the default SOCKS port 9050. Takes precedence over --proxy
+    #[arg(long)]
+    tor: bool,
+    /// Publish a temporary onion service using this control port of the Tor daemon, so that the peer can connect to us directly through Tor
+    #[arg(
+        long,
+        requires = "tor",
+        value_name = "PORT",
+        num_args = 0..=1,
+        default_missing_value = "9051",
+    )]
+    tor_control_port: Option<u16>,
+    /// Take the words of the code from a custom wordlist: a text file with one word per line, or a JSON file. The receiving side may use it for tab completion
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    wordlist: Option<PathBuf>,
+    /// Compare a verifier with the other side before transferring anything
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "hex"
+    )]
+    verify: Option<VerifierFormat>,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum VerifierFormat {
+    /* Like the Python implementation */
+    Hex,
+    Words,
+    Emoji,
+}
+
+impl From<VerifierFormat> for magic_wormhole::VerifierFormat {
+    fn from(format: VerifierFormat) -> Self {
+        match format {
+            VerifierFormat::Hex => Self::Hex,
+            VerifierFormat::Words => Self::Words,
+            VerifierFormat::Emoji => Self::Emoji,
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -166,7 +318,7 @@ enum ForwardCommand {
 
 #[derive(Debug, Subcommand)]
 enum WormholeCommand {
-    /// Send a file or a folder
+    /// Send a file, a folder or a text message
     #[command(visible_alias = "tx")]
     Send {
         #[clap(flatten)]
@@ -176,13 +328,15 @@ enum WormholeCommand {
         #[clap(flatten)]
         common_send: CommonSenderArgs,
     },
-    /// Receive a file or a folder
+    /// Receive a file, a folder or a text message
     #[command(visible_alias = "rx")]
     Receive {
         /// Accept file transfer without asking for confirmation
         #[arg(long, visible_alias = "yes")]
         noconfirm: bool,
         #[command(flatten)]
+        options: ReceiveOptions,
+        #[command(flatten)]
         common: CommonArgs,
         #[command(flatten)]
         common_follower: CommonFollowerArgs,
@@ -217,6 +371,35 @@ enum WormholeCommand {
     /// Forward ports from one machine to another
     #[command(subcommand)]
     Forward(ForwardCommand),
+    /// Run a rendezvous server
+    #[command(
+        after_help = "Clients connect to it with `--rendezvous-server ws://HOSTNAME:PORT/v1`. \
+        All state is kept in memory."
+    )]
+    Server {
+        /// The address to listen on
+        #[arg(long, value_name = "ADDRESS", default_value = "[::]:4000")]
+        listen: String,
+        /// A message to show to all clients when they connect
+        #[arg(long, value_name = "MESSAGE")]
+        motd: Option<String>,
+    },
+    /// Run a transit relay server
+    #[command(
+        after_help = "Clients use it with `--relay-server tcp://HOSTNAME:PORT` \
+        (browser clients use the WebSocket address, `ws://HOSTNAME:PORT`)."
+    )]
+    Relay {
+        /// The address to listen on for TCP connections
+        #[arg(long, value_name = "ADDRESS", default_value = "[::]:4001")]
+        listen: String,
+        /// Also listen for WebSocket connections on this address
+        #[arg(long, value_name = "ADDRESS")]
+        websocket: Option<String>,
+        /// Close connections after this many seconds without any traffic
+        #[arg(long, value_name = "SECONDS", default_value_t = 600)]
+        idle_timeout: u64,
+    },
     /// Generate shell completions for the wormhole CLI
     #[command(hide = true)]
     Completion {
@@ -260,10 +443,28 @@ async fn main() -> eyre::Result<()> {
 
     let app = WormholeCli::parse();
 
-    let mut term = Term::stdout();
+    /* When the received data goes to stdout, everything else must go to stderr */
+    let to_stdout = matches!(
+        &app.command,
+        WormholeCommand::Receive {
+            options: ReceiveOptions { stdout: true, .. },
+            ..
+        }
+    );
+    let mut term = if to_stdout {
+        Term::stderr()
+    } else {
+        Term::stdout()
+    };
+    let log_writer = if to_stdout {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
 
     if app.log {
         tracing_subscriber::fmt()
+            .with_writer(log_writer)
             .with_max_level(tracing::Level::TRACE)
             .with_env_filter(EnvFilter::new(
                 "magic_wormhole::core=trace,mio=debug,ws=error",
@@ -273,6 +474,7 @@ async fn main() -> eyre::Result<()> {
         tracing::trace!("Logging enabled.");
     } else {
         tracing_subscriber::fmt()
+            .with_writer(log_writer)
             .with_max_level(tracing::Level::INFO)
             .with_env_filter(EnvFilter::new("mio=debug"))
             .with_target(false)
@@ -289,12 +491,29 @@ async fn main() -> eyre::Result<()> {
         WormholeCommand::Send {
             common,
             common_leader: CommonLeaderArgs { code, code_length },
-            common_send: CommonSenderArgs { file_name, files },
+            common_send:
+                CommonSenderArgs {
+                    file_name,
+                    text,
+                    files,
+                },
             ..
         } => {
-            let offer = make_send_offer(files, file_name).await?;
+            /* Answering the verification prompt would eat data from stdin */
+            eyre::ensure!(
+                common.verify.is_none()
+                    || (text.as_deref() != Some("-")
+                        && !files.iter().any(|file| file.as_os_str() == "-")),
+                "--verify can't be used when sending from stdin"
+            );
+            let payload = make_send_payload(files, file_name, read_text_arg(text)?).await?;
 
             let transit_abilities = parse_transit_args(&common);
+            let print_code: &PrintCodeFn = if common.lan {
+                &sender_print_lan_code
+            } else {
+                &sender_print_code
+            };
             let (wormhole, _code, relay_hints) = match util::cancellable(
                 Box::pin(parse_and_connect(
                     &mut term,
@@ -303,7 +522,7 @@ async fn main() -> eyre::Result<()> {
                     Some(code_length),
                     true,
                     transfer::APP_CONFIG,
-                    Some(&sender_print_code),
+                    Some(print_code),
                     clipboard.as_mut(),
                 )),
                 ctrl_c(),
@@ -317,7 +536,7 @@ async fn main() -> eyre::Result<()> {
             Box::pin(send(
                 wormhole,
                 relay_hints,
-                offer,
+                payload,
                 transit_abilities,
                 ctrl_c.clone(),
             ))
@@ -329,10 +548,29 @@ async fn main() -> eyre::Result<()> {
             timeout,
             common,
             common_leader: CommonLeaderArgs { code, code_length },
-            common_send: CommonSenderArgs { file_name, files },
+            common_send:
+                CommonSenderArgs {
+                    file_name,
+                    text,
+                    files,
+                },
             ..
         } => {
+            eyre::ensure!(
+                !files.iter().any(|file| file.as_os_str() == "-"),
+                "Sending from stdin to multiple people is not supported"
+            );
+            eyre::ensure!(
+                common.verify.is_none(),
+                "--verify is not supported when sending to multiple people"
+            );
+            eyre::ensure!(
+                !common.lan,
+                "--lan is not supported when sending to multiple people"
+            );
+            let text = read_text_arg(text)?;
             let transit_abilities = parse_transit_args(&common);
+            let proxy = parse_proxy_args(&common);
             let (wormhole, code, relay_hints) = {
                 let connect_fut = Box::pin(parse_and_connect(
                     &mut term,
@@ -356,17 +594,20 @@ async fn main() -> eyre::Result<()> {
                 &code,
                 files,
                 file_name,
+                text,
                 tries,
                 timeout,
                 wormhole,
                 &mut term,
                 transit_abilities,
+                proxy,
                 ctrl_c,
             ))
             .await?;
         },
         WormholeCommand::Receive {
             noconfirm,
+            options,
             common,
             common_follower: CommonFollowerArgs { code },
             common_receiver: CommonReceiverArgs { file_path },
@@ -395,6 +636,7 @@ async fn main() -> eyre::Result<()> {
                 relay_hints,
                 &file_path,
                 noconfirm,
+                &options,
                 transit_abilities,
                 ctrl_c,
             ))
@@ -417,7 +659,7 @@ async fn main() -> eyre::Result<()> {
                         /* Either HOST:PORT or PORT */
                         if target.contains(':') {
                             /* Extract the :PORT at the end */
-                            let port = target.split(':').last().unwrap();
+                            let port = target.split(':').next_back().unwrap();
                             let host = url::Host::parse(&target[..target.len() - port.len() - 1])
                                 .map_err(eyre::Error::from)
                                 .context("Invalid host")?;
@@ -515,6 +757,55 @@ async fn main() -> eyre::Result<()> {
                 offer.reject().await?;
             }
         },
+        WormholeCommand::Server { listen, motd } => {
+            let mut server = magic_wormhole::rendezvous::server::MailboxServer::bind(&*listen)
+                .await
+                .with_context(|| format!("Failed to listen on {listen}"))?;
+            if let Some(motd) = motd {
+                server = server.motd(motd);
+            }
+            writeln!(term, "Rendezvous server listening on {}", server.url()?)?;
+            match util::cancellable(Box::pin(server.run()), ctrl_c()).await {
+                Ok(result) => result.context("Rendezvous server failed")?,
+                Err(_) => return Ok(()),
+            }
+        },
+        WormholeCommand::Relay {
+            listen,
+            websocket,
+            idle_timeout,
+        } => {
+            use magic_wormhole::transit::server::RelayServer;
+            let mut server = RelayServer::bind(&*listen)
+                .await
+                .with_context(|| format!("Failed to listen on {listen}"))?
+                .idle_timeout(std::time::Duration::from_secs(idle_timeout));
+            if let Some(websocket) = websocket {
+                server = server
+                    .websocket(&*websocket)
+                    .await
+                    .with_context(|| format!("Failed to listen on {websocket}"))?;
+            }
+            writeln!(term, "Transit relay listening on {}", server.local_addr()?)?;
+            if let Some(address) = server.websocket_addr() {
+                writeln!(term, "WebSocket relay listening on {}", address?)?;
+            }
+            let usage = server.usage();
+            match util::cancellable(Box::pin(server.run()), ctrl_c()).await {
+                Ok(result) => result.context("Transit relay failed")?,
+                Err(_) => {
+                    let usage = usage.get();
+                    writeln!(
+                        term,
+                        "Relayed {} sessions ({}) from {} connections",
+                        usage.sessions,
+                        indicatif::HumanBytes(usage.bytes_relayed),
+                        usage.connections,
+                    )?;
+                    return Ok(());
+                },
+            }
+        },
         WormholeCommand::Completion { shell } => {
             let mut cmd = WormholeCli::command();
             let binary_name = cmd.get_name().to_string();
@@ -552,14 +843,57 @@ async fn main() -> eyre::Result<()> {
 }
 
 fn parse_transit_args(args: &CommonArgs) -> transit::Abilities {
-    match (args.force_direct, args.force_relay) {
+    let abilities = match (args.force_direct || args.lan, args.force_relay) {
         (false, false) => transit::Abilities::ALL,
         (true, false) => transit::Abilities::FORCE_DIRECT,
         (false, true) => transit::Abilities::FORCE_RELAY,
         (true, true) => unreachable!("These flags are mutually exclusive"),
+    };
+    transit::Abilities {
+        parallel_v1: args.parallel,
+        ..abilities
     }
 }
 
+fn parse_proxy_args(args: &CommonArgs) -> Option<magic_wormhole::proxy::Proxy> {
+    if args.tor {
+        let tor = magic_wormhole::proxy::Proxy::tor();
+        Some(match args.tor_control_port {
+            Some(port) => tor.with_tor_control_port(port),
+            None => tor,
+        })
+    } else if let Some(proxy) = &args.proxy {
+        Some(with_no_proxy_env(proxy.clone()))
+    } else if args.lan || args.force_direct {
+        None
+    } else {
+        let all_proxy = env_var_any_case("ALL_PROXY")?;
+        match all_proxy.parse::<magic_wormhole::proxy::Proxy>() {
+            Ok(proxy) => Some(with_no_proxy_env(proxy)),
+            Err(error) => {
+                /* Don't print the value, it may contain credentials */
+                tracing::warn!("Ignoring the proxy in $ALL_PROXY: {}", error);
+                None
+            },
+        }
+    }
+}
+
+fn with_no_proxy_env(proxy: magic_wormhole::proxy::Proxy) -> magic_wormhole::proxy::Proxy {
+    match env_var_any_case("NO_PROXY") {
+        Some(no_proxy) => proxy.with_no_proxy(&no_proxy),
+        None => proxy,
+    }
+}
+
+/// Read an environment variable in its upper or lower case spelling, like curl does
+fn env_var_any_case(name: &str) -> Option<String> {
+    std::env::var(name)
+        .or_else(|_| std::env::var(name.to_ascii_lowercase()))
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
 type PrintCodeFn = dyn Fn(&mut Term, &magic_wormhole::Code, &Option<url::Url>) -> eyre::Result<()>;
 
 /**
@@ -580,13 +914,15 @@ async fn parse_and_connect(
     print_code: Option<&PrintCodeFn>,
     clipboard: Option<&mut Clipboard>,
 ) -> eyre::Result<(Wormhole, magic_wormhole::Code, Vec<transit::RelayHint>)> {
+    let verify = common_args.verify;
+    let proxy = parse_proxy_args(&common_args);
     // TODO handle relay servers with multiple endpoints better
     let mut relay_hints: Vec<transit::RelayHint> = common_args
         .relay_server
         .into_iter()
         .map(|url| transit::RelayHint::from_urls(url.host_str().map(str::to_owned), [url]))
         .collect::<Result<_, transit::RelayHintParseError>>()?;
-    if relay_hints.is_empty() {
+    if relay_hints.is_empty() && !common_args.lan {
         relay_hints.push(transit::RelayHint::from_urls(
             None,
             [magic_wormhole::transit::DEFAULT_RELAY_SERVER
@@ -594,19 +930,64 @@ async fn parse_and_connect(
                 .unwrap()],
         )?)
     }
-    let code = code
-        .map(Result::Ok)
-        .or_else(|| (!is_send).then(enter_code))
-        .transpose()?
-        .map(magic_wormhole::Code);
-
     /* We need to track that information for when we generate a QR code */
     let mut uri_rendezvous = None;
     if let Some(rendezvous_server) = common_args.rendezvous_server {
         uri_rendezvous = Some(rendezvous_server.clone());
         app_config = app_config.rendezvous_url(rendezvous_server.to_string().into());
     }
+    if let Some(proxy) = proxy {
+        tracing::info!("Connecting through proxy {}", proxy);
+        app_config = app_config.proxy(Some(proxy));
+    }
+
+    let num_words = code_length.unwrap_or(2);
+    let wordlist = match &common_args.wordlist {
+        Some(path) => {
+            let wordlist = Wordlist::from_file(num_words, path)
+                .with_context(|| format!("Failed to load the wordlist {}", path.display()))?;
+            if is_send && code.is_none() {
+                writeln!(
+                    term,
+                    "The code has {:.1} bits of entropy ({:.1} bits per word)",
+                    wordlist.entropy(),
+                    wordlist.entropy_per_word()
+                )?;
+                if wordlist.entropy() < 16.0 {
+                    writeln!(
+                        term,
+                        "{} Codes with less than 16 bits of entropy are easy to guess, consider a longer --code-length",
+                        style("Warning:").yellow().bold()
+                    )?;
+                }
+            }
+            wordlist
+        },
+        None => magic_wormhole::wordlist::default_wordlist(num_words),
+    };
+
+    let code = match code {
+        Some(code) => Some(code),
+        None if !is_send => {
+            /* Suggest the nameplates that are currently in use while typing */
+            let nameplates = if common_args.lan {
+                Vec::new()
+            } else {
+                MailboxConnection::list_nameplates(&app_config)
+                    .await
+                    .map_err(|err| tracing::warn!("Failed to list nameplates: {}", err))
+                    .unwrap_or_default()
+            };
+            Some(enter_code(&nameplates, wordlist.clone())?)
+        },
+        None => None,
+    }
+    .map(magic_wormhole::Code);
     let mailbox_connection = match code {
+        Some(_) if is_send && common_args.lan => {
+            eyre::bail!("--code can't be used with --lan, the nameplate gets picked randomly")
+        },
+        Some(code) if common_args.lan => MailboxConnection::connect_lan(app_config, code).await?,
         Some(code) => {
             if is_send {
                 print_code.expect("`print_code` must be `Some` when `is_send` is `true`")(
@@ -618,8 +999,12 @@ async fn parse_and_connect(
             MailboxConnection::connect(app_config, code, true).await?
         },
         None => {
-            let mailbox_connection =
-                MailboxConnection::create(app_config, code_length.unwrap()).await?;
+            let mailbox_connection = if common_args.lan {
+                MailboxConnection::create_lan_with_password(app_config, &wordlist.choose_words())
+                    .await?
+            } else {
+                MailboxConnection::create_with_wordlist(app_config, &wordlist).await?
+            };
 
             /* Print code and also copy it to clipboard */
             if is_send {
@@ -642,13 +1027,66 @@ async fn parse_and_connect(
     print_welcome(term, mailbox_connection.welcome())?;
     let code = mailbox_connection.code().clone();
     let wormhole = Wormhole::connect(mailbox_connection).await?;
+    if let Some(format) = verify {
+        writeln!(
+            term,
+            "Verifier: {}",
+            style(wormhole.verifier_string(format.into())).bold()
+        )?;
+        if !util::ask_user("Does it match the verifier shown on the other side?", false).await {
+            wormhole.close().await?;
+            eyre::bail!("Verification rejected, abandoning the transfer");
+        }
+    }
     eyre::Result::<_>::Ok((wormhole, code, relay_hints))
 }
 
+/// What to send: either some files or a text message
+enum SendPayload {
+    Offer(transfer::offer::OfferSend),
+    Text(String),
+}
+
+/// Resolve the `--text` argument, reading the message from stdin if it is "-"
+fn read_text_arg(text: Option<String>) -> eyre::Result<Option<String>> {
+    match text.as_deref() {
+        Some("-") => {
+            tracing::info!("Reading text message from stdin...");
+            let text = std::io::read_to_string(std::io::stdin())
+                .context("Failed to read text message from stdin")?;
+            Ok(Some(text))
+        },
+        _ => Ok(text),
+    }
+}
+
+async fn make_send_payload(
+    files: Vec<PathBuf>,
+    file_name: Option<String>,
+    text: Option<String>,
+) -> eyre::Result<SendPayload> {
+    match text {
+        Some(text) => Ok(SendPayload::Text(text)),
+        None => Ok(SendPayload::Offer(make_send_offer(files, file_name).await?)),
+    }
+}
+
 async fn make_send_offer(
     mut files: Vec<PathBuf>,
     file_name: Option<String>,
 ) -> eyre::Result<transfer::offer::OfferSend> {
+    if files.iter().any(|file| file.as_os_str() == "-") {
+        eyre::ensure!(
+            files.len() == 1,
+            "Can't send stdin together with other files"
+        );
+        tracing::info!("Sending the data read from stdin");
+        return Ok(transfer::offer::OfferSend::new_stream(
+            file_name.unwrap_or_else(|| "stdin".into()),
+            async_std::io::stdin(),
+        ));
+    }
+
     for file in &files {
         eyre::ensure!(
             async_std::path::Path::new(&file).exists().await,
@@ -708,6 +1146,19 @@ fn create_progress_bar(file_size: u64) -> ProgressBar {
     pb
 }
 
+/// For streams, the total size is not known in advance
+fn create_stream_progress_bar() -> ProgressBar {
+    use indicatif::ProgressStyle;
+
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(
+        ProgressStyle::default_spinner()
+            .template("[{elapsed_precise}] {spinner} {bytes} ({bytes_per_sec})")
+            .unwrap(),
+    );
+    pb
+}
+
 fn create_progress_handler(pb: ProgressBar) -> impl FnMut(u64, u64) {
     move |sent, total| {
         if sent == 0 {
@@ -719,15 +1170,60 @@ fn create_progress_handler(pb: ProgressBar) -> impl FnMut(u64, u64) {
     }
 }
 
-fn enter_code() -> eyre::Result<String> {
+fn enter_code(
+    nameplates: &[magic_wormhole::Nameplate],
+    wordlist: Wordlist,
+) -> eyre::Result<String> {
     use dialoguer::Input;
 
+    let completion = CodeCompletion {
+        nameplates: nameplates.iter().map(ToString::to_string).collect(),
+        wordlist,
+    };
     Input::new()
-        .with_prompt("Enter code")
+        .with_prompt("Enter code (press Tab to complete)")
+        .completion_with(&completion)
         .interact_text()
         .map_err(From::from)
 }
 
+/// Complete codes while typing them: first the nameplate, then the words
+struct CodeCompletion {
+    nameplates: Vec<String>,
+    wordlist: Wordlist,
+}
+
+impl dialoguer::Completion for CodeCompletion {
+    fn get(&self, input: &str) -> Option<String> {
+        let candidates: Vec<String> = match input.split_once('-') {
+            None => self
+                .nameplates
+                .iter()
+                .filter(|nameplate| nameplate.starts_with(input))
+                .map(|nameplate| format!("{nameplate}-"))
+                .collect(),
+            Some((nameplate, words)) => self
+                .wordlist
+                .get_completions(words)
+                .into_iter()
+                .map(|words| format!("{nameplate}-{words}"))
+                .collect(),
+        };
+
+        /* Complete as far as all candidates agree */
+        let (first, rest) = candidates.split_first()?;
+        let common = rest.iter().fold(first.as_str(), |common, candidate| {
+            let len = common
+                .char_indices()
+                .zip(candidate.chars())
+                .find(|((_, a), b)| a != b)
+                .map_or(common.len(), |((i, _), _)| i);
+            &common[..len]
+        });
+        (common.len() > input.len()).then(|| common.to_owned())
+    }
+}
+
 fn print_welcome(term: &mut Term, welcome: Option<&str>) -> eyre::Result<()> {
     if let Some(welcome) = &welcome {
         writeln!(term, "Got welcome from server: {}", welcome)?;
@@ -770,6 +1266,26 @@ fn sender_print_code(
     Ok(())
 }
 
+// For --lan, where links and other clients don't help
+fn sender_print_lan_code(
+    term: &mut Term,
+    code: &magic_wormhole::Code,
+    _: &Option<url::Url>,
+) -> eyre::Result<()> {
+    writeln!(
+        term,
+        "\nThis wormhole's code is: {} (it has been copied to your clipboard)",
+        style(&code).bold()
+    )?;
+    writeln!(
+        term,
+        "On the other side, enter that code on the same network with: {} {}\n",
+        style("wormhole-rs receive --lan").bold(),
+        style(&code).bold()
+    )?;
+    Ok(())
+}
+
 // For port forwarding
 fn server_print_code(
     term: &mut Term,
@@ -793,11 +1309,25 @@ fn server_print_code(
 async fn send(
     wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
-    offer: transfer::offer::OfferSend,
+    payload: SendPayload,
     transit_abilities: transit::Abilities,
     ctrl_c: impl Fn() -> futures::future::BoxFuture<'static, ()>,
 ) -> eyre::Result<()> {
-    let pb = create_progress_bar(0);
+    let offer = match payload {
+        SendPayload::Offer(offer) => offer,
+        SendPayload::Text(text) => {
+            transfer::send_text(wormhole, text, ctrl_c())
+                .await
+                .context("Send process failed")?;
+            tracing::info!("Text message sent");
+            return Ok(());
+        },
+    };
+    let pb = if offer.has_streams() {
+        create_stream_progress_bar()
+    } else {
+        create_progress_bar(0)
+    };
     let pb2 = pb.clone();
     transfer::send(
         wormhole,
@@ -819,11 +1349,13 @@ async fn send_many(
     code: &magic_wormhole::Code,
     files: Vec<PathBuf>,
     file_name: Option<String>,
+    text: Option<String>,
     max_tries: u64,
     timeout: Duration,
     wormhole: Wormhole,
     term: &mut Term,
     transit_abilities: transit::Abilities,
+    proxy: Option<magic_wormhole::proxy::Proxy>,
     ctrl_c: impl Fn() -> futures::future::BoxFuture<'static, ()>,
 ) -> eyre::Result<()> {
     tracing::warn!("Reminder that you are sending the file to multiple people, and this may reduce the overall security. See the help page for more information.");
@@ -838,7 +1370,7 @@ async fn send_many(
     /* Special-case the first send with reusing the existing connection */
     send_in_background(
         relay_hints.clone(),
-        make_send_offer(files.clone(), file_name.clone()).await?,
+        make_send_payload(files.clone(), file_name.clone(), text.clone()).await?,
         wormhole,
         term.clone(),
         &mp,
@@ -861,13 +1393,18 @@ async fn send_many(
         }
 
         let wormhole = Wormhole::connect(
-            MailboxConnection::connect(transfer::APP_CONFIG, code.clone(), false).await?,
+            MailboxConnection::connect(
+                transfer::APP_CONFIG.proxy(proxy.clone()),
+                code.clone(),
+                false,
+            )
+            .await?,
         )
         .await?;
 
         send_in_background(
             relay_hints.clone(),
-            make_send_offer(files.clone(), file_name.clone()).await?,
+            make_send_payload(files.clone(), file_name.clone(), text.clone()).await?,
             wormhole,
             term.clone(),
             &mp,
@@ -879,35 +1416,43 @@ async fn send_many(
 
     async fn send_in_background(
         relay_hints: Vec<transit::RelayHint>,
-        offer: transfer::offer::OfferSend,
+        payload: SendPayload,
         wormhole: Wormhole,
         mut term: Term,
         mp: &MultiProgress,
         transit_abilities: transit::Abilities,
         cancel: impl Future<Output = ()> + Send + 'static,
     ) -> eyre::Result<()> {
-        writeln!(&mut term, "Sending file to peer").unwrap();
+        match &payload {
+            SendPayload::Offer(_) => writeln!(&mut term, "Sending file to peer").unwrap(),
+            SendPayload::Text(_) => writeln!(&mut term, "Sending text message to peer").unwrap(),
+        }
         let pb = create_progress_bar(0);
         let pb = mp.add(pb);
         async_std::task::spawn(async move {
             let pb2 = pb.clone();
             let result = async move {
-                transfer::send(
-                    wormhole,
-                    relay_hints,
-                    transit_abilities,
-                    offer,
-                    &transit_handler,
-                    create_progress_handler(pb2),
-                    cancel,
-                )
-                .await?;
+                match payload {
+                    SendPayload::Offer(offer) => {
+                        transfer::send(
+                            wormhole,
+                            relay_hints,
+                            transit_abilities,
+                            offer,
+                            &transit_handler,
+                            create_progress_handler(pb2),
+                            cancel,
+                        )
+                        .await?
+                    },
+                    SendPayload::Text(text) => transfer::send_text(wormhole, text, cancel).await?,
+                }
                 eyre::Result::<_>::Ok(())
             };
             match result.await {
                 Ok(_) => {
                     pb.finish();
-                    tracing::info!("Successfully sent file to someone");
+                    tracing::info!("Successfully sent to someone");
                 },
                 Err(e) => {
                     pb.abandon();
@@ -926,37 +1471,66 @@ async fn receive(
     relay_hints: Vec<transit::RelayHint>,
     target_dir: &std::path::Path,
     noconfirm: bool,
+    options: &ReceiveOptions,
     transit_abilities: transit::Abilities,
     ctrl_c: impl Fn() -> futures::future::BoxFuture<'static, ()>,
 ) -> eyre::Result<()> {
-    #[cfg(not(feature = "experimental-transfer-v2"))]
-    {
-        let req = transfer::request_file(wormhole, relay_hints, transit_abilities, ctrl_c())
-            .await
-            .context("Could not get an offer")?;
-        /* If None, the task got cancelled */
-        if let Some(req) = req {
-            receive_inner_v1(req, target_dir, noconfirm, ctrl_c).await
-        } else {
+    let req = transfer::request_text_or_file(wormhole, relay_hints, transit_abilities, ctrl_c())
+        .await
+        .context("Could not get an offer")?;
+
+    match req {
+        Some(transfer::TextOrFile::Text(text)) => {
+            use std::io::IsTerminal;
+            if std::io::stdout().is_terminal() {
+                println!("{}", escape_control_chars(&text));
+            } else {
+                println!("{}", text);
+            }
             Ok(())
-        }
+        },
+        #[cfg(not(feature = "experimental-transfer-v2"))]
+        Some(transfer::TextOrFile::File(req)) => {
+            receive_inner_v1(req, target_dir, noconfirm, options, ctrl_c).await
+        },
+        #[cfg(feature = "experimental-transfer-v2")]
+        Some(transfer::TextOrFile::File(transfer::ReceiveRequest::V1(req))) => {
+            warn_v2_only(options);
+            receive_inner_v1(req, target_dir, noconfirm, options, ctrl_c).await
+        },
+        #[cfg(feature = "experimental-transfer-v2")]
+        Some(transfer::TextOrFile::File(transfer::ReceiveRequest::V2(req))) => {
+            receive_inner_v2(req, target_dir, noconfirm, options, ctrl_c).await
+        },
+        /* If None, the task got cancelled */
+        None => Ok(()),
+        Some(_) => eyre::bail!("Received an unsupported offer"),
     }
-    #[cfg(feature = "experimental-transfer-v2")]
-    {
-        let req = transfer::request(wormhole, relay_hints, transit_abilities, ctrl_c())
-            .await
-            .context("Could not get an offer")?;
+}
 
-        match req {
-            Some(transfer::ReceiveRequest::V1(req)) => {
-                receive_inner_v1(req, target_dir, noconfirm, ctrl_c).await
-            },
-            #[cfg(feature = "experimental-transfer-v2")]
-            Some(transfer::ReceiveRequest::V2(req)) => {
-                receive_inner_v2(req, target_dir, noconfirm, ctrl_c).await
-            },
-            None => Ok(()),
-        }
+/// Escape control characters (except for newlines and tabs) in untrusted text
+///
+/// This prevents the peer from sending terminal escape sequences.
+fn escape_control_chars(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\n' | '\t' => c.to_string(),
+            c if c.is_control() => c.escape_default().to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(feature = "experimental-transfer-v2")]
+fn warn_v2_only(options: &ReceiveOptions) {
+    if options.resume {
+        tracing::warn!("The sender does not support resuming transfers, starting from scratch");
+    }
+    if options.sync {
+        tracing::warn!("The sender does not support skipping existing files, receiving everything");
+    }
+    if !options.include.is_empty() || !options.exclude.is_empty() {
+        tracing::warn!("The sender does not support selecting files, receiving everything");
     }
 }
 
@@ -964,6 +1538,7 @@ async fn receive_inner_v1(
     req: transfer::ReceiveRequestV1,
     target_dir: &std::path::Path,
     noconfirm: bool,
+    options: &ReceiveOptions,
     ctrl_c: impl Fn() -> futures::future::BoxFuture<'static, ()>,
 ) -> eyre::Result<()> {
     use async_std::fs::OpenOptions;
@@ -971,23 +1546,26 @@ async fn receive_inner_v1(
     /*
      * Control flow is a bit tricky here:
      * - First of all, we ask if we want to receive the file at all
+     * - Folders get unpacked, but never into an existing destination
      * - Then, we check if the file already exists
      * - If it exists, ask whether to overwrite and act accordingly
      * - If it doesn't, directly accept, but DON'T overwrite any files
      */
 
     use number_prefix::NumberPrefix;
+    let directory_name = req
+        .directory_name()
+        .filter(|_| !options.no_extract && !options.stdout);
+    let size = match NumberPrefix::binary(req.file_size() as f64) {
+        NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
+        NumberPrefix::Prefixed(prefix, n) => format!("{:.1} {}B in size", n, prefix.symbol()),
+    };
     if !(noconfirm
         || util::ask_user(
-            format!(
-                "Receive file '{}' ({})?",
-                req.file_name(),
-                match NumberPrefix::binary(req.file_size() as f64) {
-                    NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
-                    NumberPrefix::Prefixed(prefix, n) =>
-                        format!("{:.1} {}B in size", n, prefix.symbol()),
-                },
-            ),
+            match &directory_name {
+                Some(directory_name) => format!("Receive folder '{}' ({})?", directory_name, size),
+                None => format!("Receive file '{}' ({})?", req.file_name(), size),
+            },
             true,
         )
         .await)
@@ -995,6 +1573,43 @@ async fn receive_inner_v1(
         return req.reject().await.context("Could not reject offer");
     }
 
+    if options.stdout {
+        let pb = create_progress_bar(req.file_size());
+        return req
+            .accept(
+                &transit_handler,
+                create_progress_handler(pb),
+                &mut async_std::io::stdout(),
+                ctrl_c(),
+            )
+            .await
+            .context("Receive process failed");
+    }
+
+    if let Some(directory_name) = directory_name {
+        let directory_path = target_dir.join(directory_name);
+        if directory_path.exists() {
+            req.reject().await.context("Could not reject offer")?;
+            eyre::bail!(
+                "Target destination {} exists, not overwriting it",
+                directory_path.display()
+            );
+        }
+
+        let pb = create_progress_bar(req.file_size());
+        return req
+            .accept_directory(
+                &transit_handler,
+                create_progress_handler(pb),
+                target_dir,
+                options.symlinks.into(),
+                !options.no_metadata,
+                ctrl_c(),
+            )
+            .await
+            .context("Receive process failed");
+    }
+
     // TODO validate untrusted input here
     let file_path = std::path::Path::new(target_dir).join(req.file_name());
 
@@ -1045,14 +1660,42 @@ async fn receive_inner_v1(
     .context("Receive process failed")
 }
 
+/// Name of the directory collecting the partially received files of `offer`, to pick them up with `--resume`
+///
+/// This must not change between runs or versions, so it is a SHA-256 digest of the offer's JSON
+/// encoding, which is canonical as all maps in an offer are sorted.
+#[cfg(feature = "experimental-transfer-v2")]
+fn partial_dir_name(offer: &transfer::offer::Offer) -> eyre::Result<String> {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::digest(serde_json::to_vec(offer)?);
+    Ok(format!("wormhole-partial-{}", hex::encode(&digest[..8])))
+}
+
 #[cfg(feature = "experimental-transfer-v2")]
 async fn receive_inner_v2(
     req: transfer::ReceiveRequestV2,
     target_dir: &std::path::Path,
     noconfirm: bool,
+    options: &ReceiveOptions,
     ctrl_c: impl Fn() -> futures::future::BoxFuture<'static, ()>,
 ) -> eyre::Result<()> {
-    let offer = req.offer();
+    let full_offer = req.offer();
+
+    /* Only accept what got selected with --include and --exclude */
+    let mut offer = (*full_offer).clone();
+    offer.retain(|path| options.is_selected(path));
+    if offer.top_level_paths().next().is_none() {
+        req.reject().await.context("Could not reject offer")?;
+        eyre::bail!("None of the offered files match the given --include and --exclude patterns");
+    }
+    if options.stdout {
+        let single_file = offer.top_level_paths().count() == 1
+            && matches!(offer.iter_file_paths().next(), Some(path) if path.len() == 1);
+        if !single_file {
+            req.reject().await.context("Could not reject offer")?;
+            eyre::bail!("Only single files can be written to stdout, but the offer contains folders or multiple files");
+        }
+    }
     let file_size = offer.total_size();
     let offer_name = offer.offer_name();
 
@@ -1063,6 +1706,7 @@ async fn receive_inner_v2(
                 "Receive {} ({})?",
                 offer_name,
                 match NumberPrefix::binary(file_size as f64) {
+                    _ if offer.has_streams() => "unknown size".to_string(),
                     NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
                     NumberPrefix::Prefixed(prefix, n) =>
                         format!("{:.1} {}B in size", n, prefix.symbol()),
@@ -1075,18 +1719,68 @@ async fn receive_inner_v2(
         return req.reject().await.context("Could not reject offer");
     }
 
-    let pb = create_progress_bar(file_size);
+    let pb = if offer.has_streams() {
+        create_stream_progress_bar()
+    } else {
+        create_progress_bar(file_size)
+    };
 
     let on_progress = move |received, _total| {
         pb.set_position(received);
     };
 
-    /* Create a temporary directory for receiving */
-    use rand::Rng;
-    let tmp_dir = target_dir.join(format!(
-        "wormhole-tmp-{:06}",
-        rand::thread_rng().gen_range(0..1_000_000)
-    ));
+    if options.stdout {
+        let answer = offer.set_content(|_path| transfer::offer::AcceptInner {
+            offset: 0,
+            sha256: None,
+            content: transfer::offer::new_accept_content(|_append| {
+                futures::future::ready(Ok(async_std::io::stdout()))
+            }),
+        });
+        return req
+            .accept(&transit_handler, answer, on_progress, ctrl_c())
+            .await
+            .context("Receive process failed");
+    }
+
+    /* Update the destination directly. We hash what is already there, and the sender skips everything that matches.
+     * Each file only replaces the existing one once it is complete.
+     */
+    if options.sync {
+        let answer = offer
+            .accept_all_replacing(target_dir)
+            .await
+            .context("Failed to check for existing files")?;
+        offer.create_directories(target_dir).await?;
+        req.accept(&transit_handler, answer, on_progress, ctrl_c())
+            .await
+            .context("Receive process failed")?;
+        offer
+            .create_symlinks(target_dir, options.symlinks.into())
+            .await
+            .context("Failed to create symlinks")?;
+        if !options.no_metadata {
+            offer
+                .restore_metadata(target_dir)
+                .await
+                .context("Failed to restore file metadata")?;
+        }
+        return Ok(());
+    }
+
+    /* Create a temporary directory for receiving. Its name must only depend on the offer, so that `--resume`
+     * finds the partially received files of an interrupted transfer again, even if that one ran without it.
+     */
+    let tmp_dir = target_dir.join(partial_dir_name(&full_offer)?);
+    if !options.resume && async_std::path::Path::new(&tmp_dir).exists().await {
+        tracing::info!(
+            "Discarding the partially received files in {}",
+            tmp_dir.display()
+        );
+        async_std::fs::remove_dir_all(&tmp_dir)
+            .await
+            .with_context(|| format!("Failed to delete {}", tmp_dir.display()))?;
+    }
     async_std::fs::create_dir_all(&tmp_dir)
         .await
         .context("Failed to create temporary directory for receiving")?;
@@ -1095,13 +1789,34 @@ async fn receive_inner_v2(
     offer.create_directories(&tmp_dir).await?;
 
     /* Accept the offer and receive it */
-    let answer = offer.accept_all(&tmp_dir);
+    let answer = if options.resume {
+        offer
+            .accept_all_resumable(&tmp_dir)
+            .await
+            .context("Failed to check for partially received files")?
+    } else {
+        offer.accept_all(&tmp_dir)
+    };
     req.accept(&transit_handler, answer, on_progress, ctrl_c())
         .await
-        .context("Receive process failed")?;
+        .with_context(|| {
+            format!(
+                "Receive process failed, partially received files are kept in {} (use --resume to continue)",
+                tmp_dir.display()
+            )
+        })?;
 
-    // /* Put in all the symlinks last, this greatly reduces the attack surface */
-    // offer.create_symlinks(&tmp_dir).await?;
+    /* Put in all the symlinks last, this greatly reduces the attack surface */
+    offer
+        .create_symlinks(&tmp_dir, options.symlinks.into())
+        .await
+        .context("Failed to create symlinks")?;
+    if !options.no_metadata {
+        offer
+            .restore_metadata(&tmp_dir)
+            .await
+            .context("Failed to restore file metadata")?;
+    }
 
     /* TODO walk the output directory and delete things we did not accept; this will be important for resumption */
 
@@ -1165,8 +1880,80 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_code_completion() {
+        use dialoguer::Completion;
+
+        let completion = CodeCompletion {
+            nameplates: vec!["4".into(), "42".into(), "7".into()],
+            wordlist: magic_wormhole::wordlist::default_wordlist(2),
+        };
+        assert_eq!(completion.get("7"), Some("7-".into()));
+        assert_eq!(completion.get("4"), None);
+        assert_eq!(completion.get("42"), Some("42-".into()));
+        assert_eq!(completion.get("1"), None);
+        assert_eq!(completion.get("7-guitari"), Some("7-guitarist-".into()));
+        assert_eq!(
+            completion.get("7-guitarist-rev"),
+            Some("7-guitarist-revenge".into())
+        );
+        assert_eq!(completion.get("7-ar"), None);
+    }
+
+    #[test]
+    fn test_escape_control_chars() {
+        assert_eq!(escape_control_chars("hello\nworld\t!"), "hello\nworld\t!");
+        assert_eq!(
+            escape_control_chars("\x1b]0;pwned\x07\r"),
+            "\\u{1b}]0;pwned\\u{7}\\r"
+        );
+        assert_eq!(escape_control_chars("grüße \u{9b}2J"), "grüße \\u{9b}2J");
+    }
+
     #[test]
     fn verify_cli() {
         WormholeCli::command().debug_assert();
     }
+
+    #[cfg(feature = "experimental-transfer-v2")]
+    #[test]
+    fn test_partial_dir_name() {
+        let offer: transfer::offer::Offer = serde_json::from_str(
+            r#"{"content":{"folder":{"type":"directory","content":{"b":{"type":"regular-file","size":2},"a":{"type":"regular-file","size":1}}}}}"#,
+        )
+        .unwrap();
+        /* The name must stay the same across versions, or resuming older transfers breaks */
+        assert_eq!(
+            partial_dir_name(&offer).unwrap(),
+            "wormhole-partial-eec659eb824c71cb"
+        );
+    }
+
+    #[cfg(feature = "experimental-transfer-v2")]
+    #[test]
+    fn test_receive_selection() {
+        let options = |args: &[&str]| {
+            let cli =
+                WormholeCli::try_parse_from(["wormhole-rs", "receive"].iter().chain(args.iter()))
+                    .unwrap();
+            match cli.command {
+                WormholeCommand::Receive { options, .. } => options,
+                _ => unreachable!(),
+            }
+        };
+        let path = |path: &str| path.split('/').map(ToOwned::to_owned).collect::<Vec<_>>();
+
+        let all = options(&[]);
+        assert!(all.is_selected(&path("project/src/main.rs")));
+
+        let some = options(&["--include", "project/docs", "--include", "**/*.rs"]);
+        assert!(some.is_selected(&path("project/docs/index.md")));
+        assert!(some.is_selected(&path("project/src/main.rs")));
+        assert!(!some.is_selected(&path("project/README")));
+
+        let excluded = options(&["--exclude", "*/target", "--exclude", "**/*.tmp"]);
+        assert!(excluded.is_selected(&path("project/src/main.rs")));
+        assert!(!excluded.is_selected(&path("project/target/debug/main")));
+        assert!(!excluded.is_selected(&path("project/file.tmp")));
+    }
 }
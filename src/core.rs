@@ -1,14 +1,17 @@
 #![allow(deprecated)]
 
 pub(super) mod key;
+#[cfg(all(feature = "lan", not(target_family = "wasm")))]
+pub mod lan;
+pub mod proxy;
 pub mod rendezvous;
 mod server_messages;
 #[cfg(test)]
 mod test;
-mod wordlist;
+pub mod wordlist;
 
 use serde_derive::{Deserialize, Serialize};
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::VecDeque};
 
 use self::{rendezvous::*, server_messages::EncryptedMessage};
 
@@ -51,6 +54,10 @@ pub enum WormholeError {
     /// Nameplate is unclaimed
     #[error("Nameplate is unclaimed: {}", _0)]
     UnclaimedNameplate(Nameplate),
+    /// Announcing or finding a nameplate on the local network failed
+    #[cfg(all(feature = "lan", not(target_family = "wasm")))]
+    #[error("Local network discovery failed")]
+    Lan(#[source] std::io::Error),
 }
 
 impl WormholeError {
@@ -138,6 +145,29 @@ impl<V: serde::Serialize + Send + Sync + 'static> MailboxConnection<V> {
         .await
     }
 
+    /// Create a connection to a mailbox which is configured with a `Code` starting with the nameplate and random words from a custom wordlist.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: Application configuration
+    /// * `wordlist`: The wordlist to take the words from. It also determines the number of words.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> eyre::Result<()> { async_std::task::block_on(async {
+    /// use magic_wormhole::{transfer::APP_CONFIG, wordlist::Wordlist, MailboxConnection};
+    /// let wordlist = Wordlist::from_file(4, "eff_short_wordlist_1.txt")?;
+    /// let mailbox_connection = MailboxConnection::create_with_wordlist(APP_CONFIG, &wordlist).await?;
+    /// # Ok(()) })}
+    /// ```
+    pub async fn create_with_wordlist(
+        config: AppConfig<V>,
+        wordlist: &wordlist::Wordlist,
+    ) -> Result<Self, WormholeError> {
+        Self::create_with_password(config, &wordlist.choose_words()).await
+    }
+
     /// Create a connection to a mailbox which is configured with a `Code` containing the nameplate and the given password.
     ///
     /// # Arguments
@@ -158,8 +188,7 @@ impl<V: serde::Serialize + Send + Sync + 'static> MailboxConnection<V> {
         config: AppConfig<V>,
         password: &str,
     ) -> Result<Self, WormholeError> {
-        let (mut server, welcome) =
-            RendezvousServer::connect(&config.id, &config.rendezvous_url).await?;
+        let (mut server, welcome) = RendezvousServer::connect_with_config(&config).await?;
         let (nameplate, mailbox) = server.allocate_claim_open().await?;
         let code = Code::new(&nameplate, password);
 
@@ -197,8 +226,7 @@ impl<V: serde::Serialize + Send + Sync + 'static> MailboxConnection<V> {
         code: Code,
         allocate: bool,
     ) -> Result<Self, WormholeError> {
-        let (mut server, welcome) =
-            RendezvousServer::connect(&config.id, &config.rendezvous_url).await?;
+        let (mut server, welcome) = RendezvousServer::connect_with_config(&config).await?;
         let nameplate = code.nameplate();
         if !allocate {
             let nameplates = server.list_nameplates().await?;
@@ -218,6 +246,116 @@ impl<V: serde::Serialize + Send + Sync + 'static> MailboxConnection<V> {
         })
     }
 
+    /// Create a mailbox on a rendezvous server of our own, and announce it on the local network
+    ///
+    /// This works without any server: the peer finds us with [`MailboxConnection::connect_lan`],
+    /// as long as both sides are on the same network. The nameplate is a random three-digit number,
+    /// and the rendezvous URL and proxy of `config` are not used. See the [`lan`] module for details.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: Application configuration
+    /// * `code_length`: number of words used for the password. The words are taken from the default wordlist.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> eyre::Result<()> { async_std::task::block_on(async {
+    /// use magic_wormhole::{transfer::APP_CONFIG, MailboxConnection, Wormhole};
+    /// let mailbox_connection = MailboxConnection::create_lan(APP_CONFIG, 2).await?;
+    /// println!("The code is {}", mailbox_connection.code());
+    /// let wormhole = Wormhole::connect(mailbox_connection).await?;
+    /// # Ok(()) })}
+    /// ```
+    #[cfg(all(feature = "lan", not(target_family = "wasm")))]
+    pub async fn create_lan(
+        config: AppConfig<V>,
+        code_length: usize,
+    ) -> Result<Self, WormholeError> {
+        Self::create_lan_with_password(
+            config,
+            &wordlist::default_wordlist(code_length).choose_words(),
+        )
+        .await
+    }
+
+    /// Create a mailbox on a rendezvous server of our own and announce it on the local network, using the given password
+    ///
+    /// See [`MailboxConnection::create_lan`].
+    #[cfg(all(feature = "lan", not(target_family = "wasm")))]
+    pub async fn create_lan_with_password(
+        config: AppConfig<V>,
+        password: &str,
+    ) -> Result<Self, WormholeError> {
+        let mut host = lan::Host::start().await.map_err(WormholeError::Lan)?;
+        let config = config.rendezvous_url(host.url().into()).proxy(None);
+        let code = Code::new(&lan::random_nameplate(), password);
+        let mut mailbox_connection = Self::connect(config, code, true).await?;
+
+        host.announce(
+            &mailbox_connection.config.id,
+            &mailbox_connection.code.nameplate(),
+        )
+        .await
+        .map_err(WormholeError::Lan)?;
+        mailbox_connection.server.keep_lan_host(host);
+        Ok(mailbox_connection)
+    }
+
+    /// Connect to a mailbox that the peer announces on the local network
+    ///
+    /// This waits up to [`lan::DISCOVERY_TIMEOUT`] for the peer's announcement, and fails with
+    /// [`WormholeError::UnclaimedNameplate`] if there is none. The rendezvous URL and proxy of
+    /// `config` are not used.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: Application configuration
+    /// * `code`: The `Code` that the peer got from [`MailboxConnection::create_lan`]
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> eyre::Result<()> { async_std::task::block_on(async {
+    /// use magic_wormhole::{transfer::APP_CONFIG, Code, MailboxConnection, Nameplate, Wormhole};
+    /// let code = Code::new(&Nameplate::new("512"), "password");
+    /// let mailbox_connection = MailboxConnection::connect_lan(APP_CONFIG, code).await?;
+    /// let wormhole = Wormhole::connect(mailbox_connection).await?;
+    /// # Ok(()) })}
+    /// ```
+    #[cfg(all(feature = "lan", not(target_family = "wasm")))]
+    pub async fn connect_lan(config: AppConfig<V>, code: Code) -> Result<Self, WormholeError> {
+        let nameplate = code.nameplate();
+        let address = lan::locate(&config.id, &nameplate)
+            .await
+            .map_err(WormholeError::Lan)?
+            .ok_or(WormholeError::UnclaimedNameplate(nameplate))?;
+        let config = config
+            .rendezvous_url(format!("ws://{address}/v1").into())
+            .proxy(None);
+        Self::connect(config, code, false).await
+    }
+
+    /// List the nameplates that are currently claimed on the rendezvous server
+    ///
+    /// This opens a short-lived connection to the server. It is meant for suggesting nameplates
+    /// to users while they type a code, like with [`wordlist::Wordlist::get_completions`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> eyre::Result<()> { async_std::task::block_on(async {
+    /// use magic_wormhole::{transfer::APP_CONFIG, MailboxConnection};
+    /// let nameplates = MailboxConnection::list_nameplates(&APP_CONFIG).await?;
+    /// # Ok(()) })}
+    /// ```
+    pub async fn list_nameplates(config: &AppConfig<V>) -> Result<Vec<Nameplate>, WormholeError> {
+        let (mut server, _welcome) = RendezvousServer::connect_with_config(config).await?;
+        let nameplates = server.list_nameplates().await?;
+        server.shutdown(Mood::Happy).await?;
+        Ok(nameplates)
+    }
+
     /// Shut down the connection to the mailbox
     ///
     /// # Arguments
@@ -229,8 +367,12 @@ impl<V: serde::Serialize + Send + Sync + 'static> MailboxConnection<V> {
     /// ```
     /// # fn main() -> eyre::Result<()> { use magic_wormhole::WormholeError;
     /// async_std::task::block_on(async {
+    /// # let server = magic_wormhole::rendezvous::server::MailboxServer::bind("127.0.0.1:0").await?;
+    /// # let url = server.url()?;
+    /// # async_std::task::spawn(server.run());
     /// use magic_wormhole::{transfer::APP_CONFIG, MailboxConnection, Mood};
     /// let config = APP_CONFIG;
+    /// # let config = config.rendezvous_url(url.into());
     /// let mailbox_connection = MailboxConnection::create_with_password(config, "secret")
     ///     .await?;
     /// mailbox_connection.shutdown(Mood::Happy).await?;
@@ -267,6 +409,11 @@ pub struct Wormhole {
     #[allow(deprecated)]
     server: RendezvousServer,
     phase: u64,
+    /// Messages of other phases than the ones asked for, like dilation messages before dilating
+    stashed: VecDeque<(Phase, Vec<u8>)>,
+    /// The versions of the dilation protocol the peer supports, and its abilities for it
+    #[cfg(feature = "experimental-dilation")]
+    peer_dilation: (Vec<String>, Option<serde_json::Value>),
     key: key::Key<key::WormholeKey>,
     appid: AppID,
     /// The cryptographic verifier code for the connection
@@ -365,6 +512,15 @@ impl Wormhole {
         /* Send versions message */
         let mut versions = key::VersionsMessage::new();
         versions.set_app_versions(serde_json::to_value(&config.app_version).unwrap());
+        #[cfg(feature = "experimental-dilation")]
+        {
+            versions.can_dilate = crate::dilation::VERSIONS
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            versions.dilation_abilities =
+                Some(serde_json::to_value(crate::dilation::ABILITIES).unwrap());
+        }
         let (version_phase, version_msg) = key::build_version_msg(server.side(), &key, &versions);
         server.send_peer_message(version_phase, version_msg).await?;
         let peer_version = server.next_peer_message_some().await?;
@@ -377,6 +533,8 @@ impl Wormhole {
                 serde_json::from_slice(&plaintext).map_err(WormholeError::ProtocolJson)
             })?;
 
+        #[cfg(feature = "experimental-dilation")]
+        let peer_dilation = (versions.can_dilate, versions.dilation_abilities);
         let peer_version = versions.app_versions;
 
         if server.needs_nameplate_release() {
@@ -391,6 +549,9 @@ impl Wormhole {
             server,
             appid: config.id,
             phase: 0,
+            stashed: VecDeque::new(),
+            #[cfg(feature = "experimental-dilation")]
+            peer_dilation,
             key: key::Key::new(key.into()),
             verifier: Box::new(key::derive_verifier(&key)),
             our_version: Box::new(config.app_version),
@@ -402,11 +563,18 @@ impl Wormhole {
     pub async fn send(&mut self, plaintext: Vec<u8>) -> Result<(), WormholeError> {
         let phase_string = Phase::numeric(self.phase);
         self.phase += 1;
-        let data_key = key::derive_phase_key(self.server.side(), &self.key, &phase_string);
+        self.send_in_phase(phase_string, plaintext).await
+    }
+
+    /** Send an encrypted message to peer in the given phase */
+    pub(crate) async fn send_in_phase(
+        &mut self,
+        phase: Phase,
+        plaintext: Vec<u8>,
+    ) -> Result<(), WormholeError> {
+        let data_key = key::derive_phase_key(self.server.side(), &self.key, &phase);
         let (_nonce, encrypted) = key::encrypt_data(&data_key, &plaintext);
-        self.server
-            .send_peer_message(phase_string, encrypted)
-            .await?;
+        self.server.send_peer_message(phase, encrypted).await?;
         Ok(())
     }
 
@@ -429,23 +597,40 @@ impl Wormhole {
 
     /** Receive an encrypted message from peer */
     pub async fn receive(&mut self) -> Result<Vec<u8>, WormholeError> {
+        self.receive_in_phase(|phase| phase.to_num().is_some())
+            .await
+            .map(|(_phase, message)| message)
+    }
+
+    /**
+     * Receive the next encrypted message from peer in a phase matching `wanted`
+     *
+     * Messages of other phases are kept for whoever asks for them later.
+     */
+    pub(crate) async fn receive_in_phase(
+        &mut self,
+        wanted: impl Fn(&Phase) -> bool,
+    ) -> Result<(Phase, Vec<u8>), WormholeError> {
+        if let Some(index) = self.stashed.iter().position(|(phase, _)| wanted(phase)) {
+            return Ok(self.stashed.remove(index).unwrap());
+        }
         loop {
             let peer_message = match self.server.next_peer_message().await? {
                 Some(peer_message) => peer_message,
                 None => continue,
             };
-            if peer_message.phase.to_num().is_none() {
-                // TODO: log and ignore, for future expansion
-                todo!("log and ignore, for future expansion");
-            }
 
             // TODO maybe reorder incoming messages by phase numeral?
             let decrypted_message = peer_message
                 .decrypt(&self.key)
                 .ok_or(WormholeError::Crypto)?;
 
-            // Send to client
-            return Ok(decrypted_message);
+            if wanted(&peer_message.phase) {
+                return Ok((peer_message.phase, decrypted_message));
+            }
+            tracing::trace!("Keeping message of phase {} for later", peer_message.phase);
+            self.stashed
+                .push_back((peer_message.phase, decrypted_message));
         }
     }
 
@@ -493,6 +678,14 @@ impl Wormhole {
         &self.key
     }
 
+    /**
+     * The proxy configured in the [`AppConfig`], to be used for transit connections as well
+     */
+    #[cfg_attr(not(feature = "transit"), allow(dead_code))]
+    pub(crate) fn proxy(&self) -> Option<&proxy::Proxy> {
+        self.server.proxy()
+    }
+
     /**
      * If you're paranoid, let both sides check that they calculated the same verifier.
      *
@@ -510,6 +703,15 @@ impl Wormhole {
         &self.verifier
     }
 
+    /**
+     * The [verifier](Self::verifier) in a human readable form, for users to compare on both sides
+     *
+     * The formatting is deterministic, so any application can show the same string.
+     */
+    pub fn verifier_string(&self, format: VerifierFormat) -> String {
+        format.format(self.verifier())
+    }
+
     /**
      * Our "app version" information that we sent. See the [`peer_version`](Self::peer_version()) for more information.
      */
@@ -527,6 +729,14 @@ impl Wormhole {
         #[allow(deprecated)]
         &self.peer_version
     }
+
+    /**
+     * The versions of the dilation protocol the peer supports, and its abilities for it
+     */
+    #[cfg(feature = "experimental-dilation")]
+    pub(crate) fn peer_dilation(&self) -> (&[String], Option<&serde_json::Value>) {
+        (&self.peer_dilation.0, self.peer_dilation.1.as_ref())
+    }
 }
 
 /// The close command accepts an optional "mood" string: this allows clients to tell the server
@@ -554,6 +764,56 @@ pub enum Mood {
     Unwelcome,
 }
 
+/**
+ * Human readable representations of a [verifier](Wormhole::verifier)
+ *
+ * Users should compare these on both sides. Matching strings mean that nobody is in the middle of the
+ * connection. All formats are deterministic, so that different applications display the same string.
+ */
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+#[non_exhaustive]
+pub enum VerifierFormat {
+    /// The whole verifier as lowercase hex, like the Python implementation shows it
+    #[default]
+    Hex,
+    /// The first six bytes as words from the [PGP word list](https://en.wikipedia.org/wiki/PGP_word_list), separated by spaces
+    Words,
+    /// The first 48 bits as eight emoji, six bits each
+    Emoji,
+}
+
+/// The emoji for [`VerifierFormat::Emoji`], indexed by six bits. Never change this list
+const VERIFIER_EMOJI: [char; 64] = [
+    '🐶', '🐱', '🐭', '🐹', '🐰', '🦊', '🐻', '🐼', '🐨', '🐯', '🦁', '🐮', '🐷', '🐸', '🐵', '🐔',
+    '🐧', '🐦', '🐤', '🦆', '🦉', '🦇', '🐺', '🐴', '🦄', '🐝', '🐛', '🦋', '🐌', '🐞', '🐢', '🐍',
+    '🐙', '🦀', '🐬', '🐳', '🍎', '🍐', '🍊', '🍋', '🍌', '🍉', '🍇', '🍓', '🍒', '🍑', '🍍', '🥝',
+    '🍅', '🥕', '🌽', '🍄', '🌵', '🌲', '🌻', '🌙', '⭐', '🔥', '🌈', '🎈', '🎁', '🔑', '🔔', '🚀',
+];
+
+impl VerifierFormat {
+    /// Format a verifier. Use [`Wormhole::verifier_string`] to format the verifier of a connection.
+    pub fn format(self, verifier: &[u8]) -> String {
+        match self {
+            VerifierFormat::Hex => hex::encode(verifier),
+            VerifierFormat::Words => {
+                wordlist::pgp_words(&verifier[..6.min(verifier.len())]).join(" ")
+            },
+            VerifierFormat::Emoji => verifier
+                .chunks(3)
+                .take(2)
+                .flat_map(|chunk| {
+                    let mut bytes = [0; 4];
+                    bytes[1..][..chunk.len()].copy_from_slice(chunk);
+                    let bits = u32::from_be_bytes(bytes);
+                    (0..4)
+                        .rev()
+                        .map(move |i| VERIFIER_EMOJI[(bits >> (i * 6)) as usize & 63])
+                })
+                .collect(),
+        }
+    }
+}
+
 /**
  * Wormhole configuration corresponding to an uppler layer protocol
  *
@@ -573,6 +833,10 @@ pub struct AppConfig<V> {
     pub rendezvous_url: Cow<'static, str>,
     /// The client application version
     pub app_version: V,
+    /// Keepalive settings for the rendezvous connection, `None` disables it
+    pub rendezvous_keepalive: Option<rendezvous::Keepalive>,
+    /// The proxy to tunnel all connections through, `None` connects directly
+    pub proxy: Option<proxy::Proxy>,
 }
 
 impl<V> AppConfig<V> {
@@ -587,6 +851,18 @@ impl<V> AppConfig<V> {
         self.rendezvous_url = rendezvous_url;
         self
     }
+
+    /// Set the keepalive settings for the rendezvous connection, `None` disables it
+    pub fn rendezvous_keepalive(mut self, keepalive: Option<rendezvous::Keepalive>) -> Self {
+        self.rendezvous_keepalive = keepalive;
+        self
+    }
+
+    /// Set the proxy to tunnel all connections through, `None` connects directly
+    pub fn proxy(mut self, proxy: Option<proxy::Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
 }
 
 impl<V: serde::Serialize> AppConfig<V> {
@@ -713,6 +989,19 @@ impl Phase {
         Phase(phase.to_string().into())
     }
 
+    /// The phases of the dilation messages, which are numbered on their own
+    #[cfg(feature = "experimental-dilation")]
+    pub(crate) fn dilation(phase: u64) -> Self {
+        Phase(format!("dilate-{phase}").into())
+    }
+
+    #[cfg(feature = "experimental-dilation")]
+    pub(crate) fn is_dilation(&self) -> bool {
+        self.0
+            .strip_prefix("dilate-")
+            .is_some_and(|phase| phase.parse::<u64>().is_ok())
+    }
+
     #[allow(dead_code)]
     pub fn is_version(&self) -> bool {
         self == &Self::VERSION
@@ -121,6 +121,16 @@ pub struct VersionsMessage {
     pub abilities: Vec<String>,
     #[serde(default)]
     pub app_versions: serde_json::Value,
+    /// The versions of the dilation protocol we support, see [`crate::dilation`]
+    #[serde(default, rename = "can-dilate", skip_serializing_if = "Vec::is_empty")]
+    pub can_dilate: Vec<String>,
+    /// How to connect for dilation, in the format of the Transit abilities
+    #[serde(
+        default,
+        rename = "dilation-abilities",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dilation_abilities: Option<serde_json::Value>,
     // resume: Option<WormholeResume>,
 }
 
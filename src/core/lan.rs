@@ -0,0 +1,325 @@
+//! Find the peer on the local network, without a rendezvous server
+//!
+//! The sending side runs a [rendezvous server](super::rendezvous::server::MailboxServer) of its own
+//! and announces the nameplate it claimed there with a UDP broadcast beacon. The receiving side
+//! listens for the beacon of its nameplate and connects to the announcing server. From there on,
+//! everything works as usual, including the PAKE: someone else announcing the same nameplate can't
+//! do more than making the key exchange fail.
+//!
+//! Beacons are sent to [`DISCOVERY_PORT`] every second, at the IPv4 broadcast address of each
+//! network interface and of the loopback network. They stop once the peer claimed the nameplate.
+//!
+//! Use it with [`MailboxConnection::create_lan`](crate::MailboxConnection::create_lan) and
+//! [`MailboxConnection::connect_lan`](crate::MailboxConnection::connect_lan).
+
+use async_std::net::{TcpListener, UdpSocket};
+use futures::future::{AbortHandle, Abortable};
+use serde_derive::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use super::{rendezvous::server::MailboxServer, AppID, Nameplate};
+use crate::util;
+
+/// The UDP port the beacons are sent to
+pub const DISCOVERY_PORT: u16 = 4002;
+/**
+ * Where to send the beacons for receivers on this machine
+ *
+ * With SO_REUSEPORT, a unicast beacon only reaches one of the receivers, so we broadcast on the
+ * loopback network. Not all systems treat it as broadcast domain though, so we send both.
+ */
+const LOOPBACK_ADDRESSES: [Ipv4Addr; 2] = [Ipv4Addr::new(127, 255, 255, 255), Ipv4Addr::LOCALHOST];
+
+/// How often to announce our nameplate
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to listen for the beacon of a nameplate before giving up
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Beacon {
+    WormholeLanV1 {
+        appid: AppID,
+        nameplate: Nameplate,
+        /// The port of the rendezvous server, at the address the beacon came from
+        port: u16,
+    },
+}
+
+/** Pick a nameplate for the local network
+ *
+ * Every sender runs its own server, so they can't coordinate. Three digits make collisions unlikely.
+ */
+pub(crate) fn random_nameplate() -> Nameplate {
+    use rand::Rng;
+
+    Nameplate::new(rand::thread_rng().gen_range(100..1000).to_string())
+}
+
+type WaitingForPeer = dyn Fn(&AppID, &Nameplate) -> bool + Send + Sync;
+
+/**
+ * Our rendezvous server, and the beacon announcing it
+ *
+ * Dropping it stops both. Connections that are already established stay open, so that the peer
+ * can still close the mailbox after we are gone.
+ */
+pub(crate) struct Host {
+    address: SocketAddr,
+    waiting_for_peer: Arc<WaitingForPeer>,
+    tasks: Vec<AbortHandle>,
+}
+
+impl std::fmt::Debug for Host {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("Host")
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Host {
+    /// Start a rendezvous server on all interfaces, on a port assigned by the OS
+    pub(crate) async fn start() -> io::Result<Self> {
+        let server = MailboxServer::from_listener(listen_dual_stack()?);
+        let mut host = Self {
+            address: server.local_addr()?,
+            waiting_for_peer: Arc::new(server.waiting_for_peer()),
+            tasks: Vec::new(),
+        };
+        host.spawn(async move {
+            if let Err(error) = server.run().await {
+                tracing::warn!("Our rendezvous server stopped: {error}");
+            }
+        });
+        Ok(host)
+    }
+
+    /// The URL for ourselves to connect to the server
+    pub(crate) fn url(&self) -> String {
+        let localhost: SocketAddr = match self.address {
+            SocketAddr::V4(_) => (Ipv4Addr::LOCALHOST, self.address.port()).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::LOCALHOST, self.address.port()).into(),
+        };
+        format!("ws://{localhost}/v1")
+    }
+
+    fn spawn(&mut self, task: impl std::future::Future<Output = ()> + Send + 'static) {
+        let (handle, registration) = AbortHandle::new_pair();
+        async_std::task::spawn(Abortable::new(task, registration));
+        self.tasks.push(handle);
+    }
+
+    /**
+     * Announce a nameplate until the peer claimed it
+     *
+     * We must have claimed it already, otherwise the announcement stops right away.
+     */
+    pub(crate) async fn announce(
+        &mut self,
+        appid: &AppID,
+        nameplate: &Nameplate,
+    ) -> io::Result<()> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.set_broadcast(true)?;
+        let beacon = serde_json::to_vec(&Beacon::WormholeLanV1 {
+            appid: appid.clone(),
+            nameplate: nameplate.clone(),
+            port: self.address.port(),
+        })
+        .unwrap();
+
+        let waiting_for_peer = self.waiting_for_peer.clone();
+        let (appid, nameplate) = (appid.clone(), nameplate.clone());
+        tracing::debug!("Announcing nameplate {nameplate} on the local network");
+        self.spawn(async move {
+            while waiting_for_peer(&appid, &nameplate) {
+                for address in broadcast_addresses() {
+                    if let Err(error) = socket.send_to(&beacon, (address, DISCOVERY_PORT)).await {
+                        tracing::trace!("Failed to send a beacon to {address}: {error}");
+                    }
+                }
+                util::sleep(BEACON_INTERVAL).await;
+            }
+            tracing::debug!("The peer claimed nameplate {nameplate}, stopped announcing it");
+        });
+        Ok(())
+    }
+}
+
+/**
+ * Listen for TCP connections over both IPv4 and IPv6
+ *
+ * Some platforms (e.g. Windows) or system configurations make IPv6 sockets IPv6-only by default,
+ * so we explicitly turn that off. Without IPv6 support, we fall back to IPv4 only.
+ */
+fn listen_dual_stack() -> io::Result<TcpListener> {
+    let socket = match Socket::new(Domain::IPV6, Type::STREAM, None).and_then(|socket| {
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+        Ok(socket)
+    }) {
+        Ok(socket) => socket,
+        Err(error) => {
+            tracing::debug!("Failed to listen on IPv6, falling back to IPv4: {error}");
+            let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+            socket
+        },
+    };
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    Ok(std::net::TcpListener::from(socket).into())
+}
+
+/**
+ * Bind to the [`DISCOVERY_PORT`] so that other programs can do so as well
+ *
+ * Multiple receivers on the same machine must be able to wait for their beacons at the same time.
+ */
+fn bind_discovery_port() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    /* Like for transit: on most BSD and Linux systems we need both REUSEADDR and REUSEPORT,
+     * Windows only has REUSEADDR but it does what we want.
+     */
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).into())?;
+    socket.set_nonblocking(true)?;
+    Ok(std::net::UdpSocket::from(socket).into())
+}
+
+/** Where to send the beacons to */
+fn broadcast_addresses() -> Vec<Ipv4Addr> {
+    let interfaces = if_addrs::get_if_addrs().unwrap_or_else(|error| {
+        tracing::debug!("Failed to list the network interfaces: {error}");
+        Vec::new()
+    });
+    LOOPBACK_ADDRESSES
+        .into_iter()
+        .chain(
+            interfaces
+                .into_iter()
+                .filter(|interface| !interface.is_loopback())
+                .filter_map(|interface| match interface.addr {
+                    if_addrs::IfAddr::V4(addr) => addr.broadcast,
+                    if_addrs::IfAddr::V6(_) => None,
+                }),
+        )
+        .collect()
+}
+
+/**
+ * Listen for the beacon announcing a nameplate
+ *
+ * Returns the address of the rendezvous server that announced it, or `None` if we got no
+ * beacon within [`DISCOVERY_TIMEOUT`].
+ */
+pub(crate) async fn locate(appid: &AppID, nameplate: &Nameplate) -> io::Result<Option<SocketAddr>> {
+    let socket = bind_discovery_port()?;
+    tracing::debug!("Looking for nameplate {nameplate} on the local network");
+
+    let receive = async {
+        let mut buffer = [0; 1024];
+        loop {
+            let (length, from) = socket.recv_from(&mut buffer).await?;
+            match serde_json::from_slice(&buffer[..length]) {
+                Ok(Beacon::WormholeLanV1 {
+                    appid: their_appid,
+                    nameplate: their_nameplate,
+                    port,
+                }) if their_appid == *appid && their_nameplate == *nameplate => {
+                    let address = SocketAddr::new(from.ip(), port);
+                    tracing::debug!("Found nameplate {nameplate} at {address}");
+                    return Ok(address);
+                },
+                Ok(beacon) => tracing::trace!("Ignoring beacon from {from}: {beacon:?}"),
+                Err(error) => tracing::trace!("Invalid beacon from {from}: {error}"),
+            }
+        }
+    };
+    match util::timeout(DISCOVERY_TIMEOUT, receive).await {
+        Ok(result) => result.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_beacon_encoding() {
+        let beacon = Beacon::WormholeLanV1 {
+            appid: AppID::new("lothar.com/wormhole/text-or-file-xfer"),
+            nameplate: Nameplate::new("123"),
+            port: 4242,
+        };
+        let json = serde_json::json!({
+            "type": "wormhole-lan-v1",
+            "appid": "lothar.com/wormhole/text-or-file-xfer",
+            "nameplate": "123",
+            "port": 4242,
+        });
+        assert_eq!(serde_json::to_value(&beacon).unwrap(), json);
+        assert_eq!(serde_json::from_value::<Beacon>(json).unwrap(), beacon);
+    }
+
+    #[async_std::test]
+    async fn test_listen_dual_stack() {
+        let listener = listen_dual_stack().unwrap();
+        let port = listener.local_addr().unwrap().port();
+        async_std::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_bind_discovery_port_twice() {
+        let first = bind_discovery_port().unwrap();
+        let second = bind_discovery_port().unwrap();
+
+        let sender = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+        sender.set_broadcast(true).unwrap();
+        /* Other tests might be announcing nameplates at the same time */
+        let beacon = rand::random::<[u8; 16]>();
+        /* Only on this machine, other interfaces might loop their broadcasts back as well */
+        for address in LOOPBACK_ADDRESSES {
+            sender
+                .send_to(&beacon, (address, DISCOVERY_PORT))
+                .await
+                .unwrap();
+        }
+
+        for socket in [first, second] {
+            let receive = async {
+                let mut buffer = [0; 1024];
+                loop {
+                    let (length, _) = socket.recv_from(&mut buffer).await.unwrap();
+                    if buffer[..length] == beacon {
+                        break;
+                    }
+                }
+            };
+            util::timeout(Duration::from_secs(5), receive)
+                .await
+                .expect("Both receivers should get the beacon");
+        }
+    }
+}
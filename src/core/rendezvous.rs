@@ -5,18 +5,65 @@
 #[cfg(not(target_family = "wasm"))]
 use async_tungstenite::tungstenite as ws2;
 use futures::prelude::*;
-use std::collections::VecDeque;
+use std::{collections::VecDeque, time::Duration};
 
 use crate::core::{
+    proxy::Proxy,
     server_messages::{InboundMessage, OutboundMessage, PermissionRequired, SubmitPermission},
-    AppID, EncryptedMessage, Mailbox, Mood, MySide, Nameplate, Phase,
+    AppConfig, AppID, EncryptedMessage, Mailbox, Mood, MySide, Nameplate, Phase,
 };
 
+#[cfg(not(target_family = "wasm"))]
+pub mod server;
+
 /// Some rendezvous server you might use.
 ///
 /// Two applications that want to communicate with each other *must* use the same rendezvous server.
 pub const DEFAULT_RENDEZVOUS_SERVER: &str = "ws://relay.magic-wormhole.io:4000/v1";
 
+/// How long to wait before the first attempt to reconnect to the rendezvous server
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+/// The delay between attempts doubles until it reaches this
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Give up after this many failed attempts to reconnect
+const RECONNECT_ATTEMPTS: u32 = 10;
+
+/**
+ * Keepalive settings for the connection to the rendezvous server
+ *
+ * While waiting for the peer, the connection might be idle for a long time. Some NATs and
+ * firewalls silently drop such connections. To prevent this (and to notice when it happens
+ * anyways), we send a websocket ping whenever the connection has been quiet for `interval`.
+ * If the server does not answer within `timeout`, the connection is considered dead and
+ * will be re-established.
+ *
+ * This has no effect on WASM, where browsers don't allow sending pings.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Keepalive {
+    /// Send a ping after the connection has been quiet for this long
+    pub interval: Duration,
+    /// Give up on the connection if the server does not answer a ping within this time
+    pub timeout: Duration,
+}
+
+impl Keepalive {
+    /// Ping every 30 seconds, and wait for 30 seconds for an answer
+    pub const DEFAULT: Self = Self::new(Duration::from_secs(30), Duration::from_secs(30));
+
+    /// Create new keepalive settings
+    pub const fn new(interval: Duration, timeout: Duration) -> Self {
+        Self { interval, timeout }
+    }
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
 /// An error occurred when connecting to the rendezvous server
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
@@ -57,6 +104,9 @@ pub enum RendezvousError {
         #[source]
         ws_stream_wasm::WsErr,
     ),
+    /// The server did not answer our keepalive ping
+    #[error("The rendezvous server did not answer a ping within {:?}", _0)]
+    Timeout(Duration),
 }
 
 impl RendezvousError {
@@ -74,6 +124,11 @@ impl RendezvousError {
     pub(self) fn server(error: impl Into<Box<str>>) -> Self {
         Self::Server(error.into())
     }
+
+    /** Did we lose the connection to the server (as opposed to the server telling us something went wrong)? */
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(self, Self::IO(_) | Self::Timeout(_))
+    }
 }
 
 type MessageQueue = VecDeque<EncryptedMessage>;
@@ -85,6 +140,7 @@ struct NameplateList(Vec<Nameplate>);
 #[cfg(not(target_family = "wasm"))]
 struct WsConnection {
     connection: async_tungstenite::WebSocketStream<async_tungstenite::async_std::ConnectStream>,
+    keepalive: Option<Keepalive>,
 }
 
 #[cfg(target_family = "wasm")]
@@ -204,13 +260,40 @@ impl WsConnection {
         }
     }
 
+    /** Wait for the next websocket message, pinging the server if it takes too long */
+    #[cfg(not(target_family = "wasm"))]
+    async fn next_ws_message(&mut self) -> Result<ws2::Message, RendezvousError> {
+        let Some(keepalive) = self.keepalive else {
+            return Ok(self
+                .connection
+                .next()
+                .await
+                .ok_or(ws2::Error::AlreadyClosed)??);
+        };
+
+        let mut pinged = false;
+        loop {
+            let wait = if pinged {
+                keepalive.timeout
+            } else {
+                keepalive.interval
+            };
+            /* Receiving from the stream is cancel safe */
+            match crate::util::timeout(wait, self.connection.next()).await {
+                Ok(message) => return Ok(message.ok_or(ws2::Error::AlreadyClosed)??),
+                Err(_) if pinged => return Err(RendezvousError::Timeout(keepalive.timeout)),
+                Err(_) => {
+                    tracing::trace!("Connection is idle, sending a ping");
+                    self.connection.send(ws2::Message::Ping(Vec::new())).await?;
+                    pinged = true;
+                },
+            }
+        }
+    }
+
     #[cfg(not(target_family = "wasm"))]
     async fn receive_message(&mut self) -> Result<Option<InboundMessage>, RendezvousError> {
-        let message = self
-            .connection
-            .next()
-            .await
-            .expect("TODO this should always be Some")?;
+        let message = self.next_ws_message().await?;
         match message {
             ws2::Message::Text(message_plain) => {
                 let message = serde_json::from_str(&message_plain)?;
@@ -227,7 +310,7 @@ impl WsConnection {
             ws2::Message::Binary(_) => Err(RendezvousError::protocol(
                 "WebSocket messages must be UTF-8 encoded text",
             )),
-            /* Ignore ping pong for now */
+            /* Pings are answered by the websocket library, and pongs only matter for the keepalive */
             ws2::Message::Ping(_) => Ok(None),
             ws2::Message::Pong(_) => Ok(None),
             ws2::Message::Close(_) => {
@@ -247,7 +330,7 @@ impl WsConnection {
             .connection
             .next()
             .await
-            .expect("TODO this should always be Some");
+            .ok_or(ws_stream_wasm::WsErr::ConnectionNotOpen)?;
         match message {
             ws_stream_wasm::WsMessage::Text(message_plain) => {
                 let message = serde_json::from_str(&message_plain)?;
@@ -326,6 +409,15 @@ pub struct RendezvousServer {
     connection: WsConnection,
     state: Option<MailboxMachine>,
     side: MySide,
+    /* Needed to reconnect */
+    appid: AppID,
+    relay_url: String,
+    #[cfg_attr(target_family = "wasm", allow(dead_code))]
+    keepalive: Option<Keepalive>,
+    proxy: Option<Proxy>,
+    /* If we run the server ourselves, it lives as long as we are connected */
+    #[cfg(all(feature = "lan", not(target_family = "wasm")))]
+    lan_host: Option<crate::core::lan::Host>,
 }
 
 #[allow(deprecated)]
@@ -334,6 +426,9 @@ impl std::fmt::Debug for RendezvousServer {
         fmt.debug_struct("RendezvousServer")
             .field("state", &self.state)
             .field("side", &self.side)
+            .field("appid", &self.appid)
+            .field("relay_url", &self.relay_url)
+            .field("proxy", &self.proxy)
             .finish()
     }
 }
@@ -351,13 +446,102 @@ impl RendezvousServer {
         appid: &AppID,
         relay_url: &str,
     ) -> Result<(Self, Option<String>), RendezvousError> {
+        Self::connect_with_config(&AppConfig {
+            id: appid.clone(),
+            rendezvous_url: relay_url.to_owned().into(),
+            app_version: (),
+            rendezvous_keepalive: Some(Keepalive::DEFAULT),
+            proxy: None,
+        })
+        .await
+    }
+
+    /**
+     * Connect to the rendezvous server, using the URL, keepalive and proxy settings of `config`
+     */
+    pub async fn connect_with_config<V>(
+        config: &AppConfig<V>,
+    ) -> Result<(Self, Option<String>), RendezvousError> {
+        let AppConfig {
+            id: appid,
+            rendezvous_url: relay_url,
+            rendezvous_keepalive: keepalive,
+            proxy,
+            ..
+        } = config;
         let side = MySide::generate();
+        let (connection, motd) =
+            Self::connect_and_bind(appid, relay_url, &side, *keepalive, proxy.as_ref()).await?;
+
+        tracing::info!("Connected to rendezvous server.");
+
+        Ok((
+            Self {
+                connection,
+                state: None,
+                side,
+                appid: appid.clone(),
+                relay_url: relay_url.to_string(),
+                keepalive: *keepalive,
+                proxy: proxy.clone(),
+                #[cfg(all(feature = "lan", not(target_family = "wasm")))]
+                lan_host: None,
+            },
+            motd,
+        ))
+    }
+
+    /** The proxy through which we are connected, if any */
+    pub(crate) fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    /** Keep the server we are connected to running until we are done */
+    #[cfg(all(feature = "lan", not(target_family = "wasm")))]
+    pub(crate) fn keep_lan_host(&mut self, host: crate::core::lan::Host) {
+        self.lan_host = Some(host);
+    }
+
+    /** Open the websocket, do the permission negotiation and bind to our side */
+    async fn connect_and_bind(
+        appid: &AppID,
+        relay_url: &str,
+        side: &MySide,
+        #[cfg_attr(target_family = "wasm", allow(unused_variables))] keepalive: Option<Keepalive>,
+        #[cfg_attr(target_family = "wasm", allow(unused_variables))] proxy: Option<&Proxy>,
+    ) -> Result<(WsConnection, Option<String>), RendezvousError> {
         let mut connection;
 
         #[cfg(not(target_arch = "wasm32"))]
         {
-            let (stream, _) = async_tungstenite::async_std::connect_async(relay_url).await?;
-            connection = WsConnection { connection: stream };
+            let (stream, _) = match proxy {
+                Some(proxy) => {
+                    use ws2::client::IntoClientRequest;
+
+                    let request = relay_url.into_client_request()?;
+                    let uri = request.uri();
+                    let host = uri
+                        .host()
+                        .ok_or(ws2::Error::Url(ws2::error::UrlError::NoHostName))?
+                        .trim_start_matches('[')
+                        .trim_end_matches(']')
+                        .to_owned();
+                    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                        Some("wss") => 443,
+                        _ => 80,
+                    });
+                    let socket = proxy.connect(&host, port).await.map_err(ws2::Error::Io)?;
+                    async_tungstenite::async_std::client_async_tls_with_connector_and_config(
+                        request, socket, None, None,
+                    )
+                    .await?
+                },
+                None => async_tungstenite::async_std::connect_async(relay_url).await?,
+            };
+            connection = WsConnection {
+                connection: stream,
+                keepalive,
+            };
         }
 
         #[cfg(target_arch = "wasm32")]
@@ -409,16 +593,97 @@ impl RendezvousServer {
             .send_message(&OutboundMessage::bind(appid.clone(), side.clone()), None)
             .await?;
 
-        tracing::info!("Connected to rendezvous server.");
+        Ok((connection, welcome.motd))
+    }
 
-        Ok((
-            Self {
-                connection,
-                state: None,
-                side,
+    /**
+     * Reconnect to the server after the connection got lost, with exponential backoff
+     *
+     * We bind to the same side again, so that the server recognizes us. If we have a mailbox,
+     * the nameplate (if still held) is claimed again and the mailbox reopened. The server will
+     * then send all messages of the mailbox again, the ones we already processed are discarded.
+     */
+    async fn reconnect(&mut self, error: RendezvousError) -> Result<(), RendezvousError> {
+        /* Boxed, because connecting makes for a huge future which would bloat all our callers */
+        Box::pin(self.reconnect_inner(error)).await
+    }
+
+    async fn reconnect_inner(&mut self, mut error: RendezvousError) -> Result<(), RendezvousError> {
+        let mut delay = RECONNECT_INITIAL_DELAY;
+        for attempt in 1..=RECONNECT_ATTEMPTS {
+            tracing::warn!(
+                "Lost connection to the rendezvous server ({}), reconnecting in {:?} (attempt {}/{})",
+                error,
+                delay,
+                attempt,
+                RECONNECT_ATTEMPTS
+            );
+            crate::util::sleep(delay).await;
+            match self.try_reconnect().await {
+                Ok(()) => {
+                    tracing::info!("Reconnected to rendezvous server.");
+                    return Ok(());
+                },
+                Err(new_error) if new_error.is_connection_lost() => error = new_error,
+                Err(new_error) => return Err(new_error),
+            }
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+        Err(error)
+    }
+
+    async fn try_reconnect(&mut self) -> Result<(), RendezvousError> {
+        let (connection, _motd) = Self::connect_and_bind(
+            &self.appid,
+            &self.relay_url,
+            &self.side,
+            self.keepalive,
+            self.proxy.as_ref(),
+        )
+        .await?;
+        self.connection = connection;
+
+        let Some(MailboxMachine {
+            nameplate,
+            mailbox,
+            mut queue,
+            processed,
+        }) = self.state.take()
+        else {
+            return Ok(());
+        };
+
+        let reopened = async {
+            if let Some(nameplate) = &nameplate {
+                self.send_message(&OutboundMessage::claim(nameplate.clone()))
+                    .await?;
+                match self.receive_reply().await? {
+                    RendezvousReply::Claimed(claimed) if claimed == mailbox => (),
+                    other => return Err(RendezvousError::invalid_message("claimed", other)),
+                }
+            }
+            self.open_directly(mailbox.clone()).await
+        }
+        .await;
+
+        /* Restore our state, no matter whether reopening worked */
+        match &mut self.state {
+            Some(state) => {
+                queue.append(&mut state.queue);
+                state.queue = queue;
+                state.nameplate = nameplate;
+                state.processed = processed;
             },
-            welcome.motd,
-        ))
+            None => {
+                self.state = Some(MailboxMachine {
+                    nameplate,
+                    mailbox,
+                    queue,
+                    processed,
+                })
+            },
+        }
+        reopened
     }
 
     /** A random unique string for this session */
@@ -438,13 +703,24 @@ impl RendezvousServer {
             .await
     }
 
+    /**
+     * Send a message to the peer
+     *
+     * If the connection gets lost, we reconnect and send it again. The peer
+     * discards the message if it arrives twice.
+     */
     pub(crate) async fn send_peer_message(
         &mut self,
         phase: Phase,
         body: Vec<u8>,
     ) -> Result<(), RendezvousError> {
-        self.send_message(&OutboundMessage::Add { body, phase })
-            .await
+        let message = OutboundMessage::Add { body, phase };
+        loop {
+            match self.send_message(&message).await {
+                Err(error) if error.is_connection_lost() => self.reconnect(error).await?,
+                result => return result,
+            }
+        }
     }
 
     pub(crate) async fn next_peer_message_some(
@@ -471,7 +747,16 @@ impl RendezvousServer {
                 return Ok(None);
             }
         }
-        match self.connection.receive_message().await? {
+        let message = match self.connection.receive_message().await {
+            Err(error) if error.is_connection_lost() => {
+                /* Reopening the mailbox will fill our queue again */
+                self.reconnect(error).await?;
+                return Ok(None);
+            },
+            result => result?,
+        };
+        let machine = self.state.as_mut().unwrap();
+        match message {
             Some(InboundMessage::Message(message)) => {
                 if machine.receive_message(&message, &self.side) {
                     Ok(Some(message))
@@ -0,0 +1,558 @@
+//! A rendezvous server ("mailbox server") implementation
+//!
+//! It speaks the same protocol as the [Python mailbox server](https://github.com/magic-wormhole/magic-wormhole-mailbox-server),
+//! so clients of all implementations can use it. All state is kept in memory, a restart of the server
+//! drops all nameplates and mailboxes.
+//!
+//! ```no_run
+//! use magic_wormhole::rendezvous::server::MailboxServer;
+//! # #[async_std::main] async fn main() -> std::io::Result<()> {
+//! let server = MailboxServer::bind("[::]:4000").await?;
+//! println!("Listening on {}", server.url()?);
+//! server.run().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use async_tungstenite::tungstenite as ws2;
+use futures::{channel::mpsc, prelude::*};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "lan")]
+use crate::core::AppID;
+use crate::core::{
+    server_messages::{EncryptedMessage, InboundMessage, OutboundMessage, WelcomeMessage},
+    Mailbox, Nameplate,
+};
+
+/// Mailboxes without any connected client get deleted after this time
+const MAILBOX_EXPIRATION: Duration = Duration::from_secs(2 * 60 * 60);
+/// How often to check for expired mailboxes
+const EXPIRATION_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// A rendezvous server, listening for websocket connections
+pub struct MailboxServer {
+    listener: TcpListener,
+    motd: Option<String>,
+    state: Arc<Mutex<State>>,
+}
+
+impl std::fmt::Debug for MailboxServer {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("MailboxServer")
+            .field("listener", &self.listener)
+            .field("motd", &self.motd)
+            .finish()
+    }
+}
+
+impl MailboxServer {
+    /// Listen on the given address. Use port 0 to get a free port assigned by the OS.
+    pub async fn bind(address: impl ToSocketAddrs) -> std::io::Result<Self> {
+        Ok(Self::from_listener(TcpListener::bind(address).await?))
+    }
+
+    /// Serve on a listener that has already been set up, e.g. with custom socket options
+    pub fn from_listener(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            motd: None,
+            state: Default::default(),
+        }
+    }
+
+    /// Set a "message of the day", which clients show to their users when connecting
+    pub fn motd(mut self, motd: impl Into<String>) -> Self {
+        self.motd = Some(motd.into());
+        self
+    }
+
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The URL clients should use to connect to this server, like `ws://127.0.0.1:4000/v1`
+    pub fn url(&self) -> std::io::Result<String> {
+        Ok(format!("ws://{}/v1", self.local_addr()?))
+    }
+
+    /// Check whether a nameplate is claimed by exactly one side, i.e. still waits for the peer.
+    /// This keeps working while the server runs.
+    #[cfg(feature = "lan")]
+    pub(crate) fn waiting_for_peer(
+        &self,
+    ) -> impl Fn(&AppID, &Nameplate) -> bool + Send + Sync + 'static {
+        let state = self.state.clone();
+        move |appid, nameplate| {
+            state
+                .lock()
+                .unwrap()
+                .apps
+                .get(appid.as_ref())
+                .and_then(|app| app.nameplates.get(nameplate.as_ref()))
+                .is_some_and(|nameplate| nameplate.sides.len() == 1)
+        }
+    }
+
+    /**
+     * Serve clients
+     *
+     * This only returns if accepting new connections fails. Errors with individual clients
+     * are logged and only terminate the respective connection.
+     */
+    pub async fn run(self) -> std::io::Result<()> {
+        let state = self.state.clone();
+        let expire = async move {
+            loop {
+                async_std::task::sleep(EXPIRATION_CHECK_INTERVAL).await;
+                state.lock().unwrap().expire(Instant::now());
+            }
+        };
+
+        let accept = async {
+            for connection_id in 0.. {
+                let (stream, peer) = self.listener.accept().await?;
+                tracing::debug!("New connection from {peer}");
+                let state = self.state.clone();
+                let motd = self.motd.clone();
+                async_std::task::spawn(async move {
+                    if let Err(error) = handle_connection(stream, connection_id, state, motd).await
+                    {
+                        tracing::debug!("Connection to {peer} failed: {error}");
+                    }
+                });
+            }
+            unreachable!()
+        };
+
+        futures::pin_mut!(expire);
+        futures::pin_mut!(accept);
+        futures::future::select(expire, accept)
+            .await
+            .factor_first()
+            .0
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    connection_id: u64,
+    state: Arc<Mutex<State>>,
+    motd: Option<String>,
+) -> Result<(), ws2::Error> {
+    let (mut sink, mut stream) = async_tungstenite::accept_async(stream).await?.split();
+
+    /* Everything we send goes through this channel, so that other connections can deliver messages too */
+    let (tx, mut rx) = mpsc::unbounded::<InboundMessage>();
+    let send_task = async move {
+        while let Some(message) = rx.next().await {
+            tracing::trace!("Sending {message}");
+            sink.send(ws2::Message::Text(serde_json::to_string(&message).unwrap()))
+                .await?;
+        }
+        sink.close().await
+    };
+
+    let mut connection = Connection {
+        id: connection_id,
+        state,
+        tx: tx.clone(),
+        app: None,
+        side: None,
+        allocated: None,
+        claimed: None,
+        mailbox: None,
+        closed: false,
+    };
+    let receive_task = async move {
+        let _ = tx.unbounded_send(InboundMessage::Welcome {
+            welcome: WelcomeMessage {
+                motd,
+                ..Default::default()
+            },
+        });
+
+        while let Some(message) = stream.next().await {
+            match message? {
+                ws2::Message::Text(text) => connection.receive(&text),
+                ws2::Message::Close(_) => break,
+                /* Pings get answered by the websocket library */
+                _ => (),
+            }
+        }
+        /* Close our sending half as well */
+        drop(connection);
+        tx.close_channel();
+        Ok(())
+    };
+
+    let (received, sent) = futures::future::join(receive_task, send_task).await;
+    received.and(sent)
+}
+
+/// The state of a single client connection
+struct Connection {
+    id: u64,
+    state: Arc<Mutex<State>>,
+    tx: mpsc::UnboundedSender<InboundMessage>,
+    app: Option<String>,
+    side: Option<String>,
+    allocated: Option<String>,
+    claimed: Option<String>,
+    mailbox: Option<String>,
+    closed: bool,
+}
+
+impl Connection {
+    fn send(&self, message: InboundMessage) {
+        /* If this fails, the connection is already closing */
+        let _ = self.tx.unbounded_send(message);
+    }
+
+    fn receive(&mut self, text: &str) {
+        let value: serde_json::Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(_) => {
+                self.send(InboundMessage::Error {
+                    error: "Messages must be JSON".into(),
+                    orig: Box::new(text.into()),
+                });
+                return;
+            },
+        };
+        /* Every message gets acknowledged first, only then comes the reply */
+        self.send(InboundMessage::Ack);
+
+        let result = serde_json::from_value(value.clone())
+            .map_err(|_| "unknown message type or missing fields")
+            .and_then(|message| self.handle(message));
+        if let Err(error) = result {
+            self.send(InboundMessage::Error {
+                error: error.into(),
+                orig: Box::new(value),
+            });
+        }
+    }
+
+    fn handle(&mut self, message: OutboundMessage) -> Result<(), &'static str> {
+        tracing::trace!("Received {message}");
+        let (app, side) = match (&message, &self.app, &self.side) {
+            (OutboundMessage::Ping { ping }, _, _) => {
+                self.send(InboundMessage::Pong { pong: *ping });
+                return Ok(());
+            },
+            (OutboundMessage::SubmitPermission(_), _, _) => {
+                /* We don't require any permission */
+                return Ok(());
+            },
+            (OutboundMessage::Bind { appid, side }, None, _) => {
+                self.app = Some(appid.as_ref().to_owned());
+                self.side = Some((***side).to_owned());
+                return Ok(());
+            },
+            (OutboundMessage::Bind { .. }, Some(_), _) => return Err("already bound"),
+            (_, Some(app), Some(side)) => (app.clone(), side.clone()),
+            _ => return Err("must bind first"),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let app = state.apps.entry(app).or_default();
+        let now = Instant::now();
+        match message {
+            OutboundMessage::List => {
+                let nameplates = app.nameplates.keys().map(Nameplate::new).collect();
+                self.send(InboundMessage::Nameplates { nameplates });
+            },
+            OutboundMessage::Allocate => {
+                if self.allocated.is_some() {
+                    return Err("you already allocated one, don't be greedy");
+                }
+                let nameplate = app.allocate_nameplate();
+                app.claim_nameplate(&nameplate, &side, now)?;
+                self.allocated = Some(nameplate.clone());
+                self.send(InboundMessage::Allocated {
+                    nameplate: Nameplate::new(nameplate),
+                });
+            },
+            OutboundMessage::Claim { nameplate } => {
+                if self.claimed.is_some() {
+                    return Err("only one claim per connection");
+                }
+                let mailbox = app.claim_nameplate(&nameplate, &side, now)?;
+                self.claimed = Some(nameplate);
+                self.send(InboundMessage::Claimed {
+                    mailbox: Mailbox(mailbox),
+                });
+            },
+            OutboundMessage::Release { nameplate } => {
+                if self
+                    .claimed
+                    .as_ref()
+                    .is_some_and(|claimed| *claimed != nameplate)
+                {
+                    return Err("release and claim must use same nameplate");
+                }
+                app.release_nameplate(&nameplate, &side);
+                self.claimed = None;
+                self.send(InboundMessage::Released);
+            },
+            OutboundMessage::Open { mailbox } => {
+                if self.mailbox.is_some() {
+                    return Err("only one open per connection");
+                }
+                let entry =
+                    app.mailboxes
+                        .entry(mailbox.0.clone())
+                        .or_insert_with(|| MailboxState {
+                            sides: HashSet::new(),
+                            messages: Vec::new(),
+                            listeners: HashMap::new(),
+                            updated: now,
+                        });
+                if !entry.sides.contains(&side) && entry.sides.len() >= 2 {
+                    return Err("crowded");
+                }
+                entry.sides.insert(side);
+                entry.updated = now;
+                for message in &entry.messages {
+                    self.send(InboundMessage::Message(message.clone()));
+                }
+                entry.listeners.insert(self.id, self.tx.clone());
+                self.mailbox = Some(mailbox.0);
+            },
+            OutboundMessage::Add { phase, body } => {
+                let entry = match &self.mailbox {
+                    Some(mailbox) if !self.closed => app.mailboxes.get_mut(mailbox),
+                    _ => None,
+                }
+                .ok_or("must open mailbox before adding")?;
+                let message = EncryptedMessage {
+                    side: side.into(),
+                    phase,
+                    body,
+                };
+                entry.updated = now;
+                entry.messages.push(message.clone());
+                entry.listeners.retain(|_, listener| {
+                    listener
+                        .unbounded_send(InboundMessage::Message(message.clone()))
+                        .is_ok()
+                });
+            },
+            OutboundMessage::Close { mailbox, mood } => {
+                match &self.mailbox {
+                    Some(opened) if *opened == mailbox.0 => (),
+                    Some(_) => return Err("open and close must use same mailbox"),
+                    None => return Err("must open mailbox before closing"),
+                }
+                if self.closed {
+                    return Err("only one close per connection");
+                }
+                tracing::debug!("Mailbox {mailbox} closed with mood {mood}");
+                app.close_mailbox(&mailbox.0, &side, self.id);
+                self.closed = true;
+                self.send(InboundMessage::Closed);
+            },
+            OutboundMessage::Ping { .. }
+            | OutboundMessage::SubmitPermission(_)
+            | OutboundMessage::Bind { .. } => unreachable!(),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        /* Stop delivering messages, but keep the claims: the client might reconnect */
+        if let (Some(app), Some(mailbox)) = (&self.app, &self.mailbox) {
+            let mut state = self.state.lock().unwrap();
+            if let Some(mailbox) = state
+                .apps
+                .get_mut(app)
+                .and_then(|app| app.mailboxes.get_mut(mailbox))
+            {
+                mailbox.listeners.remove(&self.id);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    apps: HashMap<String, AppState>,
+}
+
+impl State {
+    /// Delete all mailboxes that have been inactive for too long, together with their nameplates
+    fn expire(&mut self, now: Instant) {
+        for app in self.apps.values_mut() {
+            app.mailboxes.retain(|id, mailbox| {
+                let keep = !mailbox.listeners.is_empty()
+                    || now.duration_since(mailbox.updated) < MAILBOX_EXPIRATION;
+                if !keep {
+                    tracing::debug!("Mailbox {id} expired");
+                }
+                keep
+            });
+            let mailboxes = &app.mailboxes;
+            app.nameplates
+                .retain(|_, nameplate| mailboxes.contains_key(&nameplate.mailbox));
+        }
+        self.apps
+            .retain(|_, app| !app.nameplates.is_empty() || !app.mailboxes.is_empty());
+    }
+}
+
+/// Nameplates and mailboxes are scoped per app ID
+#[derive(Default)]
+struct AppState {
+    nameplates: HashMap<String, NameplateState>,
+    mailboxes: HashMap<String, MailboxState>,
+}
+
+struct NameplateState {
+    mailbox: String,
+    /// The sides that currently claim this nameplate
+    sides: HashSet<String>,
+}
+
+struct MailboxState {
+    /// The sides that opened this mailbox and did not close it yet
+    sides: HashSet<String>,
+    messages: Vec<EncryptedMessage>,
+    /// All connections that currently have the mailbox opened
+    listeners: HashMap<u64, mpsc::UnboundedSender<InboundMessage>>,
+    updated: Instant,
+}
+
+impl AppState {
+    /// Pick a random free nameplate, keeping them as short as possible
+    fn allocate_nameplate(&self) -> String {
+        use rand::seq::SliceRandom;
+
+        let mut rng = rand::thread_rng();
+        let mut range = 1..10u64;
+        loop {
+            let free = range
+                .clone()
+                .map(|id| id.to_string())
+                .filter(|id| !self.nameplates.contains_key(id))
+                .collect::<Vec<_>>();
+            if let Some(id) = free.choose(&mut rng) {
+                break id.clone();
+            }
+            range = range.end..range.end * 10;
+        }
+    }
+
+    /// Claim a nameplate (creating it if necessary) and return the ID of its mailbox
+    fn claim_nameplate(
+        &mut self,
+        nameplate: &str,
+        side: &str,
+        now: Instant,
+    ) -> Result<String, &'static str> {
+        let entry = self
+            .nameplates
+            .entry(nameplate.to_owned())
+            .or_insert_with(|| {
+                use rand::RngCore;
+
+                let mut id = [0; 8];
+                rand::rngs::OsRng.fill_bytes(&mut id);
+                NameplateState {
+                    mailbox: hex::encode(id),
+                    sides: HashSet::new(),
+                }
+            });
+        if !entry.sides.contains(side) && entry.sides.len() >= 2 {
+            return Err("crowded");
+        }
+        entry.sides.insert(side.to_owned());
+        let mailbox = entry.mailbox.clone();
+
+        /* Claiming counts as activity on the mailbox, and prevents the nameplate from expiring */
+        self.mailboxes
+            .entry(mailbox.clone())
+            .or_insert_with(|| MailboxState {
+                sides: HashSet::new(),
+                messages: Vec::new(),
+                listeners: HashMap::new(),
+                updated: now,
+            })
+            .updated = now;
+        Ok(mailbox)
+    }
+
+    fn release_nameplate(&mut self, nameplate: &str, side: &str) {
+        if let Some(entry) = self.nameplates.get_mut(nameplate) {
+            entry.sides.remove(side);
+            if entry.sides.is_empty() {
+                self.nameplates.remove(nameplate);
+            }
+        }
+    }
+
+    fn close_mailbox(&mut self, mailbox: &str, side: &str, connection_id: u64) {
+        if let Some(entry) = self.mailboxes.get_mut(mailbox) {
+            entry.sides.remove(side);
+            entry.listeners.remove(&connection_id);
+            if entry.sides.is_empty() {
+                self.mailboxes.remove(mailbox);
+                self.nameplates
+                    .retain(|_, nameplate| nameplate.mailbox != mailbox);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allocate_nameplate() {
+        let mut app = AppState::default();
+        let now = Instant::now();
+        for _ in 0..9 {
+            let nameplate = app.allocate_nameplate();
+            assert_eq!(nameplate.len(), 1);
+            app.claim_nameplate(&nameplate, "side", now).unwrap();
+        }
+        assert_eq!(app.allocate_nameplate().len(), 2);
+    }
+
+    #[test]
+    fn test_claim_crowded() {
+        let mut app = AppState::default();
+        let now = Instant::now();
+        let mailbox = app.claim_nameplate("1", "a", now).unwrap();
+        assert_eq!(app.claim_nameplate("1", "b", now).unwrap(), mailbox);
+        assert_eq!(app.claim_nameplate("1", "a", now).unwrap(), mailbox);
+        assert_eq!(app.claim_nameplate("1", "c", now), Err("crowded"));
+
+        app.release_nameplate("1", "a");
+        app.release_nameplate("1", "b");
+        assert!(app.nameplates.is_empty());
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut state = State::default();
+        let now = Instant::now();
+        let app = state.apps.entry("app".into()).or_default();
+        app.claim_nameplate("1", "a", now).unwrap();
+
+        state.expire(now + MAILBOX_EXPIRATION / 2);
+        assert_eq!(state.apps["app"].nameplates.len(), 1);
+        state.expire(now + MAILBOX_EXPIRATION);
+        assert!(state.apps.is_empty());
+    }
+}
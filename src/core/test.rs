@@ -20,10 +20,39 @@ pub const APP_CONFIG: AppConfig<()> = AppConfig::<()> {
     id: TEST_APPID,
     rendezvous_url: Cow::Borrowed(crate::rendezvous::DEFAULT_RENDEZVOUS_SERVER),
     app_version: (),
+    rendezvous_keepalive: Some(crate::rendezvous::Keepalive::DEFAULT),
+    proxy: None,
 };
 
 const TIMEOUT: Duration = Duration::from_secs(60);
 
+/// The tests use a rendezvous server on localhost, so that they don't depend on the public one
+fn rendezvous_url() -> Cow<'static, str> {
+    static URL: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    URL.get_or_init(|| {
+        async_std::task::block_on(async {
+            let server = crate::rendezvous::server::MailboxServer::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let url = server.url().unwrap();
+            async_std::task::spawn(server.run());
+            url
+        })
+    })
+    .as_str()
+    .into()
+}
+
+fn app_config() -> AppConfig<()> {
+    APP_CONFIG.rendezvous_url(rendezvous_url())
+}
+
+fn transfer_config() -> AppConfig<transfer::AppVersion> {
+    transfer::APP_CONFIG
+        .id(TEST_APPID)
+        .rendezvous_url(rendezvous_url())
+}
+
 /// Utility method that logs information of the transit result
 ///
 /// Example usage:
@@ -47,11 +76,321 @@ pub(crate) fn log_transit_connection(info: crate::transit::TransitInfo) {
     tracing::info!("{info}")
 }
 
+/// The tests use a transit relay on localhost as well
 fn default_relay_hints() -> Vec<transit::RelayHint> {
-    vec![
-        transit::RelayHint::from_urls(None, [transit::DEFAULT_RELAY_SERVER.parse().unwrap()])
-            .unwrap(),
-    ]
+    static HINT: std::sync::OnceLock<transit::RelayHint> = std::sync::OnceLock::new();
+    let hint = HINT.get_or_init(|| {
+        async_std::task::block_on(async {
+            let server = transit::server::RelayServer::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let hint = server.relay_hint().unwrap();
+            async_std::task::spawn(server.run());
+            hint
+        })
+    });
+    vec![hint.clone()]
+}
+
+/** Establish a transit connection over the relay server only */
+async fn connect_transit(
+    leader_abilities: transit::Abilities,
+    follower_abilities: transit::Abilities,
+) -> eyre::Result<(
+    (transit::Transit, transit::TransitInfo),
+    (transit::Transit, transit::TransitInfo),
+)> {
+    let key = || crate::Key::<transit::TransitKey>::new(Box::new([7u8; 32].into()));
+    let leader = transit::init(leader_abilities, None, default_relay_hints()).await?;
+    let follower = transit::init(follower_abilities, None, default_relay_hints()).await?;
+    let (leader_abilities, leader_hints) = (*leader.our_abilities(), leader.our_hints().clone());
+    let (follower_abilities, follower_hints) =
+        (*follower.our_abilities(), follower.our_hints().clone());
+
+    Ok(futures::try_join!(
+        leader.leader_connect(key(), follower_abilities, follower_hints),
+        follower.follower_connect(key(), leader_abilities, leader_hints),
+    )?)
+}
+
+async fn connect_transit_relay(
+    leader_abilities: transit::Abilities,
+    follower_abilities: transit::Abilities,
+) -> eyre::Result<(transit::Transit, transit::Transit)> {
+    let ((leader, leader_info), (follower, _)) =
+        connect_transit(leader_abilities, follower_abilities).await?;
+    assert!(matches!(
+        leader_info.conn_type,
+        transit::ConnectionType::Relay { .. }
+    ));
+    Ok((leader, follower))
+}
+
+async fn transit_relay(
+    leader_abilities: transit::Abilities,
+    follower_abilities: transit::Abilities,
+) -> eyre::Result<()> {
+    let (mut leader, mut follower) =
+        connect_transit_relay(leader_abilities, follower_abilities).await?;
+    leader.send_record(b"hello").await?;
+    assert_eq!(&*follower.receive_record().await?, b"hello");
+    follower.send_record(b"world").await?;
+    assert_eq!(&*leader.receive_record().await?, b"world");
+    Ok(())
+}
+
+#[test(async_std::test)]
+pub async fn test_transit_relay() -> eyre::Result<()> {
+    transit_relay(
+        transit::Abilities::FORCE_RELAY,
+        transit::Abilities::FORCE_RELAY,
+    )
+    .await
+}
+
+/** Copy data larger than a record through a transit stream in both directions at once */
+#[test(async_std::test)]
+pub async fn test_transit_stream() -> eyre::Result<()> {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    let (leader, follower) = connect_transit_relay(
+        transit::Abilities::FORCE_RELAY,
+        transit::Abilities::FORCE_RELAY,
+    )
+    .await?;
+    let data: Vec<u8> = (0..3 * transit::RECORD_SIZE + 1234)
+        .map(|i| (i % 251) as u8)
+        .collect();
+
+    let (mut leader_reader, mut leader_writer) = leader.into_stream().split();
+    let mut follower = follower.into_stream();
+
+    /* The follower echoes everything back */
+    let echo = async {
+        let mut received = Vec::new();
+        follower.read_to_end(&mut received).await?;
+        follower.write_all(&received).await?;
+        follower.close().await?;
+        eyre::Result::<_>::Ok(received)
+    };
+    let send = async {
+        leader_writer.write_all(&data[..10]).await?;
+        leader_writer.flush().await?;
+        leader_writer.write_all(&data[10..]).await?;
+        leader_writer.close().await?;
+        eyre::Result::<_>::Ok(())
+    };
+    let receive = async {
+        let mut echoed = Vec::new();
+        leader_reader.read_to_end(&mut echoed).await?;
+        eyre::Result::<_>::Ok(echoed)
+    };
+    let (received, (), echoed) = futures::try_join!(echo, send, receive)?;
+    assert_eq!(received, data);
+    assert_eq!(echoed, data);
+
+    /* Writing after closing fails */
+    assert!(leader_writer.write_all(b"more").await.is_err());
+    Ok(())
+}
+
+/** Stripe records across parallel connections, over the relay and directly */
+#[test(async_std::test)]
+pub async fn test_transit_parallel() -> eyre::Result<()> {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    for abilities in [transit::Abilities::FORCE_RELAY, transit::Abilities::ALL] {
+        let abilities = transit::Abilities {
+            parallel_v1: 3,
+            ..abilities
+        };
+        let ((mut leader, leader_info), (mut follower, follower_info)) =
+            connect_transit(abilities, abilities).await?;
+        assert_eq!(leader_info.connections, 3);
+        assert_eq!(follower_info.connections, 3);
+        if abilities.can_direct() {
+            assert_eq!(leader_info.conn_type, transit::ConnectionType::Direct);
+        }
+
+        /* Records must arrive in order, no matter on which connection they were sent */
+        let send = async {
+            for i in 0..20u8 {
+                leader.send_record(&vec![i; 100 + i as usize]).await?;
+            }
+            leader.flush().await?;
+            eyre::Result::<_>::Ok(())
+        };
+        let receive = async {
+            for i in 0..20u8 {
+                assert_eq!(*follower.receive_record().await?, vec![i; 100 + i as usize]);
+            }
+            eyre::Result::<_>::Ok(())
+        };
+        futures::try_join!(send, receive)?;
+
+        /* Also as stream, continuing the turns where the records left off */
+        follower.send_record(b"switch").await?;
+        assert_eq!(&*leader.receive_record().await?, b"switch");
+        let data: Vec<u8> = (0..5 * transit::RECORD_SIZE + 1)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut leader = leader.into_stream();
+        let mut follower = follower.into_stream();
+        let send = async {
+            leader.write_all(&data).await?;
+            leader.close().await?;
+            eyre::Result::<_>::Ok(())
+        };
+        let receive = async {
+            let mut received = Vec::new();
+            follower.read_to_end(&mut received).await?;
+            eyre::Result::<_>::Ok(received)
+        };
+        let ((), received) = futures::try_join!(send, receive)?;
+        assert_eq!(received, data);
+    }
+
+    /* Both sides need to support it, and use noise */
+    let parallel = transit::Abilities {
+        parallel_v1: 3,
+        ..transit::Abilities::FORCE_RELAY
+    };
+    let ((_, info), _) = connect_transit(parallel, transit::Abilities::FORCE_RELAY).await?;
+    assert_eq!(info.connections, 1);
+    let secretbox = transit::Abilities {
+        noise_v1: false,
+        ..parallel
+    };
+    let ((_, info), _) = connect_transit(parallel, secretbox).await?;
+    assert_eq!(info.connections, 1);
+    Ok(())
+}
+
+/** Forward connections to a local port, but only after a delay */
+async fn start_delayed_proxy(port: u16, delay: Duration) -> std::io::Result<transit::DirectHint> {
+    let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+    let hint = transit::DirectHint::new("127.0.0.1", listener.local_addr()?.port());
+    async_std::task::spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            async_std::task::spawn(async move {
+                async_std::task::sleep(delay).await;
+                let server = async_std::net::TcpStream::connect(("127.0.0.1", port)).await?;
+                let (mut client_tx, mut server_tx) = (client.clone(), server.clone());
+                futures::try_join!(
+                    futures::io::copy(client, &mut server_tx),
+                    futures::io::copy(server, &mut client_tx),
+                )?;
+                std::io::Result::Ok(())
+            });
+        }
+    });
+    Ok(hint)
+}
+
+/** Send records back and forth, slowly */
+async fn ping_pong(
+    leader: &mut transit::Transit,
+    follower: &mut transit::Transit,
+    rounds: u32,
+) -> eyre::Result<()> {
+    for i in 0..rounds {
+        let record = i.to_be_bytes().repeat(1000);
+        leader.send_record(&record).await?;
+        leader.flush().await?;
+        assert_eq!(*follower.receive_record().await?, *record);
+        follower.send_record(&record).await?;
+        follower.flush().await?;
+        assert_eq!(*leader.receive_record().await?, *record);
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+    Ok(())
+}
+
+/** Start on the relay, then move to a direct connection that comes up later, without losing data */
+#[test(async_std::test)]
+pub async fn test_transit_migrate() -> eyre::Result<()> {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    let relay = transit::server::RelayServer::bind("127.0.0.1:0").await?;
+    let usage = relay.usage();
+    let relay_hints = vec![relay.relay_hint()?];
+    async_std::task::spawn(relay.run());
+
+    let key = || crate::Key::<transit::TransitKey>::new(Box::new([7u8; 32].into()));
+    let leader = transit::init(transit::Abilities::ALL, None, relay_hints.clone()).await?;
+    let follower = transit::init(transit::Abilities::ALL, None, relay_hints).await?;
+    /* Direct connections only succeed after the relay one got chosen */
+    let delayed = |hints: &transit::Hints| {
+        /* Not all advertised ports are listening, so proxy all of them */
+        let ports: std::collections::HashSet<u16> =
+            hints.direct_tcp.iter().map(|hint| hint.port).collect();
+        let hints = hints.clone();
+        async move {
+            let mut direct_tcp = std::collections::HashSet::new();
+            for port in ports {
+                direct_tcp.insert(start_delayed_proxy(port, Duration::from_secs(2)).await?);
+            }
+            eyre::Result::<_>::Ok(std::sync::Arc::new(transit::Hints {
+                direct_tcp,
+                ..hints
+            }))
+        }
+    };
+    let leader_hints = delayed(leader.our_hints()).await?;
+    let follower_hints = delayed(follower.our_hints()).await?;
+    let (leader_abilities, follower_abilities) =
+        (*leader.our_abilities(), *follower.our_abilities());
+
+    let ((mut leader, leader_info), (mut follower, _)) = futures::try_join!(
+        leader.leader_connect(key(), follower_abilities, follower_hints),
+        follower.follower_connect(key(), leader_abilities, leader_hints),
+    )?;
+    assert!(matches!(
+        leader_info.conn_type,
+        transit::ConnectionType::Relay { .. }
+    ));
+
+    /* Keep using the transit in both directions while the direct connection comes up */
+    ping_pong(&mut leader, &mut follower, 40).await?;
+
+    /* The relay is not used anymore */
+    assert_eq!(usage.get().sessions, 1);
+    assert_eq!(usage.get().active_sessions, 0);
+    let relayed = usage.get().bytes_relayed;
+    ping_pong(&mut leader, &mut follower, 5).await?;
+    assert_eq!(usage.get().bytes_relayed, relayed);
+
+    /* Streams work on top of the moved connection as well */
+    let data: Vec<u8> = (0..3 * transit::RECORD_SIZE + 1)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let mut leader = leader.into_stream();
+    let mut follower = follower.into_stream();
+    let send = async {
+        leader.write_all(&data).await?;
+        leader.close().await?;
+        eyre::Result::<_>::Ok(())
+    };
+    let receive = async {
+        let mut received = Vec::new();
+        follower.read_to_end(&mut received).await?;
+        eyre::Result::<_>::Ok(received)
+    };
+    let ((), received) = futures::try_join!(send, receive)?;
+    assert_eq!(received, data);
+    Ok(())
+}
+
+/** Fall back to secretbox if only one side supports noise, no matter which */
+#[test(async_std::test)]
+pub async fn test_transit_relay_secretbox() -> eyre::Result<()> {
+    let secretbox = transit::Abilities {
+        noise_v1: false,
+        ..transit::Abilities::FORCE_RELAY
+    };
+    transit_relay(secretbox, secretbox).await?;
+    transit_relay(transit::Abilities::FORCE_RELAY, secretbox).await?;
+    transit_relay(secretbox, transit::Abilities::FORCE_RELAY).await
 }
 
 #[test(async_std::test)]
@@ -59,8 +398,7 @@ pub async fn test_connect_with_unknown_code_and_allocate_passes() -> eyre::Resul
 {
     let code = generate_random_code();
 
-    let mailbox_connection =
-        MailboxConnection::connect(transfer::APP_CONFIG.id(TEST_APPID).clone(), code, true).await;
+    let mailbox_connection = MailboxConnection::connect(transfer_config(), code, true).await;
 
     assert!(mailbox_connection.is_ok());
 
@@ -72,12 +410,8 @@ pub async fn test_connect_with_unknown_code_and_no_allocate_fails() {
     tracing::info!("hola!");
     let code = generate_random_code();
 
-    let mailbox_connection = MailboxConnection::connect(
-        transfer::APP_CONFIG.id(TEST_APPID).clone(),
-        code.clone(),
-        false,
-    )
-    .await;
+    let mailbox_connection =
+        MailboxConnection::connect(transfer_config(), code.clone(), false).await;
 
     assert!(mailbox_connection.is_err());
     let error = mailbox_connection.err().unwrap();
@@ -86,7 +420,7 @@ pub async fn test_connect_with_unknown_code_and_no_allocate_fails() {
             assert_eq!(nameplate, code.nameplate());
         },
         _ => {
-            assert!(false);
+            panic!();
         },
     }
 }
@@ -134,8 +468,7 @@ async fn file_offers(
                             if self.send_bytes == self.receive_bytes {
                                 Poll::Ready(Ok(()))
                             } else {
-                                Poll::Ready(Err(io::Error::new(
-                                    io::ErrorKind::Other,
+                                Poll::Ready(Err(io::Error::other(
                                     "Send and receive are not the same",
                                 )))
                             }
@@ -183,6 +516,56 @@ async fn file_offers(
     ])
 }
 
+/** Receive an offer using the pre-made answer from [`file_offers`], with the protocol version picked by the sender */
+#[cfg(feature = "transfer")]
+async fn receive_file_offer(
+    wormhole: Wormhole,
+    answer: transfer::offer::OfferAccept,
+) -> eyre::Result<()> {
+    #[cfg(feature = "experimental-transfer-v2")]
+    let req = match transfer::request(
+        wormhole,
+        default_relay_hints(),
+        transit::Abilities::ALL_ABILITIES,
+        futures::future::pending(),
+    )
+    .await?
+    .unwrap()
+    {
+        transfer::ReceiveRequest::V1(req) => req,
+        transfer::ReceiveRequest::V2(req) => {
+            req.accept(
+                &log_transit_connection,
+                answer,
+                |_received, _total| {},
+                futures::future::pending(),
+            )
+            .await?;
+            return Ok(());
+        },
+    };
+    #[cfg(not(feature = "experimental-transfer-v2"))]
+    let req = transfer::request_file(
+        wormhole,
+        default_relay_hints(),
+        transit::Abilities::ALL_ABILITIES,
+        futures::future::pending(),
+    )
+    .await?
+    .unwrap();
+
+    // Hacky v1-compat conversion
+    let mut answer = (answer.into_iter_files().next().unwrap().1.content)(false).await?;
+    req.accept(
+        &log_transit_connection,
+        |_received, _total| {},
+        &mut answer,
+        futures::future::pending(),
+    )
+    .await?;
+    Ok(())
+}
+
 /** Send a file using the Rust implementation (using deprecated API). This does not guarantee compatibility with Python! ;) */
 #[cfg(feature = "transfer")]
 #[test(async_std::test)]
@@ -195,8 +578,7 @@ pub async fn test_file_rust2rust_deprecated() -> eyre::Result<()> {
             .name("sender".to_owned())
             .spawn(async {
                 let (welcome, wormhole_future) =
-                    Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID).clone(), 2)
-                        .await?;
+                    Wormhole::connect_without_code(transfer_config(), 2).await?;
                 if let Some(welcome) = &welcome.welcome {
                     tracing::info!("Got welcome: {}", welcome);
                 }
@@ -220,43 +602,14 @@ pub async fn test_file_rust2rust_deprecated() -> eyre::Result<()> {
             .name("receiver".to_owned())
             .spawn(async {
                 let code = code_rx.await?;
-                let config = transfer::APP_CONFIG.id(TEST_APPID);
+                let config = transfer_config();
                 tracing::info!("Got code over local: {}", &code);
                 let (welcome, wormhole) = Wormhole::connect_with_code(config.clone(), code).await?;
                 if let Some(welcome) = &welcome.welcome {
                     tracing::info!("Got welcome: {}", welcome);
                 }
 
-                // Hacky v1-compat conversion for now
-                let mut answer =
-                    (answer.into_iter_files().next().unwrap().1.content)(false).await?;
-
-                /*let transfer::ReceiveRequest::V1(req) = transfer::request(
-                    wormhole,
-                    default_relay_hints(),
-                    magic_wormhole::transit::Abilities::ALL_ABILITIES,
-                    futures::future::pending(),
-                )
-                .await?
-                .unwrap() else {
-                    panic!("v2 should be disabled for now")
-                };*/
-                let req = transfer::request_file(
-                    wormhole,
-                    default_relay_hints(),
-                    magic_wormhole::transit::Abilities::ALL_ABILITIES,
-                    futures::future::pending(),
-                )
-                .await?
-                .unwrap();
-
-                req.accept(
-                    &log_transit_connection,
-                    |_received, _total| {},
-                    &mut answer,
-                    futures::future::pending(),
-                )
-                .await?;
+                receive_file_offer(wormhole, answer).await?;
                 eyre::Result::<_>::Ok(())
             })?;
 
@@ -276,9 +629,7 @@ pub async fn test_file_rust2rust() -> eyre::Result<()> {
         let sender_task = async_std::task::Builder::new()
             .name("sender".to_owned())
             .spawn(async {
-                let mailbox_connection =
-                    MailboxConnection::create(transfer::APP_CONFIG.id(TEST_APPID).clone(), 2)
-                        .await?;
+                let mailbox_connection = MailboxConnection::create(transfer_config(), 2).await?;
                 if let Some(welcome) = &mailbox_connection.welcome {
                     tracing::info!("Got welcome: {}", welcome);
                 }
@@ -303,49 +654,261 @@ pub async fn test_file_rust2rust() -> eyre::Result<()> {
             .name("receiver".to_owned())
             .spawn(async {
                 let code = code_rx.await?;
-                let config = transfer::APP_CONFIG.id(TEST_APPID);
+                let config = transfer_config();
                 let mailbox = MailboxConnection::connect(config, code.clone(), false).await?;
                 if let Some(welcome) = mailbox.welcome.clone() {
                     tracing::info!("Got welcome: {}", welcome);
                 }
                 let wormhole = Wormhole::connect(mailbox).await?;
 
-                // Hacky v1-compat conversion for now
-                let mut answer =
-                    (answer.into_iter_files().next().unwrap().1.content)(false).await?;
+                receive_file_offer(wormhole, answer).await?;
+                eyre::Result::<_>::Ok(())
+            })?;
 
-                /*let transfer::ReceiveRequest::V1(req) = transfer::request(
+        sender_task.await?;
+        receiver_task.await?;
+    }
+    Ok(())
+}
+
+/** Send a text message using the Rust implementation */
+#[cfg(feature = "transfer")]
+#[test(async_std::test)]
+pub async fn test_text_rust2rust() -> eyre::Result<()> {
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let sender_task = async_std::task::Builder::new()
+        .name("sender".to_owned())
+        .spawn(async {
+            let mailbox_connection = MailboxConnection::create(transfer_config(), 2).await?;
+            code_tx.send(mailbox_connection.code.clone()).unwrap();
+            let wormhole = Wormhole::connect(mailbox_connection).await?;
+            transfer::send_text(wormhole, "Hello, world!", futures::future::pending()).await?;
+            eyre::Result::<_>::Ok(())
+        })?;
+    let receiver_task = async_std::task::Builder::new()
+        .name("receiver".to_owned())
+        .spawn(async {
+            let code = code_rx.await?;
+            let config = transfer_config();
+            let mailbox = MailboxConnection::connect(config, code, false).await?;
+            let wormhole = Wormhole::connect(mailbox).await?;
+
+            let offer = transfer::request_text_or_file(
+                wormhole,
+                default_relay_hints(),
+                magic_wormhole::transit::Abilities::ALL_ABILITIES,
+                futures::future::pending(),
+            )
+            .await?
+            .unwrap();
+            let transfer::TextOrFile::Text(text) = offer else {
+                panic!("expected a text message");
+            };
+            assert_eq!(text, "Hello, world!");
+            eyre::Result::<_>::Ok(())
+        })?;
+
+    sender_task.await?;
+    receiver_task.await?;
+    Ok(())
+}
+
+/** Send a folder and a tar file using the Rust implementation, only the folder gets unpacked */
+#[cfg(all(feature = "transfer", not(target_family = "wasm")))]
+#[test(async_std::test)]
+#[allow(deprecated)]
+pub async fn test_folder_rust2rust() -> eyre::Result<()> {
+    let source = tempfile::tempdir()?;
+    let folder = source.path().join("folder");
+    std::fs::create_dir_all(folder.join("sub"))?;
+    std::fs::write(folder.join("a.txt"), b"hello")?;
+    std::fs::write(folder.join("sub/b.txt"), b"world!")?;
+    std::fs::write(source.path().join("backup.tar"), b"not a folder")?;
+
+    for name in ["folder", "backup.tar"] {
+        let (code_tx, code_rx) = futures::channel::oneshot::channel();
+        let path = source.path().join(name);
+        let target = tempfile::tempdir()?;
+
+        let sender_task = async_std::task::Builder::new()
+            .name("sender".to_owned())
+            .spawn(async move {
+                let mailbox_connection = MailboxConnection::create(transfer_config(), 2).await?;
+                code_tx.send(mailbox_connection.code.clone()).unwrap();
+                let wormhole = Wormhole::connect(mailbox_connection).await?;
+                transfer::send_file_or_folder(
                     wormhole,
                     default_relay_hints(),
-                    magic_wormhole::transit::Abilities::ALL_ABILITIES,
+                    &path,
+                    name,
+                    transit::Abilities::ALL_ABILITIES,
+                    &log_transit_connection,
+                    |_sent, _total| {},
                     futures::future::pending(),
                 )
-                .await?
-                .unwrap() else {
-                    panic!("v2 should be disabled for now")
-                };*/
+                .await?;
+                eyre::Result::<_>::Ok(())
+            })?;
+        let receiver_task = async_std::task::Builder::new()
+            .name("receiver".to_owned())
+            .spawn({
+                let target = target.path().to_owned();
+                async move {
+                    let code = code_rx.await?;
+                    let mailbox =
+                        MailboxConnection::connect(transfer_config(), code, false).await?;
+                    let wormhole = Wormhole::connect(mailbox).await?;
+                    let req = transfer::request_file(
+                        wormhole,
+                        default_relay_hints(),
+                        transit::Abilities::ALL_ABILITIES,
+                        futures::future::pending(),
+                    )
+                    .await?
+                    .unwrap();
+
+                    if name == "folder" {
+                        assert!(req.is_directory());
+                        req.accept_directory(
+                            &log_transit_connection,
+                            |_received, _total| {},
+                            &target,
+                            transfer::offer::SymlinkPolicy::Recreate,
+                            true,
+                            futures::future::pending(),
+                        )
+                        .await?;
+                    } else {
+                        assert!(!req.is_directory());
+                        assert_eq!(req.file_name(), "backup.tar");
+                        req.accept(
+                            &log_transit_connection,
+                            |_received, _total| {},
+                            &mut futures::io::sink(),
+                            futures::future::pending(),
+                        )
+                        .await?;
+                    }
+                    eyre::Result::<_>::Ok(())
+                }
+            })?;
+
+        sender_task.await?;
+        receiver_task.await?;
+
+        if name == "folder" {
+            let received = target.path().join("folder");
+            assert_eq!(std::fs::read(received.join("a.txt"))?, b"hello");
+            assert_eq!(std::fs::read(received.join("sub/b.txt"))?, b"world!");
+        }
+    }
+    Ok(())
+}
+
+/** Send a folder over transfer-v2, selecting some files and continuing partially received ones */
+#[cfg(feature = "experimental-transfer-v2")]
+#[test(async_std::test)]
+pub async fn test_folder_rust2rust_v2() -> eyre::Result<()> {
+    folder_rust2rust_v2(false).await
+}
+
+/** Like [`test_folder_rust2rust_v2`], but replacing files only once they are complete, like `--sync` */
+#[cfg(feature = "experimental-transfer-v2")]
+#[test(async_std::test)]
+pub async fn test_folder_rust2rust_v2_replacing() -> eyre::Result<()> {
+    folder_rust2rust_v2(true).await
+}
+
+#[cfg(feature = "experimental-transfer-v2")]
+async fn folder_rust2rust_v2(replace: bool) -> eyre::Result<()> {
+    let source = tempfile::tempdir()?;
+    let folder = source.path().join("folder");
+    std::fs::create_dir(&folder)?;
+    std::fs::write(folder.join("complete.txt"), b"hello")?;
+    std::fs::write(folder.join("partial.txt"), b"0123456789")?;
+    std::fs::write(folder.join("excluded.txt"), b"nope")?;
+
+    /* Left over from a previous attempt */
+    let target = tempfile::tempdir()?;
+    std::fs::create_dir(target.path().join("folder"))?;
+    std::fs::write(target.path().join("folder/complete.txt"), b"hello")?;
+    std::fs::write(target.path().join("folder/partial.txt"), b"01234")?;
+    let complete_before = std::fs::metadata(target.path().join("folder/complete.txt"))?;
 
-                let req = transfer::request_file(
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+    let sender_task = async_std::task::Builder::new()
+        .name("sender".to_owned())
+        .spawn(async move {
+            let mailbox_connection = MailboxConnection::create(transfer_config(), 2).await?;
+            code_tx.send(mailbox_connection.code.clone()).unwrap();
+            let wormhole = Wormhole::connect(mailbox_connection).await?;
+            let offer =
+                transfer::offer::OfferSend::new_file_or_folder("folder".into(), &folder).await?;
+            transfer::send(
+                wormhole,
+                default_relay_hints(),
+                transit::Abilities::ALL_ABILITIES,
+                offer,
+                &log_transit_connection,
+                |_sent, _total| {},
+                futures::future::pending(),
+            )
+            .await?;
+            eyre::Result::<_>::Ok(())
+        })?;
+    let receiver_task = async_std::task::Builder::new()
+        .name("receiver".to_owned())
+        .spawn({
+            let target = target.path().to_owned();
+            async move {
+                let code = code_rx.await?;
+                let mailbox = MailboxConnection::connect(transfer_config(), code, false).await?;
+                let wormhole = Wormhole::connect(mailbox).await?;
+                let transfer::ReceiveRequest::V2(req) = transfer::request(
                     wormhole,
                     default_relay_hints(),
-                    magic_wormhole::transit::Abilities::ALL_ABILITIES,
+                    transit::Abilities::ALL_ABILITIES,
                     futures::future::pending(),
                 )
                 .await?
-                .unwrap();
+                .unwrap() else {
+                    panic!("both sides support transfer-v2");
+                };
 
+                let mut offer = (*req.offer()).clone();
+                offer.retain(|path| path.last().unwrap() != "excluded.txt");
+                let answer = if replace {
+                    offer.accept_all_replacing(&target).await?
+                } else {
+                    offer.accept_all_resumable(&target).await?
+                };
                 req.accept(
                     &log_transit_connection,
+                    answer,
                     |_received, _total| {},
-                    &mut answer,
                     futures::future::pending(),
                 )
                 .await?;
                 eyre::Result::<_>::Ok(())
-            })?;
+            }
+        })?;
 
-        sender_task.await?;
-        receiver_task.await?;
+    sender_task.await?;
+    receiver_task.await?;
+
+    let received = target.path().join("folder");
+    assert_eq!(std::fs::read(received.join("complete.txt"))?, b"hello");
+    assert_eq!(std::fs::read(received.join("partial.txt"))?, b"0123456789");
+    assert!(!received.join("excluded.txt").exists());
+
+    /* Files we already have completely are not touched at all */
+    let complete_after = std::fs::metadata(received.join("complete.txt"))?;
+    assert_eq!(complete_after.modified()?, complete_before.modified()?);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(complete_after.ino(), complete_before.ino());
     }
     Ok(())
 }
@@ -355,7 +918,7 @@ pub async fn test_file_rust2rust() -> eyre::Result<()> {
 #[cfg(feature = "transfer")]
 #[test(async_std::test)]
 pub async fn test_send_many() -> eyre::Result<()> {
-    let mailbox = MailboxConnection::create(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let mailbox = MailboxConnection::create(transfer_config(), 2).await?;
     let code = mailbox.code.clone();
     tracing::info!("The code is {:?}", code);
 
@@ -397,15 +960,10 @@ pub async fn test_send_many() -> eyre::Result<()> {
         for i in 1..5usize {
             tracing::info!("Sending file #{}", i);
             let wormhole = Wormhole::connect(
-                MailboxConnection::connect(
-                    transfer::APP_CONFIG.id(TEST_APPID),
-                    sender_code.clone(),
-                    true,
-                )
-                .await?,
+                MailboxConnection::connect(transfer_config(), sender_code.clone(), true).await?,
             )
             .await?;
-            let gen_offer = gen_offer.clone();
+            let gen_offer = gen_offer;
             senders.push(async_std::task::spawn(async move {
                 eyre::Result::Ok(
                     #[allow(deprecated)]
@@ -431,48 +989,12 @@ pub async fn test_send_many() -> eyre::Result<()> {
     for i in 0..5usize {
         tracing::info!("Receiving file #{}", i);
         let wormhole = Wormhole::connect(
-            MailboxConnection::connect(transfer::APP_CONFIG.id(TEST_APPID), code.clone(), true)
-                .await?,
+            MailboxConnection::connect(transfer_config(), code.clone(), true).await?,
         )
         .await?;
         tracing::info!("Got key: {}", &wormhole.key);
-        /*let transfer::ReceiveRequest::V1(req) = crate::transfer::request(
-            wormhole,
-            default_relay_hints(),
-            magic_wormhole::transit::Abilities::ALL_ABILITIES,
-            futures::future::pending(),
-        )
-        .await?
-        .unwrap() else {
-            panic!("v2 should be disabled for now")
-        };*/
-
-        let req = transfer::request_file(
-            wormhole,
-            default_relay_hints(),
-            magic_wormhole::transit::Abilities::ALL_ABILITIES,
-            futures::future::pending(),
-        )
-        .await?
-        .unwrap();
-
-        // Hacky v1-compat conversion for now
-        let mut answer = (gen_accept()
-            .await?
-            .into_iter_files()
-            .next()
-            .unwrap()
-            .1
-            .content)(false)
-        .await?;
-
-        req.accept(
-            &log_transit_connection,
-            |_, _| {},
-            &mut answer,
-            futures::future::pending(),
-        )
-        .await?;
+        /* Boxed, the future is too large for the stack of the test thread otherwise */
+        Box::pin(receive_file_offer(wormhole, gen_accept().await?)).await?;
     }
 
     for sender in senders.await? {
@@ -490,7 +1012,7 @@ pub async fn test_wrong_code() -> eyre::Result<()> {
     let sender_task = async_std::task::Builder::new()
         .name("sender".to_owned())
         .spawn(async {
-            let mailbox = MailboxConnection::create(APP_CONFIG, 2).await?;
+            let mailbox = MailboxConnection::create(app_config(), 2).await?;
             if let Some(welcome) = &mailbox.welcome {
                 tracing::info!("Got welcome: {}", welcome);
             }
@@ -510,7 +1032,7 @@ pub async fn test_wrong_code() -> eyre::Result<()> {
             tracing::info!("Got nameplate over local: {}", &nameplate);
             let result = Wormhole::connect(
                 MailboxConnection::connect(
-                    APP_CONFIG,
+                    app_config(),
                     /* Making a wrong code here by appending bullshit */
                     Code::new(&nameplate, "foo-bar"),
                     true,
@@ -533,12 +1055,12 @@ pub async fn test_wrong_code() -> eyre::Result<()> {
 /** Connect three people to the party and watch it explode … gracefully */
 #[test(async_std::test)]
 pub async fn test_crowded() -> eyre::Result<()> {
-    let initial_mailbox_connection = MailboxConnection::create(APP_CONFIG, 2).await?;
+    let initial_mailbox_connection = MailboxConnection::create(app_config(), 2).await?;
     tracing::info!("This test's code is: {}", &initial_mailbox_connection.code);
     let code = initial_mailbox_connection.code.clone();
 
-    let mailbox_connection_1 = MailboxConnection::connect(APP_CONFIG.clone(), code.clone(), false);
-    let mailbox_connection_2 = MailboxConnection::connect(APP_CONFIG.clone(), code.clone(), false);
+    let mailbox_connection_1 = MailboxConnection::connect(app_config(), code.clone(), false);
+    let mailbox_connection_2 = MailboxConnection::connect(app_config(), code.clone(), false);
 
     match futures::try_join!(mailbox_connection_1, mailbox_connection_2)
         .err()
@@ -558,7 +1080,7 @@ pub async fn test_crowded() -> eyre::Result<()> {
 #[async_std::test]
 pub async fn test_connect_with_code_expecting_nameplate() -> eyre::Result<()> {
     let code = generate_random_code();
-    let result = MailboxConnection::connect(APP_CONFIG, code.clone(), false).await;
+    let result = MailboxConnection::connect(app_config(), code.clone(), false).await;
     let error = result.err().unwrap();
     match error {
         magic_wormhole::WormholeError::UnclaimedNameplate(x) => {
@@ -573,6 +1095,656 @@ pub async fn test_connect_with_code_expecting_nameplate() -> eyre::Result<()> {
     Ok(())
 }
 
+#[test(async_std::test)]
+pub async fn test_list_nameplates() -> eyre::Result<()> {
+    let mailbox_connection = MailboxConnection::create(app_config(), 2).await?;
+    let nameplates = MailboxConnection::list_nameplates(&app_config()).await?;
+    assert!(nameplates.contains(&mailbox_connection.code().nameplate()));
+    mailbox_connection.shutdown(Mood::Happy).await?;
+    Ok(())
+}
+
+/** Find the peer on the local network, without any rendezvous server */
+#[cfg(feature = "lan")]
+#[test(async_std::test)]
+pub async fn test_lan() -> eyre::Result<()> {
+    /* Nothing listens there */
+    let config = APP_CONFIG.rendezvous_url("ws://127.0.0.1:1/v1".into());
+    let mailbox_connection = MailboxConnection::create_lan(config.clone(), 2).await?;
+    let code = mailbox_connection.code().clone();
+    let (mut sender, mut receiver) =
+        futures::try_join!(Wormhole::connect(mailbox_connection), async {
+            Wormhole::connect(MailboxConnection::connect_lan(config.clone(), code).await?).await
+        })?;
+    assert_eq!(sender.verifier(), receiver.verifier());
+
+    sender.send(b"hello".to_vec()).await?;
+    assert_eq!(receiver.receive().await?, b"hello");
+    receiver.send(b"world".to_vec()).await?;
+    assert_eq!(sender.receive().await?, b"world");
+
+    /* The receiver can still close the mailbox after the sender is gone */
+    sender.close().await?;
+    receiver.close().await?;
+    Ok(())
+}
+
+/** Connect two wormholes to each other via the local rendezvous server */
+#[cfg(feature = "experimental-dilation")]
+async fn connect_pair() -> eyre::Result<(Wormhole, Wormhole)> {
+    let mailbox_connection = MailboxConnection::create(app_config(), 2).await?;
+    let code = mailbox_connection.code().clone();
+    Ok(futures::try_join!(
+        Wormhole::connect(mailbox_connection),
+        async {
+            Wormhole::connect(MailboxConnection::connect(app_config(), code, false).await?).await
+        }
+    )?)
+}
+
+/** Open subchannels in both directions over a dilated wormhole */
+#[cfg(feature = "experimental-dilation")]
+#[test(async_std::test)]
+pub async fn test_dilation() -> eyre::Result<()> {
+    use crate::dilation::dilate;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    let (a, b) = connect_pair().await?;
+    let (mut a, mut b) = futures::try_join!(
+        dilate(a, default_relay_hints(), transit::Abilities::FORCE_RELAY),
+        dilate(b, default_relay_hints(), transit::Abilities::FORCE_RELAY),
+    )?;
+    assert_ne!(a.is_leader(), b.is_leader());
+
+    let mut from_a = a.open_subchannel().await?;
+    from_a.write_all(b"hello").await?;
+    from_a.close().await?;
+    let mut to_b = b.accept_subchannel().await?;
+    assert_eq!(to_b.id() % 2 == 1, a.is_leader());
+    let mut received = Vec::new();
+    to_b.read_to_end(&mut received).await?;
+    assert_eq!(received, b"hello");
+
+    let payload: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+    let mut from_b = b.open_subchannel().await?;
+    from_b.write_all(&payload).await?;
+    from_b.close().await?;
+    let mut to_a = a.accept_subchannel().await?;
+    let mut received = Vec::new();
+    to_a.read_to_end(&mut received).await?;
+    assert_eq!(received, payload);
+
+    futures::try_join!(a.close(), b.close())?;
+    Ok(())
+}
+
+/** Don't wait forever for a peer that never acknowledges anything */
+#[cfg(feature = "experimental-dilation")]
+#[test(async_std::test)]
+pub async fn test_dilation_close_timeout() -> eyre::Result<()> {
+    use crate::dilation::{dilate, DilationError};
+    use futures::AsyncWriteExt;
+
+    let (a, mut b) = connect_pair().await?;
+    /* The peer agrees to dilate, but never connects */
+    b.send_in_phase(
+        Phase::dilation(0),
+        serde_json::to_vec(&serde_json::json!({"type": "please", "side": "0000000000000000"}))?,
+    )
+    .await?;
+    let a = dilate(a, default_relay_hints(), transit::Abilities::FORCE_RELAY).await?;
+
+    let mut subchannel = a.open_subchannel().await?;
+    subchannel.write_all(b"hello").await?;
+    let result = a.close_with_timeout(Duration::from_millis(500)).await;
+    assert!(matches!(result, Err(DilationError::CloseTimeout)));
+    b.close().await?;
+    Ok(())
+}
+
+/** Keep a subchannel working while the relay drops the connection underneath it */
+#[cfg(feature = "experimental-dilation")]
+#[test(async_std::test)]
+pub async fn test_dilation_reconnect() -> eyre::Result<()> {
+    use crate::dilation::dilate;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    let relay = transit::server::RelayServer::bind("127.0.0.1:0")
+        .await?
+        .idle_timeout(Duration::from_secs(1));
+    let usage = relay.usage();
+    let relay_hints = vec![relay.relay_hint()?];
+    async_std::task::spawn(relay.run());
+
+    let (a, b) = connect_pair().await?;
+    let (a, mut b) = futures::try_join!(
+        dilate(a, relay_hints.clone(), transit::Abilities::FORCE_RELAY),
+        dilate(b, relay_hints, transit::Abilities::FORCE_RELAY),
+    )?;
+
+    let mut from_a = a.open_subchannel().await?;
+    from_a.write_all(b"before").await?;
+    let mut to_b = b.accept_subchannel().await?;
+    let mut received = [0; 6];
+    to_b.read_exact(&mut received).await?;
+    assert_eq!(&received, b"before");
+
+    /* Stay idle until the relay gives up on us */
+    async_std::task::sleep(Duration::from_secs(3)).await;
+    assert!(usage.get().timed_out >= 1);
+
+    from_a.write_all(b"after").await?;
+    let mut received = [0; 5];
+    to_b.read_exact(&mut received).await?;
+    assert_eq!(&received, b"after");
+    to_b.write_all(b"reply").await?;
+    from_a.read_exact(&mut received).await?;
+    assert_eq!(&received, b"reply");
+    assert!(usage.get().sessions >= 2);
+
+    drop((from_a, to_b));
+    futures::try_join!(a.close(), b.close())?;
+    Ok(())
+}
+
+/** A TCP proxy in front of the local rendezvous server, whose connections can be cut or frozen at will */
+struct FlakyProxy {
+    url: String,
+    connections: std::sync::Arc<std::sync::Mutex<Vec<ProxiedConnection>>>,
+    accepted: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+struct ProxiedConnection {
+    streams: [async_std::net::TcpStream; 2],
+    frozen: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl FlakyProxy {
+    async fn start() -> std::io::Result<Self> {
+        use std::sync::{atomic::*, Arc, Mutex};
+
+        let target = rendezvous_url()
+            .trim_start_matches("ws://")
+            .trim_end_matches("/v1")
+            .to_owned();
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}/v1", listener.local_addr()?);
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        /* Forward everything, unless frozen. Then the data gets silently dropped */
+        async fn forward(
+            mut from: async_std::net::TcpStream,
+            mut to: async_std::net::TcpStream,
+            frozen: Arc<AtomicBool>,
+        ) -> std::io::Result<()> {
+            use futures::{AsyncReadExt, AsyncWriteExt};
+            let mut buffer = [0; 4096];
+            loop {
+                let read = from.read(&mut buffer).await?;
+                if read == 0 {
+                    return Ok(());
+                }
+                if !frozen.load(Ordering::SeqCst) {
+                    to.write_all(&buffer[..read]).await?;
+                }
+            }
+        }
+
+        let (connections2, accepted2) = (connections.clone(), accepted.clone());
+        async_std::task::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let server = async_std::net::TcpStream::connect(&target).await?;
+                let frozen = Arc::new(AtomicBool::new(false));
+                accepted2.fetch_add(1, Ordering::SeqCst);
+                connections2.lock().unwrap().push(ProxiedConnection {
+                    streams: [client.clone(), server.clone()],
+                    frozen: frozen.clone(),
+                });
+                async_std::task::spawn(forward(client.clone(), server.clone(), frozen.clone()));
+                async_std::task::spawn(forward(server, client, frozen));
+            }
+            std::io::Result::Ok(())
+        });
+
+        Ok(Self {
+            url,
+            connections,
+            accepted,
+        })
+    }
+
+    /** Close all current connections, in both directions */
+    fn cut(&self) {
+        for connection in self.connections.lock().unwrap().drain(..) {
+            for stream in connection.streams {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+        }
+    }
+
+    /** Silently drop all traffic on the current connections, like a NAT that forgot about them */
+    fn freeze(&self) {
+        for connection in self.connections.lock().unwrap().drain(..) {
+            connection
+                .frozen
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    /** How many connections were made through the proxy */
+    fn accepted(&self) -> usize {
+        self.accepted.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+/** Lose the connection to the rendezvous server while waiting for the peer */
+#[test(async_std::test)]
+pub async fn test_rendezvous_reconnect() -> eyre::Result<()> {
+    let proxy = FlakyProxy::start().await?;
+    let mailbox_connection =
+        MailboxConnection::create(APP_CONFIG.rendezvous_url(proxy.url.clone().into()), 2).await?;
+    let code = mailbox_connection.code().clone();
+
+    let sender = async_std::task::spawn(async {
+        let mut wormhole = Wormhole::connect(mailbox_connection).await?;
+        wormhole.send(b"hello".to_vec()).await?;
+        wormhole.close().await?;
+        eyre::Result::<_>::Ok(())
+    });
+
+    /* The sender has sent its PAKE message by now and waits for ours */
+    async_std::task::sleep(Duration::from_millis(200)).await;
+    proxy.cut();
+
+    let mailbox_connection = MailboxConnection::connect(app_config(), code, false).await?;
+    let mut wormhole = Wormhole::connect(mailbox_connection).await?;
+    assert_eq!(wormhole.receive().await?, b"hello");
+    wormhole.close().await?;
+    sender.await?;
+
+    /* The sender must have reconnected through the proxy */
+    assert_eq!(proxy.accepted(), 2);
+    Ok(())
+}
+
+/** Notice a connection to the rendezvous server that silently died, thanks to the keepalive */
+#[test(async_std::test)]
+pub async fn test_rendezvous_keepalive() -> eyre::Result<()> {
+    let proxy = FlakyProxy::start().await?;
+    let config = APP_CONFIG
+        .rendezvous_url(proxy.url.clone().into())
+        .rendezvous_keepalive(Some(crate::rendezvous::Keepalive::new(
+            Duration::from_millis(200),
+            Duration::from_millis(200),
+        )));
+    let mailbox_connection = MailboxConnection::create(config, 2).await?;
+    let code = mailbox_connection.code().clone();
+
+    let sender = async_std::task::spawn(async {
+        let mut wormhole = Wormhole::connect(mailbox_connection).await?;
+        wormhole.send(b"hello".to_vec()).await?;
+        wormhole.close().await?;
+        eyre::Result::<_>::Ok(())
+    });
+
+    /* Pings keep an idle connection alive */
+    async_std::task::sleep(Duration::from_millis(1000)).await;
+    assert_eq!(proxy.accepted(), 1);
+
+    /* Nothing gets through anymore, but the connection stays open */
+    proxy.freeze();
+    async_std::future::timeout(TIMEOUT, async {
+        while proxy.accepted() < 2 {
+            async_std::task::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+
+    let mailbox_connection = MailboxConnection::connect(app_config(), code, false).await?;
+    let mut wormhole = Wormhole::connect(mailbox_connection).await?;
+    assert_eq!(wormhole.receive().await?, b"hello");
+    wormhole.close().await?;
+    sender.await?;
+    Ok(())
+}
+
+/** A minimal SOCKS5 and HTTP CONNECT proxy, which remembers where it was asked to connect to.
+ * As Tor, it forwards connections to onion services to the same port on localhost. */
+struct StandInProxy {
+    proxy: crate::proxy::Proxy,
+    targets: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl StandInProxy {
+    const USERNAME: &'static str = "user";
+    const PASSWORD: &'static str = "secret";
+
+    async fn start(kind: crate::proxy::ProxyKind) -> std::io::Result<Self> {
+        use std::sync::{Arc, Mutex};
+
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+        let proxy = crate::proxy::Proxy::new(kind, "127.0.0.1", listener.local_addr()?.port())
+            .with_credentials(Self::USERNAME.into(), Self::PASSWORD.into());
+        let targets = Arc::new(Mutex::new(Vec::new()));
+
+        let targets2 = targets.clone();
+        async_std::task::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let targets = targets2.clone();
+                async_std::task::spawn(async move {
+                    if let Err(err) = Self::serve(kind, client, targets).await {
+                        tracing::debug!("Stand-in proxy connection failed: {}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { proxy, targets })
+    }
+
+    async fn serve(
+        kind: crate::proxy::ProxyKind,
+        mut client: async_std::net::TcpStream,
+        targets: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    ) -> std::io::Result<()> {
+        use futures::{AsyncReadExt, AsyncWriteExt};
+        let fail = |message: &str| std::io::Error::other(message);
+
+        let target = match kind {
+            crate::proxy::ProxyKind::Socks5 | crate::proxy::ProxyKind::Tor => {
+                let mut header = [0u8; 2];
+                client.read_exact(&mut header).await?;
+                let mut methods = vec![0u8; header[1] as usize];
+                client.read_exact(&mut methods).await?;
+                if !methods.contains(&0x02) {
+                    client.write_all(&[0x05, 0xff]).await?;
+                    return Err(fail("client does not authenticate"));
+                }
+                client.write_all(&[0x05, 0x02]).await?;
+
+                let mut version = [0u8; 1];
+                client.read_exact(&mut version).await?;
+                let mut credentials = Vec::new();
+                for _ in 0..2 {
+                    let mut len = [0u8; 1];
+                    client.read_exact(&mut len).await?;
+                    let mut field = vec![0u8; len[0] as usize];
+                    client.read_exact(&mut field).await?;
+                    credentials.push(String::from_utf8_lossy(&field).into_owned());
+                }
+                if credentials != [Self::USERNAME, Self::PASSWORD] {
+                    client.write_all(&[0x01, 0x01]).await?;
+                    return Err(fail("wrong credentials"));
+                }
+                client.write_all(&[0x01, 0x00]).await?;
+
+                let mut request = [0u8; 4];
+                client.read_exact(&mut request).await?;
+                let host = match request[3] {
+                    0x01 => {
+                        let mut ip = [0u8; 4];
+                        client.read_exact(&mut ip).await?;
+                        std::net::Ipv4Addr::from(ip).to_string()
+                    },
+                    0x03 => {
+                        let mut len = [0u8; 1];
+                        client.read_exact(&mut len).await?;
+                        let mut host = vec![0u8; len[0] as usize];
+                        client.read_exact(&mut host).await?;
+                        String::from_utf8_lossy(&host).into_owned()
+                    },
+                    _ => return Err(fail("unsupported address type")),
+                };
+                let mut port = [0u8; 2];
+                client.read_exact(&mut port).await?;
+                format!("{}:{}", host, u16::from_be_bytes(port))
+            },
+            crate::proxy::ProxyKind::Http => {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8; 1];
+                    client.read_exact(&mut byte).await?;
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let authorization = {
+                    use base64::Engine;
+                    format!(
+                        "Proxy-Authorization: Basic {}",
+                        base64::engine::general_purpose::STANDARD.encode(format!(
+                            "{}:{}",
+                            Self::USERNAME,
+                            Self::PASSWORD
+                        ))
+                    )
+                };
+                if !request.lines().any(|line| line == authorization) {
+                    client
+                        .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                        .await?;
+                    return Err(fail("wrong credentials"));
+                }
+                match request.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["CONNECT", target, ..] => target.to_owned(),
+                    _ => return Err(fail("not a CONNECT request")),
+                }
+            },
+        };
+
+        /* There are no onion services, forward them to localhost instead */
+        let server = match target.split_once(".onion:") {
+            Some((_, port)) => format!("127.0.0.1:{}", port),
+            None => target.clone(),
+        };
+        let server = async_std::net::TcpStream::connect(&server).await?;
+        targets.lock().unwrap().push(target);
+        match kind {
+            crate::proxy::ProxyKind::Socks5 | crate::proxy::ProxyKind::Tor => {
+                client
+                    .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                    .await?
+            },
+            _ => {
+                client
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await?
+            },
+        }
+
+        let (mut client_writer, mut server_writer) = (client.clone(), server.clone());
+        futures::try_join!(
+            futures::io::copy(client, &mut server_writer),
+            futures::io::copy(server, &mut client_writer),
+        )?;
+        Ok(())
+    }
+
+    fn targets(&self) -> Vec<String> {
+        self.targets.lock().unwrap().clone()
+    }
+}
+
+/** Connect to the rendezvous server and the relay through a proxy, with direct connections disabled */
+async fn test_proxy(kind: crate::proxy::ProxyKind) -> eyre::Result<()> {
+    let proxy = StandInProxy::start(kind).await?;
+    /* Let the proxy resolve the host name */
+    let rendezvous_url = rendezvous_url().replace("127.0.0.1", "localhost");
+    let config = APP_CONFIG
+        .rendezvous_url(rendezvous_url.clone().into())
+        .proxy(Some(proxy.proxy.clone()));
+
+    let mailbox_connection = MailboxConnection::create(config.clone(), 2).await?;
+    let code = mailbox_connection.code().clone();
+    let (leader, follower) = futures::try_join!(Wormhole::connect(mailbox_connection), async {
+        Wormhole::connect(MailboxConnection::connect(config, code, false).await?).await
+    },)?;
+
+    let key = || crate::Key::<transit::TransitKey>::new(Box::new([7u8; 32].into()));
+    let init = |wormhole: &Wormhole| {
+        transit::init_with_proxy(
+            transit::Abilities::ALL,
+            None,
+            default_relay_hints(),
+            wormhole.proxy().cloned(),
+        )
+    };
+    let (leader_connector, follower_connector) =
+        futures::try_join!(init(&leader), init(&follower))?;
+    assert!(!leader_connector.our_abilities().can_direct());
+    assert!(leader_connector.our_hints().direct_tcp.is_empty());
+    let (leader_abilities, leader_hints) = (
+        *leader_connector.our_abilities(),
+        leader_connector.our_hints().clone(),
+    );
+    let (follower_abilities, follower_hints) = (
+        *follower_connector.our_abilities(),
+        follower_connector.our_hints().clone(),
+    );
+
+    let ((mut leader_transit, leader_info), (mut follower_transit, _)) = futures::try_join!(
+        leader_connector.leader_connect(key(), follower_abilities, follower_hints),
+        follower_connector.follower_connect(key(), leader_abilities, leader_hints),
+    )?;
+    assert!(matches!(
+        leader_info.conn_type,
+        transit::ConnectionType::Relay { .. }
+    ));
+    leader_transit.send_record(b"hello").await?;
+    assert_eq!(&*follower_transit.receive_record().await?, b"hello");
+
+    futures::try_join!(leader.close(), follower.close())?;
+
+    let rendezvous_target = rendezvous_url
+        .trim_start_matches("ws://")
+        .trim_end_matches("/v1");
+    let relay_target = default_relay_hints()[0]
+        .tcp
+        .iter()
+        .map(|hint| format!("{}:{}", hint.hostname, hint.port))
+        .next()
+        .unwrap();
+    let targets = proxy.targets();
+    assert_eq!(
+        targets
+            .iter()
+            .filter(|target| *target == rendezvous_target)
+            .count(),
+        2
+    );
+    assert!(
+        targets
+            .iter()
+            .filter(|target| **target == relay_target)
+            .count()
+            >= 2
+    );
+    Ok(())
+}
+
+#[test(async_std::test)]
+pub async fn test_proxy_socks5() -> eyre::Result<()> {
+    test_proxy(crate::proxy::ProxyKind::Socks5).await
+}
+
+#[test(async_std::test)]
+pub async fn test_proxy_http() -> eyre::Result<()> {
+    test_proxy(crate::proxy::ProxyKind::Http).await
+}
+
+/** A minimal Tor control port, which pretends to publish onion services */
+async fn start_tor_control_port() -> std::io::Result<u16> {
+    use futures::{AsyncBufReadExt, AsyncWriteExt, StreamExt};
+
+    let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    async_std::task::spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            async_std::task::spawn(async move {
+                let mut writer = client.clone();
+                let mut lines = futures::io::BufReader::new(client).lines();
+                while let Some(Ok(line)) = lines.next().await {
+                    let reply = match line.split(' ').next() {
+                        Some("PROTOCOLINFO") => {
+                            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n250 OK\r\n"
+                        },
+                        Some("AUTHENTICATE") => "250 OK\r\n",
+                        Some("ADD_ONION") => "250-ServiceID=standin\r\n250 OK\r\n",
+                        _ => "510 Unrecognized command\r\n",
+                    };
+                    if writer.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(port)
+}
+
+/** Connect directly through Tor, with the leader publishing an onion service for the follower */
+#[test(async_std::test)]
+pub async fn test_tor() -> eyre::Result<()> {
+    let proxy = StandInProxy::start(crate::proxy::ProxyKind::Tor).await?;
+    let tor = proxy
+        .proxy
+        .clone()
+        .with_tor_control_port(start_tor_control_port().await?);
+    let config = APP_CONFIG
+        .rendezvous_url(rendezvous_url())
+        .proxy(Some(tor.clone()));
+
+    let mailbox_connection = MailboxConnection::create(config.clone(), 2).await?;
+    let code = mailbox_connection.code().clone();
+    let (leader, follower) = futures::try_join!(Wormhole::connect(mailbox_connection), async {
+        Wormhole::connect(MailboxConnection::connect(config, code, false).await?).await
+    },)?;
+
+    let key = || crate::Key::<transit::TransitKey>::new(Box::new([7u8; 32].into()));
+    let (leader_connector, follower_connector) = futures::try_join!(
+        transit::init_with_proxy(
+            transit::Abilities::FORCE_DIRECT,
+            None,
+            vec![],
+            leader.proxy().cloned(),
+        ),
+        /* Without a control port, the follower can only connect out */
+        transit::init_with_proxy(
+            transit::Abilities::FORCE_DIRECT,
+            None,
+            vec![],
+            Some(crate::proxy::Proxy {
+                tor_control_port: None,
+                ..tor
+            }),
+        ),
+    )?;
+    let leader_hints = leader_connector.our_hints().clone();
+    assert!(leader_hints.direct_tcp.is_empty());
+    assert_eq!(leader_hints.tor_tcp.len(), 1);
+    assert!(leader_hints
+        .tor_tcp
+        .iter()
+        .all(|hint| hint.hostname == "standin.onion"));
+    assert!(follower_connector.our_hints().tor_tcp.is_empty());
+    let follower_hints = follower_connector.our_hints().clone();
+
+    let ((mut leader_transit, leader_info), (mut follower_transit, _)) = futures::try_join!(
+        leader_connector.leader_connect(key(), transit::Abilities::FORCE_DIRECT, follower_hints),
+        follower_connector.follower_connect(key(), transit::Abilities::FORCE_DIRECT, leader_hints),
+    )?;
+    assert_eq!(leader_info.conn_type, transit::ConnectionType::Direct);
+    leader_transit.send_record(b"hello").await?;
+    assert_eq!(&*follower_transit.receive_record().await?, b"hello");
+
+    futures::try_join!(leader.close(), follower.close())?;
+    assert!(proxy
+        .targets()
+        .iter()
+        .any(|target| target.starts_with("standin.onion:")));
+    Ok(())
+}
+
 fn generate_random_code() -> Code {
     let mut rng = rand::thread_rng();
     let nameplate_string = format!("{}-guitarist-revenge", rng.gen_range(1000..10000));
@@ -580,6 +1752,25 @@ fn generate_random_code() -> Code {
     Code::new(&nameplate, "guitarist-revenge")
 }
 
+#[test]
+fn test_verifier_format() {
+    use super::VerifierFormat;
+
+    let verifier: Vec<u8> = (0..32).map(|i| i * 8 + 1).collect();
+    assert_eq!(
+        VerifierFormat::Hex.format(&verifier),
+        "0109111921293139414951596169717981899199a1a9b1b9c1c9d1d9e1e9f1f9"
+    );
+    assert_eq!(
+        VerifierFormat::Words.format(&verifier),
+        "absurd applicant athens bottomless blackjack certify"
+    );
+    assert_eq!(VerifierFormat::Emoji.format(&verifier), "🐶🐧🍎🐦🐻🐤🐰🍉");
+
+    let emoji: std::collections::HashSet<_> = super::VERIFIER_EMOJI.iter().collect();
+    assert_eq!(emoji.len(), 64);
+}
+
 #[test]
 fn test_phase() {
     let p = Phase::PAKE;
@@ -1,9 +1,53 @@
+//! Wordlists for the password part of wormhole codes
+//!
+//! Codes look like `7-guitarist-revenge`: a nameplate, followed by words from a wordlist.
+//! By default, the [PGP word list](https://en.wikipedia.org/wiki/PGP_word_list) is used, alternating between its
+//! two halves. Custom lists can be loaded with [`Wordlist::from_json`], [`Wordlist::from_text`] or
+//! [`Wordlist::from_file`]. This module also provides completion of partially typed codes.
+
 use rand::{rngs::OsRng, seq::SliceRandom};
 use serde_json::{self, Value};
-use std::fmt;
+use std::{collections::HashSet, fmt};
+
+/// An error occurred when loading a wordlist
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum WordlistError {
+    /// The wordlist (or one of its parts) contains no words
+    #[error("The wordlist contains no words")]
+    Empty,
+    /// Codes need at least one word
+    #[error("Codes need at least one word")]
+    NoCodeWords,
+    /// Words must not be empty and must not contain whitespace or dashes
+    #[error(
+        "Invalid word {:?}: words must not be empty or contain whitespace or dashes",
+        _0
+    )]
+    InvalidWord(String),
+    /// A word appears more than once, which would reduce the entropy of codes
+    #[error("The word {:?} appears more than once", _0)]
+    Duplicate(String),
+    /// The JSON file has an unknown layout
+    #[error("Invalid JSON wordlist")]
+    Json(
+        #[from]
+        #[source]
+        serde_json::Error,
+    ),
+    /// Reading the file failed
+    #[error("Failed to read the wordlist")]
+    IO(
+        #[from]
+        #[source]
+        std::io::Error,
+    ),
+}
 
-#[derive(PartialEq)]
+/// A list of words to build the password part of codes from
+#[derive(Clone, PartialEq)]
 pub struct Wordlist {
+    /// The number of words in a code
     pub num_words: usize,
     words: Vec<Vec<String>>,
 }
@@ -15,12 +59,131 @@ impl fmt::Debug for Wordlist {
 }
 
 impl Wordlist {
-    #[cfg(test)]
-    pub fn new(num_words: usize, words: Vec<Vec<String>>) -> Wordlist {
-        Wordlist { num_words, words }
+    /**
+     * Create a wordlist for codes with `num_words` words
+     *
+     * The words of a code are taken from the given lists in turn, e.g. with two lists the first word comes from
+     * the first list, the second word from the second one, the third word from the first one again and so on.
+     */
+    pub fn new(num_words: usize, words: Vec<Vec<String>>) -> Result<Wordlist, WordlistError> {
+        ensure!(num_words > 0, WordlistError::NoCodeWords);
+        ensure!(!words.is_empty(), WordlistError::Empty);
+        for list in &words {
+            ensure!(!list.is_empty(), WordlistError::Empty);
+            let mut seen = HashSet::new();
+            for word in list {
+                ensure!(
+                    !word.is_empty() && !word.contains(|c: char| c == '-' || c.is_whitespace()),
+                    WordlistError::InvalidWord(word.clone())
+                );
+                ensure!(seen.insert(word), WordlistError::Duplicate(word.clone()));
+            }
+        }
+        Ok(Wordlist { num_words, words })
+    }
+
+    /**
+     * Load a wordlist from JSON
+     *
+     * Supported layouts are a plain array of words, an array of arrays of words (see [`Wordlist::new`]),
+     * and the format used by the Python implementation: an object mapping hex numbers to one word per list,
+     * like `{"00": ["aardvark", "adroitness"], "01": ["absurd", "adviser"]}`.
+     */
+    pub fn from_json(num_words: usize, json: &str) -> Result<Wordlist, WordlistError> {
+        #[derive(serde_derive::Deserialize)]
+        #[serde(untagged)]
+        enum Layout {
+            Flat(Vec<String>),
+            Nested(Vec<Vec<String>>),
+            Indexed(std::collections::BTreeMap<String, Vec<String>>),
+        }
+
+        let words = match serde_json::from_str(json)? {
+            Layout::Flat(words) => vec![words],
+            Layout::Nested(words) => words,
+            Layout::Indexed(entries) => {
+                let lists = entries.values().map(Vec::len).max().unwrap_or(0);
+                (0..lists)
+                    .map(|i| {
+                        entries
+                            .values()
+                            .filter_map(|entry| entry.get(i).cloned())
+                            .collect()
+                    })
+                    .collect()
+            },
+        };
+        Self::new(num_words, words)
+    }
+
+    /**
+     * Load a wordlist from plain text, with one word per line
+     *
+     * Empty lines and lines starting with `#` are ignored. If a line has multiple columns,
+     * the last one is taken. This allows using lists made for dice, like the
+     * [EFF short list](https://www.eff.org/dice), directly.
+     */
+    pub fn from_text(num_words: usize, text: &str) -> Result<Wordlist, WordlistError> {
+        let words = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_whitespace().next_back())
+            .map(str::to_owned)
+            .collect();
+        Self::new(num_words, vec![words])
+    }
+
+    /// Load a wordlist from a file. Files ending in `.json` are read with [`Wordlist::from_json`], all others with [`Wordlist::from_text`].
+    #[cfg(not(target_family = "wasm"))]
+    pub fn from_file(
+        num_words: usize,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Wordlist, WordlistError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            Self::from_json(num_words, &content)
+        } else {
+            Self::from_text(num_words, &content)
+        }
+    }
+
+    /// The entropy of a single word in bits, averaged over all words of a code
+    pub fn entropy_per_word(&self) -> f64 {
+        self.entropy() / self.num_words as f64
+    }
+
+    /**
+     * The entropy of a whole password in bits
+     *
+     * An attacker gets only one guess per code (a wrong guess makes the transfer fail), so even
+     * 16 bits like with the default of two words from the PGP word list are usually enough.
+     */
+    pub fn entropy(&self) -> f64 {
+        self.words
+            .iter()
+            .cycle()
+            .take(self.num_words)
+            .map(|words| (words.len() as f64).log2())
+            .sum()
     }
 
-    #[allow(dead_code)] // TODO make this API public one day
+    /**
+     * Complete a partially typed password
+     *
+     * `prefix` is the password part of a code typed so far, without the nameplate.
+     * Returns all possible completions of the last word in alphabetical order, each followed by
+     * a dash unless it is the last word of the code.
+     *
+     * ```
+     * let wordlist = magic_wormhole::wordlist::default_wordlist(2);
+     * assert_eq!(wordlist.get_completions("guitarist-rev"), ["guitarist-revenge"]);
+     * ```
+     */
     pub fn get_completions(&self, prefix: &str) -> Vec<String> {
         let count_dashes = prefix.matches('-').count();
         let mut completions = Vec::new();
@@ -55,6 +218,7 @@ impl Wordlist {
         completions
     }
 
+    /// Randomly choose a password of `num_words` words
     pub fn choose_words(&self) -> String {
         let mut rng = OsRng;
         let components: Vec<String> = self
@@ -96,6 +260,17 @@ fn load_pgpwords() -> Vec<Vec<String>> {
     vec![even_words, odd_words]
 }
 
+/// Encode bytes with the PGP word list: bytes at even positions use the two-syllable words, bytes at odd positions the three-syllable ones
+pub(crate) fn pgp_words(bytes: &[u8]) -> Vec<String> {
+    let words = load_pgpwords();
+    bytes
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| words[1 - i % 2][byte as usize].clone())
+        .collect()
+}
+
+/// The PGP word list, for codes with `num_words` words
 pub fn default_wordlist(num_words: usize) -> Wordlist {
     Wordlist {
         num_words,
@@ -107,6 +282,58 @@ pub fn default_wordlist(num_words: usize) -> Wordlist {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_load_custom() {
+        let text = "# A numeric list\n11111\tone\n11112 two\n\nthree\n";
+        let w = Wordlist::from_text(3, text).unwrap();
+        assert_eq!(w.words, vec![vecstrings("one two three")]);
+        assert_eq!(w.get_completions("one-t"), vec!["one-three-", "one-two-"]);
+
+        let w = Wordlist::from_json(2, r#"["eins", "zwei", "drei", "vier"]"#).unwrap();
+        assert_eq!(w.entropy(), 4.0);
+        let w = Wordlist::from_json(2, r#"[["a", "b"], ["c", "d", "e", "f"]]"#).unwrap();
+        assert_eq!(w.entropy_per_word(), 1.5);
+        let w = Wordlist::from_json(2, r#"{"01": ["b", "d"], "00": ["a", "c"]}"#).unwrap();
+        assert_eq!(w.words, vec![vecstrings("a b"), vecstrings("c d")]);
+
+        assert!(matches!(
+            Wordlist::from_json(2, r#"["a", "b-c"]"#),
+            Err(WordlistError::InvalidWord(_))
+        ));
+        assert!(matches!(
+            Wordlist::from_text(2, "a\nb\na"),
+            Err(WordlistError::Duplicate(_))
+        ));
+        assert!(matches!(
+            Wordlist::from_text(2, "# nothing"),
+            Err(WordlistError::Empty)
+        ));
+        assert!(matches!(
+            Wordlist::from_json(2, r#"{"words": 3}"#),
+            Err(WordlistError::Json(_))
+        ));
+        assert!(matches!(
+            Wordlist::from_text(0, "a\nb"),
+            Err(WordlistError::NoCodeWords)
+        ));
+    }
+
+    #[test]
+    fn test_pgp_words() {
+        /* Repeated bytes must get the same words every time */
+        assert_eq!(
+            pgp_words(&[0x00, 0x00, 0x00, 0x00]),
+            vecstrings("aardvark adroitness aardvark adroitness")
+        );
+        assert_eq!(pgp_words(&[0xff, 0xff]), vecstrings("zulu yucatan"));
+    }
+
+    #[test]
+    fn test_default_entropy() {
+        assert_eq!(default_wordlist(2).entropy(), 16.0);
+        assert_eq!(default_wordlist(3).entropy_per_word(), 8.0);
+    }
+
     #[test]
     fn test_load_words() {
         let w = load_pgpwords();
@@ -146,7 +373,7 @@ mod test {
             vecstrings("sausages seltzer snobol"),
         ];
 
-        let w = Wordlist::new(2, words);
+        let w = Wordlist::new(2, words).unwrap();
         assert_eq!(w.get_completions(""), vec!["green-", "purple-", "yellow-"]);
         assert_eq!(w.get_completions("pur"), vec!["purple-"]);
         assert_eq!(w.get_completions("blu"), Vec::<String>::new());
@@ -157,11 +384,11 @@ mod test {
     fn test_choose_words() {
         let few_words: Vec<Vec<String>> = vec![vecstrings("purple"), vecstrings("sausages")];
 
-        let w = Wordlist::new(2, few_words.clone());
+        let w = Wordlist::new(2, few_words.clone()).unwrap();
         assert_eq!(w.choose_words(), "purple-sausages");
-        let w = Wordlist::new(3, few_words.clone());
+        let w = Wordlist::new(3, few_words.clone()).unwrap();
         assert_eq!(w.choose_words(), "purple-sausages-purple");
-        let w = Wordlist::new(4, few_words);
+        let w = Wordlist::new(4, few_words).unwrap();
         assert_eq!(w.choose_words(), "purple-sausages-purple-sausages");
     }
 
@@ -181,12 +408,12 @@ mod test {
         .map(|s| s.to_string())
         .collect();
 
-        let w = Wordlist::new(2, more_words.clone());
+        let w = Wordlist::new(2, more_words.clone()).unwrap();
         for _ in 0..20 {
             assert!(expected2.contains(&w.choose_words()));
         }
 
-        let w = Wordlist::new(3, more_words);
+        let w = Wordlist::new(3, more_words).unwrap();
         for _ in 0..20 {
             assert!(expected3.contains(&w.choose_words()));
         }
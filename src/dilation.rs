@@ -0,0 +1,1180 @@
+//! Durable, reconnecting peer connections
+//!
+//! "Dilating" a [`Wormhole`] turns it into a long-lived connection to the peer that survives
+//! network changes. Instead of a single [`Transit`](transit::Transit), both sides get a
+//! [`DilatedWormhole`] from which they can open any number of [`Subchannel`]s. Each subchannel
+//! is a reliable, ordered byte stream implementing [`AsyncRead`] and [`AsyncWrite`].
+//!
+//! This implements the dilation protocol of the Python implementation:
+//!
+//! - The rendezvous mailbox stays open for the whole lifetime of the dilated wormhole. Dilation
+//!   messages are sent in phases of their own (`dilate-0`, `dilate-1`, …). Both sides send `please`
+//!   to elect a leader (the side with the larger random side identifier), and `connection-hints`
+//!   to tell the peer how to reach them. To start over, the leader sends `reconnect` and the
+//!   follower answers with `reconnecting`, then both sides send new hints.
+//! - The L2 layer is a [`Transit`](transit::Transit) connection with a handshake of its own. Its key
+//!   is derived from the wormhole key, and after the noise handshake the follower and then the leader
+//!   send a key confirmation record (`KCM`).
+//! - The L3 layer numbers all records that carry subchannel state (`OPEN`, `DATA`, `CLOSE`).
+//!   Every such record is kept until the peer acknowledged it, and retransmitted on the next
+//!   connection if the connection broke. Duplicates are dropped by the receiver, so the
+//!   subchannels see every byte exactly once and in order.
+//!
+//! A record is only acknowledged once it has been handed over to its subchannel. As long as a
+//! subchannel is not read from, no further records are read from the connection, so a slow reader
+//! slows the peer down instead of filling up the memory.
+//!
+//! If the connection is lost (the socket fails or the peer stops answering to pings), the leader
+//! asks for a reconnect. The follower waits for it to do so.
+//!
+//! Both sides must call [`dilate`] on their [`Wormhole`], and the wormhole must not be used for
+//! anything else afterwards.
+//!
+//! This module is experimental and requires the `experimental-dilation` feature. It has not been tested
+//! against the Python implementation yet, and its API may still change.
+
+use super::*;
+#[allow(deprecated)]
+use crate::core::Phase;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    stream::BoxStream,
+    AsyncRead, AsyncWrite, FutureExt, SinkExt, StreamExt,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+#[allow(deprecated)]
+use transit::TransitConnector;
+use transit::{Abilities, Hints, RelayHint, TransitConnectError, TransitError};
+
+/// How often a ping is sent over an idle connection
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// A connection is considered lost if nothing was received for this many ping intervals
+const PING_TIMEOUT_INTERVALS: u32 = 3;
+/// How long to wait before retrying after a failed connection attempt
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Maximum payload of a single `DATA` record
+const MAX_DATA_SIZE: usize = 32 * 1024;
+/// Stop accepting writes from the subchannels once this many bytes wait for an ack
+const MAX_UNACKED_BYTES: usize = 4 * 1024 * 1024;
+/// Stop accepting commands from the subchannels once this many records wait for an ack
+const MAX_UNACKED_RECORDS: usize = 1024;
+/// How many received records may wait in a subchannel before we stop reading from the connection
+const SUBCHANNEL_BUFFER: usize = 8;
+/// The versions of the dilation protocol we support
+pub(crate) const VERSIONS: &[&str] = &["1"];
+/// The ways we can connect for dilation, announced in the versions message
+pub(crate) const ABILITIES: Abilities = Abilities {
+    direct_tcp_v1: true,
+    relay_v1: true,
+    noise_v1: false,
+    parallel_v1: 0,
+    migrate_v1: false,
+};
+/// How long [`DilatedWormhole::close`] waits for the peer, see [`DilatedWormhole::close_with_timeout`]
+pub const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+/// An error occurred while dilating a wormhole
+pub enum DilationError {
+    /// The dilated wormhole was closed, either by us or by the peer
+    #[error("The dilated wormhole is closed")]
+    Closed,
+    /// Closing took too long, the peer might not have received everything
+    #[error("Timed out while closing the dilated wormhole")]
+    CloseTimeout,
+    /// The peer did not announce support for dilation when connecting
+    #[error("The peer does not support dilation")]
+    PeerCannotDilate,
+    /// Some deserialization went wrong, we probably got some garbage
+    #[error("Corrupt JSON message received")]
+    ProtocolJson(
+        #[from]
+        #[source]
+        serde_json::Error,
+    ),
+    /// A generic string message for "something went wrong", i.e.
+    /// the peer sent some bullshit message order
+    #[error("Protocol error: {}", _0)]
+    Protocol(Box<str>),
+    /// Unexpected message (protocol error)
+    #[error(
+        "Unexpected message (protocol error): Expected '{}', but got: {:?}",
+        _0,
+        _1
+    )]
+    ProtocolUnexpectedMessage(Box<str>, Box<dyn std::fmt::Debug + Send + Sync>),
+    /// Wormhole connection error
+    #[error("Wormhole connection error")]
+    Wormhole(
+        #[from]
+        #[source]
+        WormholeError,
+    ),
+    /// I/O error
+    #[error("I/O error")]
+    IO(
+        #[from]
+        #[source]
+        std::io::Error,
+    ),
+}
+
+impl DilationError {
+    fn protocol(message: impl Into<Box<str>>) -> Self {
+        Self::Protocol(message.into())
+    }
+
+    fn unexpected_message(
+        expected: impl Into<Box<str>>,
+        got: impl std::fmt::Debug + Send + Sync + 'static,
+    ) -> Self {
+        Self::ProtocolUnexpectedMessage(expected.into(), Box::new(got))
+    }
+}
+
+/// Messages sent over the rendezvous mailbox
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+enum DilationMessage {
+    /// Start dilating, sent exactly once by each side
+    Please { side: String },
+    /// How to reach us. There may be several of these per connection attempt
+    ConnectionHints { hints: Hints },
+    /// The leader lost the connection and wants to start over
+    Reconnect,
+    /// The follower agrees to start over, new hints will follow
+    Reconnecting,
+    #[serde(other)]
+    Unknown,
+}
+
+/// Records sent over the L2 connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Record {
+    /// Key confirmation, the first record on every new connection
+    KCM,
+    Ping(u32),
+    Pong(u32),
+    Open {
+        scid: u32,
+        seqnum: u32,
+    },
+    Data {
+        scid: u32,
+        seqnum: u32,
+        data: Vec<u8>,
+    },
+    Close {
+        scid: u32,
+        seqnum: u32,
+    },
+    Ack(u32),
+}
+
+impl Record {
+    fn encode(&self) -> Box<[u8]> {
+        let mut out = Vec::with_capacity(9);
+        match self {
+            Record::KCM => out.push(0x00),
+            Record::Ping(ping_id) => {
+                out.push(0x01);
+                out.extend_from_slice(&ping_id.to_be_bytes());
+            },
+            Record::Pong(ping_id) => {
+                out.push(0x02);
+                out.extend_from_slice(&ping_id.to_be_bytes());
+            },
+            Record::Open { scid, seqnum } => {
+                out.push(0x03);
+                out.extend_from_slice(&scid.to_be_bytes());
+                out.extend_from_slice(&seqnum.to_be_bytes());
+            },
+            Record::Data { scid, seqnum, data } => {
+                out.reserve(data.len());
+                out.push(0x04);
+                out.extend_from_slice(&scid.to_be_bytes());
+                out.extend_from_slice(&seqnum.to_be_bytes());
+                out.extend_from_slice(data);
+            },
+            Record::Close { scid, seqnum } => {
+                out.push(0x05);
+                out.extend_from_slice(&scid.to_be_bytes());
+                out.extend_from_slice(&seqnum.to_be_bytes());
+            },
+            Record::Ack(seqnum) => {
+                out.push(0x06);
+                out.extend_from_slice(&seqnum.to_be_bytes());
+            },
+        }
+        out.into_boxed_slice()
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn decode(record: &[u8]) -> Result<Self, DilationError> {
+        let u32_at = |offset: usize| -> Result<u32, DilationError> {
+            record
+                .get(offset..offset + 4)
+                .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(|| DilationError::protocol("Truncated record"))
+        };
+        Ok(match record.first() {
+            Some(0x00) => Record::KCM,
+            Some(0x01) => Record::Ping(u32_at(1)?),
+            Some(0x02) => Record::Pong(u32_at(1)?),
+            /* Newer peers may name a subprotocol after this, which we don't support yet */
+            Some(0x03) => Record::Open {
+                scid: u32_at(1)?,
+                seqnum: u32_at(5)?,
+            },
+            Some(0x04) => Record::Data {
+                scid: u32_at(1)?,
+                seqnum: u32_at(5)?,
+                data: record[9..].to_vec(),
+            },
+            Some(0x05) => Record::Close {
+                scid: u32_at(1)?,
+                seqnum: u32_at(5)?,
+            },
+            Some(0x06) => Record::Ack(u32_at(1)?),
+            Some(other) => bail!(DilationError::protocol(format!(
+                "Unknown record type {:#04x}",
+                other
+            ))),
+            None => bail!(DilationError::protocol("Empty record")),
+        })
+    }
+
+    /// The L3 sequence number, for those records that must be acknowledged
+    fn seqnum(&self) -> Option<u32> {
+        match self {
+            Record::Open { seqnum, .. }
+            | Record::Data { seqnum, .. }
+            | Record::Close { seqnum, .. } => Some(*seqnum),
+            _ => None,
+        }
+    }
+}
+
+/// Requests from the [`DilatedWormhole`] and its [`Subchannel`]s to the background task
+#[derive(Debug)]
+enum Command {
+    Open(oneshot::Sender<Subchannel>),
+    Data { scid: u32, data: Vec<u8> },
+    Close { scid: u32 },
+}
+
+/**
+ * Dilate a wormhole into a durable connection to the peer
+ *
+ * Both sides must call this. The `relay_hints` and `abilities` are used for every (re)connection
+ * attempt, just like for a regular [`Transit`](transit::Transit).
+ *
+ * This returns as soon as the leader has been elected. The actual connection is established in
+ * the background; subchannels can already be used in the meantime and their data will be sent
+ * once connected.
+ */
+#[allow(deprecated)]
+pub async fn dilate(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<RelayHint>,
+    abilities: Abilities,
+) -> Result<DilatedWormhole, DilationError> {
+    let (their_versions, their_abilities) = wormhole.peer_dilation();
+    ensure!(
+        their_versions
+            .iter()
+            .any(|version| VERSIONS.contains(&version.as_str())),
+        DilationError::PeerCannotDilate
+    );
+    let their_abilities = match their_abilities {
+        Some(their_abilities) => serde_json::from_value(their_abilities.clone())?,
+        None => ABILITIES,
+    };
+
+    let our_side = hex::encode(rand::random::<[u8; 8]>());
+    let please = DilationMessage::Please {
+        side: our_side.clone(),
+    };
+    wormhole
+        .send_in_phase(Phase::dilation(0), serde_json::to_vec(&please).unwrap())
+        .await?;
+    let (_phase, message) = wormhole.receive_in_phase(Phase::is_dilation).await?;
+    let their_side = match serde_json::from_slice(&message)? {
+        DilationMessage::Please { side } => side,
+        other => bail!(DilationError::unexpected_message("please", other)),
+    };
+    ensure!(
+        our_side != their_side,
+        DilationError::protocol("Both sides chose the same side identifier")
+    );
+    let is_leader = our_side > their_side;
+    tracing::debug!(
+        "Dilating as {}",
+        if is_leader { "leader" } else { "follower" }
+    );
+
+    let (commands_tx, commands_rx) = mpsc::channel(16);
+    let (incoming_tx, incoming_rx) = mpsc::channel(16);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let manager = Manager {
+        wormhole,
+        is_leader,
+        abilities: abilities.intersect(&ABILITIES),
+        their_abilities,
+        relay_hints,
+        next_phase: 1,
+        state: State::Connecting,
+        attempt: 0,
+        connector: None,
+        their_hints: Hints::default(),
+        fresh_hints: false,
+        connecting: None,
+        connection: None,
+        next_seqnum: 0,
+        unacked: VecDeque::new(),
+        unacked_bytes: 0,
+        last_inbound: None,
+        pending: None,
+        next_scid: if is_leader { 1 } else { 2 },
+        subchannels: HashMap::new(),
+        incoming: incoming_tx,
+        commands_tx: commands_tx.clone(),
+        next_ping: 0,
+        mailbox_open: true,
+        shutdown_deadline: None,
+    };
+    let task = async_std::task::spawn(manager.run(commands_rx, shutdown_rx));
+
+    Ok(DilatedWormhole {
+        is_leader,
+        commands: commands_tx,
+        incoming: incoming_rx,
+        shutdown: shutdown_tx,
+        task,
+    })
+}
+
+/**
+ * A dilated wormhole
+ *
+ * Use it to open subchannels to the peer and to accept those the peer opens. Call
+ * [`close`](Self::close) when done, otherwise the wormhole will be kept open.
+ */
+#[derive(Debug)]
+pub struct DilatedWormhole {
+    is_leader: bool,
+    commands: mpsc::Sender<Command>,
+    incoming: mpsc::Receiver<Subchannel>,
+    shutdown: oneshot::Sender<Duration>,
+    task: async_std::task::JoinHandle<Result<(), DilationError>>,
+}
+
+impl DilatedWormhole {
+    /// Whether we won the leader election
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+
+    /// Open a new subchannel to the peer
+    pub async fn open_subchannel(&self) -> Result<Subchannel, DilationError> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .clone()
+            .send(Command::Open(tx))
+            .await
+            .map_err(|_| DilationError::Closed)?;
+        rx.await.map_err(|_| DilationError::Closed)
+    }
+
+    /// Wait for the peer to open a subchannel
+    pub async fn accept_subchannel(&mut self) -> Result<Subchannel, DilationError> {
+        self.incoming.next().await.ok_or(DilationError::Closed)
+    }
+
+    /**
+     * Close the dilated wormhole
+     *
+     * This closes all subchannels and waits until everything that was written to them has been acknowledged
+     * by the peer, then closes the underlying wormhole. If the dilated wormhole failed, the error is returned here.
+     * Gives up after [`DEFAULT_CLOSE_TIMEOUT`].
+     */
+    pub async fn close(self) -> Result<(), DilationError> {
+        self.close_with_timeout(DEFAULT_CLOSE_TIMEOUT).await
+    }
+
+    /**
+     * Like [`close`](Self::close), but wait at most `timeout`
+     *
+     * If the peer did not acknowledge everything in time (for example because it went away), the wormhole
+     * gets closed anyway and [`DilationError::CloseTimeout`] is returned.
+     */
+    pub async fn close_with_timeout(self, timeout: Duration) -> Result<(), DilationError> {
+        /* If the task is already gone, it will report why */
+        let _ = self.shutdown.send(timeout);
+        self.task.await
+    }
+}
+
+/**
+ * A reliable byte stream to the peer, multiplexed over a [`DilatedWormhole`]
+ *
+ * Writes are buffered and sent in the background, `flush` does not wait for them to arrive.
+ * Closing a subchannel closes it in both directions; reads will return EOF once the peer
+ * closed it as well.
+ *
+ * Received data is only acknowledged once it has been handed over to the subchannel. If a subchannel
+ * is not read from, the whole dilated wormhole stops receiving after a few records.
+ */
+#[derive(Debug)]
+pub struct Subchannel {
+    scid: u32,
+    commands: mpsc::Sender<Command>,
+    data: mpsc::Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    offset: usize,
+    closed: bool,
+}
+
+impl Subchannel {
+    /// The subchannel ID. Odd IDs were opened by the leader, even ones by the follower.
+    pub fn id(&self) -> u32 {
+        self.scid
+    }
+}
+
+fn broken_pipe(_: mpsc::SendError) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "the dilated wormhole is closed",
+    )
+}
+
+impl AsyncRead for Subchannel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            if self.offset < self.buffer.len() {
+                let len = buf.len().min(self.buffer.len() - self.offset);
+                buf[..len].copy_from_slice(&self.buffer[self.offset..self.offset + len]);
+                self.offset += len;
+                return Poll::Ready(Ok(len));
+            }
+            match futures::ready!(self.data.poll_next_unpin(cx)) {
+                Some(chunk) => {
+                    self.buffer = chunk;
+                    self.offset = 0;
+                },
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Subchannel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.closed {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "the subchannel is closed",
+            )));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        futures::ready!(self.commands.poll_ready(cx)).map_err(broken_pipe)?;
+        let len = buf.len().min(MAX_DATA_SIZE);
+        let scid = self.scid;
+        self.commands
+            .start_send(Command::Data {
+                scid,
+                data: buf[..len].to_vec(),
+            })
+            .map_err(broken_pipe)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.commands.poll_flush_unpin(cx).map_err(broken_pipe)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if !self.closed {
+            futures::ready!(self.commands.poll_ready(cx)).map_err(broken_pipe)?;
+            let scid = self.scid;
+            self.commands
+                .start_send(Command::Close { scid })
+                .map_err(broken_pipe)?;
+            self.closed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Subchannel {
+    fn drop(&mut self) {
+        if !self.closed {
+            /* A fresh sender always has room for one message */
+            let _ = self
+                .commands
+                .clone()
+                .try_send(Command::Close { scid: self.scid });
+        }
+    }
+}
+
+/// The manager's view on a subchannel
+struct SubchannelState {
+    /// `None` once the peer closed the subchannel
+    data: Option<mpsc::Sender<Vec<u8>>>,
+    /// Whether we sent a `CLOSE` already
+    closing: bool,
+}
+
+/// The L2 connection currently in use
+struct Connection {
+    tx: mpsc::UnboundedSender<Box<[u8]>>,
+    rx: BoxStream<'static, Result<Box<[u8]>, TransitError>>,
+    last_received: Instant,
+}
+
+/// Where we are in (re)connecting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// We sent our hints and connect as soon as we have the peer's
+    Connecting,
+    Connected,
+    /// The leader sent `reconnect` and waits for the follower to answer
+    Flushing,
+    /// The follower lost the connection and waits for the leader to send `reconnect`
+    Lonely,
+}
+
+enum Event {
+    Mailbox(Result<Result<DilationMessage, serde_json::Error>, WormholeError>),
+    Connected(Result<(transit::Transit, transit::TransitInfo), TransitConnectError>),
+    Record(Option<Result<Box<[u8]>, TransitError>>),
+    /// The pending inbound record can be handed over now
+    Deliverable,
+    Command(Command),
+    Shutdown(Result<Duration, oneshot::Canceled>),
+    Ping,
+    ShutdownTimeout,
+}
+
+/// The background task that owns the wormhole and all connections
+#[allow(deprecated)]
+struct Manager {
+    wormhole: Wormhole,
+    is_leader: bool,
+    abilities: Abilities,
+    their_abilities: Abilities,
+    relay_hints: Vec<RelayHint>,
+    /// The number of the next `dilate-N` phase we send in
+    next_phase: u64,
+
+    /* Connection state */
+    state: State,
+    /// Counts the connection attempts, for logging
+    attempt: u32,
+    /// Set once we sent our hints for the current attempt
+    connector: Option<TransitConnector>,
+    /// All hints the peer sent so far
+    their_hints: Hints,
+    /// Whether the peer sent hints since the current attempt started
+    fresh_hints: bool,
+    connecting: Option<
+        BoxFuture<'static, Result<(transit::Transit, transit::TransitInfo), TransitConnectError>>,
+    >,
+    connection: Option<Connection>,
+
+    /* L3 state, survives reconnects */
+    next_seqnum: u32,
+    /// Records that have not been acknowledged yet, in order
+    unacked: VecDeque<Record>,
+    unacked_bytes: usize,
+    /// The highest sequence number we have handed over
+    last_inbound: Option<u32>,
+    /// A received record that waits for room in its subchannel. No more records are read until then
+    pending: Option<Record>,
+
+    next_scid: u32,
+    subchannels: HashMap<u32, SubchannelState>,
+    incoming: mpsc::Sender<Subchannel>,
+    /// Handed out to subchannels opened by the peer
+    commands_tx: mpsc::Sender<Command>,
+    next_ping: u32,
+    mailbox_open: bool,
+    /// Set once we are shutting down, until when to wait for the peer
+    shutdown_deadline: Option<Instant>,
+}
+
+#[allow(deprecated)]
+async fn next_mailbox_message(
+    wormhole: &mut Wormhole,
+    mailbox_open: bool,
+) -> Result<Result<DilationMessage, serde_json::Error>, WormholeError> {
+    if mailbox_open {
+        let (_phase, message) = wormhole.receive_in_phase(Phase::is_dilation).await?;
+        Ok(serde_json::from_slice(&message))
+    } else {
+        futures::future::pending().await
+    }
+}
+
+async fn next_record(
+    connection: &mut Option<Connection>,
+    paused: bool,
+) -> Option<Result<Box<[u8]>, TransitError>> {
+    match connection {
+        Some(connection) if !paused => connection.rx.next().await,
+        _ => futures::future::pending().await,
+    }
+}
+
+/// Wait until the pending record can be handed over, or until nobody is there to take it anymore
+async fn wait_deliverable(
+    pending: &Option<Record>,
+    subchannels: &mut HashMap<u32, SubchannelState>,
+    incoming: &mut mpsc::Sender<Subchannel>,
+) {
+    /* Errors mean that the receiver is gone, which is dealt with when delivering */
+    match pending {
+        Some(Record::Open { .. }) => {
+            let _ = futures::future::poll_fn(|cx| incoming.poll_ready(cx)).await;
+        },
+        Some(Record::Data { scid, .. }) => {
+            if let Some(sender) = subchannels
+                .get_mut(scid)
+                .and_then(|state| state.data.as_mut())
+            {
+                let _ = futures::future::poll_fn(|cx| sender.poll_ready(cx)).await;
+            }
+        },
+        Some(_) => {},
+        None => futures::future::pending().await,
+    }
+}
+
+async fn wait_connected(
+    connecting: &mut Option<
+        BoxFuture<'static, Result<(transit::Transit, transit::TransitInfo), TransitConnectError>>,
+    >,
+) -> Result<(transit::Transit, transit::TransitInfo), TransitConnectError> {
+    match connecting {
+        Some(connecting) => connecting.await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => util::sleep(deadline.saturating_duration_since(Instant::now())).await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn next_command(
+    commands: &mut mpsc::Receiver<Command>,
+    accept_commands: bool,
+) -> Option<Command> {
+    if accept_commands {
+        commands.next().await
+    } else {
+        futures::future::pending().await
+    }
+}
+
+impl Manager {
+    async fn run(
+        mut self,
+        mut commands: mpsc::Receiver<Command>,
+        shutdown: oneshot::Receiver<Duration>,
+    ) -> Result<(), DilationError> {
+        let mut ping = async_std::stream::interval(PING_INTERVAL).fuse();
+        let mut shutdown = shutdown.fuse();
+        self.start_attempt().await?;
+
+        let timed_out = loop {
+            /* Subchannels are only forgotten once both sides closed them */
+            if self.shutdown_deadline.is_some()
+                && self.unacked.is_empty()
+                && self.subchannels.is_empty()
+            {
+                break false;
+            }
+
+            let event = futures::select! {
+                message = next_mailbox_message(&mut self.wormhole, self.mailbox_open).fuse() => {
+                    Event::Mailbox(message)
+                },
+                result = wait_connected(&mut self.connecting).fuse() => Event::Connected(result),
+                record = next_record(&mut self.connection, self.pending.is_some()).fuse() => {
+                    Event::Record(record)
+                },
+                _ = wait_deliverable(
+                    &self.pending,
+                    &mut self.subchannels,
+                    &mut self.incoming,
+                ).fuse() => Event::Deliverable,
+                command = next_command(
+                    &mut commands,
+                    self.shutdown_deadline.is_none()
+                        && self.unacked_bytes < MAX_UNACKED_BYTES
+                        && self.unacked.len() < MAX_UNACKED_RECORDS,
+                ).fuse() => {
+                    /* We hold a sender ourselves, so the channel can't run dry */
+                    Event::Command(command.unwrap())
+                },
+                timeout = shutdown => Event::Shutdown(timeout),
+                _ = ping.next() => Event::Ping,
+                _ = wait_until(self.shutdown_deadline).fuse() => Event::ShutdownTimeout,
+            };
+
+            match event {
+                Event::Mailbox(Ok(Ok(message))) => self.on_message(message).await?,
+                Event::Mailbox(Ok(Err(err))) => {
+                    tracing::warn!("Ignoring corrupt mailbox message: {}", err);
+                },
+                Event::Mailbox(Err(err)) => {
+                    tracing::warn!(
+                        "Lost the connection to the rendezvous server, cannot reconnect anymore: {}",
+                        err
+                    );
+                    self.mailbox_open = false;
+                },
+                Event::Connected(result) => {
+                    self.connecting = None;
+                    match result {
+                        Ok((transit, info)) => self.on_connected(transit, info),
+                        Err(err) => {
+                            tracing::warn!("Connection attempt {} failed: {}", self.attempt, err);
+                            if self.is_leader {
+                                util::sleep(RETRY_DELAY).await;
+                            }
+                            self.lost_connection().await?;
+                        },
+                    }
+                },
+                Event::Record(Some(Ok(record))) => {
+                    if let Some(connection) = &mut self.connection {
+                        connection.last_received = Instant::now();
+                    }
+                    /* Newer peers might send records we don't know yet, so this is not fatal */
+                    match Record::decode(&record) {
+                        Ok(record) => self.on_record(record),
+                        Err(err) => tracing::warn!("Ignoring invalid record: {}", err),
+                    }
+                },
+                Event::Record(Some(Err(err))) => {
+                    tracing::warn!("Connection lost: {}", err);
+                    self.lost_connection().await?;
+                },
+                Event::Record(None) => {
+                    tracing::warn!("Connection closed by peer");
+                    self.lost_connection().await?;
+                },
+                Event::Deliverable => self.deliver(),
+                Event::Command(command) => self.on_command(command),
+                Event::Shutdown(Ok(timeout)) => self.shutdown(timeout),
+                /* Without a handle, the wormhole stays open just like before */
+                Event::Shutdown(Err(oneshot::Canceled)) => {},
+                Event::ShutdownTimeout => {
+                    tracing::warn!(
+                        "The peer did not acknowledge {} records and close {} subchannels in time, closing anyway",
+                        self.unacked.len(),
+                        self.subchannels.len()
+                    );
+                    break true;
+                },
+                Event::Ping => {
+                    /* We don't read while a subchannel is full, so we can't expect anything either */
+                    let lost = self.pending.is_none()
+                        && self.connection.as_ref().is_some_and(|connection| {
+                            connection.last_received.elapsed()
+                                > PING_INTERVAL * PING_TIMEOUT_INTERVALS
+                        });
+                    if lost {
+                        tracing::warn!("Connection timed out");
+                        self.lost_connection().await?;
+                    } else {
+                        self.send(&Record::Ping(self.next_ping));
+                        self.next_ping = self.next_ping.wrapping_add(1);
+                    }
+                },
+            }
+        };
+
+        /* Shut down. Dropping the subchannel states signals EOF to the readers */
+        self.connection = None;
+        self.subchannels.clear();
+        self.incoming.close_channel();
+        let deadline = self.shutdown_deadline;
+        let close = self.wormhole.close();
+        /* Closing the mailbox may take its time as well, if the rendezvous server is gone */
+        match deadline {
+            Some(deadline) => {
+                /* Always leave a moment to say goodbye, even if waiting for the acks took all the time */
+                let remaining = deadline
+                    .saturating_duration_since(Instant::now())
+                    .max(RETRY_DELAY);
+                async_std::future::timeout(remaining, close)
+                    .await
+                    .map_err(|_| DilationError::CloseTimeout)??;
+            },
+            None => close.await?,
+        }
+        ensure!(!timed_out, DilationError::CloseTimeout);
+        Ok(())
+    }
+
+    /// Send a message over the mailbox, in the next dilation phase
+    #[allow(deprecated)]
+    async fn send_message(&mut self, message: &DilationMessage) -> Result<(), DilationError> {
+        ensure!(self.mailbox_open, DilationError::Closed);
+        let phase = Phase::dilation(self.next_phase);
+        self.next_phase += 1;
+        self.wormhole
+            .send_in_phase(phase, serde_json::to_vec(message).unwrap())
+            .await?;
+        Ok(())
+    }
+
+    async fn on_message(&mut self, message: DilationMessage) -> Result<(), DilationError> {
+        match message {
+            DilationMessage::ConnectionHints { hints } => self.on_hints(hints),
+            DilationMessage::Reconnect if !self.is_leader => {
+                tracing::debug!("The leader asked for a reconnect");
+                self.send_message(&DilationMessage::Reconnecting).await?;
+                self.start_attempt().await?;
+            },
+            DilationMessage::Reconnecting if self.is_leader && self.state == State::Flushing => {
+                self.start_attempt().await?;
+            },
+            other => tracing::debug!("Ignoring unexpected mailbox message: {:?}", other),
+        }
+        Ok(())
+    }
+
+    /// Drop the current connection and start connecting again
+    #[allow(deprecated)]
+    async fn start_attempt(&mut self) -> Result<(), DilationError> {
+        ensure!(self.mailbox_open, DilationError::Closed);
+        self.attempt += 1;
+        tracing::debug!("Starting connection attempt {}", self.attempt);
+        self.state = State::Connecting;
+        self.connection = None;
+        self.connecting = None;
+        self.connector = None;
+        self.fresh_hints = false;
+
+        let connector = transit::init_with_proxy(
+            self.abilities,
+            None,
+            self.relay_hints.clone(),
+            self.wormhole.proxy().cloned(),
+        )
+        .await?
+        .for_dilation();
+        self.send_message(&DilationMessage::ConnectionHints {
+            hints: (**connector.our_hints()).clone(),
+        })
+        .await?;
+        self.connector = Some(connector);
+        self.maybe_connect();
+        Ok(())
+    }
+
+    /// The connection or the attempt to get one failed, find a new one
+    async fn lost_connection(&mut self) -> Result<(), DilationError> {
+        self.connection = None;
+        self.connecting = None;
+        self.connector = None;
+        if self.is_leader {
+            self.state = State::Flushing;
+            self.send_message(&DilationMessage::Reconnect).await?;
+        } else {
+            ensure!(self.mailbox_open, DilationError::Closed);
+            self.state = State::Lonely;
+        }
+        Ok(())
+    }
+
+    fn on_hints(&mut self, hints: Hints) {
+        /* Older hints might still work, connecting to them does no harm */
+        self.their_hints.direct_tcp.extend(hints.direct_tcp);
+        self.their_hints.tor_tcp.extend(hints.tor_tcp);
+        for relay in hints.relay {
+            if !self.their_hints.relay.contains(&relay) {
+                self.their_hints.relay.push(relay);
+            }
+        }
+        self.fresh_hints = true;
+        self.maybe_connect();
+    }
+
+    /// Start connecting once both sides have sent their hints
+    #[allow(deprecated)]
+    fn maybe_connect(&mut self) {
+        if self.state != State::Connecting || !self.fresh_hints {
+            return;
+        }
+        let Some(connector) = self.connector.take() else {
+            return;
+        };
+        let key = self
+            .wormhole
+            .key()
+            .derive_subkey_from_purpose::<transit::TransitKey>("dilation-v1");
+        self.connecting = Some(Box::pin(connector.connect(
+            self.is_leader,
+            key,
+            self.their_abilities,
+            Arc::new(self.their_hints.clone()),
+        )));
+    }
+
+    fn on_connected(&mut self, transit: transit::Transit, info: transit::TransitInfo) {
+        tracing::info!(
+            "Connected in attempt {} ({:?})",
+            self.attempt,
+            info.conn_type
+        );
+        self.state = State::Connected;
+        let (sink, stream) = transit.split();
+        let (tx, rx) = mpsc::unbounded::<Box<[u8]>>();
+        /* Write errors will show up on the reading side as well */
+        async_std::task::spawn(rx.map(Ok).forward(sink));
+        self.connection = Some(Connection {
+            tx,
+            rx: stream.boxed(),
+            last_received: Instant::now(),
+        });
+
+        /* The handshake exchanged the KCM records already */
+        for record in &self.unacked {
+            Self::send_on(&self.connection, record);
+        }
+    }
+
+    fn on_record(&mut self, record: Record) {
+        tracing::trace!("Received {:?}", record);
+        if let Some(seqnum) = record.seqnum() {
+            if self.last_inbound.is_some_and(|last| seqnum <= last) {
+                tracing::debug!("Dropping duplicate record {}", seqnum);
+                /* Our previous ack might have gotten lost */
+                self.send(&Record::Ack(seqnum));
+            } else {
+                self.pending = Some(record);
+            }
+            return;
+        }
+
+        match record {
+            Record::KCM => tracing::debug!("Ignoring repeated key confirmation"),
+            Record::Ping(ping_id) => self.send(&Record::Pong(ping_id)),
+            Record::Pong(_) => {},
+            Record::Ack(seqnum) => {
+                while let Some(front) = self.unacked.front() {
+                    if front.seqnum().unwrap() > seqnum {
+                        break;
+                    }
+                    if let Some(Record::Data { data, .. }) = self.unacked.pop_front() {
+                        self.unacked_bytes -= data.len();
+                    }
+                }
+            },
+            Record::Open { .. } | Record::Data { .. } | Record::Close { .. } => unreachable!(),
+        }
+    }
+
+    /// Hand the pending record over to its subchannel, then acknowledge it
+    fn deliver(&mut self) {
+        let seqnum = match self.pending.take() {
+            Some(Record::Open { scid, seqnum }) => {
+                if self.subchannels.contains_key(&scid) {
+                    tracing::warn!("Peer opened subchannel {} twice", scid);
+                } else {
+                    let subchannel = self.new_subchannel(scid);
+                    /* Dropping the subchannel closes it again */
+                    if self.incoming.try_send(subchannel).is_err() {
+                        tracing::debug!("Nobody accepts subchannel {} anymore", scid);
+                    }
+                }
+                seqnum
+            },
+            Some(Record::Data { scid, seqnum, data }) => {
+                match self
+                    .subchannels
+                    .get_mut(&scid)
+                    .and_then(|state| state.data.as_mut())
+                {
+                    Some(sender) => {
+                        /* The reader might already be gone, that's fine */
+                        let _ = sender.try_send(data);
+                    },
+                    None => tracing::debug!("Dropping data for closed subchannel {}", scid),
+                }
+                seqnum
+            },
+            Some(Record::Close { scid, seqnum }) => {
+                if let Some(state) = self.subchannels.remove(&scid) {
+                    if !state.closing {
+                        self.queue(|seqnum| Record::Close { scid, seqnum });
+                    }
+                }
+                seqnum
+            },
+            _ => return,
+        };
+        self.last_inbound = Some(seqnum);
+        self.send(&Record::Ack(seqnum));
+        /* We did not read while waiting, so don't count that against the peer */
+        if let Some(connection) = &mut self.connection {
+            connection.last_received = Instant::now();
+        }
+    }
+
+    fn on_command(&mut self, command: Command) {
+        match command {
+            Command::Open(reply) => {
+                let scid = self.next_scid;
+                self.next_scid += 2;
+                let subchannel = self.new_subchannel(scid);
+                self.queue(|seqnum| Record::Open { scid, seqnum });
+                let _ = reply.send(subchannel);
+            },
+            Command::Data { scid, data } => {
+                if self
+                    .subchannels
+                    .get(&scid)
+                    .is_some_and(|state| !state.closing)
+                {
+                    self.unacked_bytes += data.len();
+                    self.queue(|seqnum| Record::Data { scid, seqnum, data });
+                } else {
+                    tracing::debug!("Dropping data for closed subchannel {}", scid);
+                }
+            },
+            Command::Close { scid } => {
+                if let Some(state) = self.subchannels.get_mut(&scid) {
+                    if !state.closing {
+                        state.closing = true;
+                        self.queue(|seqnum| Record::Close { scid, seqnum });
+                    }
+                }
+            },
+        }
+    }
+
+    /// Close all subchannels, and give the peer until `timeout` to acknowledge everything
+    fn shutdown(&mut self, timeout: Duration) {
+        self.shutdown_deadline = Some(Instant::now() + timeout);
+        let mut scids: Vec<u32> = self.subchannels.keys().copied().collect();
+        scids.sort_unstable();
+        for scid in scids {
+            let state = self.subchannels.get_mut(&scid).unwrap();
+            /* Nobody is going to read anymore */
+            state.data = None;
+            if !state.closing {
+                state.closing = true;
+                self.queue(|seqnum| Record::Close { scid, seqnum });
+            }
+        }
+    }
+
+    fn new_subchannel(&mut self, scid: u32) -> Subchannel {
+        let (data_tx, data_rx) = mpsc::channel(SUBCHANNEL_BUFFER);
+        self.subchannels.insert(
+            scid,
+            SubchannelState {
+                data: Some(data_tx),
+                closing: false,
+            },
+        );
+        Subchannel {
+            scid,
+            commands: self.commands_tx.clone(),
+            data: data_rx,
+            buffer: Vec::new(),
+            offset: 0,
+            closed: false,
+        }
+    }
+
+    /// Assign the next sequence number to a record, and send it if we are connected
+    fn queue(&mut self, record: impl FnOnce(u32) -> Record) {
+        let record = record(self.next_seqnum);
+        self.next_seqnum += 1;
+        self.send(&record);
+        self.unacked.push_back(record);
+    }
+
+    fn send(&self, record: &Record) {
+        Self::send_on(&self.connection, record);
+    }
+
+    fn send_on(connection: &Option<Connection>, record: &Record) {
+        if let Some(connection) = connection {
+            tracing::trace!("Sending {:?}", record);
+            /* If the writer is gone, the reader will notice soon enough */
+            let _ = connection.tx.unbounded_send(record.encode());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_encoding() {
+        let records = [
+            Record::KCM,
+            Record::Ping(7),
+            Record::Pong(7),
+            Record::Open { scid: 1, seqnum: 0 },
+            Record::Data {
+                scid: 1,
+                seqnum: 1,
+                data: b"hello".to_vec(),
+            },
+            Record::Close { scid: 1, seqnum: 2 },
+            Record::Ack(2),
+        ];
+        for record in records {
+            assert_eq!(Record::decode(&record.encode()).unwrap(), record);
+        }
+        assert_eq!(
+            &*Record::Data {
+                scid: 3,
+                seqnum: 258,
+                data: vec![0xff],
+            }
+            .encode(),
+            &[0x04, 0, 0, 0, 3, 0, 0, 1, 2, 0xff]
+        );
+        assert!(Record::decode(&[]).is_err());
+        assert!(Record::decode(&[0x03, 0, 0]).is_err());
+        assert_eq!(
+            Record::decode(&[0x03, 0, 0, 0, 1, 0, 0, 0, 2, b'x']).unwrap(),
+            Record::Open { scid: 1, seqnum: 2 }
+        );
+        assert!(Record::decode(&[0x42]).is_err());
+    }
+}
@@ -43,6 +43,8 @@ pub const APP_CONFIG: crate::AppConfig<AppVersion> = crate::AppConfig::<AppVersi
         transit_abilities: transit::Abilities::ALL_ABILITIES,
         other: serde_json::Value::Null,
     },
+    rendezvous_keepalive: Some(crate::rendezvous::Keepalive::DEFAULT),
+    proxy: None,
 };
 
 /**
@@ -161,10 +163,11 @@ pub async fn serve(
         .downcast_ref()
         .expect("You may only use a Wormhole instance with the correct AppVersion type!");
     let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version().clone())?;
-    let connector = transit::init(
+    let connector = transit::init_with_proxy(
         our_version.transit_abilities,
         Some(peer_version.transit_abilities),
         relay_hints,
+        wormhole.proxy().cloned(),
     )
     .await?;
 
@@ -399,7 +402,7 @@ impl ForwardingServe {
         let (mut connection_rd, connection_wr) = stream.split();
         let mut backchannel_tx = self.backchannel_tx.clone();
         let worker = async_std::task::spawn_local(async move {
-            let mut buffer = vec![0; 4096];
+            let mut buffer = vec![0; transit::RECORD_SIZE];
             /* Ignore errors */
             macro_rules! break_on_err {
                 ($expr:expr) => {
@@ -544,10 +547,11 @@ pub async fn connect(
         .downcast_ref()
         .expect("You may only use a Wormhole instance with the correct AppVersion type!");
     let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version().clone())?;
-    let connector = transit::init(
+    let connector = transit::init_with_proxy(
         our_version.transit_abilities,
         Some(peer_version.transit_abilities),
         relay_hints,
+        wormhole.proxy().cloned(),
     )
     .await?;
     let bind_address = bind_address.unwrap_or_else(|| std::net::IpAddr::V6("::".parse().unwrap()));
@@ -846,7 +850,7 @@ impl ForwardConnect {
             .await?;
 
         let worker = async_std::task::spawn_local(async move {
-            let mut buffer = vec![0; 4096];
+            let mut buffer = vec![0; transit::RECORD_SIZE];
             /* Ignore errors */
             macro_rules! break_on_err {
                 ($expr:expr) => {
@@ -14,6 +14,9 @@
 //!
 //! As an alternative to file transfer, there is the [`forwarding`] module, which allows to forward arbitrary TCP connections over the Wormhole/Transit tunnel.
 //!
+//! For long-lived connections that should survive network changes, a [`Wormhole`] can be turned into a durable connection with
+//! multiplexed streams using the experimental [`dilation`] module (`experimental-dilation` feature).
+//!
 //! Transferring large amounts of data should not be done over the rendezvous server. Instead, you have to set up a [`transit`]
 //! connection. A transit is little more than an encrypted TcpConnection. If a direct connection between both clients is not possible,
 //! a relay server will transparently connect them together. Transit is used by the file transfer for example, but any other AppID protocol
@@ -28,6 +31,8 @@
 #[macro_use]
 mod util;
 mod core;
+#[cfg(feature = "experimental-dilation")]
+pub mod dilation;
 #[cfg(feature = "forwarding")]
 pub mod forwarding;
 #[cfg(feature = "transfer")]
@@ -40,6 +45,9 @@ pub mod uri;
 #[allow(deprecated)]
 pub use crate::core::{
     key::{GenericKey, Key, KeyPurpose, WormholeKey},
-    rendezvous, AppConfig, AppID, Code, MailboxConnection, Mood, Nameplate, Wormhole,
-    WormholeError, WormholeWelcome,
+    proxy, rendezvous, wordlist, AppConfig, AppID, Code, MailboxConnection, Mood, Nameplate,
+    VerifierFormat, Wormhole, WormholeError, WormholeWelcome,
 };
+
+#[cfg(all(feature = "lan", not(target_family = "wasm")))]
+pub use crate::core::lan;
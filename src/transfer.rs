@@ -57,6 +57,8 @@ pub const APP_CONFIG: crate::AppConfig<AppVersion> = crate::AppConfig::<AppVersi
     id: AppID(Cow::Borrowed(APPID_RAW)),
     rendezvous_url: Cow::Borrowed(crate::rendezvous::DEFAULT_RENDEZVOUS_SERVER),
     app_version: AppVersion::new(),
+    rendezvous_keepalive: Some(crate::rendezvous::Keepalive::DEFAULT),
+    proxy: None,
 };
 
 // TODO be more extensible on the JSON enum types (i.e. recognize unknown variants)
@@ -95,6 +97,10 @@ pub enum TransferError {
     #[error("Something went wrong on the other side: {}", _0)]
     PeerError(String),
 
+    /// A received path would end up outside of the target directory
+    #[error("Refusing to write to unsafe path '{}'", _0)]
+    UnsafePath(String),
+
     /// Corrupt JSON message received. Some deserialization went wrong, we probably got some garbage
     #[error("Corrupt JSON message received")]
     ProtocolJson(
@@ -183,18 +189,32 @@ pub struct AppVersion {
 impl AppVersion {
     const fn new() -> Self {
         Self {
-            // Dont advertize v2 for now
+            // Only advertize v2 when it is enabled, as the protocol is not finalized yet
+            #[cfg(not(feature = "experimental-transfer-v2"))]
+            abilities: Cow::Borrowed(&[
+                Cow::Borrowed("transfer-v1"),
+                Cow::Borrowed("tar-folder-v1"),
+            ]),
+            #[cfg(feature = "experimental-transfer-v2")]
             abilities: Cow::Borrowed(&[
-                Cow::Borrowed("transfer-v1"), /* Cow::Borrowed("experimental-transfer-v2") */
+                Cow::Borrowed("transfer-v1"),
+                Cow::Borrowed("tar-folder-v1"),
+                Cow::Borrowed("experimental-transfer-v2"),
             ]),
             #[cfg(feature = "experimental-transfer-v2")]
             transfer_v2: Some(AppVersionTransferV2Hint::new()),
         }
     }
 
-    #[allow(dead_code)]
+    #[cfg(feature = "experimental-transfer-v2")]
     fn supports_v2(&self) -> bool {
-        self.abilities.contains(&"transfer-v2".into())
+        self.abilities.contains(&"experimental-transfer-v2".into()) && self.transfer_v2.is_some()
+    }
+
+    /// Whether folders may be sent as `tar` directory offers. Other implementations (like the
+    /// Python implementation) expect folders to be sent as zip file.
+    fn supports_tar_folders(&self) -> bool {
+        self.abilities.contains(&"tar-folder-v1".into())
     }
 }
 
@@ -270,7 +290,6 @@ pub enum PeerMessage {
 }
 
 impl PeerMessage {
-    #[allow(unused)]
     fn offer_message_v1(msg: impl Into<String>) -> Self {
         PeerMessage::Offer(v1::OfferMessage::Message(msg.into()))
     }
@@ -282,7 +301,6 @@ impl PeerMessage {
         })
     }
 
-    #[allow(dead_code)]
     fn offer_directory_v1(
         name: impl Into<String>,
         mode: impl Into<String>,
@@ -299,7 +317,6 @@ impl PeerMessage {
         })
     }
 
-    #[allow(dead_code)]
     fn message_ack_v1(msg: impl Into<String>) -> Self {
         PeerMessage::Answer(v1::AnswerMessage::MessageAck(msg.into()))
     }
@@ -421,6 +438,80 @@ pub async fn request(
     }
 }
 
+/**
+ * Send a text message to the other side
+ *
+ * The message is sent directly over the wormhole, no transit connection is established.
+ * Returns once the other side acknowledged it.
+ */
+pub async fn send_text(
+    wormhole: Wormhole,
+    text: impl Into<String>,
+    cancel: impl Future<Output = ()>,
+) -> Result<(), TransferError> {
+    v1::send_text(wormhole, text.into(), cancel).await
+}
+
+/**
+ * Wait for a text message or a file offer from the other side
+ *
+ * Like [`request_file`], but additionally accepts text messages. These are acknowledged
+ * right away and returned as [`TextOrFile::Text`], the wormhole is closed afterwards.
+ *
+ * Returns `None` if the task got cancelled.
+ */
+pub async fn request_text_or_file(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_abilities: transit::Abilities,
+    cancel: impl Future<Output = ()>,
+) -> Result<Option<TextOrFile>, TransferError> {
+    #[cfg(feature = "experimental-transfer-v2")]
+    {
+        let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version().clone())?;
+        if peer_version.supports_v2() {
+            let offer = v2::request_text_or_file(
+                wormhole,
+                relay_hints,
+                peer_version,
+                transit_abilities,
+                true,
+                cancel,
+            )
+            .await?;
+            return Ok(offer.map(|offer| match offer {
+                v1::Offered::Text(text) => TextOrFile::Text(text),
+                v1::Offered::File(req) => TextOrFile::File(ReceiveRequest::V2(req)),
+            }));
+        }
+    }
+
+    let offer =
+        v1::request_text_or_file(wormhole, relay_hints, transit_abilities, true, cancel).await?;
+    Ok(offer.map(|offer| match offer {
+        v1::Offered::Text(text) => TextOrFile::Text(text),
+        #[cfg(feature = "experimental-transfer-v2")]
+        v1::Offered::File(req) => TextOrFile::File(ReceiveRequest::V1(req)),
+        #[cfg(not(feature = "experimental-transfer-v2"))]
+        v1::Offered::File(req) => TextOrFile::File(req),
+    }))
+}
+
+/**
+ * What the other side sent us, as returned by [`request_text_or_file`]
+ */
+#[must_use]
+#[non_exhaustive]
+#[allow(clippy::large_enum_variant)]
+pub enum TextOrFile {
+    /// A text message. It has already been acknowledged, nothing more to do.
+    ///
+    /// **Security warning:** this is untrusted input, be careful when printing it to a terminal.
+    Text(String),
+    /// A file offer. You *should* accept or reject it.
+    File(ReceiveRequest),
+}
+
 /// Wait for a file offer from the other side
 ///
 /// This method waits for an offer message and builds up a ReceiveRequest. It will also start building a TCP connection to the other side using the transit protocol.
@@ -547,8 +638,10 @@ where
 }
 
 /// Send a folder to the other side
-/// This isn’t a proper folder transfer as per the Wormhole protocol because it sends it in a way so
-/// that the receiver still has to manually unpack it. But it’s better than nothing
+///
+/// Other implementations receive it as a proper folder transfer in the `zipfile/deflated` mode, which
+/// requires building the zip file in advance. Receivers using this implementation get a `tar` directory
+/// offer instead, which can be streamed.
 #[cfg_attr(
     feature = "experimental-transfer-v2",
     deprecated(
@@ -686,7 +779,7 @@ mod test {
             serde_json::json!(crate::transfer::PeerMessage::transit_v1(abilities, hints)),
             serde_json::json!({
                 "transit": {
-                    "abilities-v1": [{"type":"direct-tcp-v1"},{"type":"relay-v1"}],
+                    "abilities-v1": [{"type":"direct-tcp-v1"},{"type":"relay-v1"},{"type":"noise-crypto-v1"},{"type":"migrate-v1"}],
                     "hints-v1": [
                         {"hostname":"192.168.1.8","port":46295,"type":"direct-tcp-v1"},
                         {
@@ -729,6 +822,16 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_message_offer_deserialize() {
+        let m: PeerMessage =
+            serde_json::from_str(r#"{"offer": {"message": "hello from python"}}"#).unwrap();
+        assert!(matches!(
+            m,
+            PeerMessage::Offer(v1::OfferMessage::Message(text)) if text == "hello from python"
+        ));
+    }
+
     #[test]
     fn test_message_ack() {
         let m1 = PeerMessage::message_ack_v1("ok");
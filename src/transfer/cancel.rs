@@ -107,14 +107,7 @@ pub async fn handle_run_result(
     match handle_run_result_noclose(wormhole, result).await {
         Ok(Some(((), wormhole, cancel))) => {
             /* Happy case: everything went okay. Now close the wormholhe */
-            tracing::debug!("Transfer done, doing cleanup logic");
-            wrap_timeout(
-                async {
-                    debug_err(wormhole.close().await, "close Wormhole");
-                },
-                cancel,
-            )
-            .await;
+            close_wormhole(wormhole, cancel).await;
             Ok(())
         },
         Ok(None) => Ok(()),
@@ -122,6 +115,18 @@ pub async fn handle_run_result(
     }
 }
 
+/** Close the Wormhole after a successful transfer */
+pub async fn close_wormhole(wormhole: Wormhole, cancel: impl Future<Output = ()>) {
+    tracing::debug!("Transfer done, doing cleanup logic");
+    wrap_timeout(
+        async {
+            debug_err(wormhole.close().await, "close Wormhole");
+        },
+        cancel,
+    )
+    .await;
+}
+
 /** Handle the post-{transfer, failure, cancellation} logic */
 pub async fn handle_run_result_noclose<T, C: Future<Output = ()>>(
     mut wormhole: Wormhole,
@@ -62,9 +62,48 @@ impl OfferSend {
     /// as advertized in file_size.
     pub fn new_file_custom(offer_name: String, size: u64, content: OfferContent) -> Self {
         let mut content_ = BTreeMap::new();
-        content_.insert(offer_name, OfferSendEntry::RegularFile { size, content });
+        content_.insert(
+            offer_name,
+            OfferSendEntry::RegularFile {
+                size,
+                stream: false,
+                mtime: None,
+                mode: None,
+                content,
+            },
+        );
         Self { content: content_ }
     }
+
+    /**
+     * Offer a single file from a stream of unknown length, like a pipe
+     *
+     * The stream will be read only once, so it cannot be resumed. This works best with transfer-v2:
+     * transfer-v1 needs to know the size in advance, so there the stream gets buffered into a
+     * temporary file first.
+     */
+    pub fn new_stream(offer_name: String, stream: impl AsyncRead + Unpin + Send + 'static) -> Self {
+        let stream = std::sync::Mutex::new(Some(stream));
+        let mut content = BTreeMap::new();
+        content.insert(
+            offer_name,
+            OfferSendEntry::RegularFile {
+                size: 0,
+                stream: true,
+                mtime: None,
+                mode: None,
+                content: new_offer_content(move || {
+                    let stream = stream.lock().unwrap().take();
+                    async move {
+                        stream.map(Unseekable::new).ok_or_else(|| {
+                            std::io::Error::other("The stream has already been consumed")
+                        })
+                    }
+                }),
+            },
+        );
+        Self { content }
+    }
 }
 
 impl<T> Offer<T> {
@@ -109,23 +148,143 @@ impl<T> Offer<T> {
         self.iter_files().map(|v| v.2).sum()
     }
 
+    /** Whether any of the files is a stream of unknown size, see [`OfferSend::new_stream`] */
+    pub fn has_streams(&self) -> bool {
+        self.iter_file_paths().any(|path| {
+            matches!(
+                self.get(&path),
+                Some(OfferEntry::RegularFile { stream: true, .. })
+            )
+        })
+    }
+
+    /**
+     * Only keep the entries for which `f` returns `true`
+     *
+     * `f` gets called with the path of every file, symlink and empty directory. Directories which end up
+     * empty get removed as well. Use this on a received offer before accepting it in order to only receive
+     * a subset of its files (this requires transfer-v2).
+     */
+    pub fn retain(&mut self, mut f: impl FnMut(&[String]) -> bool) {
+        let mut path = Vec::new();
+        self.content.retain(|name, entry| {
+            path.push(name.clone());
+            let keep = entry.retain(&mut path, &mut f);
+            path.pop();
+            keep
+        });
+    }
+
     #[cfg(not(target_family = "wasm"))]
     pub fn accept_all(&self, target_dir: &Path) -> OfferAccept {
+        self.accept_all_at(target_dir, |_| None)
+    }
+
+    /**
+     * Like [`accept_all`](Self::accept_all), but continue files that were partially received before
+     *
+     * Files already present in `target_dir` which are not larger than offered are hashed, and the
+     * answer asks to resume them after their existing content. The sender verifies the hash and
+     * starts again from scratch if it does not match.
+     *
+     * Files that are already complete and identical are thus skipped entirely, which allows
+     * receiving a directory again on top of a previous copy of it.
+     */
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn accept_all_resumable(&self, target_dir: &Path) -> std::io::Result<OfferAccept> {
+        let partial = self.find_partial_files(target_dir).await?;
+        Ok(self.accept_all_at(target_dir, |path| partial.get(path).copied()))
+    }
+
+    /**
+     * Like [`accept_all_resumable`](Self::accept_all_resumable), but never modify files in `target_dir` in place
+     *
+     * Every file is received into a temporary file next to it, which replaces it once complete. If the
+     * transfer fails, the previous content thus stays intact. Destinations that are symlinks, or that lie
+     * within symlinked directories, are refused, so that receiving never writes to outside of `target_dir`.
+     */
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn accept_all_replacing(&self, target_dir: &Path) -> std::io::Result<OfferAccept> {
+        for path in self.iter_file_paths() {
+            check_no_symlinks(target_dir, &path)?;
+        }
+        let partial = self.find_partial_files(target_dir).await?;
+        Ok(self.set_content(|path| {
+            let (offset, sha256) = match partial.get(path) {
+                Some(&(offset, sha256)) => (offset, Some(sha256)),
+                None => (0, None),
+            };
+            let full_path: PathBuf = target_dir.join(path.join("/"));
+            let content =
+                new_accept_content(move |append| ReplaceOnClose::create(full_path.clone(), append));
+            AcceptInner {
+                content: Box::new(content) as _,
+                offset,
+                sha256,
+            }
+        }))
+    }
+
+    /** Hash the existing prefix of all offered files in `target_dir` */
+    #[cfg(not(target_family = "wasm"))]
+    async fn find_partial_files(
+        &self,
+        target_dir: &Path,
+    ) -> std::io::Result<BTreeMap<Vec<String>, (u64, [u8; 32])>> {
+        let mut partial = BTreeMap::new();
+        /* Collect the paths first, so that the returned future stays `Send` */
+        let files: Vec<_> = self
+            .iter_files()
+            .map(|(path, _, size)| (path, size))
+            .collect();
+        for (path, size) in files {
+            let full_path = target_dir.join(path.join("/"));
+            if let Some(prefix) = hash_partial_file(&full_path, size).await? {
+                tracing::debug!("Resuming {} after {} bytes", full_path.display(), prefix.0);
+                partial.insert(path, prefix);
+            }
+        }
+        Ok(partial)
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    fn accept_all_at(
+        &self,
+        target_dir: &Path,
+        mut resume_at: impl FnMut(&[String]) -> Option<(u64, [u8; 32])>,
+    ) -> OfferAccept {
         self.set_content(|path| {
+            let (offset, sha256) = match resume_at(path) {
+                Some((offset, sha256)) => (offset, Some(sha256)),
+                None => (0, None),
+            };
             let full_path: PathBuf = target_dir.join(path.join("/"));
+            let (target_dir, path) = (target_dir.to_owned(), path.to_vec());
             let content = new_accept_content(move |append| {
                 let full_path = full_path.clone();
-                async_std::fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .append(append)
-                    .truncate(!append)
-                    .open(full_path)
+                /* Never write through symlinks the destination may already contain, not even at the file itself */
+                let checked = check_no_symlinks(&target_dir, &path);
+                async move {
+                    checked?;
+                    let mut options = async_std::fs::OpenOptions::new();
+                    options
+                        .write(true)
+                        .create(true)
+                        .append(append)
+                        .truncate(!append);
+                    /* In case one got created since the check */
+                    #[cfg(unix)]
+                    async_std::os::unix::fs::OpenOptionsExt::custom_flags(
+                        &mut options,
+                        libc::O_NOFOLLOW,
+                    );
+                    options.open(full_path).await
+                }
             });
             AcceptInner {
                 content: Box::new(content) as _,
-                offset: 0,
-                sha256: None,
+                offset,
+                sha256,
             }
         })
     }
@@ -139,14 +298,63 @@ impl<T> Offer<T> {
         Ok(())
     }
 
-    // #[cfg(not(target_family = "wasm"))]
-    // pub async fn create_symlinks(&self, target_path: &Path) -> std::io::Result<()> {
-    //     // TODO this could be made more efficient by passing around just one buffer
-    //     for (name, file) in &self.content {
-    //         file.create_symlinks(&target_path.join(name)).await?;
-    //     }
-    //     Ok(())
-    // }
+    /**
+     * Restore the modification times and permissions of all offered files and directories in `target_path`
+     *
+     * This must be called last, after all files have been received and all symlinks have been created, as
+     * the restored permissions may not allow writing anymore. Symlinks are never touched.
+     */
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn restore_metadata(&self, target_path: &Path) -> std::io::Result<()> {
+        let mut entries = Vec::new();
+        for (name, entry) in &self.content {
+            entry.collect_metadata(target_path.join(name), &mut entries);
+        }
+        async_std::task::spawn_blocking(move || {
+            for (path, mtime, mode) in entries {
+                set_metadata(&path, mtime, mode)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /** Recursively list all symlinks with their targets */
+    pub fn iter_symlinks(&self) -> impl Iterator<Item = (Vec<String>, &str)> + '_ {
+        self.content.iter().flat_map(|(name, offer)| {
+            let name = name.clone();
+            offer.iter_symlinks().map(move |mut val| {
+                val.0.insert(0, name.clone());
+                val
+            })
+        })
+    }
+
+    /**
+     * Create all offered symlinks in `target_path` according to `policy`
+     *
+     * This must be called after all files have been received, as this greatly reduces the attack surface.
+     * Symlinks pointing outside of `target_path` are never created.
+     */
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn create_symlinks(
+        &self,
+        target_path: &Path,
+        policy: SymlinkPolicy,
+    ) -> std::io::Result<()> {
+        let target_path = target_path.to_owned();
+        let symlinks = self
+            .iter_symlinks()
+            .map(|(path, target)| (path, target.to_owned()))
+            .collect::<Vec<_>>();
+        async_std::task::spawn_blocking(move || {
+            for (path, target) in symlinks {
+                create_symlink(&target_path, &path, &target, policy)?;
+            }
+            Ok(())
+        })
+        .await
+    }
 
     pub fn offer_name(&self) -> String {
         let (name, entry) = self.content.iter().next().unwrap();
@@ -204,6 +412,338 @@ impl<T> From<&Offer<T>> for Offer {
     }
 }
 
+/**
+ * Hash the content of a previously partially received file
+ *
+ * Returns its length and hash, or `None` if there is nothing to resume.
+ */
+#[cfg(not(target_family = "wasm"))]
+async fn hash_partial_file(path: &Path, size: u64) -> std::io::Result<Option<(u64, [u8; 32])>> {
+    use futures::AsyncReadExt;
+    use sha2::{digest::FixedOutput, Digest, Sha256};
+
+    let metadata = match async_std::fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if !metadata.is_file() || metadata.len() == 0 || metadata.len() > size {
+        return Ok(None);
+    }
+
+    let mut file = async_std::fs::File::open(path).await?;
+    let mut hasher = Sha256::default();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut length = 0;
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        length += n as u64;
+    }
+    if length > size {
+        return Ok(None);
+    }
+    Ok(Some((length, hasher.finalize_fixed().into())))
+}
+
+/** Fail if `path` (relative to `destination`) or any of its parent directories is a symlink */
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn check_no_symlinks(
+    destination: &Path,
+    path: impl IntoIterator<Item = impl AsRef<Path>>,
+) -> std::io::Result<()> {
+    let mut current = destination.to_owned();
+    for component in path {
+        current.push(component);
+        match std::fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.is_symlink() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Refusing to write through the symlink {}",
+                        current.display()
+                    ),
+                ));
+            },
+            Ok(_) => {},
+            /* Nothing below a missing entry can be a symlink */
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/**
+ * A file that is written next to its destination and only renamed into place when closed
+ *
+ * If it is dropped without being closed, the temporary file gets removed again.
+ */
+#[cfg(not(target_family = "wasm"))]
+struct ReplaceOnClose {
+    file: async_std::fs::File,
+    temporary: PathBuf,
+    destination: PathBuf,
+    done: bool,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl ReplaceOnClose {
+    /** When appending, the temporary file starts out with a copy of the destination */
+    async fn create(destination: PathBuf, append: bool) -> std::io::Result<Self> {
+        let mut name = std::ffi::OsString::from(".");
+        name.push(destination.file_name().unwrap_or_default());
+        name.push(".wormhole-tmp");
+        let temporary = destination.with_file_name(name);
+        if append {
+            async_std::fs::copy(&destination, &temporary).await?;
+        }
+        let file = async_std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(&temporary)
+            .await?;
+        Ok(Self {
+            file,
+            temporary,
+            destination,
+            done: false,
+        })
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl AsyncWrite for ReplaceOnClose {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        /* async-std files don't flush when closing */
+        futures::ready!(std::pin::Pin::new(&mut self.file).poll_flush(cx))?;
+        futures::ready!(std::pin::Pin::new(&mut self.file).poll_close(cx))?;
+        if !self.done {
+            std::fs::rename(&self.temporary, &self.destination)?;
+            self.done = true;
+        }
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Drop for ReplaceOnClose {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = std::fs::remove_file(&self.temporary);
+        }
+    }
+}
+
+/** What to do with symlinks when receiving */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SymlinkPolicy {
+    /** Create the symlinks as they were on the sender's side */
+    #[default]
+    Recreate,
+    /** Ignore all symlinks */
+    Skip,
+    /**
+     * Replace the symlinks with a copy of what they point to
+     *
+     * Symlinks within followed directories are not followed again.
+     */
+    Follow,
+}
+
+/**
+ * Check whether a symlink at `link` (relative to the destination directory) stays within the destination
+ *
+ * This is a purely lexical check, so in order not to be fooled by other symlinks, `..` components are only
+ * allowed at the start of the target. It is only sound if none of the parent directories of `link` is a
+ * symlink itself, which [`create_symlink`] checks as well.
+ */
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn is_symlink_inside(link: &[String], target: &str) -> bool {
+    use std::path::Component;
+
+    /* How often we may go up before leaving the destination */
+    let mut depth = link.len().saturating_sub(1);
+    let mut descending = false;
+    let mut components = 0;
+    for component in std::path::Path::new(target).components() {
+        match component {
+            Component::ParentDir if !descending && depth > 0 => depth -= 1,
+            Component::Normal(_) => descending = true,
+            Component::CurDir => {},
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+        components += 1;
+    }
+    components > 0
+}
+
+/** Create the symlink at `link` (relative to `destination`) according to `policy` */
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn create_symlink(
+    destination: &Path,
+    link: &[String],
+    target: &str,
+    policy: SymlinkPolicy,
+) -> std::io::Result<()> {
+    let link_path = destination.join(link.join("/"));
+    if !is_symlink_inside(link, target) {
+        tracing::warn!(
+            "Refusing symlink {} pointing outside of the destination ({target})",
+            link_path.display()
+        );
+        return Ok(());
+    }
+    /* Otherwise chained symlinks like `a/l1 -> ..` and `a/l1/l2 -> ..` could escape step by step */
+    match check_no_symlinks(destination, &link[..link.len().saturating_sub(1)]) {
+        Ok(()) => {},
+        Err(err) if err.kind() == std::io::ErrorKind::InvalidInput => {
+            tracing::warn!("Refusing symlink {}: {err}", link_path.display());
+            return Ok(());
+        },
+        Err(err) => return Err(err),
+    }
+
+    match policy {
+        SymlinkPolicy::Skip => {
+            tracing::debug!("Skipping symlink {}", link_path.display());
+        },
+        SymlinkPolicy::Recreate => {
+            /* Replace what a previous transfer left behind */
+            if std::fs::symlink_metadata(&link_path).is_ok_and(|metadata| metadata.is_symlink()) {
+                std::fs::remove_file(&link_path)?;
+            }
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(target, &link_path)?;
+            #[cfg(windows)]
+            {
+                let parent = link_path.parent().unwrap_or(destination);
+                if parent.join(target).is_dir() {
+                    std::os::windows::fs::symlink_dir(target, &link_path)?;
+                } else {
+                    std::os::windows::fs::symlink_file(target, &link_path)?;
+                }
+            }
+            #[cfg(not(any(unix, windows)))]
+            tracing::warn!(
+                "Symlinks are not supported on this platform, skipping {}",
+                link_path.display()
+            );
+        },
+        SymlinkPolicy::Follow => {
+            let parent = link_path.parent().unwrap_or(destination);
+            let source = parent.join(target);
+            let metadata = match std::fs::metadata(&source) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    tracing::warn!(
+                        "Cannot follow symlink {} to {target}: {err}",
+                        link_path.display()
+                    );
+                    return Ok(());
+                },
+            };
+            if metadata.is_dir() {
+                /* Copying a directory into itself would never end */
+                if parent.canonicalize()?.starts_with(source.canonicalize()?) {
+                    tracing::warn!(
+                        "Cannot follow symlink {} to its own parent directory {target}",
+                        link_path.display()
+                    );
+                    return Ok(());
+                }
+                copy_dir(&source, &link_path)?;
+            } else {
+                std::fs::copy(&source, &link_path)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/**
+ * Set the modification time and permissions of a received file or directory, but never of a symlink
+ *
+ * The permissions are untrusted input: the owner always keeps read and write access (and may enter
+ * directories), and group and others never get write access. Setuid, setgid and sticky bits are dropped.
+ */
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn set_metadata(
+    path: &Path,
+    mtime: Option<u64>,
+    mode: Option<u32>,
+) -> std::io::Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.is_symlink() {
+        return Ok(());
+    }
+
+    if let Some(mtime) = mtime {
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime);
+        /* Only Unix allows opening directories */
+        #[cfg(unix)]
+        std::fs::File::open(path)?.set_modified(mtime)?;
+        #[cfg(not(unix))]
+        if metadata.is_file() {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_modified(mtime)?;
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        let owner = if metadata.is_dir() { 0o700 } else { 0o600 };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o755 | owner))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    Ok(())
+}
+
+/** Recursively copy a directory, without following any symlinks within it */
+#[cfg(not(target_family = "wasm"))]
+fn copy_dir(source: &Path, target: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(target)?;
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &target.join(entry.file_name()))?;
+        } else if file_type.is_file() {
+            std::fs::copy(entry.path(), target.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
 pub trait AsyncReadSeek: AsyncRead + AsyncSeek {}
 impl<T> AsyncReadSeek for T where T: AsyncRead + AsyncSeek {}
 
@@ -236,6 +776,54 @@ where
     Box::new(wrap_fun) as _
 }
 
+/**
+ * Make a reader usable as [`OfferContent`], even though it cannot seek
+ *
+ * Only "seeking" to the current position is supported, everything else fails.
+ */
+struct Unseekable<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R> Unseekable<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, position: 0 }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Unseekable<R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let result = std::pin::Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let std::task::Poll::Ready(Ok(n)) = result {
+            self.position += n as u64;
+        }
+        result
+    }
+}
+
+impl<R: Unpin> AsyncSeek for Unseekable<R> {
+    fn poll_seek(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        pos: std::io::SeekFrom,
+    ) -> std::task::Poll<std::io::Result<u64>> {
+        use std::io::SeekFrom;
+        std::task::Poll::Ready(match pos {
+            SeekFrom::Start(offset) if offset == self.position => Ok(offset),
+            SeekFrom::Current(0) => Ok(self.position),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Streams cannot seek",
+            )),
+        })
+    }
+}
+
 pub type OfferSendEntry = OfferEntry<OfferContent>;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -244,60 +832,94 @@ pub type OfferSendEntry = OfferEntry<OfferContent>;
 #[serde(bound(deserialize = "T: Default"))]
 pub enum OfferEntry<T = ()> {
     RegularFile {
+        /// Size in bytes. Zero for streams
         size: u64,
+        /// The size is not known in advance, the content is sent until its end (requires transfer-v2)
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        stream: bool,
+        /// Modification time, in seconds since the Unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mtime: Option<u64>,
+        /// Unix permission bits
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<u32>,
         #[serde(skip)]
         content: T,
     },
     Directory {
+        /// Modification time, in seconds since the Unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mtime: Option<u64>,
+        /// Unix permission bits
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<u32>,
         content: BTreeMap<String, Self>,
     },
-    // Symlink {
-    //     target: String,
-    // },
+    Symlink {
+        target: String,
+    },
 }
 
 impl OfferSendEntry {
     #[cfg(not(target_family = "wasm"))]
     pub(super) async fn new(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        /* Symlinks given directly by the user are followed, all the others get preserved */
+        Self::new_inner(path, true).await
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    async fn new_inner(path: impl AsRef<Path>, follow_symlinks: bool) -> std::io::Result<Self> {
         // Workaround for https://github.com/rust-lang/rust/issues/78649
         #[inline(always)]
         fn new_recurse<'a>(
             path: impl AsRef<Path> + 'a + Send,
         ) -> futures::future::BoxFuture<'a, std::io::Result<OfferSendEntry>> {
-            Box::pin(OfferSendEntry::new(path))
+            Box::pin(OfferSendEntry::new_inner(path, false))
         }
 
         let path = path.as_ref();
-        // let metadata = async_std::fs::symlink_metadata(path).await?;
-        let metadata = async_std::fs::metadata(path).await?;
-        // let mtime = metadata.modified()?
-        //     .duration_since(std::time::SystemTime::UNIX_EPOCH)
-        //     .unwrap_or_default()
-        //     .as_secs();
+        let metadata = if follow_symlinks {
+            async_std::fs::metadata(path).await?
+        } else {
+            async_std::fs::symlink_metadata(path).await?
+        };
+        let mtime = metadata.modified().ok().map(|mtime| {
+            mtime
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
         if metadata.is_file() {
             tracing::trace!("OfferSendEntry::new {path:?} is file");
             let path = path.to_owned();
             Ok(Self::RegularFile {
                 size: metadata.len(),
+                stream: false,
+                mtime,
+                mode,
                 content: new_offer_content(move || {
                     let path = path.clone();
                     async_std::fs::File::open(path)
                 }),
             })
-        // } else if metadata.is_symlink() {
-        //     tracing::trace!("OfferSendEntry::new {path:?} is symlink");
-        //     let target = async_std::fs::read_link(path).await?;
-        //     Ok(Self::Symlink {
-        //         target: target
-        //             .to_str()
-        //             .ok_or_else(|| {
-        //                 std::io::Error::new(
-        //                     std::io::ErrorKind::Other,
-        //                     format!("{} is not UTF-8 encoded", target.display()),
-        //                 )
-        //             })?
-        //             .to_string(),
-        //     })
+        } else if metadata.is_symlink() {
+            tracing::trace!("OfferSendEntry::new {path:?} is symlink");
+            let target = async_std::fs::read_link(path).await?;
+            Ok(Self::Symlink {
+                target: target
+                    .to_str()
+                    .ok_or_else(|| {
+                        std::io::Error::other(format!("{} is not UTF-8 encoded", target.display()))
+                    })?
+                    .to_string(),
+            })
         } else if metadata.is_dir() {
             use futures::TryStreamExt;
             tracing::trace!("OfferSendEntry::new {path:?} is directory");
@@ -322,16 +944,23 @@ impl OfferSendEntry {
                 })
                 .try_collect()
                 .await?;
-            Ok(Self::Directory { content })
+            Ok(Self::Directory {
+                mtime,
+                mode,
+                content,
+            })
         } else {
-            unreachable!()
+            Err(std::io::Error::other(format!(
+                "{} is neither a file, a directory nor a symlink",
+                path.display()
+            )))
         }
     }
 }
 
 impl<T> OfferEntry<T> {
     /** Recursively list all files, without directory names or symlinks. */
-    fn iter_files(&self) -> impl Iterator<Item = (Vec<String>, &T, u64)> + '_ {
+    pub(crate) fn iter_files(&self) -> impl Iterator<Item = (Vec<String>, &T, u64)> + '_ {
         // TODO I couldn't think up a less efficient way to do this ^^
         match self {
             Self::Directory { content, .. } => {
@@ -344,10 +973,10 @@ impl<T> OfferEntry<T> {
                 });
                 Box::new(iter) as Box<dyn Iterator<Item = _>>
             },
-            Self::RegularFile { content, size } => {
+            Self::RegularFile { content, size, .. } => {
                 Box::new(std::iter::once((vec![], content, *size))) as Box<dyn Iterator<Item = _>>
             },
-            // Self::Symlink { .. } => Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _>>,
+            Self::Symlink { .. } => Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _>>,
         }
     }
 
@@ -366,7 +995,7 @@ impl<T> OfferEntry<T> {
     fn get_file(&self, path: &[String]) -> Option<(&T, u64)> {
         match path {
             [] => match self {
-                Self::RegularFile { content, size } => Some((content, *size)),
+                Self::RegularFile { content, size, .. } => Some((content, *size)),
                 _ => None,
             },
             [start, rest @ ..] => match self {
@@ -389,7 +1018,8 @@ impl<T> OfferEntry<T> {
         }
         match self {
             Self::Directory { content, .. } => {
-                async_std::fs::create_dir(target_path).await?;
+                /* Directories may already exist when resuming */
+                async_std::fs::create_dir_all(target_path).await?;
                 for (name, file) in content {
                     recurse(file, &target_path.join(name)).await?;
                 }
@@ -399,28 +1029,59 @@ impl<T> OfferEntry<T> {
         }
     }
 
-    // #[cfg(not(target_family = "wasm"))]
-    // async fn create_symlinks(&self, target_path: &Path) -> std::io::Result<()> {
-    //     #[inline(always)]
-    //     fn recurse<'a, T>(
-    //         this: &'a OfferEntry<T>,
-    //         path: &'a Path,
-    //     ) -> futures::future::LocalBoxFuture<'a, std::io::Result<()>> {
-    //         Box::pin(OfferEntry::create_symlinks(this, path))
-    //     }
-    //     match self {
-    //         Self::Symlink { target } => {
-    //             todo!()
-    //         },
-    //         Self::Directory { content, .. } => {
-    //             for (name, file) in content {
-    //                 recurse(file, &target_path.join(name)).await?;
-    //             }
-    //             Ok(())
-    //         },
-    //         _ => Ok(()),
-    //     }
-    // }
+    fn retain(&mut self, path: &mut Vec<String>, f: &mut impl FnMut(&[String]) -> bool) -> bool {
+        match self {
+            Self::Directory { content, .. } if !content.is_empty() => {
+                content.retain(|name, entry| {
+                    path.push(name.clone());
+                    let keep = entry.retain(path, f);
+                    path.pop();
+                    keep
+                });
+                !content.is_empty()
+            },
+            _ => f(path),
+        }
+    }
+
+    /** Recursively list the metadata of all entries, directories after their content */
+    #[cfg(not(target_family = "wasm"))]
+    fn collect_metadata(&self, path: PathBuf, out: &mut Vec<(PathBuf, Option<u64>, Option<u32>)>) {
+        match self {
+            Self::Directory {
+                mtime,
+                mode,
+                content,
+            } => {
+                for (name, entry) in content {
+                    entry.collect_metadata(path.join(name), out);
+                }
+                out.push((path, *mtime, *mode));
+            },
+            Self::RegularFile { mtime, mode, .. } => out.push((path, *mtime, *mode)),
+            Self::Symlink { .. } => {},
+        }
+    }
+
+    /** Recursively list all symlinks with their targets */
+    fn iter_symlinks(&self) -> impl Iterator<Item = (Vec<String>, &str)> + '_ {
+        match self {
+            Self::Directory { content, .. } => {
+                let iter = content.iter().flat_map(|(name, offer)| {
+                    let name = name.clone();
+                    offer.iter_symlinks().map(move |mut val| {
+                        val.0.insert(0, name.clone());
+                        val
+                    })
+                });
+                Box::new(iter) as Box<dyn Iterator<Item = _>>
+            },
+            Self::RegularFile { .. } => Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _>>,
+            Self::Symlink { target } => {
+                Box::new(std::iter::once((vec![], target.as_str()))) as Box<dyn Iterator<Item = _>>
+            },
+        }
+    }
 
     fn set_content<U>(
         &self,
@@ -428,11 +1089,26 @@ impl<T> OfferEntry<T> {
         f: &mut impl FnMut(&[String]) -> U,
     ) -> OfferEntry<U> {
         match self {
-            OfferEntry::RegularFile { size, .. } => OfferEntry::RegularFile {
+            OfferEntry::RegularFile {
+                size,
+                stream,
+                mtime,
+                mode,
+                ..
+            } => OfferEntry::RegularFile {
                 size: *size,
+                stream: *stream,
+                mtime: *mtime,
+                mode: *mode,
                 content: f(base_path),
             },
-            OfferEntry::Directory { content } => OfferEntry::Directory {
+            OfferEntry::Directory {
+                mtime,
+                mode,
+                content,
+            } => OfferEntry::Directory {
+                mtime: *mtime,
+                mode: *mode,
                 content: content
                     .iter()
                     .map(|(k, v)| {
@@ -443,9 +1119,9 @@ impl<T> OfferEntry<T> {
                     })
                     .collect(),
             },
-            // OfferEntry::Symlink { target } => OfferEntry::Symlink {
-            //     target: target.clone(),
-            // },
+            OfferEntry::Symlink { target } => OfferEntry::Symlink {
+                target: target.clone(),
+            },
         }
     }
 }
@@ -464,13 +1140,13 @@ impl<T: 'static + Send> OfferEntry<T> {
                 });
                 Box::new(iter) as Box<dyn Iterator<Item = _> + Send>
             },
-            Self::RegularFile { content, size } => {
+            Self::RegularFile { content, size, .. } => {
                 Box::new(std::iter::once((vec![], content, size)))
                     as Box<dyn Iterator<Item = _> + Send>
             },
-            // Self::Symlink { .. } => {
-            //     Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _> + Send>
-            // },
+            Self::Symlink { .. } => {
+                Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _> + Send>
+            },
         }
     }
 }
@@ -519,3 +1195,439 @@ pub struct AcceptInner {
     pub sha256: Option<[u8; 32]>,
     pub content: AcceptContent,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[async_std::test]
+    async fn test_accept_all_resumable() {
+        let target_dir = tempfile::tempdir().unwrap();
+        let target_dir = target_dir.path();
+        std::fs::write(target_dir.join("partial.txt"), b"hel").unwrap();
+        std::fs::write(target_dir.join("larger.txt"), b"too large").unwrap();
+        std::fs::write(target_dir.join("complete.txt"), b"done").unwrap();
+
+        let mut content = BTreeMap::new();
+        for (name, size) in [
+            ("partial.txt", 5),
+            ("larger.txt", 3),
+            ("missing.txt", 3),
+            ("complete.txt", 4),
+        ] {
+            content.insert(
+                name.to_owned(),
+                OfferEntry::RegularFile {
+                    size,
+                    stream: false,
+                    mtime: None,
+                    mode: None,
+                    content: (),
+                },
+            );
+        }
+        let offer = Offer { content };
+
+        let answer = offer.accept_all_resumable(&target_dir).await.unwrap();
+        let answer = answer
+            .iter_files()
+            .map(|(path, inner, _)| (path.join("/"), (inner.offset, inner.sha256)))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            answer["partial.txt"],
+            (3, Some(Sha256::digest(b"hel").into()))
+        );
+        assert_eq!(
+            answer["complete.txt"],
+            (4, Some(Sha256::digest(b"done").into()))
+        );
+        assert_eq!(answer["larger.txt"], (0, None));
+        assert_eq!(answer["missing.txt"], (0, None));
+    }
+
+    #[async_std::test]
+    async fn test_accept_all_replacing() {
+        use futures::AsyncWriteExt;
+
+        let target_dir = tempfile::tempdir().unwrap();
+        let target_dir = target_dir.path();
+        std::fs::write(target_dir.join("partial.txt"), b"hel").unwrap();
+        std::fs::write(target_dir.join("broken.txt"), b"old").unwrap();
+
+        let file = |size| OfferEntry::RegularFile {
+            size,
+            stream: false,
+            mtime: None,
+            mode: None,
+            content: (),
+        };
+        let offer = Offer {
+            content: [("partial.txt", 5), ("broken.txt", 3)]
+                .into_iter()
+                .map(|(name, size)| (name.to_owned(), file(size)))
+                .collect(),
+        };
+
+        let mut answer = offer
+            .accept_all_replacing(target_dir)
+            .await
+            .unwrap()
+            .into_iter_files()
+            .map(|(path, inner, _)| (path.join("/"), inner))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(answer["partial.txt"].offset, 3);
+
+        /* The existing content stays in place until the new one is complete */
+        let mut partial = (answer.remove("partial.txt").unwrap().content)(true)
+            .await
+            .unwrap();
+        partial.write_all(b"lo").await.unwrap();
+        assert_eq!(
+            std::fs::read(target_dir.join("partial.txt")).unwrap(),
+            b"hel"
+        );
+        partial.close().await.unwrap();
+        assert_eq!(
+            std::fs::read(target_dir.join("partial.txt")).unwrap(),
+            b"hello"
+        );
+
+        /* An interrupted file leaves the existing one intact */
+        let mut broken = (answer.remove("broken.txt").unwrap().content)(false)
+            .await
+            .unwrap();
+        broken.write_all(b"n").await.unwrap();
+        drop(broken);
+        assert_eq!(
+            std::fs::read(target_dir.join("broken.txt")).unwrap(),
+            b"old"
+        );
+        assert_eq!(std::fs::read_dir(target_dir).unwrap().count(), 2);
+
+        /* Never write through symlinks */
+        #[cfg(unix)]
+        {
+            let outside = tempfile::tempdir().unwrap();
+            std::os::unix::fs::symlink(outside.path(), target_dir.join("dir")).unwrap();
+            let offer = Offer {
+                content: [(
+                    "dir".to_owned(),
+                    OfferEntry::Directory {
+                        mtime: None,
+                        mode: None,
+                        content: [("file.txt".to_owned(), file(1))].into_iter().collect(),
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            };
+            assert!(offer.accept_all_replacing(target_dir).await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_retain() {
+        let file = || OfferEntry::RegularFile {
+            size: 1,
+            stream: false,
+            mtime: None,
+            mode: None,
+            content: (),
+        };
+        let dir = |content: Vec<(&str, OfferEntry)>| OfferEntry::Directory {
+            mtime: None,
+            mode: None,
+            content: content
+                .into_iter()
+                .map(|(name, entry)| (name.to_owned(), entry))
+                .collect(),
+        };
+        let mut offer = Offer {
+            content: [(
+                "project".to_owned(),
+                dir(vec![
+                    ("README", file()),
+                    ("docs", dir(vec![("index.md", file())])),
+                    ("src", dir(vec![("main.rs", file())])),
+                    ("empty", dir(vec![])),
+                ]),
+            )]
+            .into(),
+        };
+
+        offer.retain(|path| path.get(1).is_some_and(|name| name != "src"));
+        assert_eq!(
+            offer
+                .iter_file_paths()
+                .map(|path| path.join("/"))
+                .collect::<Vec<_>>(),
+            ["project/README", "project/docs/index.md"]
+        );
+        assert!(offer.get(&["project".into(), "src".into()]).is_none());
+        assert!(offer.get(&["project".into(), "empty".into()]).is_some());
+
+        offer.retain(|_| false);
+        assert!(offer.top_level_paths().next().is_none());
+    }
+
+    #[async_std::test]
+    async fn test_new_stream() {
+        use futures::{AsyncReadExt, AsyncSeekExt};
+
+        let offer = OfferSend::new_stream("stdin".into(), futures::io::Cursor::new(b"hello"));
+        assert!(matches!(
+            offer.get(&["stdin".into()]),
+            Some(OfferEntry::RegularFile {
+                size: 0,
+                stream: true,
+                ..
+            })
+        ));
+
+        let (content, _) = offer.get_file(&["stdin".into()]).unwrap();
+        let mut reader = content().await.unwrap();
+        assert_eq!(reader.seek(std::io::SeekFrom::Start(0)).await.unwrap(), 0);
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, b"hello");
+        assert!(reader.seek(std::io::SeekFrom::Start(0)).await.is_err());
+
+        /* The stream can only be consumed once */
+        assert!(content().await.is_err());
+    }
+
+    #[test]
+    fn test_is_symlink_inside() {
+        let link = |path: &str| path.split('/').map(ToOwned::to_owned).collect::<Vec<_>>();
+
+        assert!(is_symlink_inside(&link("a"), "b"));
+        assert!(is_symlink_inside(&link("a"), "./b/c"));
+        assert!(is_symlink_inside(&link("a/b/c"), "../../d"));
+        assert!(is_symlink_inside(&link("a/b"), ".."));
+        assert!(!is_symlink_inside(&link("a"), ""));
+        assert!(!is_symlink_inside(&link("a"), "../b"));
+        assert!(!is_symlink_inside(&link("a/b"), "../../b"));
+        assert!(!is_symlink_inside(&link("a"), "/etc/passwd"));
+        /* `b` might be a symlink to `..` itself */
+        assert!(!is_symlink_inside(&link("a/c"), "b/../.."));
+        assert!(!is_symlink_inside(&link("a/b/c"), "d/../e"));
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_offer_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        std::fs::write(dir.join("file.txt"), b"hello").unwrap();
+        std::os::unix::fs::symlink("file.txt", dir.join("link")).unwrap();
+        /* This used to recurse forever */
+        std::os::unix::fs::symlink(".", dir.join("cycle")).unwrap();
+
+        let offer = OfferSend::new_file_or_folder("dir".into(), &dir)
+            .await
+            .unwrap();
+        assert_eq!(offer.iter_file_paths().count(), 1);
+        assert_eq!(
+            offer.iter_symlinks().collect::<Vec<_>>(),
+            [
+                (vec!["dir".to_owned(), "cycle".to_owned()], "."),
+                (vec!["dir".to_owned(), "link".to_owned()], "file.txt"),
+            ]
+        );
+
+        /* Symlinks given directly are followed */
+        let offer = OfferSend::new_file_or_folder("link".into(), dir.join("link"))
+            .await
+            .unwrap();
+        assert_eq!(offer.total_size(), 5);
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_restore_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        std::fs::write(source.join("sub/script.sh"), b"#!/bin/sh").unwrap();
+        std::fs::set_permissions(
+            source.join("sub/script.sh"),
+            std::fs::Permissions::from_mode(0o777),
+        )
+        .unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        std::fs::File::open(source.join("sub/script.sh"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let offer = OfferSend::new_file_or_folder("sub".into(), source.join("sub"))
+            .await
+            .unwrap();
+        match offer.get(&["sub".into(), "script.sh".into()]) {
+            Some(OfferEntry::RegularFile { mtime, mode, .. }) => {
+                assert_eq!(*mtime, Some(1_000_000_000));
+                assert_eq!(*mode, Some(0o777));
+            },
+            _ => panic!("expected a file"),
+        }
+
+        /* Receive it */
+        let offer = Offer::from(&offer);
+        offer.create_directories(&target).await.unwrap();
+        std::fs::write(target.join("sub/script.sh"), b"#!/bin/sh").unwrap();
+        offer.restore_metadata(&target).await.unwrap();
+
+        let metadata = std::fs::metadata(target.join("sub/script.sh")).unwrap();
+        assert_eq!(metadata.modified().unwrap(), mtime);
+        /* Never world-writable */
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+
+        /* But always accessible to the owner */
+        let script = target.join("sub/script.sh");
+        set_metadata(&script, None, Some(0o4444)).unwrap();
+        assert_eq!(
+            std::fs::metadata(&script).unwrap().permissions().mode() & 0o7777,
+            0o644
+        );
+        set_metadata(&target.join("sub"), None, Some(0o000)).unwrap();
+        assert_eq!(
+            std::fs::metadata(target.join("sub"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o700
+        );
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_accept_all_symlinked_file() {
+        let outside = tempfile::tempdir().unwrap();
+        let victim = outside.path().join("victim.txt");
+        std::fs::write(&victim, b"precious").unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let target_dir = target_dir.path();
+        std::os::unix::fs::symlink(&victim, target_dir.join("file.txt")).unwrap();
+
+        let offer = Offer {
+            content: [(
+                "file.txt".to_owned(),
+                OfferEntry::RegularFile {
+                    size: 3,
+                    stream: false,
+                    mtime: None,
+                    mode: None,
+                    content: (),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        };
+        for append in [false, true] {
+            let (_path, inner, _) = offer
+                .accept_all(target_dir)
+                .into_iter_files()
+                .next()
+                .unwrap();
+            assert!((inner.content)(append).await.is_err());
+        }
+        assert_eq!(std::fs::read(&victim).unwrap(), b"precious");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_symlink_chained() {
+        let target_dir = tempfile::tempdir().unwrap();
+        let target_dir = target_dir.path();
+        std::fs::create_dir_all(target_dir.join("dir/a")).unwrap();
+        let path = |path: &str| path.split('/').map(ToOwned::to_owned).collect::<Vec<_>>();
+
+        /* Each of them stays inside on its own, but together they would point outside */
+        for (link, target) in [
+            ("dir/a/l1", ".."),
+            ("dir/a/l1/l2", ".."),
+            ("dir/a/l1/l2/l3", "../.."),
+        ] {
+            create_symlink(target_dir, &path(link), target, SymlinkPolicy::Recreate).unwrap();
+        }
+        assert!(std::fs::symlink_metadata(target_dir.join("dir/a/l1"))
+            .unwrap()
+            .is_symlink());
+        assert!(std::fs::symlink_metadata(target_dir.join("dir/l2")).is_err());
+        assert!(std::fs::symlink_metadata(target_dir.join("l3")).is_err());
+
+        /* Following them must not copy anything from outside either */
+        create_symlink(
+            target_dir,
+            &path("dir/a/l1/copy"),
+            "..",
+            SymlinkPolicy::Follow,
+        )
+        .unwrap();
+        assert!(std::fs::symlink_metadata(target_dir.join("dir/copy")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_symlink() {
+        let target_dir = tempfile::tempdir().unwrap();
+        let target_dir = target_dir.path();
+        std::fs::create_dir_all(target_dir.join("dir/sub")).unwrap();
+        std::fs::write(target_dir.join("dir/file.txt"), b"hello").unwrap();
+        std::fs::write(target_dir.join("dir/sub/other.txt"), b"world").unwrap();
+        let path = |path: &str| path.split('/').map(ToOwned::to_owned).collect::<Vec<_>>();
+
+        create_symlink(
+            &target_dir,
+            &path("dir/link"),
+            "file.txt",
+            SymlinkPolicy::Recreate,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_link(target_dir.join("dir/link")).unwrap(),
+            Path::new("file.txt")
+        );
+
+        create_symlink(
+            &target_dir,
+            &path("dir/skipped"),
+            "file.txt",
+            SymlinkPolicy::Skip,
+        )
+        .unwrap();
+        assert!(std::fs::symlink_metadata(target_dir.join("dir/skipped")).is_err());
+
+        create_symlink(&target_dir, &path("dir/copy"), "sub", SymlinkPolicy::Follow).unwrap();
+        assert!(!std::fs::symlink_metadata(target_dir.join("dir/copy"))
+            .unwrap()
+            .is_symlink());
+        assert_eq!(
+            std::fs::read(target_dir.join("dir/copy/other.txt")).unwrap(),
+            b"world"
+        );
+
+        /* Neither outside of the destination, nor into itself */
+        create_symlink(
+            &target_dir,
+            &path("dir/evil"),
+            "../..",
+            SymlinkPolicy::Recreate,
+        )
+        .unwrap();
+        assert!(std::fs::symlink_metadata(target_dir.join("dir/evil")).is_err());
+        create_symlink(
+            &target_dir,
+            &path("dir/sub/loop"),
+            "..",
+            SymlinkPolicy::Follow,
+        )
+        .unwrap();
+        assert!(std::fs::symlink_metadata(target_dir.join("dir/sub/loop")).is_err());
+    }
+}
@@ -1,11 +1,14 @@
 use futures::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
     StreamExt, TryFutureExt,
 };
 use sha2::{digest::FixedOutput, Digest, Sha256};
 
 use super::{offer::*, *};
 
+#[cfg(not(target_family = "wasm"))]
+mod archive;
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub enum OfferMessage {
@@ -67,6 +70,41 @@ impl TransitAck {
     }
 }
 
+/// The archive format a directory got sent in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ArchiveFormat {
+    /// The `zipfile/deflated` mode of the Python implementation
+    Zip,
+    /// The `tar` mode of this implementation, for peers with the `tar-folder-v1` ability
+    Tar,
+}
+
+impl ArchiveFormat {
+    /// The file extension that gets appended to the directory name
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+        }
+    }
+}
+
+/// Check that the name of the offered directory is a single plain path component
+#[allow(clippy::result_large_err)]
+fn check_dir_name(name: &str) -> Result<(), TransferError> {
+    let mut components = std::path::Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(std::path::Component::Normal(_)), None) => Ok(()),
+        _ => Err(TransferError::UnsafePath(name.into())),
+    }
+}
+
+/// What the other side offered us
+pub(crate) enum Offered<F> {
+    Text(String),
+    File(F),
+}
+
 pub(crate) async fn send(
     wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
@@ -79,6 +117,8 @@ pub(crate) async fn send(
 ) -> Result<(), TransferError> {
     if offer.is_multiple() {
         let folder = OfferSendEntry::Directory {
+            mtime: None,
+            mode: None,
             content: offer.content,
         };
         send_folder(
@@ -108,13 +148,31 @@ pub(crate) async fn send(
     } else {
         let (file_name, file) = offer.content.into_iter().next().unwrap();
         let (mut file, file_size) = match file {
-            OfferSendEntry::RegularFile { content, size } => {
+            OfferSendEntry::RegularFile {
+                content,
+                stream: true,
+                ..
+            } => {
+                /* Transfer v1 needs to announce the size up front, so buffer the stream */
+                let content = content();
+                let mut content = content.await?;
+                let mut buffer = async_std::fs::File::from(tempfile::tempfile()?);
+                let size = futures::io::copy(&mut content, &mut buffer).await?;
+                buffer.seek(std::io::SeekFrom::Start(0)).await?;
+                tracing::debug!("Buffered stream of {size} bytes for transfer-v1");
+                (
+                    Box::new(buffer) as Box<dyn AsyncReadSeek + Unpin + Send>,
+                    size,
+                )
+            },
+            OfferSendEntry::RegularFile { content, size, .. } => {
                 /* This must be split into two statements to appease the borrow checker (unfortunate side effect of borrow-through) */
                 let content = content();
                 let content = content.await?;
                 (content, size)
             },
-            _ => unreachable!(),
+            /* A single symlink has no representation in transfer-v1 */
+            _ => bail!(TransferError::UnsupportedOffer),
         };
         send_file(
             wormhole,
@@ -148,7 +206,13 @@ where
     H: FnMut(u64, u64) + 'static,
 {
     let run = Box::pin(async {
-        let connector = transit::init(transit_abilities, None, relay_hints).await?;
+        let connector = transit::init_with_proxy(
+            transit_abilities,
+            None,
+            relay_hints,
+            wormhole.proxy().cloned(),
+        )
+        .await?;
 
         // We want to do some transit
         tracing::debug!("Sending transit message '{:?}", connector.our_hints());
@@ -230,10 +294,14 @@ where
     cancel::handle_run_result(wormhole, result).await
 }
 
+/** The content of a folder transfer, as a sequence of readers */
+type FolderContent =
+    futures::stream::BoxStream<'static, std::io::Result<Box<dyn AsyncRead + Unpin + Send>>>;
+
 pub(crate) async fn send_folder(
     mut wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
-    mut folder_name: String,
+    folder_name: String,
     folder: OfferSendEntry,
     transit_abilities: transit::Abilities,
     transit_handler: impl FnOnce(transit::TransitInfo),
@@ -241,7 +309,14 @@ pub(crate) async fn send_folder(
     cancel: impl Future<Output = ()>,
 ) -> Result<(), TransferError> {
     let run = Box::pin(async {
-        let connector = transit::init(transit_abilities, None, relay_hints).await?;
+        let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version().clone())?;
+        let connector = transit::init_with_proxy(
+            transit_abilities,
+            None,
+            relay_hints,
+            wormhole.proxy().cloned(),
+        )
+        .await?;
 
         // We want to do some transit
         tracing::debug!("Sending transit message '{:?}", connector.our_hints());
@@ -252,91 +327,42 @@ pub(crate) async fn send_folder(
             ))
             .await?;
 
-        /* We need to know the length of what we are going to send in advance. So we already build
-         * all the headers of our file now but without the contents. We know that a file is
-         * header + contents + padding
+        /* Other implementations only understand zip files, but those must be built in advance,
+         * so they take up as much temporary disk space as the compressed folder.
+         * Our tar files can be streamed.
          */
-        tracing::debug!("Estimating the file size");
-
-        // TODO try again but without pinning
-        use futures::{
-            future::{ready, BoxFuture},
-            io::Cursor,
-        };
-        use std::io::Result as IoResult;
-
-        type WrappedDataFut = BoxFuture<'static, IoResult<Box<dyn AsyncRead + Unpin + Send>>>;
-
-        /* Type tetris :) */
-        fn wrap(buffer: impl AsRef<[u8]> + Unpin + Send + 'static) -> WrappedDataFut {
-            Box::pin(ready(IoResult::Ok(
-                Box::new(Cursor::new(buffer)) as Box<dyn AsyncRead + Unpin + Send>
-            ))) as _
-        }
-
-        /* Walk our offer recursively, concatenate all our readers into a stream that will build the tar file */
-        fn create_offer(
-            mut total_content: Vec<WrappedDataFut>,
-            total_size: &mut u64,
-            offer: OfferSendEntry,
-            path: &mut Vec<String>,
-        ) -> IoResult<Vec<WrappedDataFut>> {
-            match offer {
-                OfferSendEntry::Directory { content } => {
-                    tracing::debug!("Adding directory {path:?}");
-                    let header = tar_helper::create_header_directory(path)?;
-                    *total_size += header.len() as u64;
-                    total_content.push(wrap(header));
-
-                    for (name, file) in content {
-                        path.push(name);
-                        total_content = create_offer(total_content, total_size, file, path)?;
-                        path.pop();
-                    }
-                },
-                OfferSendEntry::RegularFile { size, content } => {
-                    tracing::debug!("Adding file {path:?}; {size} bytes");
-                    let header = tar_helper::create_header_file(path, size)?;
-                    let padding = tar_helper::padding(size);
-                    *total_size += header.len() as u64;
-                    *total_size += padding.len() as u64;
-                    *total_size += size;
-
-                    total_content.push(wrap(header));
-                    let content = content().map_ok(
-                        /* Re-box because we can't upcast trait objects */
-                        |read| Box::new(read) as Box<dyn AsyncRead + Unpin + Send>,
-                    );
-                    total_content.push(Box::pin(content) as _);
-                    total_content.push(wrap(padding));
-                },
-                // OfferSendEntry::Symlink { .. } => todo!(),
+        let (offer, content, total_size) = if !peer_version.supports_tar_folders() {
+            /* We can't build zip files on wasm, send a tar file like older versions did instead */
+            #[cfg(target_family = "wasm")]
+            {
+                let (content, total_size) = create_tar(&folder_name, folder)?;
+                (
+                    PeerMessage::offer_file_v1(format!("{folder_name}.tar"), total_size),
+                    content,
+                    total_size,
+                )
             }
-            Ok(total_content)
-        }
-
-        let mut total_size = 0;
-        let mut content = create_offer(
-            Vec::new(),
-            &mut total_size,
-            folder,
-            &mut vec![folder_name.clone()],
-        )?;
-
-        /* Finish tar file */
-        total_size += 1024;
-        content.push(wrap([0; 1024]));
-
-        let content = futures::stream::iter(content).then(|content| content);
-
-        /* Convert to stream */
+            #[cfg(not(target_family = "wasm"))]
+            {
+                create_zip(folder_name, folder).await?
+            }
+        } else {
+            let (numbytes, numfiles) = folder
+                .iter_files()
+                .fold((0, 0), |(bytes, files), (_, _, size)| {
+                    (bytes + size, files + 1)
+                });
+            let (content, total_size) = create_tar(&folder_name, folder)?;
+            (
+                PeerMessage::offer_directory_v1(folder_name, "tar", total_size, numbytes, numfiles),
+                content,
+                total_size,
+            )
+        };
 
         // Send file offer message.
-        tracing::debug!("Sending file offer ({total_size} bytes)");
-        folder_name.push_str(".tar");
-        wormhole
-            .send_json(&PeerMessage::offer_file_v1(folder_name, total_size))
-            .await?;
+        tracing::debug!("Sending folder offer ({total_size} bytes)");
+        wormhole.send_json(&offer).await?;
 
         // Wait for their transit response
         let (their_abilities, their_hints): (transit::Abilities, transit::Hints) =
@@ -393,6 +419,166 @@ pub(crate) async fn send_folder(
     cancel::handle_run_result(wormhole, result).await
 }
 
+/** Pack the folder into a temporary zip file, returns the offer, its content and its size */
+#[cfg(not(target_family = "wasm"))]
+async fn create_zip(
+    folder_name: String,
+    folder: OfferSendEntry,
+) -> Result<(PeerMessage, FolderContent, u64), TransferError> {
+    tracing::debug!("Packing the folder into a zip file");
+    let zip = archive::create_zip(folder).await?;
+    let offer = PeerMessage::offer_directory_v1(
+        folder_name,
+        "zipfile/deflated",
+        zip.zipsize,
+        zip.numbytes,
+        zip.numfiles,
+    );
+    let file = Box::new(async_std::fs::File::from(zip.file)) as Box<dyn AsyncRead + Unpin + Send>;
+    let content = futures::stream::once(futures::future::ready(Ok(file)));
+    Ok((offer, Box::pin(content), zip.zipsize))
+}
+
+/** Lazily build a tar file of the folder, returns its content and its size */
+fn create_tar(folder_name: &str, folder: OfferSendEntry) -> std::io::Result<(FolderContent, u64)> {
+    /* We need to know the length of what we are going to send in advance. So we already build
+     * all the headers of our file now but without the contents. We know that a file is
+     * header + contents + padding
+     */
+    tracing::debug!("Estimating the file size");
+
+    // TODO try again but without pinning
+    use futures::{
+        future::{ready, BoxFuture},
+        io::Cursor,
+    };
+    use std::io::Result as IoResult;
+
+    type WrappedDataFut = BoxFuture<'static, IoResult<Box<dyn AsyncRead + Unpin + Send>>>;
+
+    /* Type tetris :) */
+    fn wrap(buffer: impl AsRef<[u8]> + Unpin + Send + 'static) -> WrappedDataFut {
+        Box::pin(ready(IoResult::Ok(
+            Box::new(Cursor::new(buffer)) as Box<dyn AsyncRead + Unpin + Send>
+        ))) as _
+    }
+
+    /* Walk our offer recursively, concatenate all our readers into a stream that will build the tar file */
+    fn create_offer(
+        mut total_content: Vec<WrappedDataFut>,
+        total_size: &mut u64,
+        offer: OfferSendEntry,
+        path: &mut Vec<String>,
+    ) -> IoResult<Vec<WrappedDataFut>> {
+        match offer {
+            OfferSendEntry::Directory {
+                mtime,
+                mode,
+                content,
+            } => {
+                tracing::debug!("Adding directory {path:?}");
+                let header = tar_helper::create_header_directory(path, mtime, mode)?;
+                *total_size += header.len() as u64;
+                total_content.push(wrap(header));
+
+                for (name, file) in content {
+                    path.push(name);
+                    total_content = create_offer(total_content, total_size, file, path)?;
+                    path.pop();
+                }
+            },
+            OfferSendEntry::RegularFile {
+                size,
+                mtime,
+                mode,
+                content,
+                ..
+            } => {
+                tracing::debug!("Adding file {path:?}; {size} bytes");
+                let header = tar_helper::create_header_file(path, size, mtime, mode)?;
+                let padding = tar_helper::padding(size);
+                *total_size += header.len() as u64;
+                *total_size += padding.len() as u64;
+                *total_size += size;
+
+                total_content.push(wrap(header));
+                let content = content().map_ok(
+                    /* Re-box because we can't upcast trait objects */
+                    |read| Box::new(read) as Box<dyn AsyncRead + Unpin + Send>,
+                );
+                total_content.push(Box::pin(content) as _);
+                total_content.push(wrap(padding));
+            },
+            OfferSendEntry::Symlink { target } => {
+                tracing::debug!("Adding symlink {path:?} -> {target}");
+                let header = tar_helper::create_header_symlink(path, &target)?;
+                *total_size += header.len() as u64;
+                total_content.push(wrap(header));
+            },
+        }
+        Ok(total_content)
+    }
+
+    let mut total_size = 0;
+    let mut content = create_offer(
+        Vec::new(),
+        &mut total_size,
+        folder,
+        &mut vec![folder_name.to_owned()],
+    )?;
+
+    /* Finish tar file */
+    total_size += 1024;
+    content.push(wrap([0; 1024]));
+
+    /* Convert to stream */
+    let content = futures::stream::iter(content).then(|content| content);
+
+    Ok((Box::pin(content), total_size))
+}
+
+pub(crate) async fn send_text(
+    mut wormhole: Wormhole,
+    text: String,
+    cancel: impl Future<Output = ()>,
+) -> Result<(), TransferError> {
+    let run = Box::pin(async {
+        // Text messages go directly over the wormhole, no transit needed
+        tracing::debug!("Sending text offer");
+        wormhole
+            .send_json(&PeerMessage::offer_message_v1(text))
+            .await?;
+
+        // Wait for message_ack
+        loop {
+            let msg = wormhole.receive_json::<PeerMessage>().await??;
+            tracing::debug!("Received message: {:?}", msg);
+
+            match msg.check_err()? {
+                // The receiver may have sent its transit hints before knowing what we offer
+                PeerMessage::Transit(_) => continue,
+                PeerMessage::Answer(AnswerMessage::MessageAck(msg)) => {
+                    ensure!(msg == "ok", TransferError::AckError);
+                    break;
+                },
+                other => {
+                    bail!(TransferError::unexpected_message(
+                        "answer/message_ack",
+                        other
+                    ));
+                },
+            }
+        }
+        tracing::debug!("Text message acknowledged");
+
+        Ok(())
+    });
+
+    futures::pin_mut!(cancel);
+    let result = cancel::cancellable_2(run, cancel).await;
+    cancel::handle_run_result(wormhole, result).await
+}
+
 /**
  * Wait for a file offer from the other side
  *
@@ -402,14 +588,45 @@ pub(crate) async fn send_folder(
  * Returns `None` if the task got cancelled.
  */
 pub async fn request(
-    mut wormhole: Wormhole,
+    wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     transit_abilities: transit::Abilities,
     cancel: impl Future<Output = ()>,
 ) -> Result<Option<ReceiveRequest>, TransferError> {
+    request_text_or_file(wormhole, relay_hints, transit_abilities, false, cancel)
+        .await
+        .map(|offer| {
+            offer.map(|offer| match offer {
+                Offered::File(request) => request,
+                Offered::Text(_) => unreachable!("text offers are rejected when not accepted"),
+            })
+        })
+}
+
+/**
+ * Wait for a text message or a file offer from the other side
+ *
+ * Text messages are acknowledged right away and the wormhole gets closed. If `accept_text`
+ * is false, they are rejected with [`TransferError::UnsupportedOffer`] instead.
+ *
+ * Returns `None` if the task got cancelled.
+ */
+pub(crate) async fn request_text_or_file(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_abilities: transit::Abilities,
+    accept_text: bool,
+    cancel: impl Future<Output = ()>,
+) -> Result<Option<Offered<ReceiveRequest>>, TransferError> {
     // Error handling
     let run = Box::pin(async {
-        let connector = transit::init(transit_abilities, None, relay_hints).await?;
+        let connector = transit::init_with_proxy(
+            transit_abilities,
+            None,
+            relay_hints,
+            wormhole.proxy().cloned(),
+        )
+        .await?;
 
         // send the transit message
         tracing::debug!("Sending transit message '{:?}", connector.our_hints());
@@ -420,59 +637,96 @@ pub async fn request(
             ))
             .await?;
 
-        // receive transit message
-        let (their_abilities, their_hints): (transit::Abilities, transit::Hints) =
+        // receive transit message. Senders of text messages skip it and directly send the offer
+        let mut their_transit = None;
+        let offer = loop {
             match wormhole.receive_json::<PeerMessage>().await??.check_err()? {
-                PeerMessage::Transit(transit) => {
+                PeerMessage::Transit(transit) if their_transit.is_none() => {
                     tracing::debug!("received transit message: {:?}", transit);
-                    (transit.abilities_v1, transit.hints_v1)
+                    their_transit = Some((transit.abilities_v1, transit.hints_v1));
                 },
+                PeerMessage::Offer(offer) => break offer,
                 other => {
                     bail!(TransferError::unexpected_message("transit", other));
                 },
-            };
+            }
+        };
 
-        // 3. receive file offer message from peer
-        let (filename, filesize) =
-            match wormhole.receive_json::<PeerMessage>().await??.check_err()? {
-                PeerMessage::Offer(offer_type) => match offer_type {
-                    v1::OfferMessage::File { filename, filesize } => (filename, filesize),
-                    v1::OfferMessage::Directory {
-                        mut dirname,
-                        zipsize,
-                        ..
-                    } => {
-                        dirname.push_str(".zip");
-                        (dirname, zipsize)
-                    },
-                    _ => bail!(TransferError::UnsupportedOffer),
-                },
-                other => {
-                    bail!(TransferError::unexpected_message("offer", other));
-                },
-            };
+        // 3. handle the offer message from peer
+        let (filename, filesize, archive_format) = match offer {
+            v1::OfferMessage::Message(text) if accept_text => {
+                tracing::debug!("Received text message, sending ack");
+                wormhole
+                    .send_json(&PeerMessage::message_ack_v1("ok"))
+                    .await?;
+                return Ok(Offered::Text(text));
+            },
+            v1::OfferMessage::File { filename, filesize } => (filename, filesize, None),
+            v1::OfferMessage::Directory {
+                mut dirname,
+                mode,
+                zipsize,
+                ..
+            } => {
+                let archive_format = match mode.as_str() {
+                    "zipfile/deflated" => Some(ArchiveFormat::Zip),
+                    "tar" => Some(ArchiveFormat::Tar),
+                    _ => None,
+                };
+                if archive_format.is_some() {
+                    check_dir_name(&dirname)?;
+                }
+                dirname.push('.');
+                dirname.push_str(archive_format.unwrap_or(ArchiveFormat::Zip).extension());
+                (dirname, zipsize, archive_format)
+            },
+            _ => bail!(TransferError::UnsupportedOffer),
+        };
+        let Some((their_abilities, their_hints)) = their_transit else {
+            bail!(TransferError::unexpected_message("transit", "offer"));
+        };
 
-        Ok((filename, filesize, connector, their_abilities, their_hints))
+        Ok(Offered::File((
+            filename,
+            filesize,
+            archive_format,
+            connector,
+            their_abilities,
+            their_hints,
+        )))
     });
 
     futures::pin_mut!(cancel);
     let result = cancel::cancellable_2(run, cancel).await;
-    cancel::handle_run_result_noclose(wormhole, result)
-        .await
-        .map(|inner: Option<_>| {
-            inner.map(
-                |((filename, filesize, connector, their_abilities, their_hints), wormhole, _)| {
-                    ReceiveRequest::new(
-                        filename,
-                        filesize,
-                        connector,
-                        their_abilities,
-                        their_hints,
-                        wormhole,
-                    )
-                },
-            )
-        })
+    let Some((offer, wormhole, cancel)) =
+        cancel::handle_run_result_noclose(wormhole, result).await?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(match offer {
+        Offered::Text(text) => {
+            // Nothing more to transfer
+            cancel::close_wormhole(wormhole, cancel).await;
+            Offered::Text(text)
+        },
+        Offered::File((
+            filename,
+            filesize,
+            archive_format,
+            connector,
+            their_abilities,
+            their_hints,
+        )) => Offered::File(ReceiveRequest::new(
+            filename,
+            filesize,
+            archive_format,
+            connector,
+            their_abilities,
+            their_hints,
+            wormhole,
+        )),
+    }))
 }
 
 /**
@@ -497,6 +751,8 @@ pub struct ReceiveRequest {
 
     #[allow(dead_code)]
     offer: Arc<Offer>,
+    #[cfg_attr(target_family = "wasm", allow(dead_code))]
+    archive_format: Option<ArchiveFormat>,
     their_abilities: transit::Abilities,
     their_hints: Arc<transit::Hints>,
 }
@@ -506,6 +762,7 @@ impl ReceiveRequest {
     fn new(
         file_name: String,
         filesize: u64,
+        archive_format: Option<ArchiveFormat>,
         connector: TransitConnector,
         their_abilities: transit::Abilities,
         their_hints: transit::Hints,
@@ -519,6 +776,9 @@ impl ReceiveRequest {
             file_name.clone(),
             OfferEntry::RegularFile {
                 size: filesize,
+                stream: false,
+                mtime: None,
+                mode: None,
                 content: (),
             },
         );
@@ -534,6 +794,7 @@ impl ReceiveRequest {
             file_name,
             filesize,
             offer,
+            archive_format,
             their_abilities,
             their_hints,
         }
@@ -545,12 +806,140 @@ impl ReceiveRequest {
      * This will transfer the file and save it on disk.
      */
     pub async fn accept<F, G, W>(
-        mut self,
+        self,
         transit_handler: G,
         progress_handler: F,
         content_handler: &mut W,
         cancel: impl Future<Output = ()>,
     ) -> Result<(), TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+        G: FnOnce(transit::TransitInfo),
+        W: AsyncWrite + Unpin,
+    {
+        self.accept_inner(transit_handler, progress_handler, content_handler, cancel)
+            .await
+            .map(|_completed| ())
+    }
+
+    /**
+     * Accept the directory offer and unpack it into `target_dir`
+     *
+     * The received archive gets unpacked as it streams in, into a temporary directory within `target_dir`
+     * that only gets moved into place once the transfer is complete. Its content is untrusted: entries that
+     * would end up outside of the directory are refused, and so are symlinks pointing outside of it. The other
+     * symlinks are handled according to `symlinks`. With `restore_metadata`, the permissions and modification
+     * times stored in the archive are applied to the unpacked files.
+     *
+     * Fails if `target_dir` already contains an entry named like the offered directory.
+     *
+     * # Panics
+     *
+     * If this is not a directory offer, see [`is_directory`](Self::is_directory).
+     */
+    #[cfg(not(target_family = "wasm"))]
+    #[allow(clippy::result_large_err)]
+    pub async fn accept_directory<F, G>(
+        self,
+        transit_handler: G,
+        progress_handler: F,
+        target_dir: impl AsRef<Path>,
+        symlinks: SymlinkPolicy,
+        restore_metadata: bool,
+        cancel: impl Future<Output = ()>,
+    ) -> Result<(), TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+        G: FnOnce(transit::TransitInfo),
+    {
+        use rand::Rng;
+
+        let archive_format = self
+            .archive_format
+            .expect("accept_directory(..) must only be called on directory offers");
+        let target_dir = target_dir.as_ref();
+        let dir_name = self.directory_name().unwrap();
+        let destination = target_dir.join(&dir_name);
+
+        let tmp_dir = target_dir.join(format!(
+            "wormhole-tmp-{:06}",
+            rand::thread_rng().gen_range(0..1_000_000)
+        ));
+        async_std::fs::create_dir(&tmp_dir).await?;
+        let content_dir = tmp_dir.join("content");
+
+        let (mut writer, mut reader) = archive::pipe();
+        let extraction = async_std::task::spawn_blocking({
+            let content_dir = content_dir.clone();
+            move || {
+                std::fs::create_dir(&content_dir)?;
+                match archive_format {
+                    ArchiveFormat::Zip => {
+                        archive::extract_zip(&mut reader, &content_dir, symlinks, restore_metadata)
+                    },
+                    ArchiveFormat::Tar => archive::extract_tar(
+                        &mut reader,
+                        &dir_name,
+                        &content_dir,
+                        symlinks,
+                        restore_metadata,
+                    ),
+                }?;
+                /* Skip the padding after the end of the archive */
+                std::io::copy(&mut reader, &mut std::io::sink())?;
+                Ok(())
+            }
+        });
+        let received = self
+            .accept_inner(transit_handler, progress_handler, &mut writer, cancel)
+            .await;
+        /* Signal the end of the archive */
+        drop(writer);
+        let extracted = extraction.await;
+
+        let result = match (received, extracted) {
+            (Ok(true), Ok(())) => {
+                /* This suffers some TOCTTOU, but rename would silently replace empty directories */
+                if async_std::path::Path::new(&destination).exists().await {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        format!(
+                            "{} already exists, the received files are in {}",
+                            destination.display(),
+                            content_dir.display()
+                        ),
+                    )
+                    .into());
+                }
+                async_std::fs::rename(&content_dir, &destination)
+                    .await
+                    .map_err(Into::into)
+            },
+            /* Cancelled */
+            (Ok(false), _) => Ok(()),
+            /* The unpacking failed first, which closed the pipe */
+            (Err(TransferError::IO(err)), Err(extract_err))
+                if err.kind() == std::io::ErrorKind::BrokenPipe =>
+            {
+                Err(extract_err)
+            },
+            (Err(err), _) | (Ok(true), Err(err)) => Err(err),
+        };
+
+        if let Err(err) = async_std::fs::remove_dir_all(&tmp_dir).await {
+            tracing::warn!("Failed to delete {}: {}", tmp_dir.display(), err);
+        }
+        result
+    }
+
+    /** Returns whether the transfer completed or got cancelled */
+    async fn accept_inner<F, G, W>(
+        mut self,
+        transit_handler: G,
+        progress_handler: F,
+        content_handler: &mut W,
+        cancel: impl Future<Output = ()>,
+    ) -> Result<bool, TransferError>
     where
         F: FnMut(u64, u64) + 'static,
         G: FnOnce(transit::TransitInfo),
@@ -588,7 +977,13 @@ impl ReceiveRequest {
 
         futures::pin_mut!(cancel);
         let result = cancel::cancellable_2(run, cancel).await;
-        cancel::handle_run_result(self.wormhole, result).await
+        match cancel::handle_run_result_noclose(self.wormhole, result).await? {
+            Some(((), wormhole, cancel)) => {
+                cancel::close_wormhole(wormhole, cancel).await;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
     }
 
     /**
@@ -622,6 +1017,24 @@ impl ReceiveRequest {
     pub fn file_size(&self) -> u64 {
         self.filesize
     }
+
+    /// Whether this is a directory, which can be unpacked using [`accept_directory`](Self::accept_directory)
+    ///
+    /// In that case, [`file_name`](Self::file_name) is the name of the archive it gets sent as.
+    pub fn is_directory(&self) -> bool {
+        self.archive_format.is_some()
+    }
+
+    /// The name of the offered directory, if this is a directory offer.
+    ///
+    /// This is untrusted input, but it is checked to not contain any path separators.
+    pub fn directory_name(&self) -> Option<String> {
+        let extension = self.archive_format?.extension();
+        self.file_name
+            .strip_suffix(extension)
+            .and_then(|name| name.strip_suffix('.'))
+            .map(str::to_owned)
+    }
 }
 
 // encrypt and send the file to tcp stream and return the sha256 sum
@@ -646,12 +1059,12 @@ pub(crate) async fn send_records<'a>(
 
     let mut hasher = Sha256::default();
 
-    let mut plaintext = vec![0u8; 16 * 1024].into_boxed_slice();
+    let mut plaintext = vec![0u8; transit::RECORD_SIZE].into_boxed_slice();
     let mut sent_size = 0;
     futures::pin_mut!(files);
     while let Some(mut file) = files.next().await.transpose()? {
         loop {
-            // read a block of up to 4096 bytes
+            // read a block of up to RECORD_SIZE bytes
             let n = file.read(&mut plaintext[..]).await?;
 
             if n == 0 {
@@ -668,7 +1081,7 @@ pub(crate) async fn send_records<'a>(
             hasher.update(&plaintext[..n]);
 
             /* Don't do this. The EOF check above is sufficient */
-            // if n < 4096 {
+            // if n < RECORD_SIZE {
             //     break;
             // }
         }
@@ -707,12 +1120,12 @@ where
 
     while remaining_size > 0 {
         // 3. decrypt the vector 'enc_packet' with the key.
-        let plaintext = transit.receive_record().await?;
+        let plaintext = transit.receive_record_ref().await?;
 
-        content_handler.write_all(&plaintext).await?;
+        content_handler.write_all(plaintext).await?;
 
         // 4. calculate a rolling sha256 sum of the decrypted output.
-        hasher.update(&plaintext);
+        hasher.update(plaintext);
 
         remaining_size -= plaintext.len();
 
@@ -766,29 +1179,54 @@ mod tar_helper {
         str,
     };
 
-    pub(crate) fn create_header_file(path: &[String], size: u64) -> std::io::Result<Vec<u8>> {
+    pub(crate) fn create_header_file(
+        path: &[String],
+        size: u64,
+        mtime: Option<u64>,
+        mode: Option<u32>,
+    ) -> std::io::Result<Vec<u8>> {
         let mut header = tar::Header::new_gnu();
         header.set_size(size);
         let mut data = Vec::with_capacity(1024);
         prepare_header_path(&mut data, &mut header, path.join("/").as_ref())?;
-        header.set_mode(0o644);
+        header.set_mode(mode.unwrap_or(0o644));
+        header.set_mtime(mtime.unwrap_or(0));
         header.set_cksum();
         data.write_all(header.as_bytes())?;
         Ok(data)
     }
 
-    pub(crate) fn create_header_directory(path: &[String]) -> std::io::Result<Vec<u8>> {
+    pub(crate) fn create_header_directory(
+        path: &[String],
+        mtime: Option<u64>,
+        mode: Option<u32>,
+    ) -> std::io::Result<Vec<u8>> {
         let mut header = tar::Header::new_gnu();
         header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
         let mut data = Vec::with_capacity(1024);
         prepare_header_path(&mut data, &mut header, path.join("/").as_ref())?;
-        header.set_mode(0o755);
+        header.set_mode(mode.unwrap_or(0o755));
+        header.set_mtime(mtime.unwrap_or(0));
         header.set_cksum();
         data.write_all(header.as_bytes())?;
         // append(&mut data, header, data)?;
         Ok(data)
     }
 
+    pub(crate) fn create_header_symlink(path: &[String], target: &str) -> std::io::Result<Vec<u8>> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        let mut data = Vec::with_capacity(1024);
+        prepare_header_path(&mut data, &mut header, path.join("/").as_ref())?;
+        prepare_header_link(&mut data, &mut header, target)?;
+        header.set_mode(0o777);
+        header.set_cksum();
+        data.write_all(header.as_bytes())?;
+        Ok(data)
+    }
+
     pub(crate) fn padding(size: u64) -> &'static [u8] {
         const BLOCK: [u8; 512] = [0; 512];
         if size % 512 != 0 {
@@ -860,6 +1298,24 @@ mod tar_helper {
         Ok(())
     }
 
+    fn prepare_header_link(
+        dst: &mut dyn std::io::Write,
+        header: &mut tar::Header,
+        link_name: &str,
+    ) -> std::io::Result<()> {
+        // Same as `prepare_header_path`, but with the GNU-specific long link name extension
+        if let Err(e) = header.set_link_name(link_name) {
+            let data = path2bytes(link_name);
+            if data.len() < header.as_old().linkname.len() {
+                return Err(e);
+            }
+            let header2 = prepare_header(data.len() as u64, b'K');
+            let mut data2 = data.chain(io::repeat(0).take(1));
+            append(dst, &header2, &mut data2)?;
+        }
+        Ok(())
+    }
+
     #[cfg(any(windows, target_arch = "wasm32"))]
     pub(crate) fn path2bytes(p: &str) -> Cow<[u8]> {
         let bytes = p.as_bytes();
@@ -887,6 +1343,61 @@ mod tar_helper {
 mod test {
     use super::*;
 
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_create_tar_symlink() {
+        use futures::TryStreamExt;
+
+        let long_target = "a/".repeat(100) + "file";
+        let folder = OfferSendEntry::Directory {
+            mtime: None,
+            mode: None,
+            content: [
+                (
+                    "link".to_owned(),
+                    OfferSendEntry::Symlink {
+                        target: "file".into(),
+                    },
+                ),
+                (
+                    "long".to_owned(),
+                    OfferSendEntry::Symlink {
+                        target: long_target.clone(),
+                    },
+                ),
+            ]
+            .into(),
+        };
+
+        let (content, size) = create_tar("folder", folder).unwrap();
+        let mut tar = Vec::new();
+        for mut read in content.try_collect::<Vec<_>>().await.unwrap() {
+            read.read_to_end(&mut tar).await.unwrap();
+        }
+        assert_eq!(tar.len() as u64, size);
+
+        let mut archive = tar::Archive::new(&tar[..]);
+        let links = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.header().entry_type().is_symlink())
+            .map(|entry| {
+                (
+                    entry.path().unwrap().display().to_string(),
+                    entry.link_name().unwrap().unwrap().display().to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            [
+                ("folder/link".to_owned(), "file".to_owned()),
+                ("folder/long".to_owned(), long_target)
+            ]
+        );
+    }
+
     #[test]
     fn test_transit_ack() {
         let f1 = TransitAck::new("ok", "deadbeaf");
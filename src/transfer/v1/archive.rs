@@ -0,0 +1,785 @@
+//! Packing and unpacking of directories sent as archive
+//!
+//! Unpacking operates on untrusted input. Entries are only ever created inside of the
+//! (freshly created) target directory: absolute paths and `..` components are refused, and symlinks
+//! are only created once everything else is unpacked, if they point within the target directory.
+//! This way, no entry can be used to escape the target directory.
+
+#![allow(clippy::result_large_err)]
+
+use super::{
+    check_no_symlinks, create_symlink, set_metadata, OfferSendEntry, SymlinkPolicy, TransferError,
+};
+use futures::{io::AsyncReadExt, StreamExt};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Seek, Write},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Create a pipe to feed the received archive into the (blocking) unpacking code as it streams in
+pub(crate) fn pipe() -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = futures::channel::mpsc::channel(16);
+    (
+        PipeWriter(sender),
+        PipeReader {
+            receiver,
+            buffer: Vec::new(),
+            pos: 0,
+        },
+    )
+}
+
+/// The writing end of a [`pipe`], fails with [`io::ErrorKind::BrokenPipe`] once the reader is gone
+pub(crate) struct PipeWriter(futures::channel::mpsc::Sender<Vec<u8>>);
+
+impl futures::io::AsyncWrite for PipeWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let broken_pipe = |_| io::Error::from(io::ErrorKind::BrokenPipe);
+        futures::ready!(self.0.poll_ready(cx)).map_err(broken_pipe)?;
+        self.0.start_send(buf.to_vec()).map_err(broken_pipe)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The reading end of a [`pipe`], blocks until more data arrives
+pub(crate) struct PipeReader {
+    receiver: futures::channel::mpsc::Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buffer.len() {
+            match futures::executor::block_on(self.receiver.next()) {
+                Some(buffer) => {
+                    self.buffer = buffer;
+                    self.pos = 0;
+                },
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// A directory packed into a temporary zip file
+pub(crate) struct ZipFolder {
+    /// The zip file, positioned at its start. It gets deleted once closed.
+    pub file: fs::File,
+    /// The size of the zip file
+    pub zipsize: u64,
+    /// The total size of the uncompressed files
+    pub numbytes: u64,
+    /// The number of files
+    pub numfiles: u64,
+}
+
+/// Pack a directory into a zip file, as expected by the `zipfile/deflated` mode
+///
+/// Paths in the zip file are relative to the directory itself. The offer needs to know the size of
+/// the zip file, so it is built completely before it can be streamed to the peer. This happens on a
+/// blocking thread, as the `zip` crate only does synchronous I/O.
+pub(crate) async fn create_zip(folder: OfferSendEntry) -> Result<ZipFolder, TransferError> {
+    async_std::task::spawn_blocking(move || build_zip(folder)).await
+}
+
+/// Convert a modification time to the (time zone less) format of zip files, in UTC
+fn zip_time(mtime: Option<u64>) -> Option<zip::DateTime> {
+    let time = time::OffsetDateTime::from_unix_timestamp(mtime?.try_into().ok()?).ok()?;
+    zip::DateTime::from_date_and_time(
+        time.year().try_into().ok()?,
+        time.month().into(),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+    )
+    .ok()
+}
+
+/// The blocking part of [`create_zip`]
+fn build_zip(folder: OfferSendEntry) -> Result<ZipFolder, TransferError> {
+    let mut zip = zip::ZipWriter::new(tempfile::tempfile()?);
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    let mut numbytes = 0;
+    let mut numfiles = 0;
+    let mut buffer = vec![0u8; 16 * 1024];
+
+    /* Walk the offer depth-first, in order */
+    let mut stack = vec![(String::new(), folder)];
+    while let Some((path, entry)) = stack.pop() {
+        match entry {
+            OfferSendEntry::Directory {
+                mtime,
+                mode,
+                content,
+            } => {
+                if !path.is_empty() {
+                    tracing::trace!("Adding directory {path}");
+                    zip.add_directory(
+                        path.as_str(),
+                        options
+                            .unix_permissions(mode.unwrap_or(0o755))
+                            .last_modified_time(zip_time(mtime).unwrap_or_default()),
+                    )
+                    .map_err(io::Error::from)?;
+                }
+                for (name, entry) in content.into_iter().rev() {
+                    let path = if path.is_empty() {
+                        name
+                    } else {
+                        format!("{path}/{name}")
+                    };
+                    stack.push((path, entry));
+                }
+            },
+            OfferSendEntry::RegularFile {
+                size,
+                mtime,
+                mode,
+                content,
+                ..
+            } => {
+                tracing::trace!("Adding file {path}; {size} bytes");
+                zip.start_file(
+                    path.as_str(),
+                    options
+                        .unix_permissions(mode.unwrap_or(0o644))
+                        .last_modified_time(zip_time(mtime).unwrap_or_default())
+                        .large_file(size >= u32::MAX as u64),
+                )
+                .map_err(io::Error::from)?;
+
+                let mut content = async_std::task::block_on(content())?;
+                let mut written = 0;
+                loop {
+                    let n = async_std::task::block_on(content.read(&mut buffer))?;
+                    if n == 0 {
+                        break;
+                    }
+                    zip.write_all(&buffer[..n])?;
+                    written += n as u64;
+                }
+                ensure!(written == size, TransferError::FilesystemSkew);
+
+                numbytes += size;
+                numfiles += 1;
+            },
+            OfferSendEntry::Symlink { target } => {
+                /* Other implementations unpack these as regular files containing the target */
+                tracing::warn!(
+                    "Skipping symlink {path} -> {target}, the receiver does not support symlinks"
+                );
+            },
+        }
+    }
+
+    let mut file = zip.finish().map_err(io::Error::from)?;
+    let zipsize = file.stream_position()?;
+    file.rewind()?;
+
+    Ok(ZipFolder {
+        file,
+        zipsize,
+        numbytes,
+        numfiles,
+    })
+}
+
+/// Convert an archive entry path into a relative path, refusing everything that could escape the target
+fn sanitize_path(path: &Path) -> Result<PathBuf, TransferError> {
+    let mut sanitized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(component) => sanitized.push(component),
+            Component::CurDir => {},
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                bail!(TransferError::UnsafePath(path.display().to_string()))
+            },
+        }
+    }
+    Ok(sanitized)
+}
+
+/// Split a sanitized path into its components, as used in offers
+fn path_components(path: &Path) -> Result<Vec<String>, TransferError> {
+    path.iter()
+        .map(|component| {
+            component
+                .to_str()
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| TransferError::UnsafePath(path.display().to_string()))
+        })
+        .collect()
+}
+
+/// Create the collected symlinks, once all other entries have been unpacked
+fn create_symlinks(
+    target: &Path,
+    symlinks: Vec<(PathBuf, String)>,
+    policy: SymlinkPolicy,
+) -> Result<(), TransferError> {
+    for (path, link_target) in symlinks {
+        create_symlink(target, &path_components(&path)?, &link_target, policy)?;
+    }
+    Ok(())
+}
+
+/// Restore the collected metadata, once everything else has been unpacked
+///
+/// Archives list directories before their content, so going backwards sets the directories last.
+fn restore_metadata(
+    target: &Path,
+    metadata: Vec<(PathBuf, Option<u64>, Option<u32>)>,
+) -> Result<(), TransferError> {
+    for (path, mtime, mode) in metadata.into_iter().rev() {
+        set_metadata(&target.join(path), mtime, mode)?;
+    }
+    Ok(())
+}
+
+fn create_file(target: &Path, path: &Path, mut content: impl Read) -> io::Result<()> {
+    check_no_symlinks(target, path.parent().unwrap_or(Path::new("")))?;
+    let path = target.join(path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    io::copy(&mut content, &mut file)?;
+    Ok(())
+}
+
+/// Read the next signature of a zip file
+fn read_signature(archive: &mut impl Read) -> io::Result<[u8; 4]> {
+    let mut signature = [0; 4];
+    archive.read_exact(&mut signature)?;
+    Ok(signature)
+}
+
+/// Read the Unix permissions of the entries from the central directory of a zip file
+///
+/// The local headers in front of each entry lack them. `signature` is the already read signature
+/// of the first central directory header.
+fn read_central_directory(
+    archive: &mut impl Read,
+    mut signature: [u8; 4],
+) -> io::Result<HashMap<String, u32>> {
+    const UNIX: u8 = 3;
+    let mut modes = HashMap::new();
+    while &signature == b"PK\x01\x02" {
+        /* The fixed-size part of the central directory header, without its signature */
+        let mut header = [0; 42];
+        archive.read_exact(&mut header)?;
+        let u16_at = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let name_len = u16_at(24) as usize;
+        let extra_len = u16_at(26) as u64 + u16_at(28) as u64;
+        let external_attributes =
+            u32::from_le_bytes([header[34], header[35], header[36], header[37]]);
+
+        let mut name = vec![0; name_len];
+        archive.read_exact(&mut name)?;
+        io::copy(&mut archive.take(extra_len), &mut io::sink())?;
+        if header[1] == UNIX && external_attributes >> 16 != 0 {
+            modes.insert(
+                String::from_utf8_lossy(&name).into_owned(),
+                external_attributes >> 16,
+            );
+        }
+        signature = read_signature(archive)?;
+    }
+    Ok(modes)
+}
+
+/// Unpack a zip file into `target`, which must be a newly created directory
+///
+/// The entries get unpacked as they stream in, the central directory at the end of the file is only
+/// consulted for the permissions and to find symlinks. Those are unpacked as files containing the link
+/// target at first, like other implementations do, and get replaced at the end. Only the permissions get
+/// restored, as the modification times in zip files lack a time zone.
+pub(crate) fn extract_zip(
+    mut archive: impl Read,
+    target: &Path,
+    symlinks: SymlinkPolicy,
+    with_metadata: bool,
+) -> Result<(), TransferError> {
+    /* Every entry, by its name in the archive */
+    let mut entries = Vec::new();
+    let signature = loop {
+        let signature = read_signature(&mut archive)?;
+        if &signature != b"PK\x03\x04" {
+            break signature;
+        }
+        let mut archive = Read::chain(&signature[..], &mut archive);
+        let mut entry = zip::read::read_zipfile_from_stream(&mut archive)
+            .map_err(io::Error::from)?
+            .expect("Signature was already checked");
+        let path = entry
+            .enclosed_name()
+            .ok_or_else(|| TransferError::UnsafePath(entry.name().into()))?;
+        let path = sanitize_path(&path)?;
+
+        if path.as_os_str().is_empty() {
+            continue;
+        } else if entry.is_dir() {
+            fs::create_dir_all(target.join(&path))?;
+        } else {
+            create_file(target, &path, &mut entry)?;
+        }
+        entries.push((entry.name().to_owned(), path));
+    };
+    let modes = read_central_directory(&mut archive, signature)?;
+    /* Consume the end of central directory record, so that the sender doesn't see a closed pipe */
+    io::copy(&mut archive, &mut io::sink())?;
+
+    let mut links = Vec::new();
+    let mut metadata = Vec::new();
+    for (name, path) in entries {
+        let mode = modes.get(&name).copied();
+        if mode.is_some_and(|mode| mode & 0o170000 == 0o120000) {
+            let link_target = fs::read_to_string(target.join(&path))?;
+            fs::remove_file(target.join(&path))?;
+            links.push((path, link_target));
+        } else {
+            metadata.push((path, None, mode.map(|mode| mode & 0o7777)));
+        }
+    }
+    create_symlinks(target, links, symlinks)?;
+    if with_metadata {
+        restore_metadata(target, metadata)?;
+    }
+    Ok(())
+}
+
+/// Older versions of this implementation wrote directory headers without a size, which the `tar` crate
+/// refuses to read. This fills them in on the fly.
+struct FixEmptySizes<R> {
+    inner: R,
+    block: [u8; 512],
+    /// How much of `block` has already been read by the caller
+    pos: usize,
+    /// How much of `block` is valid
+    len: usize,
+    /// How many blocks of entry data follow before the next header
+    data_blocks: u64,
+}
+
+impl<R: Read> FixEmptySizes<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            block: [0; 512],
+            pos: 0,
+            len: 0,
+            data_blocks: 0,
+        }
+    }
+
+    fn fill_block(&mut self) -> io::Result<()> {
+        self.pos = 0;
+        self.len = 0;
+        while self.len < self.block.len() {
+            match self.inner.read(&mut self.block[self.len..])? {
+                0 => return Ok(()),
+                n => self.len += n,
+            }
+        }
+
+        if self.data_blocks > 0 {
+            self.data_blocks -= 1;
+        } else if self.block.iter().any(|b| *b != 0) {
+            let mut header = tar::Header::new_old();
+            header.as_mut_bytes().copy_from_slice(&self.block);
+            if header.size().is_err() && header.as_old().size.iter().all(|b| matches!(b, 0 | b' '))
+            {
+                header.set_size(0);
+                header.set_cksum();
+                self.block.copy_from_slice(header.as_bytes());
+            }
+            self.data_blocks = header.entry_size().unwrap_or(0).div_ceil(512);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for FixEmptySizes<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.len {
+            self.fill_block()?;
+        }
+        let n = buf.len().min(self.len - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Unpack a tar file into `target`, which must be a newly created directory
+///
+/// The tar files contain the directory itself, so a leading `dir_name` component gets stripped.
+pub(crate) fn extract_tar(
+    archive: impl Read,
+    dir_name: &str,
+    target: &Path,
+    symlinks: SymlinkPolicy,
+    with_metadata: bool,
+) -> Result<(), TransferError> {
+    let mut archive = tar::Archive::new(FixEmptySizes::new(archive));
+    let mut links = Vec::new();
+    let mut metadata = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let raw_path = entry.path()?.into_owned();
+        let path = sanitize_path(&raw_path)?;
+        let path = path.strip_prefix(dir_name).unwrap_or(&path);
+
+        let entry_type = entry.header().entry_type();
+        /* Older versions of this implementation did not set any modification time */
+        let mtime = entry.header().mtime().ok().filter(|mtime| *mtime != 0);
+        let mode = entry.header().mode().ok();
+        if path.as_os_str().is_empty() {
+            continue;
+        } else if entry_type.is_dir() {
+            fs::create_dir_all(target.join(path))?;
+            metadata.push((path.to_owned(), mtime, mode));
+        } else if entry_type.is_file() {
+            create_file(target, path, entry)?;
+            metadata.push((path.to_owned(), mtime, mode));
+        } else if entry_type.is_symlink() {
+            let link_target = entry
+                .link_name()?
+                .ok_or_else(|| TransferError::UnsafePath(raw_path.display().to_string()))?;
+            let link_target = link_target
+                .to_str()
+                .ok_or_else(|| TransferError::UnsafePath(link_target.display().to_string()))?;
+            links.push((path.to_owned(), link_target.to_owned()));
+        } else {
+            tracing::warn!(
+                "Skipping unsupported entry {} ({:?}) in received archive",
+                raw_path.display(),
+                entry_type
+            );
+        }
+    }
+    create_symlinks(target, links, symlinks)?;
+    if with_metadata {
+        restore_metadata(target, metadata)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_check_dir_name() {
+        use super::super::check_dir_name;
+
+        assert!(check_dir_name("folder").is_ok());
+        assert!(check_dir_name("..").is_err());
+        assert!(check_dir_name(".").is_err());
+        assert!(check_dir_name("").is_err());
+        assert!(check_dir_name("/etc").is_err());
+        assert!(check_dir_name("a/b").is_err());
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!(
+            sanitize_path(Path::new("./a/b")).unwrap(),
+            PathBuf::from("a/b")
+        );
+        assert!(sanitize_path(Path::new("a/../../b")).is_err());
+        assert!(sanitize_path(Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn test_extract_zip() {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        zip.add_directory("empty/", options).unwrap();
+        zip.start_file("sub/file.txt", options).unwrap();
+        zip.write_all(b"hello").unwrap();
+        let zip = zip.finish().unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        extract_zip(
+            Cursor::new(zip.into_inner()),
+            &target,
+            SymlinkPolicy::Recreate,
+            true,
+        )
+        .unwrap();
+        assert!(target.join("empty").is_dir());
+        assert_eq!(fs::read(target.join("sub/file.txt")).unwrap(), b"hello");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_zip_stream() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        zip.start_file("script.sh", options.unix_permissions(0o755))
+            .unwrap();
+        zip.write_all(b"#!/bin/sh").unwrap();
+        zip.add_symlink("link", "script.sh", options).unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+
+        /* Data arrives in small chunks, without seeking */
+        let (mut writer, reader) = pipe();
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        let extraction = std::thread::spawn({
+            let target = target.to_owned();
+            move || extract_zip(reader, &target, SymlinkPolicy::Recreate, true)
+        });
+        async_std::task::block_on(async {
+            use futures::io::AsyncWriteExt;
+            for chunk in zip.chunks(7) {
+                writer.write_all(chunk).await.unwrap();
+            }
+        });
+        drop(writer);
+        extraction.join().unwrap().unwrap();
+
+        let metadata = fs::metadata(target.join("script.sh")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        assert_eq!(
+            fs::read_link(target.join("link")).unwrap(),
+            Path::new("script.sh")
+        );
+    }
+
+    #[async_std::test]
+    async fn test_zip_roundtrip() {
+        use crate::transfer::offer::{new_offer_content, OfferSendEntry};
+
+        fn file(content: &'static [u8]) -> OfferSendEntry {
+            OfferSendEntry::RegularFile {
+                mtime: Some(1_000_000_000),
+                mode: None,
+                size: content.len() as u64,
+                stream: false,
+                content: new_offer_content(move || {
+                    futures::future::ready(Ok(futures::io::Cursor::new(content)))
+                }),
+            }
+        }
+
+        let folder = OfferSendEntry::Directory {
+            mtime: None,
+            mode: None,
+            content: [
+                ("a.txt".to_owned(), file(b"hello")),
+                (
+                    "sub".to_owned(),
+                    OfferSendEntry::Directory {
+                        mtime: None,
+                        mode: None,
+                        content: [("b.txt".to_owned(), file(b"world!"))].into(),
+                    },
+                ),
+                (
+                    "empty".to_owned(),
+                    OfferSendEntry::Directory {
+                        mtime: None,
+                        mode: None,
+                        content: Default::default(),
+                    },
+                ),
+            ]
+            .into(),
+        };
+
+        let zip = create_zip(folder).await.unwrap();
+        assert_eq!(zip.numfiles, 2);
+        assert_eq!(zip.numbytes, 11);
+        assert_eq!(zip.zipsize, zip.file.metadata().unwrap().len());
+        let mut archive = zip::ZipArchive::new(zip.file.try_clone().unwrap()).unwrap();
+        assert_eq!(
+            archive.by_name("a.txt").unwrap().last_modified(),
+            zip::DateTime::from_date_and_time(2001, 9, 9, 1, 46, 40).ok()
+        );
+        drop(archive);
+        zip.file.try_clone().unwrap().rewind().unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        extract_zip(
+            io::BufReader::new(zip.file),
+            &target,
+            SymlinkPolicy::Recreate,
+            true,
+        )
+        .unwrap();
+        assert_eq!(fs::read(target.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(target.join("sub/b.txt")).unwrap(), b"world!");
+        assert!(target.join("empty").is_dir());
+    }
+
+    #[test]
+    fn test_extract_zip_traversal() {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("../evil.txt", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"evil").unwrap();
+        let zip = zip.finish().unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        assert!(matches!(
+            extract_zip(
+                Cursor::new(zip.into_inner()),
+                &target.join("inner"),
+                SymlinkPolicy::Recreate,
+                true
+            ),
+            Err(TransferError::UnsafePath(_))
+        ));
+        assert!(!target.join("evil.txt").exists());
+    }
+
+    #[test]
+    fn test_extract_tar() {
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_cksum();
+        tar.append_data(&mut header, "dir/sub/file.txt", &b"hello"[..])
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        tar.append_link(&mut header, "dir/link", "/etc/passwd")
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        tar.append_link(&mut header, "dir/sub/inside", "file.txt")
+            .unwrap();
+        let tar = tar.into_inner().unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        extract_tar(&tar[..], "dir", &target, SymlinkPolicy::Recreate, true).unwrap();
+        assert_eq!(fs::read(target.join("sub/file.txt")).unwrap(), b"hello");
+        assert!(fs::symlink_metadata(target.join("link")).is_err());
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_link(target.join("sub/inside")).unwrap(),
+            Path::new("file.txt")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_tar_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut tar = tar::Builder::new(Vec::new());
+        for (path, mode, mtime) in [
+            ("dir/script.sh", 0o755, 1_000_000_000),
+            ("dir/old.txt", 0o600, 0),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(2);
+            header.set_mode(mode);
+            header.set_mtime(mtime);
+            header.set_cksum();
+            tar.append_data(&mut header, path, &b"hi"[..]).unwrap();
+        }
+        let tar = tar.into_inner().unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        extract_tar(&tar[..], "dir", &target, SymlinkPolicy::Recreate, true).unwrap();
+        let metadata = fs::metadata(target.join("script.sh")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        assert_eq!(
+            metadata.modified().unwrap(),
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000)
+        );
+        /* No modification time in the archive */
+        let metadata = fs::metadata(target.join("old.txt")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_ne!(metadata.modified().unwrap(), std::time::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn test_extract_tar_empty_size() {
+        /* Like the directory headers written by older versions */
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_path("dir/sub").unwrap();
+        header.as_gnu_mut().unwrap().size = [0; 12];
+        header.set_cksum();
+        let mut tar = tar::Builder::new(header.as_bytes().to_vec());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_cksum();
+        tar.append_data(&mut header, "dir/sub/file.txt", &b"hello"[..])
+            .unwrap();
+        let tar = tar.into_inner().unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        extract_tar(&tar[..], "dir", &target, SymlinkPolicy::Recreate, true).unwrap();
+        assert_eq!(fs::read(target.join("sub/file.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_extract_tar_traversal() {
+        /* The tar builder refuses to write such paths, so patch the header manually */
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..13].copy_from_slice(b"../evil.txt\0\0");
+        header.set_size(4);
+        header.set_cksum();
+        let mut tar = header.as_bytes().to_vec();
+        tar.extend_from_slice(b"evil");
+        tar.resize(tar.len() + 508 + 1024, 0);
+
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        assert!(matches!(
+            extract_tar(
+                &tar[..],
+                "inner",
+                &target.join("inner"),
+                SymlinkPolicy::Recreate,
+                true
+            ),
+            Err(TransferError::UnsafePath(_))
+        ));
+        assert!(!target.join("evil.txt").exists());
+    }
+}
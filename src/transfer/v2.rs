@@ -103,17 +103,25 @@ async fn make_transit(
     relay_hints: Vec<transit::RelayHint>,
     transit_abilities: transit::Abilities,
     peer_abilities: transit::Abilities,
+    their_hints: Option<transit::Hints>,
 ) -> Result<(transit::Transit, transit::TransitInfo), TransferError> {
-    let connector = transit::init(transit_abilities, Some(peer_abilities), relay_hints).await?;
+    let connector = transit::init_with_proxy(
+        transit_abilities,
+        Some(peer_abilities),
+        relay_hints,
+        wormhole.proxy().cloned(),
+    )
+    .await?;
 
     /* Send our transit hints */
     wormhole
         .send_json(&PeerMessage::transit_v2((**connector.our_hints()).clone()))
         .await?;
 
-    /* Receive their transit hints */
-    let their_hints: transit::Hints =
-        match wormhole.receive_json::<PeerMessage>().await??.check_err()? {
+    /* Receive their transit hints, unless that already happened */
+    let their_hints: transit::Hints = match their_hints {
+        Some(their_hints) => their_hints,
+        None => match wormhole.receive_json::<PeerMessage>().await??.check_err()? {
             PeerMessage::TransitV2(transit) => {
                 tracing::debug!("received transit message: {:?}", transit);
                 transit.hints_v2
@@ -125,7 +133,8 @@ async fn make_transit(
                     .await;
                 bail!(error)
             },
-        };
+        },
+    };
 
     /* Get a transit connection */
     let (transit, info) = match connector
@@ -172,6 +181,7 @@ pub async fn send(
                 relay_hints,
                 transit_abilities,
                 peer_abilities.transit_abilities,
+                None,
             )
             .await?
             .0)
@@ -220,18 +230,28 @@ async fn send_inner(
 
     let mut total_size = 0;
     for file in &files {
-        if let Some((_, size)) = offer.get_file(&file.file) {
-            total_size += size;
-        } else {
-            bail!(TransferError::Protocol(
-                format!("Invalid file request: {}", file.file.join("/")).into()
-            ));
+        match offer.get_file(&file.file) {
+            Some((_, size)) if file.offset <= size => total_size += size,
+            Some((_, size)) => {
+                bail!(TransferError::Protocol(
+                    format!(
+                        "Invalid file request: offset {} is beyond the end of {} ({size} bytes)",
+                        file.offset,
+                        file.file.join("/")
+                    )
+                    .into()
+                ));
+            },
+            None => {
+                bail!(TransferError::Protocol(
+                    format!("Invalid file request: {}", file.file.join("/")).into()
+                ));
+            },
         }
     }
     let mut total_sent = 0;
 
-    const BUFFER_LEN: usize = 16 * 1024;
-    let mut buffer = vec![0u8; BUFFER_LEN].into_boxed_slice();
+    let mut buffer = vec![0u8; transit::RECORD_SIZE].into_boxed_slice();
 
     for AnswerMessageInner {
         file,
@@ -239,59 +259,56 @@ async fn send_inner(
         sha256,
     } in &files
     {
-        let offset = *offset;
         /* This must be split into two statements to appease the borrow checker (unfortunate side effect of borrow-through) */
         let content = (offer.get_file(file).unwrap().0)();
         let mut content = content.await?;
         let file = file.clone();
+        let is_stream = matches!(
+            offer.get(&file),
+            Some(OfferEntry::RegularFile { stream: true, .. })
+        );
 
         /* If they specified a hash, check our local file's contents */
-        if let Some(sha256) = sha256 {
-            content.seek(std::io::SeekFrom::Start(offset)).await?;
-            let mut hasher = Sha256::default();
-            futures::io::copy(
-                (&mut content).take(offset),
-                &mut futures::io::AllowStdIo::new(&mut hasher),
-            )
-            .await?;
-            let our_hash = hasher.finalize_fixed();
-
-            /* If it doesn't match, start at 0 instead of the originally requested offset */
-            if *our_hash == sha256[..] {
-                transit
-                    .send_record(
-                        &PeerMessageV2::FileStart(FileStart {
-                            file,
-                            start_at_offset: true,
-                        })
-                        .ser_msgpack(),
-                    )
-                    .await?;
-            } else {
-                transit
-                    .send_record(
-                        &PeerMessageV2::FileStart(FileStart {
-                            file,
-                            start_at_offset: false,
-                        })
-                        .ser_msgpack(),
-                    )
-                    .await?;
-                content.seek(std::io::SeekFrom::Start(0)).await?;
-                // offset = 0; TODO
-            }
-        } else {
-            content.seek(std::io::SeekFrom::Start(offset)).await?;
-            transit
-                .send_record(
-                    &PeerMessageV2::FileStart(FileStart {
-                        file,
-                        start_at_offset: true,
-                    })
-                    .ser_msgpack(),
+        let start_at_offset = match sha256 {
+            /* Streams can only be sent from the start */
+            _ if is_stream => *offset == 0,
+            Some(sha256) => {
+                let mut hasher = Sha256::default();
+                let hashed = futures::io::copy(
+                    (&mut content).take(*offset),
+                    &mut futures::io::AllowStdIo::new(&mut hasher),
                 )
                 .await?;
-        }
+                let our_hash = hasher.finalize_fixed();
+                hashed == *offset && *our_hash == sha256[..]
+            },
+            None => true,
+        };
+
+        /* If it doesn't match, start at 0 instead of the originally requested offset */
+        let offset = if start_at_offset {
+            if *offset == offer.get_file(&file).unwrap().1 && !is_stream {
+                tracing::debug!("The receiver already has {}, skipping it", file.join("/"));
+            }
+            *offset
+        } else {
+            tracing::info!(
+                "Partial content of {} does not match, sending it from the start",
+                file.join("/")
+            );
+            0
+        };
+        content.seek(std::io::SeekFrom::Start(offset)).await?;
+        transit
+            .send_record(
+                &PeerMessageV2::FileStart(FileStart {
+                    file,
+                    start_at_offset,
+                })
+                .ser_msgpack(),
+            )
+            .await?;
+        total_sent += offset;
 
         progress_handler(total_sent, total_size);
         loop {
@@ -313,10 +330,6 @@ async fn send_inner(
                 .await?;
             total_sent += n as u64;
             progress_handler(total_sent, total_size);
-
-            if n < BUFFER_LEN {
-                break;
-            }
         }
 
         transit
@@ -331,31 +344,86 @@ async fn send_inner(
 }
 
 pub async fn request(
-    mut wormhole: Wormhole,
+    wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     peer_version: AppVersion,
     transit_abilities: transit::Abilities,
     cancel: impl Future<Output = ()>,
 ) -> Result<Option<ReceiveRequest>, TransferError> {
+    request_text_or_file(
+        wormhole,
+        relay_hints,
+        peer_version,
+        transit_abilities,
+        false,
+        cancel,
+    )
+    .await
+    .map(|offer| {
+        offer.map(|offer| match offer {
+            v1::Offered::File(request) => request,
+            v1::Offered::Text(_) => unreachable!("text offers are rejected when not accepted"),
+        })
+    })
+}
+
+/**
+ * Like [`request`], but also accept text messages if `accept_text` is true
+ *
+ * Senders of text messages don't do any transit, they directly send the offer. So we wait for
+ * their first message before sending our transit hints.
+ */
+pub(crate) async fn request_text_or_file(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    peer_version: AppVersion,
+    transit_abilities: transit::Abilities,
+    accept_text: bool,
+    cancel: impl Future<Output = ()>,
+) -> Result<Option<v1::Offered<ReceiveRequest>>, TransferError> {
     let peer_abilities = peer_version.transfer_v2.unwrap();
     futures::pin_mut!(cancel);
 
     /* Establish transit connection, close the Wormhole and switch to using the transit connection (msgpack instead of json) */
-    let ((mut transit, info), wormhole, cancel) = cancel::with_cancel_wormhole!(
+    let (connection, wormhole, cancel) = cancel::with_cancel_wormhole!(
         wormhole,
         run = async {
+            let their_hints = if accept_text {
+                match wormhole.receive_json::<PeerMessage>().await??.check_err()? {
+                    PeerMessage::TransitV2(transit) => Some(transit.hints_v2),
+                    PeerMessage::Offer(v1::OfferMessage::Message(text)) => {
+                        tracing::debug!("Received text message, sending ack");
+                        wormhole
+                            .send_json(&PeerMessage::message_ack_v1("ok"))
+                            .await?;
+                        return Ok(Err(text));
+                    },
+                    other => bail!(TransferError::unexpected_message("transit-v2", other)),
+                }
+            } else {
+                None
+            };
             make_transit(
                 &mut wormhole,
                 false,
                 relay_hints,
                 transit_abilities,
                 peer_abilities.transit_abilities,
+                their_hints,
             )
             .await
+            .map(Ok)
         },
         cancel,
         ret_cancel = None,
     );
+    let (mut transit, info) = match connection {
+        Ok(connection) => connection,
+        Err(text) => {
+            cancel::close_wormhole(wormhole, cancel).await;
+            return Ok(Some(v1::Offered::Text(text)));
+        },
+    };
 
     let (offer, transit) = cancel::with_cancel_transit!(
         transit,
@@ -382,7 +450,9 @@ pub async fn request(
         ret_cancel = None,
     );
 
-    Ok(Some(ReceiveRequest::new(transit, offer, info)))
+    Ok(Some(v1::Offered::File(ReceiveRequest::new(
+        transit, offer, info,
+    ))))
 }
 
 /**
@@ -520,50 +590,73 @@ async fn receive_inner(
             )
         );
 
-        let mut content;
+        /* Streams don't have a known size, they simply end with the 'file-end' message */
+        let is_stream = matches!(
+            offer.get(&file),
+            Some(OfferEntry::RegularFile { stream: true, .. })
+        );
+
+        /* We already have the whole file, so leave it alone instead of opening it for writing */
+        if file_start.start_at_offset && !is_stream && answer.offset == size {
+            tracing::debug!("Already have {}, skipping it", file.join("/"));
+            total_received += size;
+            progress_handler(total_received, total_size);
+            match PeerMessageV2::de_msgpack(&transit.receive_record().await?)?.check_err()? {
+                PeerMessageV2::FileEnd(_) => continue,
+                PeerMessageV2::Payload(_) => {
+                    bail!(TransferError::Protocol(
+                        format!("File too large: expected only {size} bytes, got more")
+                            .into_boxed_str()
+                    ))
+                },
+                other => {
+                    bail!(TransferError::unexpected_message("file-end", other))
+                },
+            }
+        }
+
+        /* Either append to what we already have, or start from scratch */
+        let mut content = (answer.content)(file_start.start_at_offset).await?;
         let mut received_size = 0;
         if file_start.start_at_offset {
-            content = (answer.content)(true).await?;
-            let offset = answer.offset;
-            received_size = offset;
-        } else {
-            content = (answer.content)(false).await?;
+            received_size = answer.offset;
+            total_received += answer.offset;
         }
 
         progress_handler(total_received, total_size);
-        loop {
-            let payload =
-                match PeerMessageV2::de_msgpack(&transit.receive_record().await?)?.check_err()? {
-                    PeerMessageV2::Payload(payload) => payload.payload,
-                    PeerMessageV2::FileEnd(_) => {
-                        bail!(TransferError::Protocol(
-                            format!(
+        while is_stream || received_size < size {
+            let payload = match PeerMessageV2::de_msgpack(transit.receive_record_ref().await?)?
+                .check_err()?
+            {
+                PeerMessageV2::Payload(payload) => payload.payload,
+                PeerMessageV2::FileEnd(_) if is_stream => break,
+                PeerMessageV2::FileEnd(_) => {
+                    bail!(TransferError::Protocol(
+                        format!(
                             "Unexpected message: got 'file-end' but expected {} more payload bytes",
                             size - received_size,
                         )
-                            .into_boxed_str()
-                        ))
-                    },
-                    other => {
-                        bail!(TransferError::unexpected_message("payload", other))
-                    },
-                };
+                        .into_boxed_str()
+                    ))
+                },
+                other => {
+                    bail!(TransferError::unexpected_message("payload", other))
+                },
+            };
 
             content.write_all(&payload).await?;
             received_size += payload.len() as u64;
             total_received += payload.len() as u64;
             progress_handler(total_received, total_size);
 
-            if received_size == size {
-                break;
-            } else if received_size >= size {
+            if !is_stream && received_size > size {
                 /* `received_size` must never become greater than `size` or we might panic on an integer underflow in the next iteration
                  * (only on an unhappy path, but still). Also, the progress bar might not appreciate.
                  */
                 bail!(TransferError::Protocol(
                     format!(
                         "File too large: expected only {size} bytes, got at least {} more",
-                        size - received_size
+                        received_size - size
                     )
                     .into_boxed_str()
                 ))
@@ -572,12 +665,15 @@ async fn receive_inner(
 
         content.close().await?;
 
-        let _end = match PeerMessageV2::de_msgpack(&transit.receive_record().await?)?.check_err()? {
-            PeerMessageV2::FileEnd(end) => end,
-            other => {
-                bail!(TransferError::unexpected_message("file-end", other))
-            },
-        };
+        if !is_stream {
+            let _end =
+                match PeerMessageV2::de_msgpack(&transit.receive_record().await?)?.check_err()? {
+                    PeerMessageV2::FileEnd(end) => end,
+                    other => {
+                        bail!(TransferError::unexpected_message("file-end", other))
+                    },
+                };
+        }
     }
 
     let _transfer_ack =
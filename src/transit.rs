@@ -14,7 +14,7 @@
 //! "leader" side and one "follower" side (formerly called "sender" and "receiver").
 
 #[allow(deprecated)]
-use crate::{util, Key, KeyPurpose};
+use crate::{proxy::Proxy, util, Key, KeyPurpose};
 use serde_derive::{Deserialize, Serialize};
 
 #[cfg(not(target_family = "wasm"))]
@@ -34,12 +34,29 @@ use std::{
 };
 
 mod crypto;
+#[cfg(not(target_family = "wasm"))]
+mod migrate;
+#[cfg(not(target_family = "wasm"))]
+pub mod server;
+#[cfg(not(target_family = "wasm"))]
+mod stream;
+#[cfg(not(target_family = "wasm"))]
+mod tor;
 mod transport;
 use crypto::TransitHandshakeError;
+#[cfg(not(target_family = "wasm"))]
+pub use stream::{TransitReader, TransitStream, TransitWriter};
 use transport::{TransitTransport, TransitTransportRx, TransitTransportTx};
 
 /// ULR to a default hosted relay server. Please don't abuse or DOS.
 pub const DEFAULT_RELAY_SERVER: &str = "tcp://transit.magic-wormhole.io:4001";
+/// The size of the records in which file transfers send their data
+///
+/// Records may be larger or smaller, this is only what the sending side chooses. Larger records
+/// mean fewer length prefixes, authentication tags and syscalls, and thus higher throughput.
+/// With the noise protocol, this exceeds the message size limit of the noise spec on purpose.
+pub const RECORD_SIZE: usize = 64 * 1024;
+
 // No need to make public, it's hard-coded anyways (:
 // Open an issue if you want an API for this
 // Use <stun.stunprotocol.org:3478> for non-production testing
@@ -154,9 +171,16 @@ pub struct Abilities {
     pub direct_tcp_v1: bool,
     /** Connection over a relay */
     pub relay_v1: bool,
-    #[cfg(any())]
-    /** **Experimental** Use the [noise protocol](https://noiseprotocol.org) for the encryption. */
+    /** Use the [noise protocol](https://noiseprotocol.org) for the encryption instead of secretbox,
+     * which adds forward secrecy. Only used if both sides support it. */
     pub noise_v1: bool,
+    /** Use up to this many connections in parallel and send the records on them in turn, which
+     * helps on links with a high latency. Only used if both sides support it and use the noise
+     * protocol, values below 2 disable it. */
+    pub parallel_v1: u8,
+    /** If we had to start on a relay, keep trying to connect directly and move there once we
+     * can, while the transit is in use. Only used if both sides support it. */
+    pub migrate_v1: bool,
 }
 
 impl Abilities {
@@ -164,8 +188,9 @@ impl Abilities {
     pub const ALL: Self = Self {
         direct_tcp_v1: true,
         relay_v1: true,
-        #[cfg(any())]
-        noise_v1: false,
+        noise_v1: true,
+        parallel_v1: 0,
+        migrate_v1: true,
     };
 
     /// The abilities preset that contains all abilities
@@ -181,8 +206,9 @@ impl Abilities {
     pub const FORCE_DIRECT: Self = Self {
         direct_tcp_v1: true,
         relay_v1: false,
-        #[cfg(any())]
-        noise_v1: false,
+        noise_v1: true,
+        parallel_v1: 0,
+        migrate_v1: false,
     };
 
     /**
@@ -190,14 +216,15 @@ impl Abilities {
      *
      * If the other side forces a the usage of a direct connection the attempt will fail.
      * Note that the other side might control the relay server being used, if you really
-     * don't want your IP to potentially be disclosed use TOR instead (not supported by
-     * the Rust implementation yet).
+     * don't want your IP to potentially be disclosed use Tor instead, see
+     * [`Proxy::tor`](crate::proxy::Proxy::tor).
      */
     pub const FORCE_RELAY: Self = Self {
         direct_tcp_v1: false,
         relay_v1: true,
-        #[cfg(any())]
-        noise_v1: false,
+        noise_v1: true,
+        parallel_v1: 0,
+        migrate_v1: false,
     };
 
     /// Whether direct transfer is allowed
@@ -210,25 +237,28 @@ impl Abilities {
         self.relay_v1
     }
 
-    #[cfg(any())]
+    /// Whether noise cryptography is supported
     pub fn can_noise_crypto(&self) -> bool {
         self.noise_v1
     }
 
-    /// Whether noise cryptography is supported
-    #[deprecated(since = "0.7.0", note = "Noise cryptography is not standardized")]
-    pub fn can_noise_crypto(&self) -> bool {
-        false
+    /// Whether moving from a relay to a direct connection is supported
+    pub fn can_migrate(&self) -> bool {
+        self.migrate_v1
+    }
+
+    /// How many parallel connections are supported, at least one
+    pub fn parallel_connections(&self) -> usize {
+        self.parallel_v1.max(1) as usize
     }
 
     /// Keep only abilities that both sides support
     pub fn intersect(mut self, other: &Self) -> Self {
         self.direct_tcp_v1 &= other.direct_tcp_v1;
         self.relay_v1 &= other.relay_v1;
-        #[cfg(any())]
-        {
-            self.noise_v1 &= other.noise_v1;
-        }
+        self.noise_v1 &= other.noise_v1;
+        self.parallel_v1 = self.parallel_v1.min(other.parallel_v1);
+        self.migrate_v1 &= other.migrate_v1;
         self
     }
 }
@@ -249,12 +279,22 @@ impl serde::Serialize for Abilities {
                 "type": "relay-v1",
             }));
         }
-        #[cfg(any())]
         if self.noise_v1 {
             hints.push(serde_json::json!({
                 "type": "noise-crypto-v1",
             }));
         }
+        if self.migrate_v1 {
+            hints.push(serde_json::json!({
+                "type": "migrate-v1",
+            }));
+        }
+        if self.parallel_v1 > 1 {
+            hints.push(serde_json::json!({
+                "type": "parallel-v1",
+                "max": self.parallel_v1,
+            }));
+        }
         serde_json::Value::Array(hints).serialize(ser)
     }
 }
@@ -270,8 +310,11 @@ impl<'de> serde::Deserialize<'de> for Abilities {
             DirectTcpV1,
             RelayV1,
             RelayV2,
-            #[cfg(any())]
             NoiseCryptoV1,
+            MigrateV1,
+            ParallelV1 {
+                max: u8,
+            },
             #[serde(other)]
             Other,
         }
@@ -286,10 +329,15 @@ impl<'de> serde::Deserialize<'de> for Abilities {
                 Ability::RelayV1 => {
                     abilities.relay_v1 = true;
                 },
-                #[cfg(any())]
                 Ability::NoiseCryptoV1 => {
                     abilities.noise_v1 = true;
                 },
+                Ability::MigrateV1 => {
+                    abilities.migrate_v1 = true;
+                },
+                Ability::ParallelV1 { max } => {
+                    abilities.parallel_v1 = max;
+                },
                 _ => (),
             }
         }
@@ -303,6 +351,7 @@ impl<'de> serde::Deserialize<'de> for Abilities {
 #[non_exhaustive]
 enum HintSerde {
     DirectTcpV1(DirectHint),
+    TorTcpV1(DirectHint),
     RelayV1(RelayHint),
     #[serde(other)]
     Unknown,
@@ -313,6 +362,8 @@ enum HintSerde {
 pub struct Hints {
     /** Hints for direct connection */
     pub direct_tcp: HashSet<DirectHint>,
+    /** Onion services of the peer, only reachable through Tor */
+    pub tor_tcp: HashSet<DirectHint>,
     /** List of relay servers */
     pub relay: Vec<RelayHint>,
 }
@@ -325,6 +376,7 @@ impl Hints {
     ) -> Self {
         Self {
             direct_tcp: direct_tcp.into_iter().collect(),
+            tor_tcp: HashSet::new(),
             relay: relay.into_iter().collect(),
         }
     }
@@ -337,6 +389,7 @@ impl<'de> serde::Deserialize<'de> for Hints {
     {
         let hints: Vec<HintSerde> = serde::Deserialize::deserialize(de)?;
         let mut direct_tcp = HashSet::new();
+        let mut tor_tcp = HashSet::new();
         let mut relay = Vec::<RelayHint>::new();
         let mut relay_v2 = Vec::<RelayHint>::new();
 
@@ -345,6 +398,9 @@ impl<'de> serde::Deserialize<'de> for Hints {
                 HintSerde::DirectTcpV1(hint) => {
                     direct_tcp.insert(hint);
                 },
+                HintSerde::TorTcpV1(hint) => {
+                    tor_tcp.insert(hint);
+                },
                 HintSerde::RelayV1(hint) => {
                     relay_v2.push(hint);
                 },
@@ -359,7 +415,11 @@ impl<'de> serde::Deserialize<'de> for Hints {
         }
         relay.extend(relay_v2.into_iter().map(Into::into));
 
-        Ok(Hints { direct_tcp, relay })
+        Ok(Hints {
+            direct_tcp,
+            tor_tcp,
+            relay,
+        })
     }
 }
 
@@ -369,8 +429,9 @@ impl serde::Serialize for Hints {
         S: serde::Serializer,
     {
         let direct = self.direct_tcp.iter().cloned().map(HintSerde::DirectTcpV1);
+        let tor = self.tor_tcp.iter().cloned().map(HintSerde::TorTcpV1);
         let relay = self.relay.iter().cloned().map(HintSerde::RelayV1);
-        ser.collect_seq(direct.chain(relay))
+        ser.collect_seq(direct.chain(tor).chain(relay))
     }
 }
 
@@ -675,6 +736,9 @@ pub struct TransitInfo {
     /// This says nothing about the actual transport protocol used.
     #[cfg(not(target_family = "wasm"))]
     pub peer_addr: SocketAddr,
+    /// The number of parallel connections. All others are of the same type as the first one,
+    /// which is described above.
+    pub connections: usize,
 }
 
 type TransitConnection = (Box<dyn TransitTransport>, TransitInfo);
@@ -727,7 +791,11 @@ impl std::fmt::Display for TransitInfo {
                     self.peer_addr,
                 )
             },
+        }?;
+        if self.connections > 1 {
+            write!(f, " ({} parallel connections)", self.connections)?;
         }
+        Ok(())
     }
 }
 
@@ -744,7 +812,11 @@ impl std::fmt::Display for TransitInfo {
             ConnectionType::Relay { name: None } => {
                 write!(f, "Established transit connection via relay",)
             },
+        }?;
+        if self.connections > 1 {
+            write!(f, " ({} parallel connections)", self.connections)?;
         }
+        Ok(())
     }
 }
 
@@ -761,6 +833,7 @@ pub fn log_transit_connection(
         conn_type,
         #[cfg(not(target_family = "wasm"))]
         peer_addr,
+        connections: 1,
     };
 
     tracing::info!("{info}");
@@ -777,9 +850,29 @@ pub fn log_transit_connection(
 )]
 #[allow(deprecated)]
 pub async fn init(
+    abilities: Abilities,
+    peer_abilities: Option<Abilities>,
+    relay_hints: Vec<RelayHint>,
+) -> Result<TransitConnector, std::io::Error> {
+    init_with_proxy(abilities, peer_abilities, relay_hints, None).await
+}
+
+/**
+ * Initialize a relay handshake, tunneling all connections through a proxy
+ *
+ * Like [`init`], but connections to the relay servers go through `proxy`. Direct connections
+ * cannot be tunneled, so they are disabled if a proxy is given. Proxies are not supported on
+ * WASM, where the browser handles them.
+ *
+ * Tor is the exception: we connect to the peer's hints through it as well, and if its control
+ * port is configured, we publish an onion service as `tor-tcp-v1` hint instead of our IP addresses.
+ */
+#[allow(deprecated)]
+pub(crate) async fn init_with_proxy(
     mut abilities: Abilities,
     peer_abilities: Option<Abilities>,
     relay_hints: Vec<RelayHint>,
+    #[cfg_attr(target_family = "wasm", allow(unused_variables))] proxy: Option<Proxy>,
 ) -> Result<TransitConnector, std::io::Error> {
     let mut our_hints = Hints::default();
     #[cfg(not(target_family = "wasm"))]
@@ -789,9 +882,36 @@ pub async fn init(
         abilities = abilities.intersect(&peer_abilities);
     }
 
+    #[cfg(not(target_family = "wasm"))]
+    let tor = proxy
+        .as_ref()
+        .filter(|proxy| proxy.kind == crate::proxy::ProxyKind::Tor);
+    #[cfg(not(target_family = "wasm"))]
+    let mut onion_service = None;
+
+    #[cfg(not(target_family = "wasm"))]
+    if proxy.is_some() && tor.is_none() && abilities.can_direct() {
+        tracing::debug!("Disabling direct connections, since we are behind a proxy");
+        abilities.direct_tcp_v1 = false;
+    }
+
+    /* With Tor, we must not reveal our IP addresses. Offer an onion service instead, if we can */
+    #[cfg(not(target_family = "wasm"))]
+    if let Some(tor) = tor.filter(|_| abilities.can_direct()) {
+        if let Some(control_port) = tor.tor_control_port {
+            match tor::OnionService::create(tor, control_port).await {
+                Ok(service) => {
+                    our_hints.tor_tcp.insert(service.hint.clone());
+                    onion_service = Some(service);
+                },
+                Err(err) => tracing::warn!("Failed to publish an onion service: {}", err),
+            }
+        }
+    }
+
     /* Detect our IP addresses if the ability is enabled */
     #[cfg(not(target_family = "wasm"))]
-    if abilities.can_direct() {
+    if abilities.can_direct() && tor.is_none() {
         let create_sockets = async {
             /* Do a STUN query to get our public IP. If it works, we must reuse the same socket (port)
              * so that we will be NATted to the same port again. If it doesn't, simply bind a new socket
@@ -886,8 +1006,13 @@ pub async fn init(
     Ok(TransitConnector {
         #[cfg(not(target_family = "wasm"))]
         sockets,
+        #[cfg(not(target_family = "wasm"))]
+        onion_service,
+        #[cfg(not(target_family = "wasm"))]
+        proxy: proxy.map(Arc::new),
         our_abilities: abilities,
         our_hints: Arc::new(our_hints),
+        dilation: false,
     })
 }
 
@@ -929,12 +1054,27 @@ pub struct TransitConnector {
      */
     #[cfg(not(target_family = "wasm"))]
     sockets: Option<(MaybeConnectedSocket, TcpListener)>,
+    /* Our onion service when using Tor, which we listen on instead of the sockets */
+    #[cfg(not(target_family = "wasm"))]
+    onion_service: Option<tor::OnionService>,
+    /* Connect to the relay servers (and with Tor, to the peer) through this */
+    #[cfg(not(target_family = "wasm"))]
+    proxy: Option<Arc<Proxy>>,
     our_abilities: Abilities,
     our_hints: Arc<Hints>,
+    /* Do the handshake of the dilation protocol instead of the Transit one */
+    dilation: bool,
 }
 
 #[allow(deprecated)]
 impl TransitConnector {
+    /// Connect for a [dilated wormhole](crate::dilation), which has a handshake of its own
+    #[cfg(feature = "experimental-dilation")]
+    pub(crate) fn for_dilation(mut self) -> Self {
+        self.dilation = true;
+        self
+    }
+
     /// The abilities that we've sent to the other side
     pub fn our_abilities(&self) -> &Abilities {
         &self.our_abilities
@@ -980,15 +1120,23 @@ impl TransitConnector {
         let Self {
             #[cfg(not(target_family = "wasm"))]
             sockets,
+            #[cfg(not(target_family = "wasm"))]
+            onion_service,
+            #[cfg(not(target_family = "wasm"))]
+            proxy,
             our_abilities,
             our_hints,
+            dilation,
         } = self;
         let transit_key = Arc::new(transit_key);
+        let parallel = Self::parallel_connections(&our_abilities, &their_abilities);
 
         let start = Instant::now();
         let mut connection_stream = Box::pin(
             Self::connect_inner(
                 true,
+                dilation,
+                parallel,
                 transit_key,
                 our_abilities,
                 our_hints,
@@ -996,6 +1144,10 @@ impl TransitConnector {
                 their_hints,
                 #[cfg(not(target_family = "wasm"))]
                 sockets,
+                #[cfg(not(target_family = "wasm"))]
+                onion_service,
+                #[cfg(not(target_family = "wasm"))]
+                proxy,
             )
             .filter_map(|result| async {
                 match result {
@@ -1008,16 +1160,50 @@ impl TransitConnector {
             }),
         );
 
-        let (mut transit, mut finalizer, mut conn_info) =
-            util::timeout(std::time::Duration::from_secs(60), connection_stream.next())
-                .await
-                .map_err(|_| {
-                    tracing::debug!("`leader_connect` timed out");
-                    TransitConnectError::Handshake
-                })?
-                .ok_or(TransitConnectError::Handshake)?;
+        let first = util::timeout(std::time::Duration::from_secs(60), connection_stream.next())
+            .await
+            .map_err(|_| {
+                tracing::debug!("`leader_connect` timed out");
+                TransitConnectError::Handshake
+            })?
+            .ok_or(TransitConnectError::Handshake)?;
+        let mut connections = vec![first];
+
+        if parallel > 1 {
+            tracing::debug!("Collecting up to {parallel} parallel transit connections …");
+            /* Like below, wait some more depending on how long the first connection took. But give
+             * the other connections a fair chance, which are started at the same time.
+             */
+            let to_wait = start.elapsed().mul_f32(0.3).clamp(
+                std::time::Duration::from_millis(500),
+                std::time::Duration::from_secs(1),
+            );
+            let can_direct = our_abilities.can_direct() && their_abilities.can_direct();
+            let _ = util::timeout(to_wait, async {
+                while let Some(connection) = connection_stream.next().await {
+                    connections.push(connection);
+                    let direct = connections
+                        .iter()
+                        .filter(|(_, _, info)| info.conn_type == ConnectionType::Direct)
+                        .count();
+                    if direct >= parallel || (!can_direct && connections.len() >= parallel) {
+                        break;
+                    }
+                }
+            })
+            .await;
 
-        if conn_info.conn_type != ConnectionType::Direct && our_abilities.can_direct() {
+            /* Mixing direct and relayed connections would make us as slow as the relay */
+            if connections
+                .iter()
+                .any(|(_, _, info)| info.conn_type == ConnectionType::Direct)
+            {
+                connections.retain(|(_, _, info)| info.conn_type == ConnectionType::Direct);
+            }
+            connections.truncate(parallel);
+            tracing::debug!("Using {} parallel transit connections", connections.len());
+        } else if connections[0].2.conn_type != ConnectionType::Direct && our_abilities.can_direct()
+        {
             tracing::debug!(
                 "Established transit connection over relay. Trying to find a direct connection …"
             );
@@ -1032,14 +1218,10 @@ impl TransitConnector {
                 elapsed.mul_f32(0.3)
             };
             let _ = util::timeout(to_wait, async {
-                while let Some((new_transit, new_finalizer, new_conn_info)) =
-                    connection_stream.next().await
-                {
+                while let Some(new_connection) = connection_stream.next().await {
                     /* We already got a connection, so we're only interested in direct ones */
-                    if new_conn_info.conn_type == ConnectionType::Direct {
-                        transit = new_transit;
-                        finalizer = new_finalizer;
-                        conn_info = new_conn_info;
+                    if new_connection.2.conn_type == ConnectionType::Direct {
+                        connections[0] = new_connection;
                         tracing::debug!("Found direct connection; using that instead.");
                         break;
                     }
@@ -1051,27 +1233,49 @@ impl TransitConnector {
             tracing::debug!("Established direct transit connection");
         }
 
+        let mut conn_info = connections[0].2.clone();
+        conn_info.connections = connections.len();
+        let mut connections = futures::future::try_join_all(connections.into_iter().map(
+            |(mut socket, finalizer, _)| async move {
+                let (tx, rx) = finalizer.handshake_finalize(&mut socket).await?;
+                Ok::<_, TransitHandshakeError>(Connection { socket, tx, rx })
+            },
+        ))
+        .await
+        .map_err(|e| {
+            tracing::debug!("`handshake_finalize` failed: {e}");
+            TransitConnectError::Handshake
+        })?;
+
+        if parallel > 1 {
+            let count = connections.len();
+            for (index, connection) in connections.iter_mut().enumerate() {
+                let header = serde_json::to_vec(&ParallelHeader { index, count })
+                    .expect("Serialization cannot fail");
+                connection
+                    .tx
+                    .encrypt(&mut connection.socket, &header)
+                    .await
+                    .map_err(|e| {
+                        tracing::debug!("Sending the parallel connection header failed: {e}");
+                        TransitConnectError::Handshake
+                    })?;
+            }
+        }
+
+        #[cfg(not(target_family = "wasm"))]
+        if Self::can_migrate(&our_abilities, &their_abilities, parallel, &conn_info) {
+            let connection = connections.pop().expect("We have exactly one connection");
+            connections.push(Self::migrate(connection, true, connection_stream));
+            return Ok((Transit::new(connections), conn_info));
+        }
+
         /* Cancel all remaining non-finished handshakes. We could send "nevermind" to explicitly tell
          * the other side (probably, this is mostly for relay server statistics), but eeh, nevermind :)
          */
         std::mem::drop(connection_stream);
 
-        let (tx, rx) = finalizer
-            .handshake_finalize(&mut transit)
-            .await
-            .map_err(|e| {
-                tracing::debug!("`handshake_finalize` failed: {e}");
-                TransitConnectError::Handshake
-            })?;
-
-        Ok((
-            Transit {
-                socket: transit,
-                tx,
-                rx,
-            },
-            conn_info,
-        ))
+        Ok((Transit::new(connections), conn_info))
     }
 
     /**
@@ -1086,14 +1290,22 @@ impl TransitConnector {
         let Self {
             #[cfg(not(target_family = "wasm"))]
             sockets,
+            #[cfg(not(target_family = "wasm"))]
+            onion_service,
+            #[cfg(not(target_family = "wasm"))]
+            proxy,
             our_abilities,
             our_hints,
+            dilation,
         } = self;
         let transit_key = Arc::new(transit_key);
+        let parallel = Self::parallel_connections(&our_abilities, &their_abilities);
 
         let mut connection_stream = Box::pin(
             Self::connect_inner(
                 false,
+                dilation,
+                parallel,
                 transit_key,
                 our_abilities,
                 our_hints,
@@ -1101,6 +1313,10 @@ impl TransitConnector {
                 their_hints,
                 #[cfg(not(target_family = "wasm"))]
                 sockets,
+                #[cfg(not(target_family = "wasm"))]
+                onion_service,
+                #[cfg(not(target_family = "wasm"))]
+                proxy,
             )
             .filter_map(|result| async {
                 match result {
@@ -1113,13 +1329,33 @@ impl TransitConnector {
             }),
         );
 
-        let transit = match util::timeout(
-            std::time::Duration::from_secs(60),
-            &mut connection_stream.next(),
-        )
-        .await
-        {
-            Ok(Some((mut socket, finalizer, conn_info))) => {
+        let transit = util::timeout(std::time::Duration::from_secs(60), async {
+            let (mut socket, finalizer, mut conn_info) = connection_stream
+                .next()
+                .await
+                .ok_or(TransitConnectError::Handshake)?;
+            let (tx, rx) = finalizer
+                .handshake_finalize(&mut socket)
+                .await
+                .map_err(|e| {
+                    tracing::debug!("`handshake_finalize` failed: {e}");
+                    TransitConnectError::Handshake
+                })?;
+            let mut connection = Connection { socket, tx, rx };
+            if parallel == 1 {
+                return Ok((vec![connection], conn_info));
+            }
+
+            /* The leader tells us on each connection where it belongs */
+            let header = receive_parallel_header(&mut connection, parallel).await?;
+            let mut connections: Vec<Option<Connection>> =
+                std::iter::repeat_with(|| None).take(header.count).collect();
+            connections[header.index] = Some(connection);
+            while connections.iter().any(Option::is_none) {
+                let (mut socket, finalizer, _) = connection_stream
+                    .next()
+                    .await
+                    .ok_or(TransitConnectError::Handshake)?;
                 let (tx, rx) = finalizer
                     .handshake_finalize(&mut socket)
                     .await
@@ -1127,21 +1363,101 @@ impl TransitConnector {
                         tracing::debug!("`handshake_finalize` failed: {e}");
                         TransitConnectError::Handshake
                     })?;
+                let mut connection = Connection { socket, tx, rx };
+                let other = receive_parallel_header(&mut connection, parallel).await?;
+                ensure!(
+                    other.count == header.count && connections[other.index].is_none(),
+                    TransitConnectError::Protocol(
+                        "Inconsistent parallel connection headers".into()
+                    )
+                );
+                connections[other.index] = Some(connection);
+            }
+            tracing::debug!("Using {} parallel transit connections", header.count);
 
-                Ok((Transit { socket, tx, rx }, conn_info))
-            },
-            Ok(None) | Err(_) => {
-                tracing::debug!("`follower_connect` timed out");
-                Err(TransitConnectError::Handshake)
-            },
-        };
+            conn_info.connections = header.count;
+            Ok((
+                connections.into_iter().map(Option::unwrap).collect(),
+                conn_info,
+            ))
+        })
+        .await
+        .unwrap_or_else(|_| {
+            tracing::debug!("`follower_connect` timed out");
+            Err(TransitConnectError::Handshake)
+        });
+        #[allow(unused_mut)] // For WASM targets
+        let (mut connections, conn_info) = transit?;
+
+        #[cfg(not(target_family = "wasm"))]
+        if Self::can_migrate(&our_abilities, &their_abilities, parallel, &conn_info) {
+            let connection = connections.pop().expect("We have exactly one connection");
+            connections.push(Self::migrate(connection, false, connection_stream));
+            return Ok((Transit::new(connections), conn_info));
+        }
 
         /* Cancel all remaining non-finished handshakes. We could send "nevermind" to explicitly tell
          * the other side (probably, this is mostly for relay server statistics), but eeh, nevermind :)
          */
         std::mem::drop(connection_stream);
 
-        transit
+        Ok((Transit::new(connections), conn_info))
+    }
+
+    /**
+     * How many connections to use in parallel
+     *
+     * This requires noise on both sides. With secretbox, all connections would use the same key,
+     * and thus reuse the nonces.
+     */
+    fn parallel_connections(our_abilities: &Abilities, their_abilities: &Abilities) -> usize {
+        if our_abilities.can_noise_crypto() && their_abilities.can_noise_crypto() {
+            our_abilities
+                .parallel_connections()
+                .min(their_abilities.parallel_connections())
+        } else {
+            1
+        }
+    }
+
+    /** Whether to keep looking for a direct connection after we had to start on a relay */
+    #[cfg_attr(target_family = "wasm", allow(dead_code))]
+    fn can_migrate(
+        our_abilities: &Abilities,
+        their_abilities: &Abilities,
+        parallel: usize,
+        info: &TransitInfo,
+    ) -> bool {
+        our_abilities.can_migrate()
+            && their_abilities.can_migrate()
+            && our_abilities.can_direct()
+            && their_abilities.can_direct()
+            && parallel == 1
+            && info.conn_type != ConnectionType::Direct
+    }
+
+    /** Let the connection move to the first direct connection the remaining attempts yield */
+    #[cfg(not(target_family = "wasm"))]
+    fn migrate(
+        connection: Connection,
+        is_leader: bool,
+        connection_stream: impl Stream<Item = HandshakeResult> + Send + 'static,
+    ) -> Connection {
+        tracing::debug!(
+            "Established transit connection over relay, moving to a direct one once we can"
+        );
+        /* Don't keep the listening port open forever */
+        let probe = connection_stream
+            .take_until(util::sleep(std::time::Duration::from_secs(60)))
+            .boxed();
+        Connection {
+            socket: Box::new(migrate::MigratingTransport::new(
+                connection.socket,
+                is_leader,
+                probe,
+            )),
+            ..connection
+        }
     }
 
     /** Try to establish a connection with the peer.
@@ -1152,25 +1468,36 @@ impl TransitConnector {
      *
      * If the receiving end of the channel for the results is closed before all futures in the return
      * value are cancelled/dropped.
+     *
+     * With `parallel` connections, each hint is tried that many times, and the listening
+     * port keeps accepting connections. With `dilation`, the handshake of the dilation
+     * protocol is used instead of the Transit one.
      */
+    #[allow(clippy::too_many_arguments)]
     fn connect_inner(
         is_leader: bool,
+        dilation: bool,
+        parallel: usize,
         transit_key: Arc<Key<TransitKey>>,
         our_abilities: Abilities,
         our_hints: Arc<Hints>,
         their_abilities: Abilities,
         their_hints: Arc<Hints>,
         #[cfg(not(target_family = "wasm"))] sockets: Option<(MaybeConnectedSocket, TcpListener)>,
+        #[cfg(not(target_family = "wasm"))] onion_service: Option<tor::OnionService>,
+        #[cfg(not(target_family = "wasm"))] proxy: Option<Arc<Proxy>>,
     ) -> impl Stream<Item = Result<HandshakeResult, TransitHandshakeError>> + 'static {
         /* Have Some(sockets) → Can direct */
         #[cfg(not(target_family = "wasm"))]
         assert!(sockets.is_none() || our_abilities.can_direct());
 
-        let cryptor = if our_abilities.can_noise_crypto() && their_abilities.can_noise_crypto() {
+        let cryptor = if dilation {
+            Arc::new(crypto::NoiseInit::dilation(transit_key.clone()))
+                as Arc<dyn crypto::TransitCryptoInit>
+        } else if our_abilities.can_noise_crypto() && their_abilities.can_noise_crypto() {
             tracing::debug!("Using noise protocol for encryption");
-            Arc::new(crypto::NoiseInit {
-                key: transit_key.clone(),
-            }) as Arc<dyn crypto::TransitCryptoInit>
+            Arc::new(crypto::NoiseInit::transit(transit_key.clone()))
+                as Arc<dyn crypto::TransitCryptoInit>
         } else {
             tracing::debug!("Using secretbox for encryption");
             Arc::new(crypto::SecretboxInit {
@@ -1194,8 +1521,44 @@ impl TransitConnector {
 
         #[cfg(not(target_family = "wasm"))]
         let (socket, listener) = sockets.unzip();
+        /* With Tor, the peer reaches us through our onion service instead. It is only
+         * published as long as its control connection is open, so keep that with the listener.
+         */
+        #[cfg(not(target_family = "wasm"))]
+        let (listener, onion_control) = match onion_service {
+            Some(service) => (Some(service.listener), Some(service.control)),
+            None => (listener, None),
+        };
         #[cfg(not(target_family = "wasm"))]
-        if our_abilities.can_direct() && their_abilities.can_direct() {
+        let tor = proxy
+            .clone()
+            .filter(|proxy| proxy.kind == crate::proxy::ProxyKind::Tor);
+        #[cfg(not(target_family = "wasm"))]
+        if let (Some(tor), true) = (
+            tor,
+            our_abilities.can_direct() && their_abilities.can_direct(),
+        ) {
+            /* Connect to each hint of the peer through Tor. Their local addresses can't be reached from there */
+            connectors = Box::new(
+                connectors.chain(
+                    their_hints
+                        .tor_tcp
+                        .clone()
+                        .into_iter()
+                        .chain(
+                            their_hints
+                                .direct_tcp
+                                .clone()
+                                .into_iter()
+                                .filter(transport::is_routable),
+                        )
+                        .take(50)
+                        .flat_map(move |hint| std::iter::repeat(hint).take(parallel))
+                        .map(move |hint| transport::connect_tor_direct(hint, tor.clone()))
+                        .map(|fut| Box::pin(fut) as ConnectorFuture),
+                ),
+            ) as BoxIterator<ConnectorFuture>;
+        } else if our_abilities.can_direct() && their_abilities.can_direct() {
             let local_addr = socket.map(|socket| {
                 Arc::new(
                     socket
@@ -1212,6 +1575,7 @@ impl TransitConnector {
                         .into_iter()
                         /* Nobody should have that many IP addresses, even with NATing */
                         .take(50)
+                        .flat_map(move |hint| std::iter::repeat(hint).take(parallel))
                         .map(move |hint| transport::connect_tcp_direct(local_addr.clone(), hint))
                         .map(|fut| Box::pin(fut) as ConnectorFuture),
                 ),
@@ -1259,12 +1623,16 @@ impl TransitConnector {
                                 .enumerate()
                                 .map(move |(i, h)| (i, h, name.clone()))
                             })
-                            .map(|(index, host, name)| async move {
-                                util::sleep(std::time::Duration::from_secs(
-                                    index as u64 * 5,
-                                ))
-                                .await;
-                                transport::connect_tcp_relay(host, name).await
+                            .flat_map(move |connector| std::iter::repeat(connector).take(parallel))
+                            .map(move |(index, host, name)| {
+                                let proxy = proxy.clone();
+                                async move {
+                                    util::sleep(std::time::Duration::from_secs(
+                                        index as u64 * 5,
+                                    ))
+                                    .await;
+                                    transport::connect_tcp_relay(host, name, proxy).await
+                                }
                             })
                             .map(|fut| Box::pin(fut) as ConnectorFuture),
                     ),
@@ -1303,6 +1671,7 @@ impl TransitConnector {
                                     .enumerate()
                                     .map(move |(i, u)| (i, u, name.clone()))
                             })
+                            .flat_map(move |connector| std::iter::repeat(connector).take(parallel))
                             .map(|(index, url, name)| async move {
                                 util::sleep(std::time::Duration::from_secs(
                                     index as u64 * 5,
@@ -1347,44 +1716,56 @@ impl TransitConnector {
         )
             as BoxIterator<BoxFuture<Result<HandshakeResult, TransitHandshakeError>>>;
 
-        /* Also listen on some port just in case. */
+        /* Also listen on some port just in case. With parallel connections, accept as many */
         #[cfg(not(target_family = "wasm"))]
         if let Some(listener) = listener {
+            let listener = Arc::new(listener);
+            let onion_control = Arc::new(onion_control);
             connectors = Box::new(
                 connectors.chain(
-                    std::iter::once(async move {
-                        let transit_key = transit_key.clone();
-                        let tside = tside.clone();
-                        let cryptor = cryptor.clone();
-                        let connect = || async {
-                            let (socket, peer) = listener.accept().await?;
-                            let (socket, info) =
-                                transport::wrap_tcp_connection(socket, ConnectionType::Direct)?;
-                            tracing::debug!("Got connection from {}!", peer);
-                            let (transit, finalizer) = handshake_exchange(
-                                is_leader,
-                                tside.clone(),
-                                socket,
-                                &ConnectionType::Direct,
-                                &*cryptor,
-                                transit_key.clone(),
-                            )
-                            .await?;
-                            Result::<_, TransitHandshakeError>::Ok((transit, finalizer, info))
-                        };
-                        loop {
-                            match connect().await {
-                                Ok(success) => break Ok(success),
-                                Err(err) => {
-                                    tracing::debug!(
-                                        "Some handshake failed on the listening port: {:?}",
-                                        err
-                                    );
-                                    continue;
-                                },
-                            }
-                        }
+                    std::iter::repeat_with(move || {
+                        (
+                            listener.clone(),
+                            onion_control.clone(),
+                            transit_key.clone(),
+                            tside.clone(),
+                            cryptor.clone(),
+                        )
                     })
+                    .take(parallel)
+                    .map(
+                        move |(listener, onion_control, transit_key, tside, cryptor)| async move {
+                            let _onion_control = onion_control;
+                            let connect = || async {
+                                let (socket, peer) = listener.accept().await?;
+                                let (socket, info) =
+                                    transport::wrap_tcp_connection(socket, ConnectionType::Direct)?;
+                                tracing::debug!("Got connection from {}!", peer);
+                                let (transit, finalizer) = handshake_exchange(
+                                    is_leader,
+                                    tside.clone(),
+                                    socket,
+                                    &ConnectionType::Direct,
+                                    &*cryptor,
+                                    transit_key.clone(),
+                                )
+                                .await?;
+                                Result::<_, TransitHandshakeError>::Ok((transit, finalizer, info))
+                            };
+                            loop {
+                                match connect().await {
+                                    Ok(success) => break Ok(success),
+                                    Err(err) => {
+                                        tracing::debug!(
+                                            "Some handshake failed on the listening port: {:?}",
+                                            err
+                                        );
+                                        continue;
+                                    },
+                                }
+                            }
+                        },
+                    )
                     .map(|fut| {
                         Box::pin(fut) as BoxFuture<Result<HandshakeResult, TransitHandshakeError>>
                     }),
@@ -1401,30 +1782,82 @@ impl TransitConnector {
  *
  * While you can manually send and receive bytes over the TCP stream, this is not recommended as the transit protocol
  * also specifies an encrypted record pipe that does all the hard work for you. See the provided methods.
+ *
+ * If both sides support [parallel connections](Abilities::parallel_v1), there may be multiple
+ * connections underneath. Records are then sent on them in turn, and received in the same order.
  */
 pub struct Transit {
-    /** Raw transit connection */
+    connections: Vec<Connection>,
+    /** Index of the connection for the next record to send */
+    send_index: usize,
+    /** Index of the connection for the next record to receive */
+    receive_index: usize,
+}
+
+/** One of the connections of a [`Transit`] */
+struct Connection {
     socket: Box<dyn TransitTransport>,
     tx: Box<dyn crypto::TransitCryptoEncrypt>,
     rx: Box<dyn crypto::TransitCryptoDecrypt>,
 }
 
+/**
+ * The first record on each of the parallel connections
+ *
+ * Tells the follower in which order to receive the records on the connections.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+struct ParallelHeader {
+    index: usize,
+    count: usize,
+}
+
 impl Transit {
+    fn new(connections: Vec<Connection>) -> Self {
+        assert!(!connections.is_empty());
+        Self {
+            connections,
+            send_index: 0,
+            receive_index: 0,
+        }
+    }
+
     /** Receive and decrypt one message from the other side. */
     pub async fn receive_record(&mut self) -> Result<Box<[u8]>, TransitError> {
-        self.rx.decrypt(&mut self.socket).await
+        self.receive_record_ref().await.map(Into::into)
+    }
+
+    /**
+     * Like [`receive_record`](Self::receive_record), but without allocating
+     *
+     * The record borrows an internal buffer, which is reused for the next one.
+     */
+    pub async fn receive_record_ref(&mut self) -> Result<&[u8], TransitError> {
+        let index = self.receive_index;
+        self.receive_index = (index + 1) % self.connections.len();
+        let connection = &mut self.connections[index];
+        connection.rx.decrypt(&mut connection.socket).await
     }
 
     /** Send an encrypted message to the other side */
     pub async fn send_record(&mut self, plaintext: &[u8]) -> Result<(), TransitError> {
         assert!(!plaintext.is_empty());
-        self.tx.encrypt(&mut self.socket, plaintext).await
+        let index = self.send_index;
+        self.send_index = (index + 1) % self.connections.len();
+        let connection = &mut self.connections[index];
+        connection
+            .tx
+            .encrypt(&mut connection.socket, plaintext)
+            .await
     }
 
     /// Flush the socket
     pub async fn flush(&mut self) -> Result<(), TransitError> {
         tracing::debug!("Flush");
-        self.socket.flush().await.map_err(Into::into)
+        for connection in &mut self.connections {
+            connection.socket.flush().await?;
+        }
+        Ok(())
     }
 
     /** Convert the transit connection to a [`Stream`]/[`Sink`] pair */
@@ -1435,23 +1868,134 @@ impl Transit {
         impl futures::sink::Sink<Box<[u8]>, Error = TransitError>,
         impl futures::stream::Stream<Item = Result<Box<[u8]>, TransitError>>,
     ) {
-        let (reader, writer) = self.socket.split();
+        let (sender, receiver) = self.into_halves();
         (
-            futures::sink::unfold(
-                (writer, self.tx),
-                |(mut writer, mut tx), plaintext: Box<[u8]>| async move {
-                    tx.encrypt(&mut writer, &plaintext)
-                        .await
-                        .map(|()| (writer, tx))
-                },
-            ),
-            futures::stream::try_unfold((reader, self.rx), |(mut reader, mut rx)| async move {
-                rx.decrypt(&mut reader)
-                    .await
-                    .map(|record| Some((record, (reader, rx))))
+            futures::sink::unfold(sender, |mut sender, plaintext: Box<[u8]>| async move {
+                sender.send(&plaintext).await.map(|()| sender)
+            }),
+            futures::stream::try_unfold(receiver, |mut receiver| async move {
+                let record = receiver.receive().await?.into();
+                Ok(Some((record, receiver)))
             }),
         )
     }
+
+    /**
+     * Convert the transit connection into a byte stream, implementing [`AsyncRead`] and [`AsyncWrite`]
+     *
+     * This allows running any existing protocol over the transit. The other side must use a
+     * [`TransitStream`] as well.
+     */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn into_stream(self) -> TransitStream {
+        let (sender, receiver) = self.into_halves();
+        TransitStream::new(sender, receiver)
+    }
+
+    /** Split each connection into its reading and writing half */
+    #[cfg(not(target_family = "wasm"))]
+    fn into_halves(self) -> (RecordSender, RecordReceiver) {
+        let (readers, writers) = self
+            .connections
+            .into_iter()
+            .map(|connection| {
+                let (reader, writer) = connection.socket.split();
+                ((reader, connection.rx), (writer, connection.tx))
+            })
+            .unzip();
+        (
+            RecordSender {
+                connections: writers,
+                next: self.send_index,
+            },
+            RecordReceiver {
+                connections: readers,
+                next: self.receive_index,
+            },
+        )
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+type WriteHalf = futures::io::WriteHalf<Box<dyn TransitTransport>>;
+#[cfg(not(target_family = "wasm"))]
+type ReadHalf = futures::io::ReadHalf<Box<dyn TransitTransport>>;
+
+/** The writing halves of the connections of a split [`Transit`] */
+#[cfg(not(target_family = "wasm"))]
+struct RecordSender {
+    connections: Vec<(WriteHalf, Box<dyn crypto::TransitCryptoEncrypt>)>,
+    next: usize,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl RecordSender {
+    async fn send(&mut self, plaintext: &[u8]) -> Result<(), TransitError> {
+        let index = self.next;
+        self.next = (index + 1) % self.connections.len();
+        let (writer, tx) = &mut self.connections[index];
+        tx.encrypt(writer, plaintext).await
+    }
+
+    async fn flush(&mut self) -> Result<(), TransitError> {
+        for (writer, _) in &mut self.connections {
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), TransitError> {
+        for (writer, _) in &mut self.connections {
+            writer.close().await?;
+        }
+        Ok(())
+    }
+}
+
+/** The reading halves of the connections of a split [`Transit`] */
+#[cfg(not(target_family = "wasm"))]
+struct RecordReceiver {
+    connections: Vec<(ReadHalf, Box<dyn crypto::TransitCryptoDecrypt>)>,
+    next: usize,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl RecordReceiver {
+    async fn receive(&mut self) -> Result<&[u8], TransitError> {
+        let index = self.next;
+        self.next = (index + 1) % self.connections.len();
+        let (reader, rx) = &mut self.connections[index];
+        rx.decrypt(reader).await
+    }
+}
+
+/** Receive the [`ParallelHeader`] on a finalized connection of the follower */
+async fn receive_parallel_header(
+    connection: &mut Connection,
+    parallel: usize,
+) -> Result<ParallelHeader, TransitConnectError> {
+    let record = connection
+        .rx
+        .decrypt(&mut connection.socket)
+        .await
+        .map_err(|e| {
+            tracing::debug!("Receiving the parallel connection header failed: {e}");
+            TransitConnectError::Handshake
+        })?;
+    let header: ParallelHeader = serde_json::from_slice(record).map_err(|e| {
+        TransitConnectError::Protocol(format!("Invalid parallel connection header: {e}").into())
+    })?;
+    ensure!(
+        header.index < header.count && header.count <= parallel,
+        TransitConnectError::Protocol(
+            format!(
+                "Invalid parallel connection header: connection {} of {}, but we only support {parallel}",
+                header.index, header.count
+            )
+            .into()
+        )
+    );
+    Ok(header)
 }
 
 type HandshakeResult = (
@@ -1515,12 +2059,148 @@ mod test {
     pub fn test_abilities_encoding() {
         assert_eq!(
             serde_json::to_value(Abilities::ALL).unwrap(),
-            json!([{"type": "direct-tcp-v1"}, {"type": "relay-v1"}])
+            json!([
+                {"type": "direct-tcp-v1"},
+                {"type": "relay-v1"},
+                {"type": "noise-crypto-v1"},
+                {"type": "migrate-v1"}
+            ])
         );
         assert_eq!(
             serde_json::to_value(Abilities::FORCE_DIRECT).unwrap(),
-            json!([{"type": "direct-tcp-v1"}])
+            json!([{"type": "direct-tcp-v1"}, {"type": "noise-crypto-v1"}])
+        );
+    }
+
+    #[test]
+    pub fn test_abilities_negotiation() {
+        /* Older clients and the Python implementation don't know about noise */
+        let theirs: Abilities =
+            serde_json::from_value(json!([{"type": "direct-tcp-v1"}, {"type": "relay-v1"}]))
+                .unwrap();
+        assert!(!theirs.can_noise_crypto());
+        assert!(!Abilities::ALL.intersect(&theirs).can_noise_crypto());
+        assert!(!Abilities::ALL.intersect(&theirs).can_migrate());
+
+        let theirs: Abilities = serde_json::from_value(json!([
+            {"type": "relay-v1"},
+            {"type": "noise-crypto-v1"},
+            {"type": "unknown-v1"}
+        ]))
+        .unwrap();
+        let negotiated = Abilities::ALL.intersect(&theirs);
+        assert!(negotiated.can_noise_crypto());
+        assert!(negotiated.can_relay());
+        assert!(!negotiated.can_direct());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    pub fn test_parallel_abilities() {
+        let ours = Abilities {
+            parallel_v1: 4,
+            ..Abilities::ALL
+        };
+        assert_eq!(
+            serde_json::to_value(ours).unwrap(),
+            json!([
+                {"type": "direct-tcp-v1"},
+                {"type": "relay-v1"},
+                {"type": "noise-crypto-v1"},
+                {"type": "migrate-v1"},
+                {"type": "parallel-v1", "max": 4}
+            ])
+        );
+        assert_eq!(Abilities::ALL.parallel_connections(), 1);
+
+        let theirs: Abilities = serde_json::from_value(json!([
+            {"type": "relay-v1"},
+            {"type": "noise-crypto-v1"},
+            {"type": "parallel-v1", "max": 2}
+        ]))
+        .unwrap();
+        assert_eq!(ours.intersect(&theirs).parallel_connections(), 2);
+        assert_eq!(TransitConnector::parallel_connections(&ours, &theirs), 2);
+        assert_eq!(
+            TransitConnector::parallel_connections(&ours, &Abilities::ALL),
+            1
         );
+
+        /* Parallel connections need noise */
+        let theirs = Abilities {
+            noise_v1: false,
+            ..theirs
+        };
+        assert_eq!(TransitConnector::parallel_connections(&ours, &theirs), 1);
+    }
+
+    /** Both handshakes over a local TCP connection, then send records both ways */
+    async fn crypto_roundtrip(
+        leader: &dyn crypto::TransitCryptoInit,
+        follower: &dyn crypto::TransitCryptoInit,
+    ) -> Result<(), TransitHandshakeError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut leader_socket: Box<dyn TransitTransport> =
+            Box::new(TcpStream::connect(listener.local_addr()?).await?);
+        let mut follower_socket: Box<dyn TransitTransport> = Box::new(listener.accept().await?.0);
+
+        /* The secretbox follower waits for the leader to finalize */
+        let ((mut leader_tx, mut leader_rx), (mut follower_tx, mut follower_rx)) = futures::try_join!(
+            async {
+                leader
+                    .handshake_leader(&mut *leader_socket)
+                    .await?
+                    .handshake_finalize(&mut *leader_socket)
+                    .await
+            },
+            async {
+                follower
+                    .handshake_follower(&mut *follower_socket)
+                    .await?
+                    .handshake_finalize(&mut *follower_socket)
+                    .await
+            },
+        )?;
+
+        leader_tx
+            .encrypt(&mut *leader_socket, b"hello")
+            .await
+            .unwrap();
+        assert_eq!(
+            follower_rx.decrypt(&mut *follower_socket).await.unwrap(),
+            b"hello"
+        );
+        follower_tx
+            .encrypt(&mut *follower_socket, b"world")
+            .await
+            .unwrap();
+        assert_eq!(
+            leader_rx.decrypt(&mut *leader_socket).await.unwrap(),
+            b"world"
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[allow(deprecated)]
+    pub async fn test_noise_handshake() {
+        let key = Arc::new(Key::<TransitKey>::new(Box::new([7u8; 32].into())));
+        let noise = crypto::NoiseInit::transit(key.clone());
+        crypto_roundtrip(&noise, &noise).await.unwrap();
+
+        let dilation = crypto::NoiseInit::dilation(key.clone());
+        crypto_roundtrip(&dilation, &dilation).await.unwrap();
+        /* The confirmation messages differ, so the two must not be mixed up */
+        assert!(crypto_roundtrip(&noise, &dilation).await.is_err());
+
+        let secretbox = crypto::SecretboxInit { key: key.clone() };
+        crypto_roundtrip(&secretbox, &secretbox).await.unwrap();
+
+        /* A different key must not work */
+        let other_noise = crypto::NoiseInit::transit(Arc::new(Key::<TransitKey>::new(Box::new(
+            [8u8; 32].into(),
+        ))));
+        assert!(crypto_roundtrip(&noise, &other_noise).await.is_err());
     }
 
     #[test]
@@ -1562,4 +2242,42 @@ mod test {
             ])
         )
     }
+    #[test]
+    pub fn test_tor_hints_encoding() {
+        let hints = json!([
+            {
+                "type": "tor-tcp-v1",
+                "hostname": "abcdefghijklmnop.onion",
+                "port": 1234,
+                "priority": 0.0
+            },
+            {
+                "type": "direct-tcp-v1",
+                "hostname": "192.168.1.8",
+                "port": 46295
+            }
+        ]);
+        let hints: Hints = serde_json::from_value(hints).unwrap();
+        assert_eq!(
+            hints.tor_tcp,
+            [DirectHint::new("abcdefghijklmnop.onion", 1234)].into()
+        );
+        assert_eq!(
+            hints.direct_tcp,
+            [DirectHint::new("192.168.1.8", 46295)].into()
+        );
+
+        let hints = Hints {
+            tor_tcp: [DirectHint::new("abcdefghijklmnop.onion", 1234)].into(),
+            ..Hints::default()
+        };
+        assert_eq!(
+            serde_json::to_value(hints).unwrap(),
+            json!([{
+                "type": "tor-tcp-v1",
+                "hostname": "abcdefghijklmnop.onion",
+                "port": 1234
+            }])
+        );
+    }
 }
@@ -12,7 +12,7 @@ use super::{
 use crate::Key;
 use async_trait::async_trait;
 use crypto_secretbox as secretbox;
-use crypto_secretbox::{aead::Aead, KeyInit};
+use crypto_secretbox::{aead::AeadInPlace, KeyInit};
 use futures::{future::BoxFuture, io::AsyncWriteExt};
 use std::sync::Arc;
 
@@ -66,7 +66,7 @@ pub(super) trait TransitCryptoInitFinalizer: Send {
     fn handshake_finalize(
         self: Box<Self>,
         socket: &mut dyn TransitTransport,
-    ) -> BoxFuture<Result<DynTransitCrypto, TransitHandshakeError>>;
+    ) -> BoxFuture<'_, Result<DynTransitCrypto, TransitHandshakeError>>;
 }
 
 /// Due to poorly chosen abstractions elsewhere, the [`TransitCryptoInitFinalizer`] trait is also
@@ -75,7 +75,7 @@ impl TransitCryptoInitFinalizer for DynTransitCrypto {
     fn handshake_finalize(
         self: Box<Self>,
         _socket: &mut dyn TransitTransport,
-    ) -> BoxFuture<Result<DynTransitCrypto, TransitHandshakeError>> {
+    ) -> BoxFuture<'_, Result<DynTransitCrypto, TransitHandshakeError>> {
         Box::pin(futures::future::ready(Ok(*self)))
     }
 }
@@ -152,7 +152,7 @@ impl TransitCryptoInit for SecretboxInit {
             fn handshake_finalize(
                 self: Box<Self>,
                 socket: &mut dyn TransitTransport,
-            ) -> BoxFuture<Result<DynTransitCrypto, TransitHandshakeError>> {
+            ) -> BoxFuture<'_, Result<DynTransitCrypto, TransitHandshakeError>> {
                 Box::pin(async move {
                     socket.write_all(b"go\n").await?;
 
@@ -160,10 +160,12 @@ impl TransitCryptoInit for SecretboxInit {
                         Box::new(SecretboxCryptoEncrypt {
                             skey: self.skey,
                             snonce: Default::default(),
+                            buffer: Vec::new(),
                         }) as Box<dyn TransitCryptoEncrypt>,
                         Box::new(SecretboxCryptoDecrypt {
                             rkey: self.rkey,
                             rnonce: Default::default(),
+                            buffer: Vec::new(),
                         }) as Box<dyn TransitCryptoDecrypt>,
                     ))
                 })
@@ -216,10 +218,12 @@ impl TransitCryptoInit for SecretboxInit {
             Box::new(SecretboxCryptoEncrypt {
                 skey,
                 snonce: Default::default(),
+                buffer: Vec::new(),
             }) as Box<dyn TransitCryptoEncrypt>,
             Box::new(SecretboxCryptoDecrypt {
                 rkey,
                 rnonce: Default::default(),
+                buffer: Vec::new(),
             }) as Box<dyn TransitCryptoDecrypt>,
         )) as Box<dyn TransitCryptoInitFinalizer>)
     }
@@ -237,12 +241,32 @@ type NoiseCipherState = noise_protocol::CipherState<noise_rust_crypto::ChaCha20P
 /// ← "Magic-Wormhole Dilation Handshake v1 Follower\n\n"
 /// → psk, e // Handshake
 /// ← e, ee
-/// ← "" // First real message
-/// → "" // Not in this method, to confirm the connection
+/// ← confirmation // First real message
+/// → confirmation // Not in this method, to confirm the connection
 ///
 /// The noise protocol pattern used is "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s"
 pub struct NoiseInit {
     pub key: Arc<Key<TransitKey>>,
+    /// The first record of each side, see [`NoiseInit::transit`] and [`NoiseInit::dilation`]
+    pub confirmation: &'static [u8],
+}
+
+impl NoiseInit {
+    /// For Transit connections, which are confirmed with an empty record
+    pub fn transit(key: Arc<Key<TransitKey>>) -> Self {
+        Self {
+            key,
+            confirmation: b"",
+        }
+    }
+
+    /// For dilation connections, which are confirmed with a key confirmation message (`KCM`) record
+    pub fn dilation(key: Arc<Key<TransitKey>>) -> Self {
+        Self {
+            key,
+            confirmation: &[0x00],
+        }
+    }
 }
 
 #[async_trait]
@@ -278,40 +302,49 @@ impl TransitCryptoInit for NoiseInit {
         assert!(handshake.completed());
         let (tx, mut rx) = handshake.get_ciphers();
 
-        // ← ""
+        // ← confirmation
         let peer_confirmation_message = rx.decrypt_vec(&socket.read_transit_message().await?)?;
         ensure!(
-            peer_confirmation_message.is_empty(),
+            peer_confirmation_message == self.confirmation,
             TransitHandshakeError::HandshakeFailed
         );
 
         struct Finalizer {
             tx: NoiseCipherState,
             rx: NoiseCipherState,
+            confirmation: &'static [u8],
         }
 
         impl TransitCryptoInitFinalizer for Finalizer {
             fn handshake_finalize(
                 mut self: Box<Self>,
                 socket: &mut dyn TransitTransport,
-            ) -> BoxFuture<Result<DynTransitCrypto, TransitHandshakeError>> {
+            ) -> BoxFuture<'_, Result<DynTransitCrypto, TransitHandshakeError>> {
                 Box::pin(async move {
-                    // → ""
+                    // → confirmation
                     socket
-                        .write_transit_message(&self.tx.encrypt_vec(&[]))
+                        .write_transit_message(&self.tx.encrypt_vec(self.confirmation))
                         .await?;
 
                     Ok::<_, TransitHandshakeError>((
-                        Box::new(NoiseCryptoEncrypt { tx: self.tx })
-                            as Box<dyn TransitCryptoEncrypt>,
-                        Box::new(NoiseCryptoDecrypt { rx: self.rx })
-                            as Box<dyn TransitCryptoDecrypt>,
+                        Box::new(NoiseCryptoEncrypt {
+                            tx: self.tx,
+                            buffer: Vec::new(),
+                        }) as Box<dyn TransitCryptoEncrypt>,
+                        Box::new(NoiseCryptoDecrypt {
+                            rx: self.rx,
+                            buffer: Vec::new(),
+                        }) as Box<dyn TransitCryptoDecrypt>,
                     ))
                 })
             }
         }
 
-        Ok(Box::new(Finalizer { tx, rx }))
+        Ok(Box::new(Finalizer {
+            tx,
+            rx,
+            confirmation: self.confirmation,
+        }))
     }
 
     async fn handshake_follower(
@@ -346,25 +379,36 @@ impl TransitCryptoInit for NoiseInit {
         // Warning: rx and tx are swapped here (read the `get_ciphers` doc carefully)
         let (mut rx, mut tx) = handshake.get_ciphers();
 
-        // → ""
-        socket.write_transit_message(&tx.encrypt_vec(&[])).await?;
+        // → confirmation
+        socket
+            .write_transit_message(&tx.encrypt_vec(self.confirmation))
+            .await?;
 
-        // ← ""
+        // ← confirmation
         let peer_confirmation_message = rx.decrypt_vec(&socket.read_transit_message().await?)?;
         ensure!(
-            peer_confirmation_message.is_empty(),
+            peer_confirmation_message == self.confirmation,
             TransitHandshakeError::HandshakeFailed
         );
 
         Ok(Box::new((
-            Box::new(NoiseCryptoEncrypt { tx }) as Box<dyn TransitCryptoEncrypt>,
-            Box::new(NoiseCryptoDecrypt { rx }) as Box<dyn TransitCryptoDecrypt>,
+            Box::new(NoiseCryptoEncrypt {
+                tx,
+                buffer: Vec::new(),
+            }) as Box<dyn TransitCryptoEncrypt>,
+            Box::new(NoiseCryptoDecrypt {
+                rx,
+                buffer: Vec::new(),
+            }) as Box<dyn TransitCryptoDecrypt>,
         )) as Box<dyn TransitCryptoInitFinalizer>)
     }
 }
 
 type DynTransitCrypto = (Box<dyn TransitCryptoEncrypt>, Box<dyn TransitCryptoDecrypt>);
 
+/* Both directions reuse a buffer for all records and encrypt in place. Each record is written
+ * to the socket with a single call, including its length prefix.
+ */
 #[async_trait]
 pub(super) trait TransitCryptoEncrypt: Send {
     async fn encrypt(
@@ -376,17 +420,23 @@ pub(super) trait TransitCryptoEncrypt: Send {
 
 #[async_trait]
 pub(super) trait TransitCryptoDecrypt: Send {
-    async fn decrypt(
-        &mut self,
+    /** Receive and decrypt a record. It borrows an internal buffer, which is reused for the next one. */
+    async fn decrypt<'a>(
+        &'a mut self,
         socket: &mut dyn TransitTransportRx,
-    ) -> Result<Box<[u8]>, TransitError>;
+    ) -> Result<&'a [u8], TransitError>;
 }
 
+const SECRETBOX_NONCE_SIZE: usize = secretbox::SecretBox::<secretbox::XSalsa20Poly1305>::NONCE_SIZE;
+const SECRETBOX_TAG_SIZE: usize = secretbox::SecretBox::<secretbox::XSalsa20Poly1305>::TAG_SIZE;
+
 struct SecretboxCryptoEncrypt {
     /** Our key, used for sending */
     pub skey: Key<TransitTxKey>,
     /** Nonce for sending */
     pub snonce: secretbox::Nonce,
+    /** The length prefix, nonce, MAC and ciphertext of the record being sent */
+    pub buffer: Vec<u8>,
 }
 
 struct SecretboxCryptoDecrypt {
@@ -398,6 +448,8 @@ struct SecretboxCryptoDecrypt {
      * We'll count as receiver and track if messages come in in order
      */
     pub rnonce: secretbox::Nonce,
+    /** The nonce, MAC and ciphertext of the last received record, decrypted in place */
+    pub buffer: Vec<u8>,
 }
 
 #[async_trait]
@@ -407,25 +459,25 @@ impl TransitCryptoEncrypt for SecretboxCryptoEncrypt {
         socket: &mut dyn TransitTransportTx,
         plaintext: &[u8],
     ) -> Result<(), TransitError> {
+        const HEADER_SIZE: usize = 4 + SECRETBOX_NONCE_SIZE + SECRETBOX_TAG_SIZE;
         let nonce = &mut self.snonce;
-        let sodium_key = secretbox::Key::from_slice(&self.skey);
+        let buffer = &mut self.buffer;
 
-        let ciphertext = {
-            let nonce_le = secretbox::Nonce::from_slice(nonce);
+        /* Length prefix, nonce and a placeholder for the MAC, which libsodium puts in front */
+        buffer.clear();
+        buffer.extend_from_slice(&((HEADER_SIZE - 4 + plaintext.len()) as u32).to_be_bytes());
+        buffer.extend_from_slice(nonce);
+        buffer.extend_from_slice(&[0; SECRETBOX_TAG_SIZE]);
+        buffer.extend_from_slice(plaintext);
 
-            let cipher = secretbox::XSalsa20Poly1305::new(sodium_key);
-            cipher
-                .encrypt(nonce_le, plaintext)
-                /* TODO replace with (TransitError::Crypto) after the next xsalsa20poly1305 update */
-                .map_err(|_| TransitError::Crypto)?
-        };
+        let cipher = secretbox::XSalsa20Poly1305::new(secretbox::Key::from_slice(&self.skey));
+        let tag = cipher
+            .encrypt_in_place_detached(nonce, &[], &mut buffer[HEADER_SIZE..])
+            .map_err(|_| TransitError::Crypto)?;
+        buffer[HEADER_SIZE - SECRETBOX_TAG_SIZE..HEADER_SIZE].copy_from_slice(&tag);
 
         // send the encrypted record
-        socket
-            .write_all(&((ciphertext.len() + nonce.len()) as u32).to_be_bytes())
-            .await?;
-        socket.write_all(nonce).await?;
-        socket.write_all(&ciphertext).await?;
+        socket.write_all(buffer).await?;
 
         crate::util::sodium_increment_be(nonce);
 
@@ -435,54 +487,63 @@ impl TransitCryptoEncrypt for SecretboxCryptoEncrypt {
 
 #[async_trait]
 impl TransitCryptoDecrypt for SecretboxCryptoDecrypt {
-    async fn decrypt(
-        &mut self,
+    async fn decrypt<'a>(
+        &'a mut self,
         socket: &mut dyn TransitTransportRx,
-    ) -> Result<Box<[u8]>, TransitError> {
+    ) -> Result<&'a [u8], TransitError> {
         let nonce = &mut self.rnonce;
 
-        let enc_packet = socket.read_transit_message().await?;
+        socket.read_transit_message_into(&mut self.buffer).await?;
 
         use std::io::{Error, ErrorKind};
         ensure!(
-            enc_packet.len() >= secretbox::SecretBox::<secretbox::XSalsa20Poly1305>::NONCE_SIZE,
+            self.buffer.len() >= SECRETBOX_NONCE_SIZE,
             Error::new(
                 ErrorKind::InvalidData,
                 "Message must be long enough to contain at least the nonce"
             )
         );
 
-        // 3. decrypt the vector 'enc_packet' with the key.
-        let plaintext = {
-            let (received_nonce, ciphertext) = enc_packet
-                .split_at(secretbox::SecretBox::<secretbox::XSalsa20Poly1305>::NONCE_SIZE);
-            {
-                // Nonce check
-                ensure!(
-                    nonce.as_slice() == received_nonce,
-                    TransitError::Nonce(received_nonce.into(), nonce.as_slice().into()),
-                );
-
-                crate::util::sodium_increment_be(nonce);
-            }
+        // 3. decrypt the buffer in place with the key.
+        let (received_nonce, ciphertext) = self.buffer.split_at_mut(SECRETBOX_NONCE_SIZE);
+        {
+            // Nonce check
+            ensure!(
+                nonce.as_slice() == received_nonce,
+                TransitError::Nonce((*received_nonce).into(), nonce.as_slice().into()),
+            );
 
-            let cipher = secretbox::XSalsa20Poly1305::new(secretbox::Key::from_slice(&self.rkey));
-            cipher
-                .decrypt(secretbox::Nonce::from_slice(received_nonce), ciphertext)
-                /* TODO replace with (TransitError::Crypto) after the next xsalsa20poly1305 update */
-                .map_err(|_| TransitError::Crypto)?
-        };
+            crate::util::sodium_increment_be(nonce);
+        }
+
+        ensure!(ciphertext.len() >= SECRETBOX_TAG_SIZE, TransitError::Crypto);
+        let (tag, ciphertext) = ciphertext.split_at_mut(SECRETBOX_TAG_SIZE);
+        let cipher = secretbox::XSalsa20Poly1305::new(secretbox::Key::from_slice(&self.rkey));
+        cipher
+            .decrypt_in_place_detached(
+                secretbox::Nonce::from_slice(received_nonce),
+                &[],
+                ciphertext,
+                secretbox::aead::Tag::<secretbox::XSalsa20Poly1305>::from_slice(tag),
+            )
+            .map_err(|_| TransitError::Crypto)?;
 
-        Ok(plaintext.into_boxed_slice())
+        Ok(&self.buffer[SECRETBOX_NONCE_SIZE + SECRETBOX_TAG_SIZE..])
     }
 }
 
+const NOISE_TAG_SIZE: usize = 16;
+
 struct NoiseCryptoEncrypt {
     tx: NoiseCipherState,
+    /** The length prefix and ciphertext of the record being sent */
+    buffer: Vec<u8>,
 }
 
 struct NoiseCryptoDecrypt {
     rx: NoiseCipherState,
+    /** The last received record, decrypted in place */
+    buffer: Vec<u8>,
 }
 
 #[async_trait]
@@ -492,20 +553,32 @@ impl TransitCryptoEncrypt for NoiseCryptoEncrypt {
         socket: &mut dyn TransitTransportTx,
         plaintext: &[u8],
     ) -> Result<(), TransitError> {
-        socket
-            .write_transit_message(&self.tx.encrypt_vec(plaintext))
-            .await?;
+        /* The noise spec limits messages to 65535 bytes including the tag. Records of RECORD_SIZE
+         * deliberately exceed that, since the length prefix has four bytes and larger records
+         * mean more throughput. Dilation, where the peer might enforce the limit, keeps its
+         * records well below it. */
+        let buffer = &mut self.buffer;
+        buffer.clear();
+        buffer.extend_from_slice(&((plaintext.len() + NOISE_TAG_SIZE) as u32).to_be_bytes());
+        buffer.extend_from_slice(plaintext);
+        buffer.extend_from_slice(&[0; NOISE_TAG_SIZE]);
+        self.tx.encrypt_in_place(&mut buffer[4..], plaintext.len());
+
+        socket.write_all(buffer).await?;
         Ok(())
     }
 }
 
 #[async_trait]
 impl TransitCryptoDecrypt for NoiseCryptoDecrypt {
-    async fn decrypt(
-        &mut self,
+    async fn decrypt<'a>(
+        &'a mut self,
         socket: &mut dyn TransitTransportRx,
-    ) -> Result<Box<[u8]>, TransitError> {
-        let plaintext = self.rx.decrypt_vec(&socket.read_transit_message().await?)?;
-        Ok(plaintext.into_boxed_slice())
+    ) -> Result<&'a [u8], TransitError> {
+        socket.read_transit_message_into(&mut self.buffer).await?;
+        let ciphertext_len = self.buffer.len();
+        ensure!(ciphertext_len >= NOISE_TAG_SIZE, TransitError::Crypto);
+        let plaintext_len = self.rx.decrypt_in_place(&mut self.buffer, ciphertext_len)?;
+        Ok(&self.buffer[..plaintext_len])
     }
 }
@@ -0,0 +1,299 @@
+//! Move a relayed transit connection to a direct one while it is in use
+//!
+//! If we had to start on a relay, the remaining connection attempts continue in the background.
+//! Once one of them yields a direct connection, the leader finalizes its handshake there, and the
+//! follower acknowledges it with `migrate\n`. From then on, each side moves its writing to the
+//! new connection whenever it is ready: it first sends how many bytes it wrote to the old one (as
+//! big endian `u64`), and then continues on the new one. The reading side stitches both byte
+//! streams together at that offset. Once both directions moved, the old connection is closed.
+//!
+//! The record encryption doesn't notice any of this, it continues over the stitched byte stream.
+//! This also means that a tampered offset will make the decryption fail instead of going unnoticed.
+
+use super::{
+    ConnectionType, HandshakeResult, TransitHandshakeError, TransitInfo, TransitTransport,
+    TransitTransportRx,
+};
+use futures::{
+    future::BoxFuture, stream::BoxStream, AsyncRead, AsyncWrite, AsyncWriteExt, FutureExt,
+    StreamExt,
+};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The follower's confirmation that it will use the new connection as well
+const ACK: &[u8] = b"migrate\n";
+
+type Finalizing =
+    BoxFuture<'static, Result<(Box<dyn TransitTransport>, TransitInfo), TransitHandshakeError>>;
+
+/**
+ * A transit socket that moves to a direct connection once one comes up
+ *
+ * Connection attempts are only made progress on while the socket is in use.
+ */
+pub(super) struct MigratingTransport {
+    is_leader: bool,
+    /** The connection we started with, until both directions moved away from it */
+    old: Option<Box<dyn TransitTransport>>,
+    /** The direct connection, once both sides agreed on it */
+    new: Option<Box<dyn TransitTransport>>,
+    /** The remaining connection attempts */
+    probe: Option<BoxStream<'static, HandshakeResult>>,
+    /** Completing the handshake on a new direct connection */
+    finalizing: Option<Finalizing>,
+    /** Bytes written to the old connection */
+    written: u64,
+    /** How much of the offset we already sent on the new connection */
+    header_sent: usize,
+    write_moved: bool,
+    /** Bytes read from the old connection */
+    read: u64,
+    /** Where the peer stopped writing to the old connection, once we received it */
+    header: [u8; 8],
+    header_received: usize,
+    read_moved: bool,
+}
+
+impl MigratingTransport {
+    pub(super) fn new(
+        socket: Box<dyn TransitTransport>,
+        is_leader: bool,
+        probe: BoxStream<'static, HandshakeResult>,
+    ) -> Self {
+        Self {
+            is_leader,
+            old: Some(socket),
+            new: None,
+            probe: Some(probe),
+            finalizing: None,
+            written: 0,
+            header_sent: 0,
+            write_moved: false,
+            read: 0,
+            header: [0; 8],
+            header_received: 0,
+            read_moved: false,
+        }
+    }
+
+    fn finalize(&self, (mut socket, finalizer, info): HandshakeResult) -> Finalizing {
+        let is_leader = self.is_leader;
+        async move {
+            /* We keep using the encryption of the old connection, this only completes the handshake */
+            finalizer.handshake_finalize(&mut socket).await?;
+            if is_leader {
+                socket.read_expect(ACK).await?;
+            } else {
+                socket.write_all(ACK).await?;
+                socket.flush().await?;
+            }
+            Ok((socket, info))
+        }
+        .boxed()
+    }
+
+    /** Make progress on finding a direct connection */
+    fn poll_probe(&mut self, cx: &mut Context<'_>) {
+        loop {
+            if let Some(finalizing) = &mut self.finalizing {
+                match finalizing.poll_unpin(cx) {
+                    Poll::Ready(Ok((socket, info))) => {
+                        tracing::info!("Moving the transit connection: {info}");
+                        self.new = Some(socket);
+                        self.finalizing = None;
+                        /* Cancel all other attempts */
+                        self.probe = None;
+                        return;
+                    },
+                    Poll::Ready(Err(error)) => {
+                        tracing::debug!("Moving to a direct connection failed: {error}");
+                        self.finalizing = None;
+                    },
+                    Poll::Pending => return,
+                }
+            }
+
+            let Some(probe) = &mut self.probe else {
+                return;
+            };
+            match probe.poll_next_unpin(cx) {
+                Poll::Ready(Some(connection)) => {
+                    /* We only care about better connections than the one we have */
+                    if connection.2.conn_type == ConnectionType::Direct {
+                        self.finalizing = Some(self.finalize(connection));
+                    }
+                },
+                Poll::Ready(None) => {
+                    tracing::debug!("Found no direct connection to move to");
+                    self.probe = None;
+                    return;
+                },
+                Poll::Pending => return,
+            }
+        }
+    }
+
+    /** Once we have the new connection, move our writing to it */
+    fn poll_move_write(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let (Some(old), Some(new), false) = (&mut self.old, &mut self.new, self.write_moved) else {
+            return Poll::Ready(Ok(()));
+        };
+        futures::ready!(Pin::new(old).poll_flush(cx))?;
+        let header = self.written.to_be_bytes();
+        while self.header_sent < header.len() {
+            let sent =
+                futures::ready!(Pin::new(&mut *new).poll_write(cx, &header[self.header_sent..]))?;
+            if sent == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.header_sent += sent;
+        }
+        tracing::debug!("Moved writing after {} bytes", self.written);
+        self.write_moved = true;
+        self.close_old_if_done();
+        Poll::Ready(Ok(()))
+    }
+
+    fn close_old_if_done(&mut self) {
+        if self.write_moved && self.read_moved {
+            tracing::debug!("Closing the old transit connection");
+            self.old = None;
+        }
+    }
+}
+
+impl AsyncRead for MigratingTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        this.poll_probe(cx);
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            if this.read_moved {
+                let new = this
+                    .new
+                    .as_mut()
+                    .expect("We only move to an existing connection");
+                return Pin::new(new).poll_read(cx, buf);
+            }
+
+            /* Learn where the peer stopped writing to the old connection */
+            if let Some(new) = &mut this.new {
+                while this.header_received < this.header.len() {
+                    match Pin::new(&mut *new)
+                        .poll_read(cx, &mut this.header[this.header_received..])
+                    {
+                        Poll::Ready(Ok(0)) => {
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                        },
+                        Poll::Ready(Ok(read)) => this.header_received += read,
+                        Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                        Poll::Pending => break,
+                    }
+                }
+            }
+            let moved_at = (this.header_received == this.header.len())
+                .then(|| u64::from_be_bytes(this.header));
+
+            let mut limit = buf.len();
+            if let Some(moved_at) = moved_at {
+                if this.read > moved_at {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "the peer moved to the new connection before data we already received",
+                    )));
+                }
+                if this.read == moved_at {
+                    tracing::debug!("Moved reading after {} bytes", this.read);
+                    this.read_moved = true;
+                    this.close_old_if_done();
+                    continue;
+                }
+                limit = limit.min((moved_at - this.read).try_into().unwrap_or(usize::MAX));
+            }
+
+            let old = this
+                .old
+                .as_mut()
+                .expect("We read from the old connection until moving");
+            let read = futures::ready!(Pin::new(old).poll_read(cx, &mut buf[..limit]))?;
+            if read == 0 {
+                if moved_at.is_some() {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                if this.new.is_some() {
+                    /* The peer closed the old connection, we will learn where to continue */
+                    return Poll::Pending;
+                }
+            }
+            this.read += read as u64;
+            return Poll::Ready(Ok(read));
+        }
+    }
+}
+
+impl AsyncWrite for MigratingTransport {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        this.poll_probe(cx);
+        futures::ready!(this.poll_move_write(cx))?;
+
+        if this.write_moved {
+            let new = this
+                .new
+                .as_mut()
+                .expect("We only move to an existing connection");
+            Pin::new(new).poll_write(cx, buf)
+        } else {
+            let old = this
+                .old
+                .as_mut()
+                .expect("We write to the old connection until moving");
+            let written = futures::ready!(Pin::new(old).poll_write(cx, buf))?;
+            this.written += written as u64;
+            Poll::Ready(Ok(written))
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        this.poll_probe(cx);
+        futures::ready!(this.poll_move_write(cx))?;
+        if let Some(old) = &mut this.old {
+            futures::ready!(Pin::new(old).poll_flush(cx))?;
+        }
+        if let Some(new) = &mut this.new {
+            futures::ready!(Pin::new(new).poll_flush(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        this.probe = None;
+        this.finalizing = None;
+        /* Tell the peer where we stopped, so that it can tell a closed connection from a moved one */
+        futures::ready!(this.poll_move_write(cx))?;
+        if let Some(old) = &mut this.old {
+            futures::ready!(Pin::new(old).poll_close(cx))?;
+        }
+        if let Some(new) = &mut this.new {
+            futures::ready!(Pin::new(new).poll_close(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
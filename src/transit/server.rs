@@ -0,0 +1,641 @@
+//! A transit relay server implementation
+//!
+//! When two clients can't reach each other directly, they both connect to a relay server, which glues
+//! the two connections together. This server speaks the same protocol as the [Python transit relay](https://github.com/magic-wormhole/magic-wormhole-transit-relay),
+//! over plain TCP and optionally over WebSockets (which is what clients in the browser use).
+//!
+//! Each client starts with a line `please relay <token> for side <side>\n`. Two clients with the same token
+//! (but different sides) get paired, both receive `ok\n` and from then on all bytes are forwarded unchanged.
+//! The relay never sees any plaintext, as the transit connection is encrypted end-to-end.
+//!
+//! ```no_run
+//! use magic_wormhole::transit::server::RelayServer;
+//! # #[async_std::main] async fn main() -> std::io::Result<()> {
+//! let server = RelayServer::bind("[::]:4001")
+//!     .await?
+//!     .websocket("[::]:4002")
+//!     .await?;
+//! println!("Listening on {:?}", server.relay_hint()?);
+//! server.run().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use async_tungstenite::tungstenite as ws2;
+use futures::{channel::oneshot, prelude::*};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::RelayHint;
+
+/// Close connections after this time without any traffic, unless configured otherwise
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Handshake lines longer than this are rejected
+const MAX_HANDSHAKE_LENGTH: usize = 1024;
+/// Read buffer size for TCP connections
+const BUFFER_LEN: usize = 64 * 1024;
+/// Clients may send this much data before they get paired, more than that gets them disconnected
+const MAX_EARLY_DATA: usize = 4 * 1024;
+/// Wait at least this long after accepting a connection failed, doubling up to [`MAX_ACCEPT_BACKOFF`]
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/** Usage statistics of a [`RelayServer`], since it was started */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Usage {
+    /// Accepted connections, over TCP and WebSocket
+    pub connections: u64,
+    /// Pairs of connections that got relayed
+    pub sessions: u64,
+    /// Sessions that are currently being relayed
+    pub active_sessions: u64,
+    /// Connections that went away or timed out before a partner showed up
+    pub lonely: u64,
+    /// Connections that sent too much data before a partner showed up
+    pub impatient: u64,
+    /// Connections that sent an invalid handshake or failed with an error
+    pub errory: u64,
+    /// Sessions that got closed because there was no traffic for too long
+    pub timed_out: u64,
+    /// Total number of bytes forwarded between clients, in both directions
+    pub bytes_relayed: u64,
+}
+
+/**
+ * Shared handle to the usage counters of a [`RelayServer`]
+ *
+ * Obtain it with [`RelayServer::usage`] before starting the server, it stays valid while the server runs.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct UsageHandle(Arc<Mutex<Usage>>);
+
+impl UsageHandle {
+    /// A snapshot of the current counters
+    pub fn get(&self) -> Usage {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn update(&self, update: impl FnOnce(&mut Usage)) {
+        update(&mut self.0.lock().unwrap())
+    }
+}
+
+/// A transit relay server, listening for TCP and optionally WebSocket connections
+pub struct RelayServer {
+    tcp: TcpListener,
+    websocket: Option<TcpListener>,
+    idle_timeout: Duration,
+    usage: UsageHandle,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl std::fmt::Debug for RelayServer {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("RelayServer")
+            .field("tcp", &self.tcp)
+            .field("websocket", &self.websocket)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("usage", &self.usage)
+            .finish()
+    }
+}
+
+impl RelayServer {
+    /// Listen for TCP connections on the given address. Use port 0 to get a free port assigned by the OS.
+    pub async fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            tcp: TcpListener::bind(address).await?,
+            websocket: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            usage: Default::default(),
+            pending: Default::default(),
+        })
+    }
+
+    /// Additionally listen for WebSocket connections on the given address
+    pub async fn websocket(mut self, address: impl ToSocketAddrs) -> io::Result<Self> {
+        self.websocket = Some(TcpListener::bind(address).await?);
+        Ok(self)
+    }
+
+    /**
+     * Close connections without any traffic for this long
+     *
+     * This applies both to clients waiting for their partner and to relayed sessions.
+     * The default is [`DEFAULT_IDLE_TIMEOUT`].
+     */
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// The address of the TCP listener
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.local_addr()
+    }
+
+    /// The address of the WebSocket listener, if any
+    pub fn websocket_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.websocket.as_ref().map(TcpListener::local_addr)
+    }
+
+    /**
+     * A relay hint pointing to this server
+     *
+     * It is built from the addresses the server is listening on. If these are unspecified
+     * (like `[::]`), clients on other machines will need a hint with the actual hostname instead.
+     */
+    pub fn relay_hint(&self) -> io::Result<RelayHint> {
+        let mut urls = vec![format!("tcp://{}", self.local_addr()?)];
+        if let Some(address) = self.websocket_addr() {
+            urls.push(format!("ws://{}/", address?));
+        }
+        Ok(RelayHint::from_urls(
+            None,
+            urls.iter()
+                .map(|url| url.parse().expect("Invalid relay URL")),
+        )
+        .expect("Invalid relay URL"))
+    }
+
+    /// Get a handle to the usage statistics of this server
+    pub fn usage(&self) -> UsageHandle {
+        self.usage.clone()
+    }
+
+    /**
+     * Serve clients
+     *
+     * This runs forever. Errors with individual clients are logged and only terminate the
+     * respective connection. Failing to accept new connections (for example when running out
+     * of file descriptors) is logged as well, and retried after a short pause.
+     */
+    pub async fn run(self) -> io::Result<()> {
+        let tcp = self.accept(&self.tcp, false);
+        let websocket = async {
+            match &self.websocket {
+                Some(listener) => self.accept(listener, true).await,
+                None => future::pending().await,
+            }
+        };
+
+        futures::pin_mut!(tcp);
+        futures::pin_mut!(websocket);
+        future::select(tcp, websocket).await.factor_first().0
+    }
+
+    async fn accept(&self, listener: &TcpListener, websocket: bool) -> io::Result<()> {
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+        for connection_id in 0.. {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    connection
+                },
+                Err(error) => {
+                    tracing::warn!("Failed to accept a connection: {error}");
+                    async_std::task::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                },
+            };
+            tracing::debug!("New connection from {peer}");
+            self.usage.update(|usage| usage.connections += 1);
+
+            let session = Session {
+                id: (connection_id << 1) | websocket as u64,
+                idle_timeout: self.idle_timeout,
+                usage: self.usage.clone(),
+                pending: self.pending.clone(),
+            };
+            async_std::task::spawn(async move {
+                let result = if websocket {
+                    match async_tungstenite::accept_async(stream).await {
+                        Ok(stream) => session.run(websocket_io(stream)).await,
+                        Err(error) => Err(io::Error::other(error)),
+                    }
+                } else {
+                    session.run(tcp_io(stream)).await
+                };
+                if let Err(error) = result {
+                    tracing::debug!("Connection from {peer} failed: {error}");
+                }
+            });
+        }
+        unreachable!()
+    }
+}
+
+type Reader = Pin<Box<dyn Stream<Item = io::Result<Vec<u8>>> + Send>>;
+type Writer = Pin<Box<dyn Sink<Vec<u8>, Error = io::Error> + Send>>;
+
+/// A connection that finished its handshake and waits for its partner
+struct Waiting {
+    id: u64,
+    side: Option<String>,
+    partner: oneshot::Sender<(Reader, Writer, Vec<u8>)>,
+}
+
+/// Waiting connections by token
+type Pending = HashMap<String, Vec<Waiting>>;
+
+fn tcp_io(stream: TcpStream) -> (Reader, Writer) {
+    let reader = stream::try_unfold(stream.clone(), |mut stream| async move {
+        let mut buffer = vec![0; BUFFER_LEN];
+        let n = stream.read(&mut buffer).await?;
+        buffer.truncate(n);
+        Ok((n > 0).then_some((buffer, stream)))
+    });
+    let writer = sink::unfold(stream, |mut stream, data: Vec<u8>| async move {
+        stream.write_all(&data).await?;
+        Ok::<_, io::Error>(stream)
+    });
+    (Box::pin(reader), Box::pin(writer))
+}
+
+fn websocket_io<S>(stream: async_tungstenite::WebSocketStream<S>) -> (Reader, Writer)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (writer, reader) = stream.split();
+    let reader = reader
+        .map_err(io::Error::other)
+        .try_take_while(|message| future::ready(Ok(!message.is_close())))
+        /* Pings get answered by the websocket library */
+        .try_filter_map(|message| {
+            future::ready(Ok(match message {
+                ws2::Message::Binary(data) => Some(data),
+                ws2::Message::Text(text) => Some(text.into_bytes()),
+                _ => None,
+            }))
+        });
+    let writer = writer
+        .sink_map_err(io::Error::other)
+        .with(|data| future::ready(Ok(ws2::Message::Binary(data))));
+    (Box::pin(reader), Box::pin(writer))
+}
+
+/**
+ * Parse a handshake line (without the newline)
+ *
+ * Returns the token and the side, if given. Very old clients don't send their side.
+ */
+fn parse_handshake(line: &[u8]) -> Option<(String, Option<String>)> {
+    let is_hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|c| c.is_ascii_hexdigit());
+
+    let line = std::str::from_utf8(line)
+        .ok()?
+        .strip_prefix("please relay ")?;
+    let (token, side) = match line.split_once(" for side ") {
+        Some((token, side)) => (token, Some(side)),
+        None => (line, None),
+    };
+    if !is_hex(token, 64) || side.is_some_and(|side| !is_hex(side, 16)) {
+        return None;
+    }
+    Some((token.to_owned(), side.map(str::to_owned)))
+}
+
+/// Everything a connection needs from the server
+struct Session {
+    id: u64,
+    idle_timeout: Duration,
+    usage: UsageHandle,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl Session {
+    async fn run(self, (mut reader, mut writer): (Reader, Writer)) -> io::Result<()> {
+        let result = self.relay(&mut reader, &mut writer).await;
+        if result.is_err() {
+            self.usage.update(|usage| usage.errory += 1);
+        }
+        let _ = writer.close().await;
+        result
+    }
+
+    async fn relay(&self, reader: &mut Reader, writer: &mut Writer) -> io::Result<()> {
+        /* Read the handshake line. Anything after it gets forwarded to the partner later on */
+        let mut buffer = Vec::new();
+        let newline = loop {
+            if let Some(position) = buffer.iter().position(|&c| c == b'\n') {
+                break position;
+            }
+            if buffer.len() > MAX_HANDSHAKE_LENGTH {
+                return self.bad_handshake(writer).await;
+            }
+            match self.read(reader).await? {
+                Some(data) => buffer.extend_from_slice(&data),
+                /* The client went away, no harm done */
+                None => return Ok(()),
+            }
+        };
+        let Some((token, side)) = parse_handshake(&buffer[..newline]) else {
+            return self.bad_handshake(writer).await;
+        };
+        let early_data = buffer.split_off(newline + 1);
+        if early_data.len() > MAX_EARLY_DATA {
+            tracing::debug!("Connection {} sent too much data too early", self.id);
+            self.usage.update(|usage| usage.impatient += 1);
+            return Ok(());
+        }
+
+        /* Find a partner, or register and wait for one. Whoever comes second hands over their connection */
+        let mut partner = (
+            std::mem::replace(reader, Box::pin(stream::empty())),
+            std::mem::replace(writer, Box::pin(sink::drain().sink_map_err(|e| match e {}))),
+            early_data,
+        );
+        let receiver = loop {
+            let candidate = {
+                let mut pending = self.pending.lock().unwrap();
+                let waiting = pending.entry(token.clone()).or_default();
+                match waiting
+                    .iter()
+                    .position(|waiting| side.is_none() || waiting.side != side)
+                {
+                    Some(index) => {
+                        let candidate = waiting.remove(index);
+                        if waiting.is_empty() {
+                            pending.remove(&token);
+                        }
+                        candidate
+                    },
+                    None => {
+                        let (sender, receiver) = oneshot::channel();
+                        waiting.push(Waiting {
+                            id: self.id,
+                            side: side.clone(),
+                            partner: sender,
+                        });
+                        break receiver;
+                    },
+                }
+            };
+            match candidate.partner.send(partner) {
+                Ok(()) => {
+                    tracing::debug!("Paired connection {} with {}", self.id, candidate.id);
+                    return Ok(());
+                },
+                /* That one gave up in the meantime, try the next one */
+                Err(returned) => partner = returned,
+            }
+        };
+        *reader = partner.0;
+        *writer = partner.1;
+        let mut early_data = partner.2;
+
+        /* Wait for our partner, while watching our own connection */
+        futures::pin_mut!(receiver);
+        let (mut partner_reader, mut partner_writer, partner_data) = loop {
+            let next = future::select(receiver.as_mut(), reader.next());
+            match async_std::future::timeout(self.idle_timeout, next).await {
+                Ok(future::Either::Left((Ok(partner), _))) => break partner,
+                Ok(future::Either::Right((Some(Ok(data)), _)))
+                    if early_data.len() + data.len() <= MAX_EARLY_DATA =>
+                {
+                    early_data.extend(data)
+                },
+                Ok(future::Either::Right((Some(Ok(_)), _))) => {
+                    tracing::debug!("Connection {} sent too much data too early", self.id);
+                    self.unregister();
+                    self.usage.update(|usage| usage.impatient += 1);
+                    return Ok(());
+                },
+                result => {
+                    /* Timed out, disconnected or failed: give up */
+                    self.unregister();
+                    self.usage.update(|usage| usage.lonely += 1);
+                    return match result {
+                        Ok(future::Either::Right((Some(Err(error)), _))) => Err(error),
+                        _ => Ok(()),
+                    };
+                },
+            }
+        };
+
+        self.usage.update(|usage| {
+            usage.sessions += 1;
+            usage.active_sessions += 1;
+        });
+        let result = async {
+            writer.send(b"ok\n".to_vec()).await?;
+            partner_writer.send(b"ok\n".to_vec()).await?;
+            if !early_data.is_empty() {
+                self.forward(&mut partner_writer, early_data).await?;
+            }
+            if !partner_data.is_empty() {
+                self.forward(writer, partner_data).await?;
+            }
+            self.shuffle(reader, writer, &mut partner_reader, &mut partner_writer)
+                .await
+        }
+        .await;
+        let _ = partner_writer.close().await;
+        self.usage.update(|usage| usage.active_sessions -= 1);
+        result
+    }
+
+    /** Stop waiting for a partner */
+    fn unregister(&self) {
+        self.pending.lock().unwrap().retain(|_, waiting| {
+            waiting.retain(|waiting| waiting.id != self.id);
+            !waiting.is_empty()
+        });
+    }
+
+    /** Forward data between both sides, until one of them closes the connection */
+    async fn shuffle(
+        &self,
+        reader: &mut Reader,
+        writer: &mut Writer,
+        partner_reader: &mut Reader,
+        partner_writer: &mut Writer,
+    ) -> io::Result<()> {
+        loop {
+            let next = future::select(reader.next(), partner_reader.next());
+            match async_std::future::timeout(self.idle_timeout, next).await {
+                Err(_) => {
+                    tracing::debug!("Session of connection {} timed out", self.id);
+                    self.usage.update(|usage| usage.timed_out += 1);
+                    return Ok(());
+                },
+                Ok(future::Either::Left((Some(data), _))) => {
+                    self.forward(partner_writer, data?).await?
+                },
+                Ok(future::Either::Right((Some(data), _))) => self.forward(writer, data?).await?,
+                /* One side closed the connection, so we close the other one as well */
+                Ok(_) => return Ok(()),
+            }
+        }
+    }
+
+    async fn forward(&self, writer: &mut Writer, data: Vec<u8>) -> io::Result<()> {
+        let len = data.len() as u64;
+        writer.send(data).await?;
+        self.usage.update(|usage| usage.bytes_relayed += len);
+        Ok(())
+    }
+
+    async fn read(&self, reader: &mut Reader) -> io::Result<Option<Vec<u8>>> {
+        async_std::future::timeout(self.idle_timeout, reader.next())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Handshake timed out"))?
+            .transpose()
+    }
+
+    async fn bad_handshake(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.send(b"bad handshake\n".to_vec()).await?;
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid relay handshake",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    async fn connect(server: &RelayServer, side: &str) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(server.local_addr()?).await?;
+        stream
+            .write_all(format!("please relay {TOKEN} for side {side}\n").as_bytes())
+            .await?;
+        Ok(stream)
+    }
+
+    async fn expect(stream: &mut (impl AsyncRead + Unpin), expected: &[u8]) -> io::Result<()> {
+        let mut buffer = vec![0; expected.len()];
+        stream.read_exact(&mut buffer).await?;
+        assert_eq!(buffer, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_handshake() {
+        assert_eq!(
+            parse_handshake(format!("please relay {TOKEN} for side 0123456789abcdef").as_bytes()),
+            Some((TOKEN.into(), Some("0123456789abcdef".into())))
+        );
+        assert_eq!(
+            parse_handshake(format!("please relay {TOKEN}").as_bytes()),
+            Some((TOKEN.into(), None))
+        );
+        assert_eq!(
+            parse_handshake(b"please relay 1234 for side 0123456789abcdef"),
+            None
+        );
+        assert_eq!(
+            parse_handshake(format!("please relay {TOKEN} for side me").as_bytes()),
+            None
+        );
+        assert_eq!(parse_handshake(b"GET / HTTP/1.1"), None);
+    }
+
+    #[async_std::test]
+    async fn test_relay() -> io::Result<()> {
+        let server = RelayServer::bind("127.0.0.1:0").await?;
+        let usage = server.usage();
+        let mut first = connect(&server, "0000000000000001").await?;
+        let mut second = connect(&server, "0000000000000002").await?;
+        async_std::task::spawn(server.run());
+
+        expect(&mut first, b"ok\n").await?;
+        expect(&mut second, b"ok\n").await?;
+        first.write_all(b"hello").await?;
+        expect(&mut second, b"hello").await?;
+        second.write_all(b"world!").await?;
+        expect(&mut first, b"world!").await?;
+
+        /* Closing one side closes the other as well */
+        drop(first);
+        assert_eq!(second.read(&mut [0; 16]).await?, 0);
+
+        let usage = usage.get();
+        assert_eq!(usage.connections, 2);
+        assert_eq!(usage.sessions, 1);
+        assert_eq!(usage.bytes_relayed, 11);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_bad_handshake() -> io::Result<()> {
+        let server = RelayServer::bind("127.0.0.1:0").await?;
+        let usage = server.usage();
+        let mut stream = TcpStream::connect(server.local_addr()?).await?;
+        async_std::task::spawn(server.run());
+
+        stream.write_all(b"please relay me\n").await?;
+        expect(&mut stream, b"bad handshake\n").await?;
+        assert_eq!(stream.read(&mut [0; 16]).await?, 0);
+        assert_eq!(usage.get().errory, 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_idle_timeout() -> io::Result<()> {
+        let server = RelayServer::bind("127.0.0.1:0")
+            .await?
+            .idle_timeout(Duration::from_millis(100));
+        let usage = server.usage();
+        let mut stream = connect(&server, "0000000000000001").await?;
+        async_std::task::spawn(server.run());
+
+        /* Nobody else shows up */
+        assert_eq!(stream.read(&mut [0; 16]).await?, 0);
+        assert_eq!(usage.get().lonely, 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_impatient() -> io::Result<()> {
+        let server = RelayServer::bind("127.0.0.1:0").await?;
+        let usage = server.usage();
+        let mut stream = connect(&server, "0000000000000001").await?;
+        async_std::task::spawn(server.run());
+
+        /* A bit of data gets buffered for the partner, but not arbitrarily much */
+        stream.write_all(b"hello").await?;
+        stream.write_all(&[0; MAX_EARLY_DATA]).await?;
+        assert_eq!(stream.read(&mut [0; 16]).await?, 0);
+        assert_eq!(usage.get().impatient, 1);
+        assert_eq!(usage.get().lonely, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_websocket() -> io::Result<()> {
+        let server = RelayServer::bind("127.0.0.1:0")
+            .await?
+            .websocket("127.0.0.1:0")
+            .await?;
+        let url = format!("ws://{}/", server.websocket_addr().unwrap()?);
+        let mut tcp = connect(&server, "0000000000000001").await?;
+        async_std::task::spawn(server.run());
+
+        let (websocket, _) = async_tungstenite::async_std::connect_async(url)
+            .await
+            .map_err(io::Error::other)?;
+        let (mut reader, mut writer) = websocket_io(websocket);
+        writer
+            .send(format!("please relay {TOKEN} for side 0000000000000002\n").into_bytes())
+            .await?;
+
+        expect(&mut tcp, b"ok\n").await?;
+        assert_eq!(reader.next().await.transpose()?, Some(b"ok\n".to_vec()));
+        tcp.write_all(b"hello").await?;
+        assert_eq!(reader.next().await.transpose()?, Some(b"hello".to_vec()));
+        Ok(())
+    }
+}
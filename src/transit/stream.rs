@@ -0,0 +1,288 @@
+//! A byte stream on top of the encrypted record pipe of a [`Transit`](super::Transit)
+//!
+//! Writes are collected into records of up to [`RECORD_SIZE`] bytes, which are encrypted and sent
+//! once they are full or when the stream is flushed. Closing the stream sends an empty record as
+//! authenticated end-of-stream marker, so that the reader can tell a clean shutdown apart from a
+//! connection that broke. Both sides must use a [`TransitStream`], since plain records sent with
+//! [`send_record`](super::Transit::send_record) are not framed in any way.
+
+use super::{RecordReceiver, RecordSender, TransitError, RECORD_SIZE};
+use futures::{future::BoxFuture, stream::BoxStream, AsyncRead, AsyncWrite, FutureExt, StreamExt};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+fn into_io_error(error: TransitError) -> std::io::Error {
+    match error {
+        TransitError::IO(error) => error,
+        error => std::io::Error::new(std::io::ErrorKind::InvalidData, error),
+    }
+}
+
+/**
+ * A [`Transit`](super::Transit) as reliable byte stream, implementing [`AsyncRead`] and [`AsyncWrite`]
+ *
+ * Created with [`Transit::into_stream`](super::Transit::into_stream). Like a `BufWriter`, written
+ * data is only sent once a whole record is full, so don't forget to `flush` or `close` the stream.
+ * Use [`split`](Self::split) to read and write concurrently from different tasks.
+ */
+pub struct TransitStream {
+    reader: TransitReader,
+    writer: TransitWriter,
+}
+
+impl TransitStream {
+    pub(super) fn new(sender: RecordSender, receiver: RecordReceiver) -> Self {
+        Self {
+            reader: TransitReader::new(receiver),
+            writer: TransitWriter {
+                state: WriterState::Idle((sender, Vec::with_capacity(RECORD_SIZE))),
+                buffer: Vec::with_capacity(RECORD_SIZE),
+                closed: false,
+            },
+        }
+    }
+
+    /** Split the stream into its reading and writing half */
+    pub fn split(self) -> (TransitReader, TransitWriter) {
+        (self.reader, self.writer)
+    }
+}
+
+impl AsyncRead for TransitStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TransitStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_close(cx)
+    }
+}
+
+/** The reading half of a [`TransitStream`] */
+pub struct TransitReader {
+    records: BoxStream<'static, Result<Box<[u8]>, TransitError>>,
+    buffer: Box<[u8]>,
+    offset: usize,
+    eof: bool,
+}
+
+impl TransitReader {
+    fn new(receiver: RecordReceiver) -> Self {
+        let records = futures::stream::try_unfold(receiver, |mut receiver| async move {
+            let record = receiver.receive().await?.into();
+            Ok(Some((record, receiver)))
+        })
+        .boxed();
+        Self {
+            records,
+            buffer: Box::default(),
+            offset: 0,
+            eof: false,
+        }
+    }
+}
+
+impl AsyncRead for TransitReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            if self.offset < self.buffer.len() {
+                let len = buf.len().min(self.buffer.len() - self.offset);
+                buf[..len].copy_from_slice(&self.buffer[self.offset..self.offset + len]);
+                self.offset += len;
+                return Poll::Ready(Ok(len));
+            }
+            if self.eof {
+                return Poll::Ready(Ok(0));
+            }
+            match futures::ready!(self.records.poll_next_unpin(cx)) {
+                /* The empty record marks the end of the stream */
+                Some(Ok(record)) if record.is_empty() => self.eof = true,
+                Some(Ok(record)) => {
+                    self.buffer = record;
+                    self.offset = 0;
+                },
+                Some(Err(error)) => return Poll::Ready(Err(into_io_error(error))),
+                None => unreachable!("The record stream only ends with an error"),
+            }
+        }
+    }
+}
+
+/* The connections, and the buffer of the last sent record for reuse */
+type WriterParts = (RecordSender, Vec<u8>);
+
+enum WriterState {
+    Idle(WriterParts),
+    /* Sending a record, or closing the socket */
+    Busy(BoxFuture<'static, (WriterParts, Result<(), TransitError>)>),
+    /* A previous operation failed, the socket is unusable */
+    Broken,
+}
+
+/**
+ * The writing half of a [`TransitStream`]
+ *
+ * While a record is being sent, the next one is already filled.
+ */
+pub struct TransitWriter {
+    state: WriterState,
+    buffer: Vec<u8>,
+    closed: bool,
+}
+
+impl TransitWriter {
+    /** Wait for the current operation to finish */
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.state {
+            WriterState::Idle(..) => Poll::Ready(Ok(())),
+            WriterState::Busy(operation) => {
+                let (parts, result) = futures::ready!(operation.poll_unpin(cx));
+                match result {
+                    Ok(()) => {
+                        self.state = WriterState::Idle(parts);
+                        Poll::Ready(Ok(()))
+                    },
+                    Err(error) => {
+                        self.state = WriterState::Broken;
+                        Poll::Ready(Err(into_io_error(error)))
+                    },
+                }
+            },
+            WriterState::Broken => Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "a previous write to the transit failed",
+            ))),
+        }
+    }
+
+    /** Start an operation on the socket. The writer must be idle. */
+    fn start(
+        &mut self,
+        operation: impl FnOnce(
+            WriterParts,
+        ) -> BoxFuture<'static, (WriterParts, Result<(), TransitError>)>,
+    ) {
+        match std::mem::replace(&mut self.state, WriterState::Broken) {
+            WriterState::Idle(parts) => self.start_with(parts, operation),
+            _ => unreachable!("The writer must be idle"),
+        }
+    }
+
+    fn start_with(
+        &mut self,
+        parts: WriterParts,
+        operation: impl FnOnce(
+            WriterParts,
+        ) -> BoxFuture<'static, (WriterParts, Result<(), TransitError>)>,
+    ) {
+        self.state = WriterState::Busy(operation(parts));
+    }
+
+    /** Start sending the buffered data as one record, once the previous one is sent */
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        futures::ready!(self.poll_idle(cx))?;
+        if self.buffer.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        let mut record = std::mem::take(&mut self.buffer);
+        let (sender, spare) = match std::mem::replace(&mut self.state, WriterState::Broken) {
+            WriterState::Idle(parts) => parts,
+            _ => unreachable!("The writer must be idle"),
+        };
+        /* Reuse the buffer of the previous record */
+        self.buffer = spare;
+        self.start_with((sender, Vec::new()), move |(mut sender, _)| {
+            async move {
+                let mut result = sender.send(&record).await;
+                if result.is_ok() {
+                    result = sender.flush().await;
+                }
+                record.clear();
+                ((sender, record), result)
+            }
+            .boxed()
+        });
+        /* Get the sending going */
+        match self.poll_idle(cx) {
+            Poll::Ready(Err(error)) => Poll::Ready(Err(error)),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    /** Send the buffered data and wait until it is written to the socket */
+    fn poll_flush_inner(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        futures::ready!(self.poll_send(cx))?;
+        self.poll_idle(cx)
+    }
+}
+
+impl AsyncWrite for TransitWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.closed {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "the transit stream is closed",
+            )));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if self.buffer.len() >= RECORD_SIZE {
+            futures::ready!(self.poll_send(cx))?;
+        }
+        let len = buf.len().min(RECORD_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_flush_inner(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        futures::ready!(self.poll_flush_inner(cx))?;
+        if !self.closed {
+            self.closed = true;
+            /* Send the end-of-stream marker, then shut down the socket */
+            self.start(|(mut sender, spare)| {
+                async move {
+                    let mut result = sender.send(&[]).await;
+                    if result.is_ok() {
+                        result = sender.close().await;
+                    }
+                    ((sender, spare), result)
+                }
+                .boxed()
+            });
+        }
+        self.poll_idle(cx)
+    }
+}
@@ -0,0 +1,122 @@
+//! Publishing temporary onion services through Tor's control port
+//!
+//! See the [control port specification](https://spec.torproject.org/control-spec/) for details.
+//! We only need three commands: `PROTOCOLINFO` to find out how to authenticate, `AUTHENTICATE`
+//! and `ADD_ONION`. The onion service lives as long as the control connection stays open.
+
+use super::DirectHint;
+use crate::proxy::Proxy;
+use async_std::net::{TcpListener, TcpStream};
+use futures::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+/** An onion service forwarding to a local listener. It is removed again when this is dropped. */
+pub(super) struct OnionService {
+    pub listener: TcpListener,
+    pub hint: DirectHint,
+    /** Keep this open, since closing the control connection removes the service */
+    pub control: TcpStream,
+}
+
+impl OnionService {
+    /** Listen on a local port and publish it as an onion service, using the control port of `tor` */
+    pub async fn create(tor: &Proxy, control_port: u16) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        let control = TcpStream::connect((tor.host.as_str(), control_port)).await?;
+        let mut connection = ControlConnection {
+            reader: BufReader::new(control.clone()),
+            writer: control.clone(),
+        };
+        connection.authenticate().await?;
+
+        /* The key is thrown away, the service is only needed for this one transfer */
+        let reply = connection
+            .command(&format!(
+                "ADD_ONION NEW:ED25519-V3 Flags=DiscardPK Port={},127.0.0.1:{}",
+                port, port
+            ))
+            .await?;
+        let service_id = reply
+            .iter()
+            .find_map(|line| line.strip_prefix("ServiceID="))
+            .ok_or_else(|| control_error("ADD_ONION did not return a service ID"))?;
+        let hint = DirectHint::new(format!("{}.onion", service_id), port);
+        tracing::debug!("Published onion service {}", hint);
+
+        Ok(Self {
+            listener,
+            hint,
+            control,
+        })
+    }
+}
+
+struct ControlConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl ControlConnection {
+    /** Send a command and return the lines of a successful reply, without the status code */
+    async fn command(&mut self, command: &str) -> std::io::Result<Vec<String>> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Err(control_error("the control connection was closed"));
+            }
+            let line = line.trim_end();
+            if !line.starts_with("250") {
+                return Err(control_error(&format!("Tor replied {:?}", line)));
+            }
+            /* "250-" continues the reply, "250 " ends it */
+            let (done, content) = (line.as_bytes().get(3) == Some(&b' '), line.get(4..));
+            lines.push(content.unwrap_or_default().to_owned());
+            if done {
+                return Ok(lines);
+            }
+        }
+    }
+
+    async fn authenticate(&mut self) -> std::io::Result<()> {
+        let reply = self.command("PROTOCOLINFO 1").await?;
+        let auth = reply
+            .iter()
+            .find_map(|line| line.strip_prefix("AUTH "))
+            .ok_or_else(|| control_error("PROTOCOLINFO did not list any authentication methods"))?;
+        let methods = auth
+            .split(' ')
+            .find_map(|field| field.strip_prefix("METHODS="))
+            .unwrap_or_default()
+            .split(',')
+            .collect::<Vec<_>>();
+        /* The path is quoted, but does not contain spaces on common setups */
+        let cookie_file = auth
+            .split(' ')
+            .find_map(|field| field.strip_prefix("COOKIEFILE="))
+            .map(|path| path.trim_matches('"').replace("\\\\", "\\"));
+
+        if methods.contains(&"NULL") {
+            self.command("AUTHENTICATE").await?;
+        } else if let (true, Some(cookie_file)) = (methods.contains(&"COOKIE"), cookie_file) {
+            let cookie = async_std::fs::read(&cookie_file).await?;
+            self.command(&format!("AUTHENTICATE {}", hex::encode(cookie)))
+                .await?;
+        } else {
+            return Err(control_error(&format!(
+                "unsupported authentication methods {:?}, enable CookieAuthentication",
+                methods
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn control_error(message: &str) -> std::io::Error {
+    std::io::Error::other(format!("Tor control port error: {}", message))
+}
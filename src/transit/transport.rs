@@ -3,6 +3,8 @@
 use super::{ConnectionType, TransitConnection, TransitHandshakeError, TransitInfo};
 #[cfg(not(target_family = "wasm"))]
 use super::{DirectHint, StunError};
+#[cfg(not(target_family = "wasm"))]
+use crate::proxy::Proxy;
 
 #[cfg(not(target_family = "wasm"))]
 use async_std::net::TcpStream;
@@ -29,6 +31,16 @@ pub(super) trait TransitTransportRx: AsyncRead + std::any::Any + Unpin + Send {
 
     /// Helper method: read a four bytes length prefix then the appropriate number of bytes
     async fn read_transit_message(&mut self) -> Result<Vec<u8>, std::io::Error> {
+        let mut buffer = Vec::new();
+        self.read_transit_message_into(&mut buffer).await?;
+        Ok(buffer)
+    }
+
+    /// Like [`read_transit_message`](Self::read_transit_message), but reuse the buffer's allocation
+    async fn read_transit_message_into(
+        &mut self,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), std::io::Error> {
         // 1. read 4 bytes from the stream. This represents the length of the encrypted packet.
         let length = {
             let mut length_arr: [u8; 4] = [0; 4];
@@ -36,15 +48,10 @@ pub(super) trait TransitTransportRx: AsyncRead + std::any::Any + Unpin + Send {
             u32::from_be_bytes(length_arr) as usize
         };
 
-        // 2. read that many bytes into an array (or a vector?)
-        let mut buffer = Vec::with_capacity(length);
-        let len = self.take(length as u64).read_to_end(&mut buffer).await?;
-        use std::io::{Error, ErrorKind};
-        ensure!(
-            len == length,
-            Error::new(ErrorKind::UnexpectedEof, "failed to read whole message")
-        );
-        Ok(buffer)
+        // 2. read that many bytes into the buffer
+        buffer.clear();
+        buffer.resize(length, 0);
+        self.read_exact(buffer).await
     }
 }
 
@@ -250,16 +257,61 @@ pub(super) async fn connect_tcp_direct(
 pub(super) async fn connect_tcp_relay(
     host: DirectHint,
     name: Option<String>,
+    proxy: Option<Arc<Proxy>>,
 ) -> Result<TransitConnection, TransitHandshakeError> {
     tracing::debug!("Connecting to relay {}", host);
-    let socket = TcpStream::connect((host.hostname.as_str(), host.port))
-        .err_into::<TransitHandshakeError>()
-        .await?;
+    let socket = match proxy {
+        Some(proxy) => proxy.connect(&host.hostname, host.port).await?,
+        None => {
+            TcpStream::connect((host.hostname.as_str(), host.port))
+                .err_into::<TransitHandshakeError>()
+                .await?
+        },
+    };
     tracing::debug!("Connected to {}!", host);
 
     wrap_tcp_connection(socket, ConnectionType::Relay { name })
 }
 
+/* Connect to a peer through Tor, which can also reach onion services */
+#[cfg(not(target_family = "wasm"))]
+pub(super) async fn connect_tor_direct(
+    hint: DirectHint,
+    tor: Arc<Proxy>,
+) -> Result<TransitConnection, TransitHandshakeError> {
+    tracing::debug!("Connecting to {} through Tor", hint);
+    let socket = tor.connect(&hint.hostname, hint.port).await?;
+    tracing::debug!("Connected to {}!", hint);
+
+    wrap_tcp_connection(socket, ConnectionType::Direct)
+}
+
+/** Whether the hint may be reachable from the internet, i.e. it is a host name or a public IP address */
+#[cfg(not(target_family = "wasm"))]
+pub(super) fn is_routable(hint: &DirectHint) -> bool {
+    let ip = match IpAddr::try_from(hint) {
+        Ok(IpAddr::V6(v6)) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        Ok(ip) => ip,
+        Err(_) => return true,
+    };
+    match ip {
+        IpAddr::V4(v4) => {
+            !(v4.is_private() || v4.is_loopback() || v4.is_link_local() || v4.is_unspecified())
+        },
+        IpAddr::V6(v6) => {
+            /* Unique local (fc00::/7) and link local (fe80::/10) addresses */
+            let prefix = v6.segments()[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || prefix & 0xfe00 == 0xfc00
+                || prefix & 0xffc0 == 0xfe80)
+        },
+    }
+}
+
 #[cfg(target_family = "wasm")]
 pub(super) async fn connect_ws_relay(
     url: url::Url,
@@ -277,6 +329,7 @@ pub(super) async fn connect_ws_relay(
         transit,
         TransitInfo {
             conn_type: ConnectionType::Relay { name },
+            connections: 1,
         },
     ))
 }
@@ -300,6 +353,7 @@ pub(super) fn wrap_tcp_connection(
         peer_addr: socket
             .peer_addr()
             .expect("Internal error: socket must be IP"),
+        connections: 1,
     };
 
     Ok((Box::new(socket), info))